@@ -0,0 +1,59 @@
+import { toRawRequest, fromRawResponse, readBody, asyncCall } from "./raw_http.js";
+
+/**
+ * A namespace of actors, bound to a name declared in the app configuration.
+ */
+export class ActorNamespace {
+    /**
+     *
+     * @param {string} name
+     */
+    constructor(name) {
+        this.name = name;
+    }
+
+    /**
+     * Returns a stub for the actor with the given id. The actor is created on its first request.
+     *
+     * @param {string} id
+     * @returns {ActorStub}
+     */
+    get(id) {
+        if(typeof(id) != "string" || !id.length) {
+            throw new TypeError("actor id must be a non-empty string");
+        }
+        return new ActorStub(this.name, id);
+    }
+}
+
+class ActorStub {
+    /**
+     *
+     * @param {string} namespace
+     * @param {string} id
+     */
+    constructor(namespace, id) {
+        this.namespace = namespace;
+        this.id = id;
+    }
+
+    /**
+     * Sends a request to the actor. All requests to an actor are handled by the same instance.
+     *
+     * @param {Request | string} input
+     * @param {Object} init
+     * @returns {Promise<Response>}
+     */
+    async fetch(input, init) {
+        let req = new Request(input, init);
+        let body = await readBody(req);
+        let [res, buffers] = await asyncCall({
+            ActorFetch: {
+                namespace: this.namespace,
+                id: this.id,
+                request: toRawRequest(req),
+            }
+        }, [body]);
+        return fromRawResponse(res, buffers[0]);
+    }
+}
@@ -0,0 +1,92 @@
+import { toRawHeaders, toRawRequest, fromRawResponse, readBody, asyncCall } from "./raw_http.js";
+
+class Cache {
+    /**
+     *
+     * @param {string} name
+     */
+    constructor(name) {
+        this._name = name;
+    }
+
+    /**
+     * Returns the cached response for a request, or `undefined`.
+     *
+     * Only GET requests match. Request headers named by the `Vary` header of a cached response
+     * must have the same values as in the request it was stored for.
+     *
+     * @param {Request | string} request
+     * @returns {Promise<Response | undefined>}
+     */
+    async match(request) {
+        let [res, buffers] = await asyncCall({
+            CacheMatch: {
+                cache: this._name,
+                request: toRawRequest(request),
+            }
+        }, []);
+        if(!res) {
+            return undefined;
+        }
+        return fromRawResponse(res, buffers[0]);
+    }
+
+    /**
+     * Stores a response. This reads the body of `response`.
+     *
+     * The response is only stored if it has a positive `max-age` or `s-maxage` in `Cache-Control`
+     * and isn't `no-store`, `no-cache` or `private`. Resolves to whether the response was stored.
+     *
+     * @param {Request | string} request
+     * @param {Response} response
+     * @returns {Promise<boolean>}
+     */
+    async put(request, response) {
+        let raw = toRawRequest(request);
+        if(raw.method.toUpperCase() != "GET") {
+            throw new TypeError("cache: only GET requests can be cached");
+        }
+        let body = await readBody(response);
+        let [stored] = await asyncCall({
+            CachePut: {
+                cache: this._name,
+                request: raw,
+                response: { status: response.status, headers: toRawHeaders(response.headers) },
+            }
+        }, [body]);
+        return stored;
+    }
+
+    /**
+     * Removes all responses stored for the URL of a request.
+     *
+     * @param {Request | string} request
+     * @returns {Promise<boolean>}
+     */
+    async delete(request) {
+        let [deleted] = await asyncCall({
+            CacheDelete: {
+                cache: this._name,
+                request: toRawRequest(request),
+            }
+        }, []);
+        return deleted;
+    }
+}
+
+class CacheStorage {
+    constructor() {
+        this.default = new Cache("");
+    }
+
+    /**
+     *
+     * @param {string} name
+     * @returns {Promise<Cache>}
+     */
+    async open(name) {
+        return new Cache("named:" + name);
+    }
+}
+
+export const caches = new CacheStorage();
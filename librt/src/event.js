@@ -0,0 +1,53 @@
+export class ExtendableEvent {
+    /**
+     * 
+     * @param {string} type 
+     */
+    constructor(type) {
+        this.type = type;
+        this._waitUntilResults = [];
+    }
+
+    /**
+     * Extends the lifetime of the event until `promise` settles.
+     * 
+     * @param {Promise} promise 
+     */
+    waitUntil(promise) {
+        // Handled right away, so that a rejection isn't reported as unhandled while we are still
+        // waiting for something else, e.g. a slow `respondWith()`.
+        this._waitUntilResults.push(Promise.resolve(promise).then(
+            () => ({ ok: true }),
+            e => ({ ok: false, reason: e }),
+        ));
+    }
+
+    /**
+     * Waits for all `waitUntil()` promises.
+     *
+     * @returns {Promise<boolean>} Whether all of them were fulfilled.
+     */
+    async _settle() {
+        let ok = true;
+
+        // Promises may be added while we are waiting.
+        while(this._waitUntilResults.length) {
+            let results = this._waitUntilResults;
+            this._waitUntilResults = [];
+            for(let result of await Promise.all(results)) {
+                if(!result.ok) {
+                    console.error(this.type + " event exception: " + result.reason);
+                    ok = false;
+                }
+            }
+        }
+        return ok;
+    }
+
+    async _finish() {
+        await this._settle();
+        _callServiceWrapper({
+            Sync: "Done",
+        }, [])
+    }
+}
@@ -0,0 +1,245 @@
+// MessagePack encoding of host calls and their results.
+//
+// Values are mapped like `JSON.stringify` does, so the host sees the same shapes as before:
+// `undefined` and function properties are skipped, `toJSON()` is respected, `NaN` and infinities
+// become `null`, and binary data is encoded as a plain object. Large buffers are passed to the host
+// separately instead.
+
+const encoder = new TextEncoder();
+const decoder = new TextDecoder();
+
+class Writer {
+    constructor() {
+        this.buf = new Uint8Array(256);
+        this.view = new DataView(this.buf.buffer);
+        this.pos = 0;
+    }
+
+    reserve(n) {
+        if(this.pos + n <= this.buf.length) return;
+        let size = this.buf.length * 2;
+        while(size < this.pos + n) size *= 2;
+        let buf = new Uint8Array(size);
+        buf.set(this.buf.subarray(0, this.pos));
+        this.buf = buf;
+        this.view = new DataView(buf.buffer);
+    }
+
+    u8(x) {
+        this.reserve(1);
+        this.buf[this.pos++] = x;
+    }
+
+    u16(x) {
+        this.reserve(2);
+        this.view.setUint16(this.pos, x);
+        this.pos += 2;
+    }
+
+    u32(x) {
+        this.reserve(4);
+        this.view.setUint32(this.pos, x);
+        this.pos += 4;
+    }
+
+    bytes(x) {
+        this.reserve(x.length);
+        this.buf.set(x, this.pos);
+        this.pos += x.length;
+    }
+
+    // Writes a header with a short form below `fixLimit`, and 8/16/32-bit length forms.
+    header(len, fixMarker, fixLimit, marker8, marker16, marker32) {
+        if(len < fixLimit) {
+            this.u8(fixMarker | len);
+        } else if(len < 0x100 && marker8 !== null) {
+            this.u8(marker8);
+            this.u8(len);
+        } else if(len < 0x10000) {
+            this.u8(marker16);
+            this.u16(len);
+        } else {
+            this.u8(marker32);
+            this.u32(len);
+        }
+    }
+
+    number(x) {
+        if(!Number.isFinite(x)) {
+            this.u8(0xc0);
+        } else if(!Number.isInteger(x)) {
+            this.reserve(9);
+            this.buf[this.pos++] = 0xcb;
+            this.view.setFloat64(this.pos, x);
+            this.pos += 8;
+        } else if(x >= 0 && x < 0x80) {
+            this.u8(x);
+        } else if(x < 0 && x >= -32) {
+            this.u8(x & 0xff);
+        } else if(x >= 0 && x <= 0xffffffff) {
+            this.u8(0xce);
+            this.u32(x);
+        } else if(x < 0 && x >= -0x80000000) {
+            this.reserve(5);
+            this.buf[this.pos++] = 0xd2;
+            this.view.setInt32(this.pos, x);
+            this.pos += 4;
+        } else if(Number.isSafeInteger(x)) {
+            this.reserve(9);
+            this.buf[this.pos++] = x >= 0 ? 0xcf : 0xd3;
+            this.view.setBigInt64(this.pos, BigInt(x));
+            this.pos += 8;
+        } else {
+            this.reserve(9);
+            this.buf[this.pos++] = 0xcb;
+            this.view.setFloat64(this.pos, x);
+            this.pos += 8;
+        }
+    }
+
+    value(x) {
+        if(x !== null && typeof(x) == "object" && typeof(x.toJSON) == "function") {
+            x = x.toJSON();
+        }
+        switch(typeof(x)) {
+            case "boolean":
+                this.u8(x ? 0xc3 : 0xc2);
+                return;
+            case "number":
+                this.number(x);
+                return;
+            case "string": {
+                let data = encoder.encode(x);
+                this.header(data.length, 0xa0, 32, 0xd9, 0xda, 0xdb);
+                this.bytes(data);
+                return;
+            }
+            case "object":
+                break;
+            default:
+                this.u8(0xc0);
+                return;
+        }
+        if(x === null) {
+            this.u8(0xc0);
+        } else if(Array.isArray(x)) {
+            this.header(x.length, 0x90, 16, null, 0xdc, 0xdd);
+            for(let item of x) {
+                this.value(item);
+            }
+        } else {
+            let keys = Object.keys(x).filter(k => {
+                let t = typeof(x[k]);
+                return t != "undefined" && t != "function" && t != "symbol";
+            });
+            this.header(keys.length, 0x80, 16, null, 0xde, 0xdf);
+            for(let k of keys) {
+                this.value(k);
+                this.value(x[k]);
+            }
+        }
+    }
+}
+
+/**
+ * Encodes a value into a `Uint8Array`.
+ */
+export function encode(value) {
+    let w = new Writer();
+    w.value(value);
+    return w.buf.subarray(0, w.pos);
+}
+
+class Reader {
+    constructor(data) {
+        this.buf = data instanceof ArrayBuffer ? new Uint8Array(data) : data;
+        this.view = new DataView(this.buf.buffer, this.buf.byteOffset, this.buf.byteLength);
+        this.pos = 0;
+    }
+
+    take(n) {
+        if(this.pos + n > this.buf.length) {
+            throw new TypeError("msgpack: unexpected end of data");
+        }
+        let pos = this.pos;
+        this.pos += n;
+        return pos;
+    }
+
+    str(len) {
+        let pos = this.take(len);
+        return decoder.decode(this.buf.subarray(pos, pos + len));
+    }
+
+    bin(len) {
+        let pos = this.take(len);
+        return this.buf.slice(pos, pos + len);
+    }
+
+    array(len) {
+        let output = new Array(len);
+        for(let i = 0; i < len; i++) {
+            output[i] = this.value();
+        }
+        return output;
+    }
+
+    map(len) {
+        let output = {};
+        for(let i = 0; i < len; i++) {
+            let k = this.value();
+            // Like `JSON.parse`, a `__proto__` key is an own property instead of the prototype.
+            Object.defineProperty(output, k, {
+                value: this.value(),
+                writable: true,
+                enumerable: true,
+                configurable: true,
+            });
+        }
+        return output;
+    }
+
+    value() {
+        let m = this.buf[this.take(1)];
+        if(m < 0x80) return m;
+        if(m >= 0xe0) return m - 0x100;
+        if(m >= 0xa0 && m < 0xc0) return this.str(m & 0x1f);
+        if(m >= 0x90 && m < 0xa0) return this.array(m & 0x0f);
+        if(m >= 0x80 && m < 0x90) return this.map(m & 0x0f);
+        let v = this.view;
+        switch(m) {
+            case 0xc0: return null;
+            case 0xc2: return false;
+            case 0xc3: return true;
+            case 0xc4: return this.bin(v.getUint8(this.take(1)));
+            case 0xc5: return this.bin(v.getUint16(this.take(2)));
+            case 0xc6: return this.bin(v.getUint32(this.take(4)));
+            case 0xca: return v.getFloat32(this.take(4));
+            case 0xcb: return v.getFloat64(this.take(8));
+            case 0xcc: return v.getUint8(this.take(1));
+            case 0xcd: return v.getUint16(this.take(2));
+            case 0xce: return v.getUint32(this.take(4));
+            case 0xcf: return Number(v.getBigUint64(this.take(8)));
+            case 0xd0: return v.getInt8(this.take(1));
+            case 0xd1: return v.getInt16(this.take(2));
+            case 0xd2: return v.getInt32(this.take(4));
+            case 0xd3: return Number(v.getBigInt64(this.take(8)));
+            case 0xd9: return this.str(v.getUint8(this.take(1)));
+            case 0xda: return this.str(v.getUint16(this.take(2)));
+            case 0xdb: return this.str(v.getUint32(this.take(4)));
+            case 0xdc: return this.array(v.getUint16(this.take(2)));
+            case 0xdd: return this.array(v.getUint32(this.take(4)));
+            case 0xde: return this.map(v.getUint16(this.take(2)));
+            case 0xdf: return this.map(v.getUint32(this.take(4)));
+            default:
+                throw new TypeError("msgpack: unsupported marker " + m);
+        }
+    }
+}
+
+/**
+ * Decodes a value from an `ArrayBuffer` or `Uint8Array`.
+ */
+export function decode(data) {
+    return new Reader(data).value();
+}
@@ -0,0 +1,62 @@
+import { asyncCall } from "./raw_http.js";
+
+/**
+ * A binding to a queue the app sends messages to, declared in the app configuration.
+ */
+export class QueueProducer {
+    /**
+     *
+     * @param {string} name
+     */
+    constructor(name) {
+        this.name = name;
+    }
+
+    /**
+     * Appends a message to the queue. The body must be serializable with `JSON.stringify`.
+     *
+     * @param {any} body
+     * @returns {Promise<string>} The id of the message.
+     */
+    async send(body) {
+        let text = JSON.stringify(body);
+        if(text === undefined) {
+            throw new TypeError("queue message body is not serializable");
+        }
+        let [id, _] = await asyncCall({
+            QueueSend: {
+                queue: this.name,
+            }
+        }, [new TextEncoder().encode(text)]);
+        return id;
+    }
+}
+
+/**
+ * A message delivered in a "queue" event.
+ */
+export class QueueMessage {
+    /**
+     *
+     * @param {Object} raw
+     */
+    constructor(raw) {
+        this.id = raw.id;
+        this.timestamp = new Date(raw.timestamp);
+        this.attempts = raw.attempts;
+        try {
+            this.body = JSON.parse(raw.body);
+        } catch(e) {
+            // Not sent through `QueueProducer.send`.
+            this.body = raw.body;
+        }
+        this._retry = false;
+    }
+
+    /**
+     * Delivers this message again later instead of acknowledging it.
+     */
+    retry() {
+        this._retry = true;
+    }
+}
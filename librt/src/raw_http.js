@@ -0,0 +1,95 @@
+// Conversions between `Request`/`Response` and the request and response objects of the host.
+
+/**
+ * @param {Headers} headers
+ * @returns {Object.<string, string[]>}
+ */
+export function toRawHeaders(headers) {
+    let raw = {};
+    for(let [k, v] of headers.entries()) {
+        k = k.toLowerCase();
+        if(!raw[k]) raw[k] = [];
+        raw[k].push(v);
+    }
+    return raw;
+}
+
+/**
+ * Converts a `Request` or a URL to the request object of the host. The body is not included.
+ *
+ * @param {Request | string} request
+ * @returns {Object}
+ */
+export function toRawRequest(request) {
+    if(typeof(request) == "string" || request instanceof URL) {
+        return { method: "GET", url: String(request), headers: {} };
+    }
+    return { method: request.method, url: request.url, headers: toRawHeaders(request.headers) };
+}
+
+/**
+ * @param {Object} res Response object of the host.
+ * @param {ArrayBuffer} body
+ * @returns {Response}
+ */
+export function fromRawResponse(res, body) {
+    let headers = new Headers(
+        Object.keys(res.headers)
+            .map(k => res.headers[k].map(v => [k, v]))
+            .flat()
+    );
+    return new Response(body, { status: res.status, headers: headers });
+}
+
+/**
+ * Reads the body of a `Request` or `Response` in full.
+ *
+ * @param {Request | Response} message
+ * @returns {Promise<ArrayBuffer>}
+ */
+export async function readBody(message) {
+    if(!message._streamBody) {
+        return await message.arrayBuffer();
+    }
+    let chunks = [];
+    let size = 0;
+    for await (let chunk of message._streamBody) {
+        if(typeof(chunk) == "string") {
+            chunk = new TextEncoder().encode(chunk);
+        } else if(chunk instanceof ArrayBuffer) {
+            chunk = new Uint8Array(chunk);
+        }
+        chunks.push(chunk);
+        size += chunk.byteLength;
+    }
+    let out = new Uint8Array(size);
+    let offset = 0;
+    for(let chunk of chunks) {
+        out.set(chunk, offset);
+        offset += chunk.byteLength;
+    }
+    return out.buffer;
+}
+
+/**
+ * Makes an async host call and resolves to `[result, buffers]`.
+ *
+ * @param {Object} call
+ * @param {Array} buffers
+ * @returns {Promise<Array>}
+ */
+export function asyncCall(call, buffers) {
+    return new Promise((resolve, reject) => {
+        _callServiceWrapper({
+            Async: call,
+        }, buffers, (result, buffers) => {
+            if(result.Err) {
+                reject(new Error(result.Err));
+            } else if(result.Ok.Err) {
+                reject(new Error(result.Ok.Err));
+            } else {
+                resolve([result.Ok.Ok, buffers]);
+            }
+        });
+    });
+}
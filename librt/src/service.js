@@ -0,0 +1,34 @@
+import { toRawRequest, fromRawResponse, readBody, asyncCall } from "./raw_http.js";
+
+/**
+ * A binding to another app, declared in the app configuration.
+ */
+export class ServiceBinding {
+    /**
+     *
+     * @param {string} name
+     */
+    constructor(name) {
+        this.name = name;
+    }
+
+    /**
+     * Sends a request to the bound app. The request doesn't leave the cluster, and the bound app
+     * sees the caller in `event.caller`.
+     *
+     * @param {Request | string} input
+     * @param {Object} init
+     * @returns {Promise<Response>}
+     */
+    async fetch(input, init) {
+        let req = new Request(input, init);
+        let body = await readBody(req);
+        let [res, buffers] = await asyncCall({
+            ServiceFetch: {
+                service: this.name,
+                request: toRawRequest(req),
+            }
+        }, [body]);
+        return fromRawResponse(res, buffers[0]);
+    }
+}
@@ -1,44 +1,279 @@
 import * as workerFetch from "worker-fetch";
+import { ReadableStream } from "./streams.js";
+import * as msgpack from "./msgpack.js";
+import { ActorNamespace } from "./actor.js";
+import { ServiceBinding } from "./service.js";
+import { QueueProducer, QueueMessage } from "./queue.js";
+import { ExtendableEvent } from "./event.js";
+
+const MAX_RESPONSE_BODY_CHUNK_SIZE = 1048576;
+
+/**
+ * Handlers exported by an ES module entrypoint, and the environment passed to them.
+ */
+let moduleHandlers = null;
+let moduleEnv = null;
+let bindings = {};
 
 class Console {
     constructor() {
 
     }
 
-    log(text) {
+    debug(...args) {
+        this._write("debug", args);
+    }
+
+    info(...args) {
+        this._write("info", args);
+    }
+
+    log(...args) {
+        this._write("info", args);
+    }
+
+    warn(...args) {
+        this._write("warn", args);
+    }
+
+    error(...args) {
+        this._write("error", args);
+    }
+
+    /**
+     * 
+     * @param {string} level 
+     * @param {any[]} args 
+     */
+    _write(level, args) {
         _callServiceWrapper({
             Sync: {
-                Log: "" + text
+                Log: {
+                    level: level,
+                    message: args.map(formatLogArg).join(" "),
+                    args: args.map(toLogValue),
+                }
             }
         }, []);
     }
 }
 
-class FetchEvent {
+/**
+ * Formats a console argument as text. Objects are formatted as JSON.
+ * 
+ * @param {any} x 
+ * @returns {string}
+ */
+function formatLogArg(x) {
+    if(typeof(x) == "string") return x;
+    if(x instanceof Error) return x.stack || (x.name + ": " + x.message);
+    if(typeof(x) == "function") return "[Function: " + (x.name || "anonymous") + "]";
+    if(x !== null && typeof(x) == "object") {
+        try {
+            return JSON.stringify(x, logReplacer());
+        } catch(e) {
+            // Fall through.
+        }
+    }
+    return String(x);
+}
+
+/**
+ * Converts a console argument to a value that can be stored as JSON.
+ * 
+ * @param {any} x 
+ * @returns {any}
+ */
+function toLogValue(x) {
+    if(x === null || typeof(x) == "string" || typeof(x) == "boolean") return x;
+    if(typeof(x) == "number") return isFinite(x) ? x : String(x);
+    if(x instanceof Error) return { name: x.name, message: x.message, stack: x.stack || null };
+    if(typeof(x) == "object") {
+        try {
+            return JSON.parse(JSON.stringify(x, logReplacer()));
+        } catch(e) {
+            // Fall through.
+        }
+    }
+    return formatLogArg(x);
+}
+
+/**
+ * Returns a `JSON.stringify` replacer that breaks reference cycles and keeps values JSON can't
+ * represent as text.
+ */
+function logReplacer() {
+    let seen = new WeakSet();
+    return (_key, value) => {
+        if(typeof(value) == "bigint") return value.toString() + "n";
+        if(typeof(value) == "function") return formatLogArg(value);
+        if(typeof(value) == "symbol") return value.toString();
+        if(value instanceof Error) return { name: value.name, message: value.message, stack: value.stack || null };
+        if(value !== null && typeof(value) == "object") {
+            if(seen.has(value)) return "[Circular]";
+            seen.add(value);
+        }
+        return value;
+    };
+}
+
+class Response extends workerFetch.Response {
+    /**
+     * 
+     * @param {any} body 
+     * @param {Object} init 
+     */
+    constructor(body, init) {
+        let isStream = body instanceof ReadableStream;
+        super(isStream ? null : body, init);
+        this._streamBody = isStream ? body : null;
+    }
+}
+
+class Request extends workerFetch.Request {
+    /**
+     * 
+     * @param {any} input 
+     * @param {Object} init 
+     */
+    constructor(input, init) {
+        let isStream = !!init && init.body instanceof ReadableStream;
+        super(input, isStream ? Object.assign({}, init, { body: null }) : init);
+        this._streamBody = isStream ? init.body : (input && input._streamBody) || null;
+    }
+
+    get body() {
+        return this._streamBody || super.body;
+    }
+
+    async arrayBuffer() {
+        if(!this._streamBody) {
+            return super.arrayBuffer();
+        }
+        let chunks = [];
+        let size = 0;
+        for await (let chunk of this._streamBody) {
+            chunks.push(chunk);
+            size += chunk.byteLength;
+        }
+        let out = new Uint8Array(size);
+        let offset = 0;
+        for(let chunk of chunks) {
+            out.set(chunk, offset);
+            offset += chunk.byteLength;
+        }
+        return out.buffer;
+    }
+
+    async text() {
+        if(!this._streamBody) {
+            return super.text();
+        }
+        return new TextDecoder().decode(await this.arrayBuffer());
+    }
+
+    async json() {
+        if(!this._streamBody) {
+            return super.json();
+        }
+        return JSON.parse(await this.text());
+    }
+}
+
+/**
+ * Returns a `ReadableStream` that reads the streaming body of the current request.
+ * 
+ * @returns {ReadableStream}
+ */
+function requestBodyStream() {
+    return new ReadableStream({
+        pull(controller) {
+            return new Promise((resolve, reject) => {
+                _callServiceWrapper({
+                    Async: "ReadRequestBody",
+                }, [], (result, buffers) => {
+                    if(result.Err) {
+                        reject(new Error(result.Err));
+                    } else if(result.Ok.Err) {
+                        reject(new Error(result.Ok.Err));
+                    } else {
+                        if(result.Ok.Ok) {
+                            controller.enqueue(new Uint8Array(buffers[0]));
+                        } else {
+                            controller.close();
+                        }
+                        resolve();
+                    }
+                });
+            });
+        }
+    });
+}
+
+/**
+ * Returns the `ReadableStream` body of a response, or null if the body is not a stream.
+ * 
+ * Bodies of responses from `fetch` are already buffered and are not treated as streams.
+ * 
+ * @param {workerFetch.Response} res 
+ * @returns {ReadableStream}
+ */
+function streamingBody(res) {
+    return res._streamBody || null;
+}
+
+/**
+ * Writes a chunk of the streaming response body. `null` ends the body.
+ * 
+ * @param {ArrayBufferView?} chunk 
+ * @returns {Promise<void>}
+ */
+function writeResponseBody(chunk) {
+    return new Promise((resolve, reject) => {
+        _callServiceWrapper({
+            Async: "WriteResponseBody",
+        }, chunk ? [chunk] : [], (result) => {
+            if(result.Err) {
+                reject(new Error(result.Err));
+            } else if(result.Ok.Err) {
+                reject(new Error(result.Ok.Err));
+            } else {
+                resolve();
+            }
+        });
+    });
+}
+
+class FetchEvent extends ExtendableEvent {
     /**
      * 
-     * @param {string} type 
      * @param {Object} request 
+     * @param {Object | null} actor The actor handling this request, as `{ namespace, id }`.
+     * @param {Object | null} caller The worker that sent this request, as `{ appid, depth }`.
      */
-    constructor(request) {
-        this.type = "fetch";
+    constructor(request, actor, caller) {
+        super("fetch");
         this.request = request;
+        this.actor = actor;
+        this.caller = caller;
     }
 
     async respondWith(res) {
         try {
             await this._respondWith(res);
         } catch(e) {
-            console.log("respondWith exception: " + e);
-            await this._respondWith(new Response("caught exception when handling request", { status: 500 }));
+            // Surfaces to the runtime as an unhandled rejection, with the original stack.
+            this._finish();
+            throw e;
         }
+        await this._finish();
     }
 
     async _respondWith(res) {
         if(res instanceof Promise) {
             res = await res;
         }
-        if(!(res instanceof Response)) {
+        if(!(res instanceof workerFetch.Response)) {
             throw new TypeError("respondWith: expecting a Response");
         }
 
@@ -54,21 +289,122 @@ class FetchEvent {
             headers[k].push(v);
         }
 
-        let body = await res.arrayBuffer();
+        let stream = streamingBody(res);
+        if(stream) {
+            _callServiceWrapper({
+                Sync: {
+                    SendFetchResponse: {
+                        status: res.status,
+                        headers: headers,
+                        body: { Stream: "" },
+                    }
+                }
+            }, []);
+            try {
+                for await (let chunk of stream) {
+                    if(typeof(chunk) == "string") {
+                        chunk = new TextEncoder().encode(chunk);
+                    } else if(chunk instanceof ArrayBuffer) {
+                        chunk = new Uint8Array(chunk);
+                    }
+                    for(let i = 0; i < chunk.byteLength; i += MAX_RESPONSE_BODY_CHUNK_SIZE) {
+                        await writeResponseBody(chunk.subarray(i, i + MAX_RESPONSE_BODY_CHUNK_SIZE));
+                    }
+                }
+                await writeResponseBody(null);
+            } finally {
+                // Closing before the end of the body aborts the response.
+                _callServiceWrapper({
+                    Sync: "CloseResponseBody",
+                }, []);
+            }
+        } else {
+            let body = await res.arrayBuffer();
+            _callServiceWrapper({
+                Sync: {
+                    SendFetchResponse: {
+                        status: res.status,
+                        headers: headers,
+                    }
+                }
+            }, [body]);
+        }
+    }
+}
+
+class ScheduledEvent extends ExtendableEvent {
+    /**
+     * 
+     * @param {number} scheduledTime 
+     * @param {string} cron 
+     */
+    constructor(scheduledTime, cron) {
+        super("scheduled");
+        this.scheduledTime = scheduledTime;
+        this.cron = cron;
+    }
+}
+
+class QueueEvent extends ExtendableEvent {
+    /**
+     * 
+     * @param {string} queue 
+     * @param {QueueMessage[]} messages 
+     */
+    constructor(queue, messages) {
+        super("queue");
+        this.queue = queue;
+        this.messages = messages;
+        this._failed = false;
+    }
+
+    /**
+     * Delivers all messages of this batch again later.
+     */
+    retryAll() {
+        for(let msg of this.messages) {
+            msg.retry();
+        }
+    }
+
+    async _finish() {
+        // A failed handler retries the whole batch.
+        let ok = await this._settle() && !this._failed;
+        let retry = this.messages
+            .filter(msg => !ok || msg._retry)
+            .map(msg => msg.id);
         _callServiceWrapper({
             Sync: {
-                SendFetchResponse: {
-                    status: res.status,
-                    headers: headers,
-                }
+                SendQueueResult: { retry: retry },
             }
-        }, [body]);
+        }, []);
         _callServiceWrapper({
             Sync: "Done",
         }, [])
     }
 }
 
+class PromiseRejectionEvent {
+    /**
+     * 
+     * @param {Promise} promise 
+     * @param {any} reason 
+     */
+    constructor(promise, reason) {
+        this.type = "unhandledrejection";
+        this.promise = promise;
+        this.reason = reason;
+        this.defaultPrevented = false;
+    }
+
+    /**
+     * Marks the rejection as handled, so that it isn't reported as an error of the current task.
+     */
+    preventDefault() {
+        this.defaultPrevented = true;
+    }
+}
+
 /**
  * @type {Object.<string, Object[]>}
  */
@@ -183,10 +519,11 @@ export function clearInterval(id) {
 /**
  * 
  * @param {Object} ev 
+ * @param {ArrayBuffer} bodyBuffer Binary body of a fetch request, passed separately from the event.
  */
-export function _dispatchEvent(ev) {
+export function _dispatchEvent(ev, bodyBuffer) {
     let ty = Object.keys(ev)[0];
-    console.log("event type: " + ty);
+    console.debug("event type: " + ty);
     switch(ty) {
         case "Fetch": {
             let rawReq = ev[ty].request;
@@ -198,22 +535,67 @@ export function _dispatchEvent(ev) {
             );
             
             let body = null;
-            if(rawReq.body && rawReq.body.Binary.length) {
-                body = new Uint8Array(rawReq.body.Binary).buffer;
+            if(rawReq.body.Stream !== undefined) {
+                body = requestBodyStream();
+            } else if(bodyBuffer && bodyBuffer.byteLength) {
+                body = bodyBuffer;
             }
 
-            let req = new workerFetch.Request(rawReq.url, {
+            let req = new Request(rawReq.url, {
                 method: rawReq.method,
                 headers: headers,
                 body: body,
             });
-            let targetEvent = new FetchEvent(req);
+            let targetEvent = new FetchEvent(req, ev[ty].actor, rawReq.caller);
+            try {
+                if(moduleHandlers) {
+                    if(!moduleHandlers.fetch) {
+                        throw new TypeError("module has no fetch handler");
+                    }
+                    targetEvent.respondWith(moduleHandlers.fetch(req, moduleEnv, targetEvent));
+                } else {
+                    dispatchEvent(targetEvent);
+                }
+            } catch(e) {
+                console.error("dispatchEvent exception: " + e);
+                targetEvent.respondWith(new Response("caught exception when dispatching request", { status: 500 }));
+            }
+            break;
+        }
+        case "Scheduled": {
+            let rawEvent = ev[ty];
+            let targetEvent = new ScheduledEvent(rawEvent.scheduled_time, rawEvent.cron);
             try {
-                dispatchEvent(targetEvent);
+                if(moduleHandlers) {
+                    if(moduleHandlers.scheduled) {
+                        targetEvent.waitUntil(moduleHandlers.scheduled(targetEvent, moduleEnv, targetEvent));
+                    }
+                } else {
+                    dispatchEvent(targetEvent);
+                }
             } catch(e) {
-                console.log("dispatchEvent exception: " + e);
-                targetEvent._respondWith(new Response("caught exception when dispatching request", { status: 500 }));
+                console.error("dispatchEvent exception: " + e);
             }
+            targetEvent._finish();
+            break;
+        }
+        case "Queue": {
+            let rawBatch = ev[ty];
+            let targetEvent = new QueueEvent(rawBatch.queue, rawBatch.messages.map(x => new QueueMessage(x)));
+            try {
+                if(moduleHandlers) {
+                    if(!moduleHandlers.queue) {
+                        throw new TypeError("module has no queue handler");
+                    }
+                    targetEvent.waitUntil(moduleHandlers.queue(targetEvent, moduleEnv, targetEvent));
+                } else {
+                    dispatchEvent(targetEvent);
+                }
+            } catch(e) {
+                console.error("dispatchEvent exception: " + e);
+                targetEvent._failed = true;
+            }
+            targetEvent._finish();
             break;
         }
         default: {
@@ -222,6 +604,56 @@ export function _dispatchEvent(ev) {
     }
 }
 
+/**
+ * Called by the runtime for each promise rejected without a handler.
+ * 
+ * @param {Promise} promise 
+ * @param {any} reason 
+ * @returns {boolean} Whether a listener handled the rejection.
+ */
+export function _dispatchUnhandledRejection(promise, reason) {
+    let event = new PromiseRejectionEvent(promise, reason);
+    try {
+        dispatchEvent(event);
+    } catch(e) {
+        console.error("unhandledrejection listener exception: " + e);
+    }
+    return event.defaultPrevented;
+}
+
+/**
+ * Registers the default export of an ES module entrypoint as the event handlers.
+ * 
+ * @param {Object} handlers 
+ * @param {Object.<string, string>} env 
+ */
+export function _registerModuleHandlers(handlers, env) {
+    moduleHandlers = handlers;
+    moduleEnv = Object.assign({}, env, bindings);
+}
+
+/**
+ * Creates the bindings declared in the app configuration. They are exposed as globals, and in the
+ * `env` of module handlers.
+ *
+ * @param {Object} raw
+ * @param {string[]} raw.actors Names of actor namespaces.
+ * @param {string[]} raw.services Names of service bindings.
+ * @param {string[]} raw.queues Names of queue producer bindings.
+ */
+export function _installBindings(raw) {
+    for(let name of raw.actors) {
+        bindings[name] = new ActorNamespace(name);
+    }
+    for(let name of raw.services) {
+        bindings[name] = new ServiceBinding(name);
+    }
+    for(let name of raw.queues) {
+        bindings[name] = new QueueProducer(name);
+    }
+    Object.assign(global, bindings);
+}
+
 export function getFileFromBundle(name) {
     return _callServiceWrapper({
         Sync: {
@@ -240,21 +672,20 @@ export const crypto = {
 };
 
 export const kv = require("./kv.js").kv;
+export const caches = require("./cache.js").caches;
 
 export const console = new Console();
-export const Request = workerFetch.Request;
-export const Response = workerFetch.Response;
+export { Request, Response, ReadableStream };
 export const Headers = workerFetch.Headers;
 export const fetch = workerFetch.fetch;
 
 export function _callServiceWrapper(cmd, buffers, cb) {
-    let serialized = JSON.stringify(cmd);
-    return _callService(serialized, buffers, cb);
+    return _callService(msgpack.encode(cmd), buffers, cb);
 }
 
 export function _callService(cmd, buffers, cb) {
     let wrappedCb = function(data, ...args) {
-        return cb(JSON.parse(new TextDecoder().decode(data)), ...args);
+        return cb(msgpack.decode(data), ...args);
     };
     return _rt_callService(cmd, buffers, cb ? wrappedCb : null);
 }
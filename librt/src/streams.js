@@ -0,0 +1,152 @@
+// A minimal `ReadableStream`, enough for producing streaming response bodies.
+
+class ReadableStreamDefaultController {
+    constructor(stream) {
+        this._stream = stream;
+    }
+
+    enqueue(chunk) {
+        let s = this._stream;
+        if(s._closed) {
+            throw new TypeError("enqueue: stream is closed");
+        }
+        if(s._pendingReads.length) {
+            s._pendingReads.shift().resolve({ done: false, value: chunk });
+        } else {
+            s._queue.push(chunk);
+        }
+    }
+
+    close() {
+        let s = this._stream;
+        s._closed = true;
+        if(!s._queue.length) {
+            for(let r of s._pendingReads) {
+                r.resolve({ done: true, value: undefined });
+            }
+            s._pendingReads = [];
+        }
+    }
+
+    error(e) {
+        let s = this._stream;
+        s._closed = true;
+        s._error = e;
+        s._queue = [];
+        for(let r of s._pendingReads) {
+            r.reject(e);
+        }
+        s._pendingReads = [];
+    }
+}
+
+class ReadableStreamDefaultReader {
+    constructor(stream) {
+        this._stream = stream;
+    }
+
+    /**
+     * @returns {Promise<{done: boolean, value: any}>}
+     */
+    read() {
+        let s = this._stream;
+        if(s._error !== undefined) {
+            return Promise.reject(s._error);
+        }
+        if(s._queue.length) {
+            let value = s._queue.shift();
+            s._pull();
+            return Promise.resolve({ done: false, value: value });
+        }
+        if(s._closed) {
+            return Promise.resolve({ done: true, value: undefined });
+        }
+        let p = new Promise((resolve, reject) => {
+            s._pendingReads.push({ resolve, reject });
+        });
+        s._pull();
+        return p;
+    }
+
+    cancel(reason) {
+        return this._stream.cancel(reason);
+    }
+
+    releaseLock() {
+        this._stream._locked = false;
+    }
+}
+
+export class ReadableStream {
+    /**
+     *
+     * @param {Object} source
+     */
+    constructor(source) {
+        this._source = source || {};
+        this._queue = [];
+        this._pendingReads = [];
+        this._closed = false;
+        this._error = undefined;
+        this._locked = false;
+        this._pulling = false;
+        this._controller = new ReadableStreamDefaultController(this);
+
+        if(this._source.start) {
+            Promise.resolve(this._source.start(this._controller))
+                .then(() => this._pull(), e => this._controller.error(e));
+        } else {
+            Promise.resolve().then(() => this._pull());
+        }
+    }
+
+    get locked() {
+        return this._locked;
+    }
+
+    getReader() {
+        if(this._locked) {
+            throw new TypeError("getReader: stream is locked");
+        }
+        this._locked = true;
+        return new ReadableStreamDefaultReader(this);
+    }
+
+    cancel(reason) {
+        this._closed = true;
+        this._queue = [];
+        if(this._source.cancel) {
+            return Promise.resolve(this._source.cancel(reason));
+        }
+        return Promise.resolve();
+    }
+
+    // Pulls from the source when a reader is waiting.
+    _pull() {
+        if(!this._source.pull || this._pulling || this._closed || !this._pendingReads.length) {
+            return;
+        }
+        this._pulling = true;
+        Promise.resolve(this._source.pull(this._controller))
+            .then(() => {
+                this._pulling = false;
+                this._pull();
+            }, e => {
+                this._pulling = false;
+                this._controller.error(e);
+            });
+    }
+
+    async *[Symbol.asyncIterator]() {
+        let reader = this.getReader();
+        try {
+            while(true) {
+                let { done, value } = await reader.read();
+                if(done) return;
+                yield value;
+            }
+        } finally {
+            reader.releaseLock();
+        }
+    }
+}
@@ -25,4 +25,512 @@ export function digest(algorithm, data) {
             }
         });
     });
-}
\ No newline at end of file
+}
+
+// Each `CryptoKey` owns a reference to its key on the host, dropped when the object is collected.
+const keyRegistry = new FinalizationRegistry(handle => {
+    callCrypto({ ReleaseKey: handle }, []);
+});
+
+class CryptoKey {
+    constructor(handle, type, algorithm, extractable, usages) {
+        keyRegistry.register(this, handle);
+        this._handle = handle;
+        this.type = type;
+        this.algorithm = algorithm;
+        this.extractable = extractable;
+        this.usages = usages;
+    }
+}
+
+function normalizeAlgorithm(algorithm) {
+    if(typeof(algorithm) == "string") {
+        algorithm = { name: algorithm };
+    }
+    if(!algorithm || typeof(algorithm.name) != "string") {
+        throw new TypeError("bad algorithm");
+    }
+    return Object.assign({}, algorithm, { name: algorithm.name.toUpperCase() });
+}
+
+function normalizeHash(hash) {
+    let name = typeof(hash) == "string" ? hash : (hash && hash.name);
+    let targetHash = DIGEST_MAP[typeof(name) == "string" ? name.toUpperCase() : name];
+    if(!targetHash) {
+        throw new TypeError("bad hash algorithm");
+    }
+    return { name: name.toUpperCase(), target: targetHash };
+}
+
+function checkKey(key, algorithm, usage) {
+    if(!(key instanceof CryptoKey)) {
+        throw new TypeError("expecting a CryptoKey");
+    }
+    if(key.algorithm.name.toUpperCase() != algorithm.name) {
+        throw new Error("key algorithm mismatch");
+    }
+    if(!key.usages.includes(usage)) {
+        throw new Error("key usages do not include '" + usage + "'");
+    }
+}
+
+function runAsync(f) {
+    return new Promise((resolve, reject) => {
+        queueMicrotask(() => {
+            try {
+                resolve(f());
+            } catch(e) {
+                reject(e);
+            }
+        });
+    });
+}
+
+function callCrypto(call, buffers) {
+    return _callServiceWrapper({
+        Sync: {
+            Crypto: call,
+        }
+    }, buffers);
+}
+
+const BASE64URL_ALPHABET = "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+function base64UrlEncode(buffer) {
+    let bytes = new Uint8Array(buffer);
+    let output = "";
+    for(let i = 0; i < bytes.length; i += 3) {
+        let n = (bytes[i] << 16) | ((bytes[i + 1] || 0) << 8) | (bytes[i + 2] || 0);
+        let chars = Math.min(bytes.length - i, 3) + 1;
+        for(let j = 0; j < chars; j++) {
+            output += BASE64URL_ALPHABET[(n >> (18 - j * 6)) & 63];
+        }
+    }
+    return output;
+}
+
+function base64UrlDecode(text) {
+    text = text.replace(/=+$/, "");
+    let output = new Uint8Array(Math.floor(text.length * 3 / 4));
+    let n = 0, bits = 0, pos = 0;
+    for(let c of text) {
+        let v = BASE64URL_ALPHABET.indexOf(c);
+        if(v < 0) {
+            throw new TypeError("bad base64url string");
+        }
+        n = ((n << 6) | v) & 0xffffff;
+        bits += 6;
+        if(bits >= 8) {
+            bits -= 8;
+            output[pos++] = (n >> bits) & 255;
+        }
+    }
+    return output;
+}
+
+/**
+ * Returns the host algorithm and the `algorithm` property of a secret key.
+ * 
+ * @param {Object} algorithm 
+ * @param {number} length Key length in bits.
+ */
+function secretKeyAlgorithm(algorithm, length) {
+    switch(algorithm.name) {
+        case "HMAC": {
+            let hash = normalizeHash(algorithm.hash);
+            return {
+                target: { Hmac: hash.target },
+                info: { name: "HMAC", hash: { name: hash.name }, length: length },
+            };
+        }
+        case "AES-GCM":
+            return { target: "AesGcm", info: { name: "AES-GCM", length: length } };
+        case "AES-CBC":
+            return { target: "AesCbc", info: { name: "AES-CBC", length: length } };
+        case "PBKDF2":
+            return { target: "Pbkdf2", info: { name: "PBKDF2" } };
+        case "HKDF":
+            return { target: "Hkdf", info: { name: "HKDF" } };
+        default:
+            throw new Error("unsupported algorithm: " + algorithm.name);
+    }
+}
+
+const EC_CURVES = {
+    "P-256": { target: "P256", hash: "SHA-256" },
+    "P-384": { target: "P384", hash: "SHA-384" },
+};
+
+/**
+ * Returns the host algorithm and the `algorithm` property of an asymmetric key, or `null` for
+ * secret key algorithms.
+ * 
+ * @param {Object} algorithm 
+ */
+function asymmetricKeyAlgorithm(algorithm) {
+    switch(algorithm.name) {
+        case "ECDSA": {
+            let curve = EC_CURVES[algorithm.namedCurve];
+            if(!curve) {
+                throw new Error("unsupported curve: " + algorithm.namedCurve);
+            }
+            return {
+                target: { Ecdsa: curve.target },
+                info: { name: "ECDSA", namedCurve: algorithm.namedCurve },
+            };
+        }
+        case "ED25519":
+            return { target: "Ed25519", info: { name: "Ed25519" } };
+        case "RSASSA-PKCS1-V1_5": {
+            let hash = normalizeHash(algorithm.hash);
+            return {
+                target: { RsaPkcs1: hash.target },
+                info: { name: "RSASSA-PKCS1-v1_5", hash: { name: hash.name } },
+            };
+        }
+        case "RSA-PSS": {
+            let hash = normalizeHash(algorithm.hash);
+            return {
+                target: { RsaPss: hash.target },
+                info: { name: "RSA-PSS", hash: { name: hash.name } },
+            };
+        }
+        case "ECDH":
+            if(algorithm.namedCurve != "P-256") {
+                throw new Error("unsupported curve: " + algorithm.namedCurve);
+            }
+            return {
+                target: { Ecdh: "P256" },
+                info: { name: "ECDH", namedCurve: "P-256" },
+            };
+        case "X25519":
+            return { target: { Ecdh: "X25519" }, info: { name: "X25519" } };
+        default:
+            return null;
+    }
+}
+
+function importAsymmetricKey(format, keyData, alg, extractable, keyUsages) {
+    let hostFormat, buffers, type;
+    switch(format) {
+        case "raw":
+            hostFormat = "Raw";
+            buffers = [keyData];
+            type = "public";
+            break;
+        case "spki":
+            hostFormat = "Spki";
+            buffers = [keyData];
+            type = "public";
+            break;
+        case "pkcs8":
+            hostFormat = "Pkcs8";
+            buffers = [keyData];
+            type = "private";
+            break;
+        case "jwk": {
+            if(!keyData || typeof(keyData.kty) != "string") {
+                throw new TypeError("importKey: bad JWK");
+            }
+            let jwk = { kty: keyData.kty };
+            for(let field of ["crv", "x", "y", "d", "n", "e"]) {
+                if(typeof(keyData[field]) == "string") {
+                    jwk[field] = keyData[field];
+                }
+            }
+            if(alg.info.namedCurve && jwk.crv != alg.info.namedCurve) {
+                throw new Error("importKey: JWK curve mismatch");
+            }
+            hostFormat = { Jwk: jwk };
+            buffers = [];
+            type = jwk.d === undefined ? "public" : "private";
+            break;
+        }
+        default:
+            throw new Error("importKey: unsupported format: " + format);
+    }
+    let handle = callCrypto({
+        ImportKey: { algorithm: alg.target, format: hostFormat, extractable: extractable },
+    }, buffers);
+    return new CryptoKey(handle, type, alg.info, extractable, keyUsages);
+}
+
+function jwkAlgorithm(info) {
+    switch(info.name) {
+        case "HMAC":
+            return "HS" + info.hash.name.slice("SHA-".length);
+        case "AES-GCM":
+            return "A" + info.length + "GCM";
+        case "AES-CBC":
+            return "A" + info.length + "CBC";
+    }
+}
+
+export function importKey(format, keyData, algorithm, extractable, keyUsages) {
+    return runAsync(() => {
+        algorithm = normalizeAlgorithm(algorithm);
+        extractable = !!extractable;
+        let asymmetric = asymmetricKeyAlgorithm(algorithm);
+        if(asymmetric) {
+            return importAsymmetricKey(format, keyData, asymmetric, extractable, keyUsages);
+        }
+        if((algorithm.name == "PBKDF2" || algorithm.name == "HKDF") && (format != "raw" || extractable)) {
+            throw new Error("importKey: " + algorithm.name + " keys must be raw and non-extractable");
+        }
+        let raw;
+        switch(format) {
+            case "raw":
+                raw = keyData;
+                break;
+            case "jwk":
+                if(!keyData || keyData.kty != "oct" || typeof(keyData.k) != "string") {
+                    throw new TypeError("importKey: expecting a JWK of type 'oct'");
+                }
+                raw = base64UrlDecode(keyData.k);
+                break;
+            default:
+                throw new Error("importKey: unsupported format: " + format);
+        }
+        let alg = secretKeyAlgorithm(algorithm, raw.byteLength * 8);
+        if(format == "jwk" && keyData.alg && keyData.alg != jwkAlgorithm(alg.info)) {
+            throw new Error("importKey: JWK algorithm mismatch");
+        }
+        let handle = callCrypto({
+            ImportKey: { algorithm: alg.target, format: "Raw", extractable: extractable },
+        }, [raw]);
+        return new CryptoKey(handle, "secret", alg.info, extractable, keyUsages);
+    });
+}
+
+/**
+ * Returns the length in bits of a secret key to generate or derive.
+ */
+function secretKeyLength(algorithm) {
+    let length = algorithm.length;
+    if(algorithm.name == "HMAC" && length === undefined) {
+        // Defaults to the block size of the hash function.
+        let hash = normalizeHash(algorithm.hash);
+        length = (hash.target == "Sha384" || hash.target == "Sha512") ? 1024 : 512;
+    }
+    if(typeof(length) != "number") {
+        throw new TypeError("missing key length");
+    }
+    return length;
+}
+
+export function generateKey(algorithm, extractable, keyUsages) {
+    return runAsync(() => {
+        algorithm = normalizeAlgorithm(algorithm);
+        extractable = !!extractable;
+        let asymmetric = asymmetricKeyAlgorithm(algorithm);
+        if(asymmetric) {
+            if(!asymmetric.target.Ecdh) {
+                throw new Error("generateKey: unsupported algorithm: " + algorithm.name);
+            }
+            // Private ECDH keys never leave the host. The public key is imported back as a
+            // separate key.
+            let privateHandle = callCrypto({
+                GenerateKey: { algorithm: asymmetric.target, length: 0, extractable: false },
+            }, []);
+            let publicKey = callCrypto({ PublicKey: privateHandle }, []);
+            let publicHandle = callCrypto({
+                ImportKey: { algorithm: asymmetric.target, format: "Raw", extractable: true },
+            }, [publicKey]);
+            return {
+                privateKey: new CryptoKey(privateHandle, "private", asymmetric.info, false, keyUsages),
+                publicKey: new CryptoKey(publicHandle, "public", asymmetric.info, true, []),
+            };
+        }
+        let length = secretKeyLength(algorithm);
+        let alg = secretKeyAlgorithm(algorithm, length);
+        let handle = callCrypto({
+            GenerateKey: { algorithm: alg.target, length: length, extractable: extractable },
+        }, []);
+        return new CryptoKey(handle, "secret", alg.info, extractable, keyUsages);
+    });
+}
+
+export function exportKey(format, key) {
+    return runAsync(() => {
+        if(!(key instanceof CryptoKey)) {
+            throw new TypeError("expecting a CryptoKey");
+        }
+        if(!key.extractable) {
+            throw new Error("exportKey: key is not extractable");
+        }
+        if(key.type == "public") {
+            if(format != "raw") {
+                throw new Error("exportKey: public keys can only be exported as raw");
+            }
+            return callCrypto({ PublicKey: key._handle }, []);
+        }
+        if(key.type != "secret") {
+            throw new Error("exportKey: private keys cannot be exported");
+        }
+        let raw = callCrypto({ ExportKey: key._handle }, []);
+        switch(format) {
+            case "raw":
+                return raw;
+            case "jwk":
+                return {
+                    kty: "oct",
+                    k: base64UrlEncode(raw),
+                    alg: jwkAlgorithm(key.algorithm),
+                    ext: true,
+                    key_ops: key.usages,
+                };
+            default:
+                throw new Error("exportKey: unsupported format: " + format);
+        }
+    });
+}
+
+function cipherCall(algorithm, key, data) {
+    if(!algorithm.iv) {
+        throw new TypeError("missing iv");
+    }
+    let buffers = [data, algorithm.iv];
+    switch(algorithm.name) {
+        case "AES-GCM": {
+            if(algorithm.additionalData) {
+                buffers.push(algorithm.additionalData);
+            }
+            let tagLength = algorithm.tagLength === undefined ? 128 : algorithm.tagLength;
+            return [[key._handle, { AesGcm: { tag_length: tagLength } }], buffers];
+        }
+        case "AES-CBC":
+            return [[key._handle, "AesCbc"], buffers];
+        default:
+            throw new Error("unsupported algorithm: " + algorithm.name);
+    }
+}
+
+export function encrypt(algorithm, key, data) {
+    return runAsync(() => {
+        algorithm = normalizeAlgorithm(algorithm);
+        checkKey(key, algorithm, "encrypt");
+        let [call, buffers] = cipherCall(algorithm, key, data);
+        return callCrypto({ Encrypt: call }, buffers);
+    });
+}
+
+export function decrypt(algorithm, key, data) {
+    return runAsync(() => {
+        algorithm = normalizeAlgorithm(algorithm);
+        checkKey(key, algorithm, "decrypt");
+        let [call, buffers] = cipherCall(algorithm, key, data);
+        return callCrypto({ Decrypt: call }, buffers);
+    });
+}
+
+const HASH_LENGTHS = {
+    "Sha1": 20,
+    "Sha256": 32,
+    "Sha384": 48,
+    "Sha512": 64,
+};
+
+/**
+ * Returns the hash argument of a sign or verify call.
+ */
+function signatureHash(algorithm, key) {
+    switch(algorithm.name) {
+        case "ECDSA":
+            return normalizeHash(algorithm.hash).target;
+        case "RSA-PSS": {
+            let hashLength = HASH_LENGTHS[normalizeHash(key.algorithm.hash).target];
+            if(algorithm.saltLength !== hashLength) {
+                throw new Error("RSA-PSS: saltLength must be the length of the hash output");
+            }
+            return null;
+        }
+        default:
+            return null;
+    }
+}
+
+export function sign(algorithm, key, data) {
+    return runAsync(() => {
+        algorithm = normalizeAlgorithm(algorithm);
+        checkKey(key, algorithm, "sign");
+        if(key.type == "public") {
+            throw new Error("sign: expecting a private key");
+        }
+        let hash = signatureHash(algorithm, key);
+        return callCrypto({ Sign: [key._handle, hash] }, [data]);
+    });
+}
+
+export function verify(algorithm, key, signature, data) {
+    return runAsync(() => {
+        algorithm = normalizeAlgorithm(algorithm);
+        checkKey(key, algorithm, "verify");
+        let hash = signatureHash(algorithm, key);
+        return callCrypto({ Verify: [key._handle, hash] }, [signature, data]);
+    });
+}
+
+function deriveBitsCall(algorithm, baseKey, length, usage) {
+    algorithm = normalizeAlgorithm(algorithm);
+    checkKey(baseKey, algorithm, usage);
+    let params, buffers;
+    switch(algorithm.name) {
+        case "PBKDF2":
+            if(!algorithm.salt) {
+                throw new TypeError("PBKDF2: missing salt");
+            }
+            params = {
+                Pbkdf2: {
+                    hash: normalizeHash(algorithm.hash).target,
+                    iterations: algorithm.iterations,
+                },
+            };
+            buffers = [algorithm.salt];
+            break;
+        case "HKDF":
+            if(!algorithm.salt || !algorithm.info) {
+                throw new TypeError("HKDF: missing salt or info");
+            }
+            params = { Hkdf: { hash: normalizeHash(algorithm.hash).target } };
+            buffers = [algorithm.salt, algorithm.info];
+            break;
+        case "ECDH":
+        case "X25519": {
+            let peer = algorithm.public;
+            if(!(peer instanceof CryptoKey) || peer.type != "public") {
+                throw new TypeError("expecting a public CryptoKey");
+            }
+            if(peer.algorithm.name != baseKey.algorithm.name) {
+                throw new Error("public key algorithm mismatch");
+            }
+            params = { Ecdh: { public: peer._handle } };
+            buffers = [];
+            break;
+        }
+        default:
+            throw new Error("unsupported algorithm: " + algorithm.name);
+    }
+    return callCrypto({
+        DeriveBits: [baseKey._handle, params, length === undefined ? null : length],
+    }, buffers);
+}
+
+export function deriveBits(algorithm, baseKey, length) {
+    return runAsync(() => deriveBitsCall(algorithm, baseKey, length, "deriveBits"));
+}
+
+export function deriveKey(algorithm, baseKey, derivedKeyAlgorithm, extractable, keyUsages) {
+    return runAsync(() => {
+        let derivedAlgorithm = normalizeAlgorithm(derivedKeyAlgorithm);
+        let length = secretKeyLength(derivedAlgorithm);
+        let alg = secretKeyAlgorithm(derivedAlgorithm, length);
+        extractable = !!extractable;
+        let raw = deriveBitsCall(algorithm, baseKey, length, "deriveKey");
+        let handle = callCrypto({
+            ImportKey: { algorithm: alg.target, format: "Raw", extractable: extractable },
+        }, [raw]);
+        return new CryptoKey(handle, "secret", alg.info, extractable, keyUsages);
+    });
+}
+
+export { CryptoKey };
@@ -0,0 +1,76 @@
+// Checks how `waitUntil()` promises are awaited and reported.
+//
+// Run with `npm test`.
+
+import assert from "node:assert/strict";
+import test from "node:test";
+import { ExtendableEvent } from "../src/event.js";
+
+const sleep = ms => new Promise(resolve => setTimeout(resolve, ms));
+
+/**
+ * Runs `f` with host calls and error logs captured, and fails if a promise rejection goes
+ * unhandled meanwhile.
+ */
+async function withHost(f) {
+    let calls = [];
+    let errors = [];
+    let unhandled = [];
+    let onUnhandled = reason => unhandled.push(reason);
+    let consoleError = console.error;
+
+    globalThis._callServiceWrapper = (cmd, buffers) => calls.push(cmd);
+    // Node may print its own warnings here too.
+    console.error = msg => String(msg).includes("event exception") && errors.push(msg);
+    process.on("unhandledRejection", onUnhandled);
+    try {
+        await f();
+        // Unhandled rejections are reported once the microtask queue drains.
+        await sleep(10);
+    } finally {
+        process.off("unhandledRejection", onUnhandled);
+        console.error = consoleError;
+        delete globalThis._callServiceWrapper;
+    }
+    assert.deepEqual(unhandled, []);
+    return { calls, errors };
+}
+
+test("rejected waitUntil during a slow respondWith", async () => {
+    let { calls, errors } = await withHost(async () => {
+        let event = new ExtendableEvent("fetch");
+        event.waitUntil(Promise.reject(new Error("boom")));
+
+        // Stands in for a response that takes a while, as in `FetchEvent.respondWith()`.
+        await sleep(50);
+        await event._finish();
+    });
+    assert.deepEqual(errors, ["fetch event exception: Error: boom"]);
+    assert.deepEqual(calls, [{ Sync: "Done" }]);
+});
+
+test("settles promises added while waiting", async () => {
+    let event = new ExtendableEvent("scheduled");
+    let done = [];
+    let ok;
+    await withHost(async () => {
+        event.waitUntil(sleep(10).then(() => {
+            done.push(1);
+            event.waitUntil(sleep(10).then(() => done.push(2)));
+        }));
+        ok = await event._settle();
+    });
+    assert.equal(ok, true);
+    assert.deepEqual(done, [1, 2]);
+});
+
+test("non-promise values", async () => {
+    let ok;
+    await withHost(async () => {
+        let event = new ExtendableEvent("queue");
+        event.waitUntil(undefined);
+        event.waitUntil(1);
+        ok = await event._settle();
+    });
+    assert.equal(ok, true);
+});
@@ -0,0 +1,40 @@
+// Checks that host calls encoded with MessagePack have the same shapes as with JSON.
+//
+// Run with `npm test`.
+
+import assert from "node:assert/strict";
+import test from "node:test";
+import * as msgpack from "../src/msgpack.js";
+
+function roundTrip(value) {
+    return msgpack.decode(msgpack.encode(value));
+}
+
+function viaJson(value) {
+    return JSON.parse(JSON.stringify(value));
+}
+
+const VALUES = {
+    "scalars": [null, true, false, 0, -0, 1, -1, -32, -33, 127, 128, 65536, 2 ** 32, -(2 ** 31) - 1, 2 ** 53, 0.5, "", "héllo"],
+    "non-finite numbers": [NaN, Infinity, -Infinity, { x: NaN }],
+    "long strings and arrays": ["x".repeat(70000), new Array(70000).fill(1)],
+    "skipped properties": { a: undefined, b: () => 1, c: Symbol("c"), d: 1 },
+    "array holes": [undefined, () => 1, Symbol("x")],
+    "typed arrays": { u8: new Uint8Array([1, 2, 3]), f64: new Float64Array([0.5, NaN]), buf: new ArrayBuffer(4) },
+    "toJSON": { date: new Date(0), custom: { toJSON() { return [1, 2]; } } },
+    "__proto__ keys": JSON.parse('{"__proto__": {"polluted": true}, "a": {"__proto__": 1}}'),
+    "nested": { Async: { KvGet: { namespace: "ns", lock: false } } },
+};
+
+for(let [name, value] of Object.entries(VALUES)) {
+    test(name, () => {
+        assert.deepStrictEqual(roundTrip(value), viaJson(value));
+    });
+}
+
+test("__proto__ keys don't set the prototype", () => {
+    let output = roundTrip(JSON.parse('{"__proto__": {"polluted": true}}'));
+    assert.equal(Object.getPrototypeOf(output), Object.prototype);
+    assert.equal(output.polluted, undefined);
+    assert.deepEqual(Object.keys(output), ["__proto__"]);
+});
@@ -32,5 +32,5 @@ fi
 ssh "$REMOTE" "mkdir $TMP" || exit 1
 scp "$2" "$REMOTE:$TMP/config.toml" || exit 1
 scp "$3" "$REMOTE:$TMP/bundle.tar" || exit 1
-ssh "$REMOTE" "RUST_LOG=info rusty-workers-cli app --tikv-pd \"$TIKV_PD\" add-app $TMP/config.toml --bundle $TMP/bundle.tar"
+ssh "$REMOTE" "RUST_LOG=info rusty-workers-cli app --storage \"tikv://$TIKV_PD\" add-app $TMP/config.toml --bundle $TMP/bundle.tar"
 ssh "$REMOTE" "rm -r $TMP" || exit 1
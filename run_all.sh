@@ -39,7 +39,7 @@ cd ..
 
 ./target/release/rusty-workers-fetchd --rpc-listen 127.0.0.1:3000 &
 ./target/release/rusty-workers-runtime --rpc-listen 127.0.0.1:3001 \
-    --tikv-cluster 127.0.0.1:2379 \
+    --storage tikv://127.0.0.1:2379 \
     --max-num-of-instances 100 \
     --isolate-pool-size 200 \
     --execution-concurrency 20 \
@@ -47,7 +47,8 @@ cd ..
 ./target/release/rusty-workers-proxy \
     --fetch-service 127.0.0.1:3000 \
     --http-listen 0.0.0.0:3080 \
-    --tikv-cluster 127.0.0.1:2379 \
+    --scheduler-rpc-listen 127.0.0.1:3002 \
+    --storage tikv://127.0.0.1:2379 \
     --runtimes 127.0.0.1:3001 \
     --dropout-rate 0.0002 \
     --max-time-ms 2000 \
@@ -17,6 +17,9 @@ use tokio::io::AsyncReadExt;
 enum CliError {
     #[error("bad id128")]
     BadId128,
+
+    #[error("queue '{0}' is already consumed by app {1}")]
+    QueueConsumed(String, String),
 }
 
 #[derive(Debug, StructOpt)]
@@ -40,13 +43,27 @@ enum Cmd {
 
     /// App management.
     App {
-        /// TiKV PD address.
-        #[structopt(long, env = "TIKV_PD")]
-        tikv_pd: String,
+        /// Storage URL (`tikv://pd1,pd2`, `memory://` or `file://path`).
+        #[structopt(long, env = "RW_STORAGE")]
+        storage: String,
 
         #[structopt(subcommand)]
         op: AppCmd,
     },
+
+    /// Stream new log records from runtimes.
+    Tail {
+        /// Runtime service addresses, comma-separated.
+        #[structopt(long, env = "RUNTIMES")]
+        runtimes: String,
+
+        /// Only show records of this app.
+        appid: Option<String>,
+
+        /// Only show records at this level or above (debug, info, warn or error).
+        #[structopt(long)]
+        level: Option<LogLevel>,
+    },
 }
 
 #[derive(Debug, StructOpt)]
@@ -149,6 +166,10 @@ enum AppCmd {
         appid: String,
         #[structopt(long)]
         since: String,
+
+        /// Only show records at this level or above (debug, info, warn or error).
+        #[structopt(long)]
+        level: Option<LogLevel>,
     },
     #[structopt(name = "delete-logs")]
     DeleteLogs {
@@ -156,6 +177,10 @@ enum AppCmd {
         #[structopt(long)]
         before: String,
     },
+    #[structopt(name = "queue-depth")]
+    QueueDepth { queue: String },
+    #[structopt(name = "queue-redrive")]
+    QueueRedrive { queue: String },
 }
 
 #[derive(Debug, StructOpt)]
@@ -182,6 +207,24 @@ enum RuntimeCmd {
 
     #[structopt(name = "fetch")]
     Fetch { handle: String },
+
+    #[structopt(name = "scheduled")]
+    Scheduled {
+        handle: String,
+
+        #[structopt(long, default_value = "* * * * *")]
+        cron: String,
+    },
+
+    #[structopt(name = "code-cache-stats")]
+    CodeCacheStats,
+
+    #[structopt(name = "response-cache-stats")]
+    ResponseCacheStats,
+
+    /// Print a DevTools URL for debugging a worker.
+    #[structopt(name = "inspect")]
+    Inspect { handle: String },
 }
 
 #[tokio::main]
@@ -209,10 +252,19 @@ async fn main() -> Result<()> {
                                 max_time_ms: 50,
                                 max_io_concurrency: 10,
                                 max_io_per_request: 50,
+                                max_wait_until_ms: 5000,
                             },
                             fetch_service,
                             env: Default::default(),
                             kv_namespaces: Default::default(),
+                            bundle_id: Default::default(),
+                            wasm_modules: Default::default(),
+                            actor_namespaces: Default::default(),
+                            actor: None,
+                            actor_lease: None,
+                            services: Default::default(),
+                            queue_producers: Default::default(),
+                            scheduler_service: None,
                         }
                     };
                     let script = read_file_raw(&script).await?;
@@ -228,6 +280,19 @@ async fn main() -> Result<()> {
                         .await?;
                     println!("{}", serde_json::to_string(&result).unwrap());
                 }
+                RuntimeCmd::Inspect { handle } => {
+                    let worker_handle = WorkerHandle { id: handle };
+                    let result = client.inspect(make_context(), worker_handle).await??;
+                    println!("{}", result);
+                }
+                RuntimeCmd::CodeCacheStats => {
+                    let result = client.code_cache_stats(make_context()).await?;
+                    println!("{}", serde_json::to_string(&result).unwrap());
+                }
+                RuntimeCmd::ResponseCacheStats => {
+                    let result = client.response_cache_stats(make_context()).await?;
+                    println!("{}", serde_json::to_string(&result).unwrap());
+                }
                 RuntimeCmd::List => {
                     let result = client.list_workers(make_context()).await?;
                     println!("{}", serde_json::to_string(&result).unwrap());
@@ -238,10 +303,23 @@ async fn main() -> Result<()> {
                     let result = client.fetch(make_context(), worker_handle, req).await?;
                     println!("{}", serde_json::to_string(&result).unwrap());
                 }
+                RuntimeCmd::Scheduled { handle, cron } => {
+                    let worker_handle = WorkerHandle { id: handle };
+                    let event = ScheduledEventObject {
+                        scheduled_time: SystemTime::now()
+                            .duration_since(SystemTime::UNIX_EPOCH)?
+                            .as_millis() as i64,
+                        cron,
+                    };
+                    let result = client
+                        .scheduled(make_context(), worker_handle, event)
+                        .await?;
+                    println!("{}", serde_json::to_string(&result).unwrap());
+                }
             }
         }
-        Cmd::App { tikv_pd, op } => {
-            let client = KvClient::new(vec![tikv_pd]).await?;
+        Cmd::App { storage, op } => {
+            let client = KvClient::open(&storage).await?;
             match op {
                 AppCmd::AllRoutes => {
                     print!("[");
@@ -312,6 +390,8 @@ async fn main() -> Result<()> {
                 AppCmd::AddApp { config, bundle } => {
                     let config = read_file(&config).await?;
                     let mut config: AppConfig = toml::from_str(&config)?;
+                    config.validate()?;
+                    check_queue_consumers(&client, &config).await?;
                     let bundle = read_file_raw(&bundle).await?;
 
                     cleanup_previous_app(&client, &config.id).await?;
@@ -477,7 +557,11 @@ async fn main() -> Result<()> {
                     }
                     println!("{}", keys.len());
                 }
-                AppCmd::Logs { appid, since } => {
+                AppCmd::Logs {
+                    appid,
+                    since,
+                    level,
+                } => {
                     let now = SystemTime::now();
                     let since = now - parse_duration::parse(&since)?;
 
@@ -485,6 +569,10 @@ async fn main() -> Result<()> {
                     let mut first = true;
                     client
                         .log_range(&format!("app-{}", appid), since..now, |time, text| {
+                            let record = LogRecord::decode(text);
+                            if level.map(|x| record.level < x).unwrap_or(false) {
+                                return true;
+                            }
                             if first {
                                 first = false;
                             } else {
@@ -492,7 +580,10 @@ async fn main() -> Result<()> {
                             }
                             let item = serde_json::to_string(&serde_json::json!({
                                 "time": time,
-                                "text": text,
+                                "level": record.level,
+                                "request_id": record.request_id,
+                                "text": record.message,
+                                "args": record.args,
                             }))
                             .unwrap();
                             print!("{}", item);
@@ -508,6 +599,53 @@ async fn main() -> Result<()> {
                         .await?;
                     println!("OK");
                 }
+                AppCmd::QueueDepth { queue } => {
+                    let (pending, dead) = client.queue_depth(&queue).await?;
+                    println!(
+                        "{}",
+                        serde_json::to_string(&serde_json::json!({
+                            "pending": pending,
+                            "dead": dead,
+                        }))
+                        .unwrap()
+                    );
+                }
+                AppCmd::QueueRedrive { queue } => {
+                    let now = SystemTime::now()
+                        .duration_since(SystemTime::UNIX_EPOCH)?
+                        .as_millis() as i64;
+                    let count = client.queue_redrive(&queue, now).await?;
+                    println!("{}", count);
+                }
+            }
+        }
+        Cmd::Tail {
+            runtimes,
+            appid,
+            level,
+        } => {
+            let mut addrs: Vec<SocketAddr> = vec![];
+            for elem in runtimes.split(",") {
+                addrs.push(elem.parse()?);
+            }
+            let mut rx = rusty_workers::tail::tail_runtimes(addrs, appid, level);
+            while let Some(batch) = rx.recv().await {
+                if batch.lost > 0 {
+                    eprintln!("{} records lost", batch.lost);
+                }
+                for entry in batch.entries {
+                    let record = LogRecord::decode(&entry.record);
+                    let item = serde_json::to_string(&serde_json::json!({
+                        "appid": entry.appid,
+                        "timestamp": record.timestamp,
+                        "level": record.level,
+                        "request_id": record.request_id,
+                        "text": record.message,
+                        "args": record.args,
+                    }))
+                    .unwrap();
+                    println!("{}", item);
+                }
             }
         }
     }
@@ -534,6 +672,44 @@ fn make_context() -> tarpc::context::Context {
     current
 }
 
+/// Checks that no other app consumes the queues consumed by `config`.
+async fn check_queue_consumers(client: &KvClient, config: &AppConfig) -> Result<()> {
+    if config.queues.consumers.is_empty() {
+        return Ok(());
+    }
+
+    let mut appids = vec![];
+    client
+        .app_metadata_for_each(|appid| {
+            if appid != config.id.0 {
+                appids.push(appid.to_string());
+            }
+            true
+        })
+        .await?;
+    for appid in appids {
+        let other = match client.app_metadata_get(&appid).await? {
+            Some(x) => x,
+            None => continue,
+        };
+        let other: AppConfig = match serde_json::from_slice(&other) {
+            Ok(x) => x,
+            Err(_) => continue,
+        };
+        for consumer in &config.queues.consumers {
+            if other
+                .queues
+                .consumers
+                .iter()
+                .any(|x| x.queue == consumer.queue)
+            {
+                return Err(CliError::QueueConsumed(consumer.queue.clone(), appid).into());
+            }
+        }
+    }
+    Ok(())
+}
+
 async fn cleanup_previous_app(client: &KvClient, appid: &rusty_workers::app::AppId) -> Result<()> {
     if let Some(prev_md) = client.app_metadata_get(&appid.0).await? {
         if let Ok(prev_config) = serde_json::from_slice::<AppConfig>(&prev_md) {
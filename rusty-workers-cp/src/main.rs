@@ -11,19 +11,27 @@ use hyper::{
     Body, Request, Response, StatusCode,
 };
 use rusty_workers::kv::KvClient;
+use rusty_workers::types::LogRecord;
 use serde_json::json;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 use structopt::StructOpt;
 use thiserror::Error;
 use types::*;
 
 const MAX_REQUEST_BODY_SIZE: usize = 8 * 1024 * 1024;
 
+/// Interval between keep-alive comments on a log tail stream without records.
+const TAIL_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
 #[derive(Error, Debug)]
 enum CpError {
     #[error("request body too large")]
     RequestBodyTooLarge,
+
+    #[error("log tailing is not configured")]
+    TailDisabled,
 }
 
 #[derive(Debug, StructOpt, Clone)]
@@ -33,19 +41,27 @@ struct Opt {
     #[structopt(short = "l", long)]
     http_listen: SocketAddr,
 
-    /// TiKV PD addresses.
-    #[structopt(long, env = "TIKV_PD")]
-    tikv_pd: String,
+    /// Storage URL (`tikv://pd1,pd2`, `memory://` or `file://path`).
+    #[structopt(long, env = "RW_STORAGE")]
+    storage: String,
+
+    /// Runtime service addresses to tail logs from, comma-separated.
+    #[structopt(long, env = "RUNTIMES")]
+    runtimes: Option<String>,
 }
 
 struct Server {
-    config: Opt,
     kv: KvClient,
+    runtimes: Vec<SocketAddr>,
 }
 
 impl Server {
     async fn handle(self: Arc<Self>, request: Request<Body>) -> Result<Response<Body>> {
         let req_path = request.uri().path().to_string();
+        if req_path == "/v1/logs/tail" {
+            let query = request.uri().query().unwrap_or("").to_string();
+            return self.tail_logs(&query);
+        }
         let req_body = read_request_body(request).await?;
         match req_path.as_str() {
             "/v1/list_routes" => {
@@ -81,6 +97,74 @@ impl Server {
             }
         }
     }
+
+    /// Streams new log records as server-sent events, filtered by the `appid` and `level` query
+    /// parameters.
+    fn tail_logs(&self, query: &str) -> Result<Response<Body>> {
+        if self.runtimes.is_empty() {
+            return Err(CpError::TailDisabled.into());
+        }
+        let mut opt = TailLogsOpt::default();
+        for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+            match &*key {
+                "appid" => opt.appid = Some(value.into_owned()),
+                "level" => match value.parse() {
+                    Ok(x) => opt.level = Some(x),
+                    Err(e) => {
+                        let mut res = Response::new(Body::from(format!("{}", e)));
+                        *res.status_mut() = StatusCode::BAD_REQUEST;
+                        return Ok(res);
+                    }
+                },
+                _ => {}
+            }
+        }
+
+        let mut rx =
+            rusty_workers::tail::tail_runtimes(self.runtimes.clone(), opt.appid, opt.level);
+        let (mut body_tx, body) = Body::channel();
+        tokio::spawn(async move {
+            loop {
+                let chunk = match tokio::time::timeout(TAIL_KEEPALIVE_INTERVAL, rx.recv()).await {
+                    Ok(Some(batch)) => {
+                        let mut chunk = String::new();
+                        if batch.lost > 0 {
+                            chunk.push_str(&format!("event: lost\ndata: {}\n\n", batch.lost));
+                        }
+                        for entry in batch.entries {
+                            let record = LogRecord::decode(&entry.record);
+                            let data = json!({
+                                "appid": entry.appid,
+                                "timestamp": record.timestamp,
+                                "level": record.level,
+                                "request_id": record.request_id,
+                                "text": record.message,
+                                "args": record.args,
+                            });
+                            chunk.push_str(&format!("data: {}\n\n", data));
+                        }
+                        chunk
+                    }
+                    Ok(None) => break,
+                    Err(_) => ": keepalive\n\n".to_string(),
+                };
+
+                // Fails once the client has gone. Dropping `rx` then stops polling the runtimes.
+                if body_tx.send_data(chunk.into()).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut res = Response::new(body);
+        res.headers_mut().insert(
+            "content-type",
+            HeaderValue::from_static("text/event-stream"),
+        );
+        res.headers_mut()
+            .insert("cache-control", HeaderValue::from_static("no-cache"));
+        Ok(res)
+    }
 }
 
 #[tokio::main]
@@ -89,9 +173,16 @@ async fn main() -> Result<()> {
     rusty_workers::init();
     let opt = Opt::from_args();
 
+    let mut runtimes: Vec<SocketAddr> = Vec::new();
+    if let Some(ref list) = opt.runtimes {
+        for elem in list.split(",") {
+            runtimes.push(elem.parse()?);
+        }
+    }
+
     let server = Arc::new(Server {
-        kv: KvClient::new(opt.tikv_pd.split(",").collect()).await?,
-        config: opt.clone(),
+        kv: KvClient::open(&opt.storage).await?,
+        runtimes,
     });
 
     let make_svc = make_service_fn(move |_| {
@@ -1,3 +1,4 @@
+use rusty_workers::types::LogLevel;
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -22,3 +23,9 @@ pub struct DeleteRouteOpt {
     pub domain: String,
     pub path: String,
 }
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct TailLogsOpt {
+    pub appid: Option<String>,
+    pub level: Option<LogLevel>,
+}
@@ -11,6 +11,9 @@ const MAX_RESPONSE_BODY_SIZE: usize = 1048576 * 8; // 8M
 enum FetchError {
     #[error("response body too large")]
     ResponseBodyTooLarge,
+
+    #[error("stream request body is not supported")]
+    StreamRequestBody,
 }
 
 pub struct FetchState {
@@ -80,6 +83,7 @@ async fn run_fetch(state: &FetchState, req: RequestObject) -> Result<ResponseObj
 
     let body = match req.body {
         HttpBody::Binary(bytes) => Body::from(bytes),
+        HttpBody::Stream(_) => return Err(FetchError::StreamRequestBody.into()),
     };
     *target_req.body_mut() = Some(body);
 
@@ -13,6 +13,7 @@ use hyper::{
 use rand::Rng;
 use rusty_workers::app::AppConfig;
 use rusty_workers::kv::KvClient;
+use rusty_workers::types::{LogLevel, LogRecord};
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::{Duration, SystemTime};
@@ -44,9 +45,9 @@ struct Opt {
     #[structopt(short = "l", long)]
     http_listen: SocketAddr,
 
-    /// TiKV PD addresses.
-    #[structopt(long, env = "TIKV_PD")]
-    tikv_pd: String,
+    /// Storage URL (`tikv://pd1,pd2`, `memory://` or `file://path`).
+    #[structopt(long, env = "RW_STORAGE")]
+    storage: String,
 
     /// Authentication token, 128-bit base64.
     #[structopt(long, env = "RW_AUTH_TOKEN")]
@@ -105,6 +106,7 @@ impl Server {
             "/v1/add_app" => {
                 let opt: AddAppOpt = serde_json::from_slice(&req_body)?;
                 let mut config: AppConfig = opt.config;
+                config.validate()?;
                 let bundle = base64::decode(&opt.bundle_b64)?;
 
                 cleanup_previous_app(&self.kv, &config.id).await?;
@@ -154,15 +156,25 @@ impl Server {
                 #[derive(serde::Serialize)]
                 struct Item {
                     time: String,
+                    level: LogLevel,
+                    request_id: Option<String>,
                     text: String,
+                    args: Vec<serde_json::Value>,
                 }
                 let mut items = vec![];
 
                 self.kv
                     .log_range(&format!("app-{}", opt.appid), since..now, |time, text| {
+                        let record = LogRecord::decode(text);
+                        if opt.level.map(|x| record.level < x).unwrap_or(false) {
+                            return true;
+                        }
                         items.push(Item {
                             time: time.to_string(),
-                            text: text.to_string(),
+                            level: record.level,
+                            request_id: record.request_id,
+                            text: record.message,
+                            args: record.args,
                         });
                         if items.len() >= opt.limit as usize {
                             false
@@ -189,7 +201,7 @@ async fn main() -> Result<()> {
     let opt = Opt::from_args();
 
     let server = Arc::new(Server {
-        kv: KvClient::new(opt.tikv_pd.split(",").collect()).await?,
+        kv: KvClient::open(&opt.storage).await?,
         _config: opt.clone(),
         auth_token: rusty_workers::app::decode_id128(&opt.auth_token)
             .ok_or_else(|| CpError::BadId128)?,
@@ -1,4 +1,5 @@
 use rusty_workers::app::AppConfig;
+use rusty_workers::types::LogLevel;
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -40,6 +41,10 @@ pub struct LogsOpt {
     pub appid: String,
     pub since_secs: u64,
     pub limit: u32,
+
+    /// Only return records at this level or above.
+    #[serde(default)]
+    pub level: Option<LogLevel>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
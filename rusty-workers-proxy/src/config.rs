@@ -11,4 +11,5 @@ pub struct LocalConfig {
     pub dropout_rate: f32,
     pub route_cache_size: usize,
     pub app_cache_size: usize,
+    pub actor_lease_ms: u64,
 }
@@ -3,6 +3,7 @@ extern crate log;
 
 mod config;
 mod sched;
+mod server;
 
 use anyhow::Result;
 use once_cell::sync::OnceCell;
@@ -28,6 +29,18 @@ struct Opt {
     #[structopt(long, env = "RW_FETCH_SERVICE")]
     fetch_service: SocketAddr,
 
+    /// Scheduler RPC listen address, for requests from workers to actors and service bindings.
+    #[structopt(long, env = "RW_SCHEDULER_RPC_LISTEN")]
+    scheduler_rpc_listen: Option<SocketAddr>,
+
+    /// Address of the scheduler service as seen by runtimes. Defaults to the RPC listen address.
+    #[structopt(long, env = "RW_SCHEDULER_SERVICE")]
+    scheduler_service: Option<SocketAddr>,
+
+    /// Max RPC concurrency of the scheduler service.
+    #[structopt(long, env = "RW_SCHEDULER_RPC_CONCURRENCY", default_value = "1000")]
+    scheduler_rpc_concurrency: usize,
+
     /// Runtime service backends, comma-separated.
     #[structopt(long, env = "RUNTIMES")]
     runtimes: String,
@@ -48,6 +61,10 @@ struct Opt {
     #[structopt(long, env = "RW_MAX_IO_PER_REQUEST", default_value = "50")]
     max_io_per_request: u32,
 
+    /// Max time to run `waitUntil()` work after a response is sent, in milliseconds
+    #[structopt(long, env = "RW_MAX_WAIT_UNTIL_MS", default_value = "5000")]
+    max_wait_until_ms: u32,
+
     /// Max ready instances per app
     #[structopt(long, env = "RW_MAX_READY_INSTANCES_PER_APP", default_value = "50")]
     max_ready_instances_per_app: usize,
@@ -80,13 +97,18 @@ struct Opt {
     #[structopt(long, env = "RW_ROUTE_CACHE_SIZE", default_value = "1000")]
     pub route_cache_size: usize,
 
-    /// TiKV cluster.
-    #[structopt(long, env = "RW_TIKV_CLUSTER")]
-    pub tikv_cluster: String,
+    /// Storage URL (`tikv://pd1,pd2`, `memory://` or `file://path`).
+    #[structopt(long, env = "RW_STORAGE")]
+    pub storage: String,
 
     /// Size of app cache.
     #[structopt(long, env = "RW_APP_CACHE_SIZE", default_value = "100")]
     pub app_cache_size: usize,
+
+    /// Duration of actor ownership leases, in milliseconds. An actor whose runtime has gone away is
+    /// re-created after its lease expires.
+    #[structopt(long, env = "RW_ACTOR_LEASE_MS", default_value = "30000")]
+    pub actor_lease_ms: u64,
 }
 
 #[tokio::main]
@@ -101,7 +123,7 @@ async fn main() -> Result<()> {
         runtime_cluster.push(elem.parse()?);
     }
 
-    let kv_client = rusty_workers::kv::KvClient::new(opt.tikv_cluster.split(",").collect()).await?;
+    let kv_client = rusty_workers::kv::KvClient::open(&opt.storage).await?;
 
     SCHEDULER
         .set(sched::Scheduler::new(
@@ -111,10 +133,19 @@ async fn main() -> Result<()> {
                     max_time_ms: opt.max_time_ms,
                     max_io_concurrency: opt.max_io_concurrency,
                     max_io_per_request: opt.max_io_per_request,
+                    max_wait_until_ms: opt.max_wait_until_ms,
                 },
                 fetch_service: opt.fetch_service,
                 env: Default::default(),
                 kv_namespaces: Default::default(),
+                bundle_id: Default::default(),
+                wasm_modules: Default::default(),
+                actor_namespaces: Default::default(),
+                actor: None,
+                actor_lease: None,
+                services: Default::default(),
+                queue_producers: Default::default(),
+                scheduler_service: opt.scheduler_service.or(opt.scheduler_rpc_listen),
             },
             LocalConfig {
                 max_ready_instances_per_app: opt.max_ready_instances_per_app,
@@ -124,6 +155,7 @@ async fn main() -> Result<()> {
                 dropout_rate: opt.dropout_rate,
                 route_cache_size: opt.route_cache_size,
                 app_cache_size: opt.app_cache_size,
+                actor_lease_ms: opt.actor_lease_ms,
                 runtime_cluster,
             },
             kv_client,
@@ -139,6 +171,20 @@ async fn main() -> Result<()> {
         }
     });
 
+    if let Some(addr) = opt.scheduler_rpc_listen {
+        let concurrency = opt.scheduler_rpc_concurrency;
+        tokio::spawn(async move {
+            let result =
+                server::SchedulerServer::listen(addr, concurrency, || server::SchedulerServer {
+                    scheduler: SCHEDULER.get().unwrap().clone(),
+                })
+                .await;
+            if let Err(e) = result {
+                error!("scheduler rpc server failed: {:?}", e);
+            }
+        });
+    }
+
     let make_svc = make_service_fn(|_| async move {
         Ok::<_, hyper::Error>(service_fn(|req| async move {
             let scheduler = SCHEDULER.get().unwrap();
@@ -1,16 +1,20 @@
 use crate::config::*;
 use anyhow::Result;
+use chrono::{DateTime, TimeZone, Utc};
 use futures::StreamExt;
 use lru_time_cache::LruCache;
 use rand::distributions::{Distribution, Open01, WeightedIndex};
 use rand::Rng;
 use rusty_workers::app::*;
+use rusty_workers::cron::CronSchedule;
 use rusty_workers::kv::KvClient;
 use rusty_workers::rpc::RuntimeServiceClient;
 use rusty_workers::tarpc;
 use rusty_workers::types::*;
 use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::collections::VecDeque;
+use std::future::Future;
 use std::sync::atomic::{AtomicU16, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
@@ -18,6 +22,38 @@ use thiserror::Error;
 use tokio::sync::mpsc::{Receiver, Sender};
 use tokio::sync::{Mutex as AsyncMutex, RwLock as AsyncRwLock};
 
+/// Request bodies up to this size are sent to the worker in one piece. Larger bodies are
+/// streamed.
+const INLINE_REQUEST_BODY_SIZE: usize = 64 * 1024;
+
+/// Max size of a response body to a request from a worker, to an actor or through a service
+/// binding. Streamed bodies are read in full before being returned to the caller.
+const MAX_INTERNAL_RESPONSE_BODY_SIZE: usize = 16 * 1024 * 1024;
+
+/// Max number of times a tick of a cron trigger is claimed before it's given up.
+const MAX_CRON_ATTEMPTS: u32 = 3;
+
+/// How long an instance that was busy with a previous task is skipped for.
+const BUSY_INSTANCE_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Max number of busy instances skipped in a row before a request is given up.
+const MAX_BUSY_RETRIES: u32 = 5;
+
+/// Delay before trying another instance after the first busy one. Doubled for each one after.
+const BUSY_RETRY_DELAY: Duration = Duration::from_millis(20);
+
+/// Interval between polls of the queues consumed by apps.
+const QUEUE_POLL_INTERVAL_MS: u64 = 2000;
+
+/// Duration of the lease a proxy holds on a queue while delivering its messages.
+const QUEUE_LEASE_MS: i64 = 30000;
+
+/// Max number of batches delivered from one queue in a poll.
+const QUEUE_MAX_BATCHES_PER_POLL: usize = 10;
+
+/// Max delay before a failed message is delivered again.
+const QUEUE_MAX_RETRY_DELAY_MS: i64 = 300000;
+
 #[derive(Debug, Error)]
 pub enum SchedError {
     #[error("no available instance")]
@@ -31,6 +67,21 @@ pub enum SchedError {
 
     #[error("request failed after retries")]
     RequestFailedAfterRetries,
+
+    #[error("no such actor namespace")]
+    NoSuchActorNamespace,
+
+    #[error("actor unavailable")]
+    ActorUnavailable,
+
+    #[error("no such service binding")]
+    NoSuchService,
+
+    #[error("response body too large")]
+    InternalResponseTooLarge,
+
+    #[error("script exception")]
+    ScriptException,
 }
 
 pub struct Scheduler {
@@ -82,6 +133,9 @@ struct ReadyInstance {
     /// Last active time.
     last_active: Instant,
 
+    /// Set when the instance was busy with a previous task. It isn't picked again before then.
+    busy_until: Option<Instant>,
+
     // Worker handle.
     handle: WorkerHandle,
 
@@ -145,16 +199,39 @@ impl AppState {
         self.gc_ready_instances(scheduler).await;
     }
 
+    /// Pools back an instance that was busy with a previous task, skipping it for a while.
+    async fn pool_busy_instance(&self, scheduler: &Scheduler, mut inst: ReadyInstance) {
+        inst.busy_until = Some(Instant::now() + BUSY_INSTANCE_BACKOFF);
+        self.ready_instances.lock().await.push_back(inst);
+        self.gc_ready_instances(scheduler).await;
+    }
+
     async fn get_instance(&self, scheduler: &Scheduler) -> Result<ReadyInstance> {
         self.gc_ready_instances(scheduler).await;
-        if let Some(mut inst) = self.ready_instances.lock().await.pop_front() {
+        let now = Instant::now();
+        let mut ready = self.ready_instances.lock().await;
+        let index = ready
+            .iter()
+            .position(|x| x.busy_until.map(|t| t <= now).unwrap_or(true));
+        if let Some(mut inst) = index.and_then(|i| ready.remove(i)) {
+            drop(ready);
+            inst.busy_until = None;
             inst.update_last_active();
             return Ok(inst);
         }
-
-        let clients = scheduler.clients.read().await;
+        drop(ready);
 
         // No cached instance now. Create one.
+        self.spawn_instance(scheduler, self.config.clone()).await
+    }
+
+    /// Spawns a worker with `config` on a runtime picked by load.
+    async fn spawn_instance(
+        &self,
+        scheduler: &Scheduler,
+        config: WorkerConfiguration,
+    ) -> Result<ReadyInstance> {
+        let clients = scheduler.clients.read().await;
         if clients.len() == 0 {
             return Err(SchedError::NoAvailableInstance.into());
         }
@@ -183,13 +260,14 @@ impl AppState {
             .spawn_worker(
                 tarpc::context::current(),
                 self.id.0.clone(),
-                self.config.clone(),
+                config,
                 self.bundle.clone(),
             )
             .await??;
         Ok(ReadyInstance {
             rtid,
             last_active: Instant::now(),
+            busy_until: None,
             handle,
             client,
         })
@@ -245,6 +323,8 @@ impl Scheduler {
         let me3 = me.clone();
         let me4 = me.clone();
         let me5 = me.clone();
+        let me6 = me.clone();
+        let me7 = me.clone();
         tokio::spawn(async move {
             me2.lookup_route_background(lookup_route_rx).await;
         });
@@ -257,11 +337,17 @@ impl Scheduler {
         tokio::spawn(async move {
             me5.route_cache_gc_task().await;
         });
+        tokio::spawn(async move {
+            me6.cron_task().await;
+        });
+        tokio::spawn(async move {
+            me7.queue_task().await;
+        });
         me
     }
 
     pub async fn handle_request(
-        &self,
+        self: &Arc<Self>,
         mut req: hyper::Request<hyper::Body>,
     ) -> Result<hyper::Response<hyper::Body>> {
         // Rewrite host to remove port.
@@ -310,7 +396,6 @@ impl Scheduler {
         let method = req.method().as_str().to_string();
         let mut headers = BTreeMap::new();
         let url = format!("https://{}{}", host.split(":").nth(0).unwrap(), uri); // TODO: detect https
-        let mut full_body = vec![];
 
         for (k, v) in req.headers() {
             headers
@@ -319,60 +404,95 @@ impl Scheduler {
                 .push(v.to_str()?.to_string());
         }
 
-        let mut body_error: Result<()> = Ok(());
-        req.into_body()
-            .for_each(|bytes| {
-                match bytes {
-                    Ok(x) => {
-                        if full_body.len() + x.len()
-                            > self.local_config.max_request_body_size_bytes as usize
-                        {
-                            body_error = Err(SchedError::RequestBodyTooLarge.into());
-                        } else {
-                            full_body.extend_from_slice(&x);
-                        }
-                    }
-                    Err(e) => {
-                        body_error = Err(e.into());
+        // Small bodies are read in full. If the body doesn't fit, what has been read so far is
+        // kept with the rest of the body and streamed to the worker.
+        let mut body = req.into_body();
+        let mut full_body = vec![];
+        let mut body_ended = false;
+        while full_body.len() < INLINE_REQUEST_BODY_SIZE {
+            match body.next().await {
+                Some(x) => {
+                    let x = x?;
+                    if full_body.len() + x.len()
+                        > self.local_config.max_request_body_size_bytes as usize
+                    {
+                        return Err(SchedError::RequestBodyTooLarge.into());
                     }
-                };
-                futures::future::ready(())
-            })
-            .await;
-
-        body_error?;
+                    full_body.extend_from_slice(&x);
+                }
+                None => {
+                    body_ended = true;
+                    break;
+                }
+            }
+        }
+        let mut pending_body = if body_ended {
+            None
+        } else {
+            Some((std::mem::take(&mut full_body), body))
+        };
 
-        let target_req = RequestObject {
+        let mut target_req = RequestObject {
             headers,
             method,
             url,
             body: HttpBody::Binary(full_body),
+            caller: None,
         };
 
-        let mut app = None;
-
-        for _ in 0..3 {
-            app = self.apps.lock().await.get(&appid).cloned();
-            if app.is_some() {
-                break;
-            }
-
-            // Notify the worker thread
-            drop(self.lookup_app_tx.try_send(appid.clone()));
-
-            tokio::time::sleep(Duration::from_millis(500)).await;
-        }
+        let app = self.get_app(&appid).await?;
 
-        let app = app.ok_or(SchedError::NoRouteMapping)?;
+        // Gives the streamed body back if the worker didn't take it.
+        let mut returned_body = None;
 
         // Backend retries.
-        for _ in 0..3usize {
+        let mut attempts = 0usize;
+        let mut busy = 0u32;
+        while attempts < 3 {
+            attempts += 1;
+            // A streamed body can only be sent again if it was given back.
+            if let Some(rx) = returned_body.take() {
+                match rx.await {
+                    Ok(x) => {
+                        pending_body = Some(x);
+                        target_req.body = HttpBody::Binary(vec![]);
+                    }
+                    Err(_) => break,
+                }
+            }
+
             let mut instance = app.get_instance(self).await?;
             debug!(
                 "routing request {}{} to app {}, instance {}",
                 host, uri, appid.0, instance.rtid.0
             );
 
+            if pending_body.is_some() {
+                let stream = match instance
+                    .client
+                    .create_body_stream(tarpc::context::current())
+                    .await
+                {
+                    Ok(x) => x,
+                    Err(e) => {
+                        self.clients.write().await.remove(&instance.rtid);
+                        info!("network error for instance {}: {:?}", instance.rtid.0, e);
+                        continue;
+                    }
+                };
+                let (prefix, body) = pending_body.take().unwrap();
+                let (return_tx, return_rx) = tokio::sync::oneshot::channel();
+                tokio::spawn(self.clone().pump_request_body(
+                    instance.client.clone(),
+                    stream.clone(),
+                    prefix,
+                    body,
+                    return_tx,
+                ));
+                target_req.body = HttpBody::Stream(stream);
+                returned_body = Some(return_rx);
+            }
+
             let mut fetch_context = tarpc::context::current();
             fetch_context.deadline = std::time::SystemTime::now()
                 + Duration::from_millis(self.local_config.request_timeout_ms);
@@ -387,6 +507,8 @@ impl Scheduler {
                     // Network error. Drop this and select another instance.
                     self.clients.write().await.remove(&instance.rtid);
                     info!("network error for instance {}: {:?}", instance.rtid.0, e);
+                    self.close_request_body(&mut instance, &target_req.body)
+                        .await;
                     continue;
                 }
             };
@@ -398,11 +520,36 @@ impl Scheduler {
                     // Don't pool it back.
                     // Runtime would give us a 500 instead of an error when it is recoverable.
                     match e {
+                        ExecutionError::WorkerBusy => {
+                            // Busy instances don't count as attempts.
+                            self.close_request_body(&mut instance, &target_req.body)
+                                .await;
+                            app.pool_busy_instance(self, instance).await;
+                            if !backoff_busy(&mut busy).await {
+                                break;
+                            }
+                            attempts -= 1;
+                            continue;
+                        }
                         ExecutionError::NoSuchWorker => {
                             // Backend terminated our worker "unexpectedly".
                             // Re-select another instance.
+                            self.close_request_body(&mut instance, &target_req.body)
+                                .await;
                             continue;
                         }
+                        ExecutionError::ScriptThrowsException(ref exc)
+                        | ExecutionError::UnhandledRejection(ref exc) => {
+                            // The script would fail again on another instance.
+                            info!(
+                                "app {}: {}\n{}",
+                                appid.0,
+                                e,
+                                exc.stack.as_deref().unwrap_or("(no stack trace)")
+                            );
+                            app.pool_instance(self, instance).await;
+                            return Err(SchedError::ScriptException.into());
+                        }
                         _ => {
                             info!("execution error: {:?}", e);
                             // Don't attempt to recover otherwise.
@@ -416,12 +563,25 @@ impl Scheduler {
                 }
             };
 
-            // Pool it back.
-            app.pool_instance(self, instance).await;
-
             // Build response.
             let mut res = hyper::Response::new(match fetch_res.body {
-                HttpBody::Binary(bytes) => hyper::Body::from(bytes),
+                HttpBody::Binary(bytes) => {
+                    // Pool it back.
+                    app.pool_instance(self, instance).await;
+
+                    hyper::Body::from(bytes)
+                }
+                HttpBody::Stream(stream) => {
+                    // The instance is pooled back after the body is sent.
+                    let (sender, body) = hyper::Body::channel();
+                    tokio::spawn(self.clone().forward_body_stream(
+                        app.clone(),
+                        instance,
+                        stream,
+                        sender,
+                    ));
+                    body
+                }
             });
 
             *res.status_mut() = hyper::StatusCode::from_u16(fetch_res.status)?;
@@ -440,6 +600,171 @@ impl Scheduler {
         Err(SchedError::RequestFailedAfterRetries.into())
     }
 
+    /// Streams a request body from the client to the runtime.
+    ///
+    /// Each write returns when the worker asks for the next chunk, so the client is only read
+    /// as fast as the worker consumes the body.
+    ///
+    /// The first chunk is only accepted once a worker has taken the stream. If that fails, the
+    /// body is sent back through `return_tx` untouched, so that it can be sent to another worker.
+    async fn pump_request_body(
+        self: Arc<Self>,
+        mut client: RuntimeServiceClient,
+        stream: String,
+        prefix: Vec<u8>,
+        mut body: hyper::Body,
+        return_tx: tokio::sync::oneshot::Sender<(Vec<u8>, hyper::Body)>,
+    ) {
+        if let Err(e) = self
+            .write_body_chunk(&mut client, &stream, Some(prefix.clone()))
+            .await
+        {
+            debug!("request body stream {} not taken: {:?}", stream, e);
+            drop(return_tx.send((prefix, body)));
+            drop(
+                client
+                    .close_body_stream(tarpc::context::current(), stream)
+                    .await,
+            );
+            return;
+        }
+        drop(return_tx);
+
+        let result: Result<()> = async {
+            let mut total_size = prefix.len();
+            while let Some(x) = body.next().await {
+                let x = x?;
+                total_size += x.len();
+                if total_size > self.local_config.max_request_body_size_bytes as usize {
+                    return Err(SchedError::RequestBodyTooLarge.into());
+                }
+                self.write_body_chunk(&mut client, &stream, Some(x.to_vec()))
+                    .await?;
+            }
+            self.write_body_chunk(&mut client, &stream, None).await
+        }
+        .await;
+
+        if let Err(e) = result {
+            debug!("error streaming request body {}: {:?}", stream, e);
+
+            // The worker sees an aborted stream.
+            drop(
+                client
+                    .close_body_stream(tarpc::context::current(), stream)
+                    .await,
+            );
+        }
+    }
+
+    /// Closes the streamed body of a request that failed before reaching a worker, so that
+    /// `pump_request_body` gives it back.
+    async fn close_request_body(&self, instance: &mut ReadyInstance, body: &HttpBody) {
+        if let HttpBody::Stream(ref stream) = *body {
+            let mut close_context = tarpc::context::current();
+            close_context.deadline = std::time::SystemTime::now() + Duration::from_secs(1);
+            drop(
+                instance
+                    .client
+                    .close_body_stream(close_context, stream.clone())
+                    .await,
+            );
+        }
+    }
+
+    async fn write_body_chunk(
+        &self,
+        client: &mut RuntimeServiceClient,
+        stream: &str,
+        chunk: Option<Vec<u8>>,
+    ) -> Result<()> {
+        let mut write_context = tarpc::context::current();
+        write_context.deadline = std::time::SystemTime::now()
+            + Duration::from_millis(self.local_config.request_timeout_ms);
+        client
+            .write_body_chunk(write_context, stream.to_string(), chunk)
+            .await??;
+        Ok(())
+    }
+
+    /// Forwards a streaming response body from the runtime to the client.
+    async fn forward_body_stream(
+        self: Arc<Self>,
+        app: Arc<AppState>,
+        mut instance: ReadyInstance,
+        stream: String,
+        mut sender: hyper::body::Sender,
+    ) {
+        loop {
+            let mut read_context = tarpc::context::current();
+            read_context.deadline = std::time::SystemTime::now()
+                + Duration::from_millis(self.local_config.request_timeout_ms);
+
+            let chunk = instance
+                .client
+                .read_body_chunk(read_context, stream.clone())
+                .await;
+            let chunk = match chunk {
+                Ok(Ok(Some(x))) => x,
+                Ok(Ok(None)) => break,
+                Ok(Err(e)) => {
+                    // The worker failed before ending the body. Don't let the client take the
+                    // truncated body for a complete one.
+                    info!("error reading body stream {}: {:?}", stream, e);
+                    sender.abort();
+                    break;
+                }
+                Err(e) => {
+                    info!(
+                        "network error reading body stream {} from instance {}: {:?}",
+                        stream, instance.rtid.0, e
+                    );
+                    sender.abort();
+
+                    // Release the stream on the runtime, if it's still there.
+                    let mut close_context = tarpc::context::current();
+                    close_context.deadline = std::time::SystemTime::now() + Duration::from_secs(1);
+                    drop(
+                        instance
+                            .client
+                            .close_body_stream(close_context, stream)
+                            .await,
+                    );
+                    drop(self.terminate_queue.try_send(instance));
+                    return;
+                }
+            };
+            if sender.send_data(chunk.into()).await.is_err() {
+                // Client went away. The worker gets an error on its next write.
+                debug!("client closed body stream {}", stream);
+                drop(
+                    instance
+                        .client
+                        .close_body_stream(tarpc::context::current(), stream)
+                        .await,
+                );
+                break;
+            }
+        }
+
+        app.pool_instance(&self, instance).await;
+    }
+
+    async fn get_app(&self, appid: &AppId) -> Result<Arc<AppState>> {
+        for _ in 0..3 {
+            if let Some(app) = self.apps.lock().await.get(appid).cloned() {
+                return Ok(app);
+            }
+
+            // Notify the worker thread
+            drop(self.lookup_app_tx.try_send(appid.clone()));
+
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+
+        Err(SchedError::NoRouteMapping.into())
+    }
+
     /// Query each runtime for its health/load status, etc.
     pub async fn query_runtimes(&self) {
         let mut to_drop = vec![];
@@ -606,6 +931,12 @@ impl Scheduler {
                                     || config.env != worker_config.env
                                     || decode_kv_namespaces(&config.kv_namespaces)
                                         != worker_config.kv_namespaces
+                                    || config.wasm_modules != worker_config.wasm_modules
+                                    || decode_actor_namespaces(&config.actors)
+                                        != worker_config.actor_namespaces
+                                    || decode_services(&config.services) != worker_config.services
+                                    || decode_queue_producers(&config.queues.producers)
+                                        != worker_config.queue_producers
                                 {
                                     info!("app changed. removing app {} from cache", id.0);
                                     self.apps.lock().await.remove(&id);
@@ -625,6 +956,594 @@ impl Scheduler {
         }
     }
 
+    /// Fires cron triggers at each minute boundary.
+    ///
+    /// Every proxy runs this loop. Each tick of a trigger is claimed with a lease in KV so that only one proxy
+    /// fires it at a time. A tick whose delivery fails, or whose proxy disappears, is claimed again at a later
+    /// minute boundary once the lease expires, up to `MAX_CRON_ATTEMPTS` times or until the next tick of the
+    /// trigger. A tick is skipped if no proxy is running at that time.
+    async fn cron_task(self: Arc<Self>) {
+        loop {
+            let now = Utc::now();
+            let tick = (now.timestamp() / 60 + 1) * 60;
+            let wait_ms = (tick * 1000 - now.timestamp_millis()).max(0) as u64;
+            tokio::time::sleep(Duration::from_millis(wait_ms)).await;
+
+            self.clone().run_cron_tick(Utc.timestamp(tick, 0)).await;
+        }
+    }
+
+    async fn run_cron_tick(self: Arc<Self>, tick: DateTime<Utc>) {
+        let mut appids = vec![];
+        let list_result = self
+            .kv_client
+            .app_metadata_for_each(|appid| {
+                appids.push(AppId(appid.to_string()));
+                true
+            })
+            .await;
+        if let Err(e) = list_result {
+            warn!("run_cron_tick: error listing apps: {:?}", e);
+            return;
+        }
+
+        for appid in appids {
+            let config: AppConfig = match self.kv_client.app_metadata_get(&appid.0).await {
+                Ok(Some(metadata)) => match serde_json::from_slice(&metadata) {
+                    Ok(x) => x,
+                    Err(_) => continue,
+                },
+                _ => continue,
+            };
+            for cron in config.triggers.crons {
+                let schedule: CronSchedule = match cron.parse() {
+                    Ok(x) => x,
+                    Err(e) => {
+                        warn!(
+                            "run_cron_tick: bad cron trigger for app {}: {:?}",
+                            appid.0, e
+                        );
+                        continue;
+                    }
+                };
+                let now = Utc::now().timestamp_millis();
+                let scheduled_time = if schedule.matches(&tick) {
+                    tick.timestamp_millis()
+                } else {
+                    // Retry an earlier tick that was not delivered.
+                    match self.kv_client.cron_lease_get(&appid.0, &cron).await {
+                        Ok(Some(lease)) if !lease.completed && lease.expires <= now => {
+                            lease.scheduled_time
+                        }
+                        Ok(_) => continue,
+                        Err(e) => {
+                            warn!(
+                                "run_cron_tick: error reading lease for app {}: {:?}",
+                                appid.0, e
+                            );
+                            continue;
+                        }
+                    }
+                };
+                let expires = now + (self.local_config.request_timeout_ms * 3) as i64;
+                match self
+                    .kv_client
+                    .cron_lease_claim(
+                        &appid.0,
+                        &cron,
+                        scheduled_time,
+                        now,
+                        expires,
+                        MAX_CRON_ATTEMPTS,
+                    )
+                    .await
+                {
+                    Ok(true) => {}
+                    Ok(false) => continue,
+                    Err(e) => {
+                        warn!(
+                            "run_cron_tick: error claiming lease for app {}: {:?}",
+                            appid.0, e
+                        );
+                        continue;
+                    }
+                }
+
+                let me = self.clone();
+                let appid = appid.clone();
+                tokio::spawn(async move {
+                    let event = ScheduledEventObject {
+                        scheduled_time,
+                        cron: cron.clone(),
+                    };
+                    if let Err(e) = me.fire_cron(&appid, event).await {
+                        warn!("cron trigger failed for app {}: {:?}", appid.0, e);
+                        return;
+                    }
+                    if let Err(e) = me
+                        .kv_client
+                        .cron_lease_complete(&appid.0, &cron, scheduled_time)
+                        .await
+                    {
+                        warn!(
+                            "run_cron_tick: error completing lease for app {}: {:?}",
+                            appid.0, e
+                        );
+                    }
+                });
+            }
+        }
+    }
+
+    async fn fire_cron(&self, appid: &AppId, event: ScheduledEventObject) -> Result<()> {
+        let app = self.get_app(appid).await?;
+        info!("firing cron trigger '{}' for app {}", event.cron, appid.0);
+
+        let ((), instance) = self
+            .call_instance(&app, |mut client, context, handle| {
+                let event = event.clone();
+                async move { client.scheduled(context, handle, event).await }
+            })
+            .await?;
+        app.pool_instance(self, instance).await;
+        Ok(())
+    }
+
+    /// Calls `f` on an instance of `app`, with the same retries as requests from outside.
+    ///
+    /// Another instance is tried on network errors and when the worker is gone, up to 3 times.
+    /// Busy instances are pooled back and skipped with bounded backoff. On success the instance is
+    /// returned, so that the caller can pool it back once done with it. On other errors it is
+    /// pooled back unless the error terminated the worker.
+    async fn call_instance<T, F, Fut>(&self, app: &AppState, mut f: F) -> Result<(T, ReadyInstance)>
+    where
+        F: FnMut(RuntimeServiceClient, tarpc::context::Context, WorkerHandle) -> Fut,
+        Fut: Future<Output = std::io::Result<ExecutionResult<T>>>,
+    {
+        let mut attempts = 0usize;
+        let mut busy = 0u32;
+        while attempts < 3 {
+            attempts += 1;
+            let instance = app.get_instance(self).await?;
+
+            let mut context = tarpc::context::current();
+            context.deadline = std::time::SystemTime::now()
+                + Duration::from_millis(self.local_config.request_timeout_ms);
+
+            let res = f(instance.client.clone(), context, instance.handle.clone()).await;
+            let res = match res {
+                Ok(x) => x,
+                Err(e) => {
+                    // Network error. Drop this and select another instance.
+                    self.clients.write().await.remove(&instance.rtid);
+                    info!("network error for instance {}: {:?}", instance.rtid.0, e);
+                    continue;
+                }
+            };
+            match res {
+                Ok(x) => return Ok((x, instance)),
+                Err(ExecutionError::NoSuchWorker) => continue,
+                Err(ExecutionError::WorkerBusy) => {
+                    // Busy instances don't count as attempts.
+                    app.pool_busy_instance(self, instance).await;
+                    if !backoff_busy(&mut busy).await {
+                        break;
+                    }
+                    attempts -= 1;
+                    continue;
+                }
+                Err(e) => {
+                    debug!("backend returns error: {:?}", e);
+                    if !e.terminates_worker() {
+                        app.pool_instance(self, instance).await;
+                    }
+                    return Err(e.into());
+                }
+            }
+        }
+
+        Err(SchedError::RequestFailedAfterRetries.into())
+    }
+
+    /// Delivers messages of the queues consumed by apps.
+    ///
+    /// Every proxy runs this loop. A queue is delivered by the proxy holding its lease in KV. Messages are
+    /// removed only after they have been handled, so a message may be delivered more than once if a proxy
+    /// fails in the middle of a batch or its lease expires. Failed messages are delivered again with
+    /// exponential backoff, and moved to the dead-letter namespace of the queue after `max_retries` retries.
+    async fn queue_task(self: Arc<Self>) {
+        let owner = format!("{:016x}", rand::thread_rng().gen::<u64>());
+        let active: Arc<std::sync::Mutex<BTreeSet<String>>> = Default::default();
+        loop {
+            tokio::time::sleep(Duration::from_millis(QUEUE_POLL_INTERVAL_MS)).await;
+
+            let consumers = match self.list_queue_consumers().await {
+                Ok(x) => x,
+                Err(e) => {
+                    warn!("queue_task: error listing apps: {:?}", e);
+                    continue;
+                }
+            };
+            for (_, (appid, consumer)) in consumers {
+                // Still delivering from the last poll.
+                if !active.lock().unwrap().insert(consumer.queue.clone()) {
+                    continue;
+                }
+                let me = self.clone();
+                let owner = owner.clone();
+                let active = active.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = me.run_queue_consumer(&owner, &appid, &consumer).await {
+                        warn!(
+                            "queue_task: error consuming queue {} for app {}: {:?}",
+                            consumer.queue, appid.0, e
+                        );
+                    }
+                    active.lock().unwrap().remove(&consumer.queue);
+                });
+            }
+        }
+    }
+
+    /// Returns the consumer of each queue.
+    ///
+    /// `add-app` refuses a queue that is already consumed by another app. Queues consumed by several apps
+    /// anyway are skipped, with an error logged, rather than delivered to an arbitrary one of them.
+    async fn list_queue_consumers(&self) -> Result<BTreeMap<String, (AppId, QueueConsumerConfig)>> {
+        let mut appids = vec![];
+        self.kv_client
+            .app_metadata_for_each(|appid| {
+                appids.push(AppId(appid.to_string()));
+                true
+            })
+            .await?;
+
+        let mut consumers: BTreeMap<String, (AppId, QueueConsumerConfig)> = BTreeMap::new();
+        let mut conflicts = BTreeSet::new();
+        for appid in appids {
+            let config: AppConfig = match self.kv_client.app_metadata_get(&appid.0).await {
+                Ok(Some(metadata)) => match serde_json::from_slice(&metadata) {
+                    Ok(x) => x,
+                    Err(_) => continue,
+                },
+                _ => continue,
+            };
+            for consumer in config.queues.consumers {
+                match consumers.get(&consumer.queue) {
+                    Some((other, _)) if *other != appid => {
+                        error!(
+                            "queue {} is consumed by both app {} and app {}, not delivering it",
+                            consumer.queue, other.0, appid.0
+                        );
+                        conflicts.insert(consumer.queue.clone());
+                    }
+                    Some(_) => {}
+                    None => {
+                        consumers.insert(consumer.queue.clone(), (appid.clone(), consumer));
+                    }
+                }
+            }
+        }
+        for queue in conflicts {
+            consumers.remove(&queue);
+        }
+        Ok(consumers)
+    }
+
+    async fn run_queue_consumer(
+        &self,
+        owner: &str,
+        appid: &AppId,
+        consumer: &QueueConsumerConfig,
+    ) -> Result<()> {
+        for _ in 0..QUEUE_MAX_BATCHES_PER_POLL {
+            let now = Utc::now().timestamp_millis();
+            if !self
+                .kv_client
+                .queue_lease_claim(&consumer.queue, owner, now, QUEUE_LEASE_MS)
+                .await?
+            {
+                return Ok(());
+            }
+
+            let messages = self
+                .kv_client
+                .queue_receive(&consumer.queue, now, consumer.max_batch_size)
+                .await?;
+            if messages.is_empty() {
+                return Ok(());
+            }
+            let full = messages.len() == consumer.max_batch_size as usize;
+
+            let batch = QueueBatchObject {
+                queue: consumer.queue.clone(),
+                messages: messages.iter().map(|x| x.message.clone()).collect(),
+            };
+            let retry: BTreeSet<String> = match self.deliver_queue_batch(appid, batch).await {
+                Ok(res) => res.retry.into_iter().collect(),
+                Err(e) => {
+                    warn!(
+                        "queue batch delivery failed for queue {}, app {}: {:?}",
+                        consumer.queue, appid.0, e
+                    );
+                    messages.iter().map(|x| x.message.id.clone()).collect()
+                }
+            };
+
+            let now = Utc::now().timestamp_millis();
+            for msg in &messages {
+                if !retry.contains(&msg.message.id) {
+                    self.kv_client.queue_ack(msg).await?;
+                } else if msg.message.attempts >= consumer.max_retries {
+                    info!(
+                        "moving message {} of queue {} to dead letters",
+                        msg.message.id, consumer.queue
+                    );
+                    self.kv_client
+                        .queue_dead_letter(&consumer.queue, msg)
+                        .await?;
+                } else {
+                    let delay =
+                        (1000i64 << msg.message.attempts.min(16)).min(QUEUE_MAX_RETRY_DELAY_MS);
+                    self.kv_client
+                        .queue_retry(&consumer.queue, msg, now + delay)
+                        .await?;
+                }
+            }
+
+            if !full || !retry.is_empty() {
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+
+    async fn deliver_queue_batch(
+        &self,
+        appid: &AppId,
+        batch: QueueBatchObject,
+    ) -> Result<QueueBatchResult> {
+        let app = self.get_app(appid).await?;
+        debug!(
+            "delivering {} messages of queue {} to app {}",
+            batch.messages.len(),
+            batch.queue,
+            appid.0
+        );
+
+        let (res, instance) = self
+            .call_instance(&app, |mut client, context, handle| {
+                let batch = batch.clone();
+                async move { client.queue(context, handle, batch).await }
+            })
+            .await?;
+        app.pool_instance(self, instance).await;
+        Ok(res)
+    }
+
+    /// Sends a request to an actor of an app.
+    ///
+    /// The worker that owns an actor is recorded in a lease in KV, so that at most one worker runs an actor
+    /// across the cluster. The runtime of the worker renews the lease while the worker lives, and stops the
+    /// worker once the lease has a newer epoch. A lease is released when the worker is found gone, and taken
+    /// over once it expires, e.g. after the owning runtime has disappeared.
+    pub async fn actor_fetch(
+        &self,
+        appid: &AppId,
+        actor: &ActorAddress,
+        req: RequestObject,
+    ) -> Result<ResponseObject> {
+        let app = self.get_app(appid).await?;
+        if !app.config.actor_namespaces.contains(&actor.namespace) {
+            return Err(SchedError::NoSuchActorNamespace.into());
+        }
+
+        // Backend retries.
+        for _ in 0..3usize {
+            let (lease, mut instance) = match self.get_actor_instance(&app, actor).await? {
+                Some(x) => x,
+                None => continue,
+            };
+            debug!(
+                "routing request to actor {}/{} of app {}, instance {}",
+                actor.namespace, actor.id, appid.0, instance.rtid.0
+            );
+
+            let mut fetch_context = tarpc::context::current();
+            fetch_context.deadline = std::time::SystemTime::now()
+                + Duration::from_millis(self.local_config.request_timeout_ms);
+
+            let fetch_res = instance
+                .client
+                .fetch(fetch_context, instance.handle.clone(), req.clone())
+                .await;
+            let fetch_res = match fetch_res {
+                Ok(x) => x,
+                Err(e) => {
+                    // The actor can't move until its lease expires, because the worker may still be alive.
+                    self.clients.write().await.remove(&instance.rtid);
+                    info!("network error for instance {}: {:?}", instance.rtid.0, e);
+                    return Err(SchedError::ActorUnavailable.into());
+                }
+            };
+            let mut res = match fetch_res {
+                Ok(x) => x,
+                Err(e) => {
+                    debug!("backend returns error for actor: {:?}", e);
+
+                    // Release the lease so that the next request creates the actor again.
+                    if e.terminates_worker() {
+                        drop(
+                            self.kv_client
+                                .actor_lease_renew(&appid.0, actor, &lease.handle, lease.epoch, 0)
+                                .await,
+                        );
+                    }
+                    match e {
+                        ExecutionError::NoSuchWorker => continue,
+                        _ => return Err(e.into()),
+                    }
+                }
+            };
+
+            if let HttpBody::Stream(stream) = res.body {
+                res.body = HttpBody::Binary(self.read_internal_body(&mut instance, stream).await?);
+            }
+            return Ok(res);
+        }
+
+        Err(SchedError::RequestFailedAfterRetries.into())
+    }
+
+    /// Returns the lease and the worker of an actor, creating the worker if the actor has no live owner.
+    ///
+    /// Returns `None` if the lease was changed by another proxy in the meantime.
+    async fn get_actor_instance(
+        &self,
+        app: &AppState,
+        actor: &ActorAddress,
+    ) -> Result<Option<(ActorLease, ReadyInstance)>> {
+        let lease_ms = self.local_config.actor_lease_ms;
+        let prev = self.kv_client.actor_lease_get(&app.id.0, actor).await?;
+        let now = Utc::now().timestamp_millis();
+
+        if let Some(ref lease) = prev {
+            if lease.expires > now {
+                let client = match self.clients.read().await.get(&lease.runtime) {
+                    Some(x) => x.client.clone(),
+                    None => return Err(SchedError::ActorUnavailable.into()),
+                };
+                let lease = lease.clone();
+                let instance = ReadyInstance {
+                    rtid: lease.runtime.clone(),
+                    last_active: Instant::now(),
+                    busy_until: None,
+                    handle: lease.handle.clone(),
+                    client,
+                };
+                return Ok(Some((lease, instance)));
+            }
+        }
+
+        let epoch = prev.as_ref().map(|x| x.epoch + 1).unwrap_or(1);
+        let mut config = app.config.clone();
+        config.actor = Some(actor.clone());
+        config.actor_lease = Some(ActorLeaseConfig { epoch, lease_ms });
+        let instance = app.spawn_instance(self, config).await?;
+        let lease = ActorLease {
+            runtime: instance.rtid.clone(),
+            handle: instance.handle.clone(),
+            expires: now + lease_ms as i64,
+            epoch,
+        };
+        match self
+            .kv_client
+            .actor_lease_swap(&app.id.0, actor, prev.as_ref(), Some(&lease))
+            .await
+        {
+            Ok(true) => {}
+            Ok(false) => {
+                drop(self.terminate_queue.try_send(instance));
+                return Ok(None);
+            }
+            Err(e) => {
+                drop(self.terminate_queue.try_send(instance));
+                return Err(e.into());
+            }
+        }
+        info!(
+            "actor {}/{} of app {} is now owned by instance {}",
+            actor.namespace, actor.id, app.id.0, instance.rtid.0
+        );
+
+        // Stop the previous owner if it's still around. Its runtime stops it anyway on the next
+        // renewal, since the lease now has a newer epoch.
+        if let Some(prev) = prev {
+            if let Some(rt) = self.clients.read().await.get(&prev.runtime) {
+                drop(self.terminate_queue.try_send(ReadyInstance {
+                    rtid: prev.runtime,
+                    last_active: Instant::now(),
+                    busy_until: None,
+                    handle: prev.handle,
+                    client: rt.client.clone(),
+                }));
+            }
+        }
+        Ok(Some((lease, instance)))
+    }
+
+    /// Sends a request from a worker to the app bound to one of its service bindings.
+    ///
+    /// Unlike requests from outside, these skip route lookup and are read in full before returning.
+    pub async fn service_fetch(
+        &self,
+        appid: &AppId,
+        service: &str,
+        req: RequestObject,
+    ) -> Result<ResponseObject> {
+        let caller = self.get_app(appid).await?;
+        let target = match caller.config.services.get(service) {
+            Some(x) => AppId(x.clone()),
+            None => return Err(SchedError::NoSuchService.into()),
+        };
+        let app = self.get_app(&target).await?;
+        debug!(
+            "routing request from app {} to service {} (app {})",
+            appid.0, service, target.0
+        );
+
+        let (mut res, mut instance) = self
+            .call_instance(&app, |mut client, context, handle| {
+                let req = req.clone();
+                async move { client.fetch(context, handle, req).await }
+            })
+            .await?;
+        if let HttpBody::Stream(stream) = res.body {
+            match self.read_internal_body(&mut instance, stream).await {
+                Ok(x) => res.body = HttpBody::Binary(x),
+                Err(e) => {
+                    drop(self.terminate_queue.try_send(instance));
+                    return Err(e);
+                }
+            }
+        }
+        app.pool_instance(self, instance).await;
+        Ok(res)
+    }
+
+    /// Reads a streaming response body to a request from a worker in full.
+    async fn read_internal_body(
+        &self,
+        instance: &mut ReadyInstance,
+        stream: String,
+    ) -> Result<Vec<u8>> {
+        let mut body = vec![];
+        loop {
+            let mut read_context = tarpc::context::current();
+            read_context.deadline = std::time::SystemTime::now()
+                + Duration::from_millis(self.local_config.request_timeout_ms);
+            match instance
+                .client
+                .read_body_chunk(read_context, stream.clone())
+                .await??
+            {
+                Some(x) => {
+                    if body.len() + x.len() > MAX_INTERNAL_RESPONSE_BODY_SIZE {
+                        drop(
+                            instance
+                                .client
+                                .close_body_stream(tarpc::context::current(), stream)
+                                .await,
+                        );
+                        return Err(SchedError::InternalResponseTooLarge.into());
+                    }
+                    body.extend_from_slice(&x);
+                }
+                None => return Ok(body),
+            }
+        }
+    }
+
     async fn lookup_app_background(&self, mut rx: Receiver<AppId>) {
         loop {
             let appid = match rx.recv().await {
@@ -691,6 +1610,11 @@ impl Scheduler {
         let mut target_config = self.worker_config.clone();
         target_config.env = config.env.clone();
         target_config.kv_namespaces = decode_kv_namespaces(&config.kv_namespaces);
+        target_config.bundle_id = encode_id128(&bundle_id);
+        target_config.wasm_modules = config.wasm_modules.clone();
+        target_config.actor_namespaces = decode_actor_namespaces(&config.actors);
+        target_config.services = decode_services(&config.services);
+        target_config.queue_producers = decode_queue_producers(&config.queues.producers);
 
         let state = AppState {
             id: id.clone(),
@@ -712,6 +1636,11 @@ impl SchedError {
             SchedError::NoRouteMapping => hyper::StatusCode::BAD_GATEWAY,
             SchedError::RequestBodyTooLarge => hyper::StatusCode::PAYLOAD_TOO_LARGE,
             SchedError::RequestFailedAfterRetries => hyper::StatusCode::SERVICE_UNAVAILABLE,
+            SchedError::NoSuchActorNamespace => hyper::StatusCode::NOT_FOUND,
+            SchedError::ActorUnavailable => hyper::StatusCode::SERVICE_UNAVAILABLE,
+            SchedError::NoSuchService => hyper::StatusCode::NOT_FOUND,
+            SchedError::InternalResponseTooLarge => hyper::StatusCode::BAD_GATEWAY,
+            SchedError::ScriptException => hyper::StatusCode::INTERNAL_SERVER_ERROR,
         };
         let mut res = hyper::Response::new(hyper::Body::from(
             status.canonical_reason().unwrap_or("unknown error"),
@@ -721,6 +1650,17 @@ impl SchedError {
     }
 }
 
+/// Waits before trying another instance after a busy one. Returns `false` once
+/// `MAX_BUSY_RETRIES` busy instances have been skipped in a row.
+async fn backoff_busy(busy: &mut u32) -> bool {
+    if *busy >= MAX_BUSY_RETRIES {
+        return false;
+    }
+    tokio::time::sleep(BUSY_RETRY_DELAY * (1 << *busy)).await;
+    *busy += 1;
+    true
+}
+
 fn decode_kv_namespaces(namespaces: &Vec<KvNamespaceConfig>) -> BTreeMap<String, [u8; 16]> {
     namespaces
         .iter()
@@ -741,6 +1681,24 @@ fn decode_kv_namespaces(namespaces: &Vec<KvNamespaceConfig>) -> BTreeMap<String,
         .collect()
 }
 
+fn decode_actor_namespaces(actors: &[ActorNamespaceConfig]) -> Vec<String> {
+    actors.iter().map(|x| x.name.clone()).collect()
+}
+
+fn decode_services(services: &[ServiceBindingConfig]) -> BTreeMap<String, String> {
+    services
+        .iter()
+        .map(|x| (x.name.clone(), x.app.0.clone()))
+        .collect()
+}
+
+fn decode_queue_producers(producers: &[QueueProducerConfig]) -> BTreeMap<String, String> {
+    producers
+        .iter()
+        .map(|x| (x.name.clone(), x.queue.clone()))
+        .collect()
+}
+
 fn lookup_submappings<'a>(
     path: &str,
     submappings: &'a BTreeMap<String, AppId>,
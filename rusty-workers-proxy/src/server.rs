@@ -0,0 +1,43 @@
+use crate::sched::Scheduler;
+use rusty_workers::app::AppId;
+use rusty_workers::tarpc;
+use rusty_workers::types::*;
+use std::sync::Arc;
+
+#[derive(Clone)]
+pub struct SchedulerServer {
+    pub scheduler: Arc<Scheduler>,
+}
+
+#[tarpc::server]
+impl rusty_workers::rpc::SchedulerService for SchedulerServer {
+    async fn actor_fetch(
+        self,
+        _: tarpc::context::Context,
+        appid: String,
+        actor: ActorAddress,
+        req: RequestObject,
+    ) -> GenericResult<Result<ResponseObject, String>> {
+        Ok(self
+            .scheduler
+            .actor_fetch(&AppId(appid), &actor, req)
+            .await
+            .map_err(|e| e.to_string()))
+    }
+
+    async fn service_fetch(
+        self,
+        _: tarpc::context::Context,
+        appid: String,
+        service: String,
+        req: RequestObject,
+    ) -> GenericResult<Result<ResponseObject, String>> {
+        Ok(self
+            .scheduler
+            .service_fetch(&AppId(appid), &service, req)
+            .await
+            .map_err(|e| e.to_string()))
+    }
+}
+
+rusty_workers::impl_listen!(SchedulerServer, rusty_workers::rpc::SchedulerService);
@@ -0,0 +1,120 @@
+//! Throughput of moving bytes between host code and `ArrayBuffer`s, for digests, fetch bodies
+//! and KV values.
+//!
+//! Every benchmark has a `_cells` variant that goes through `Cell<u8>` one byte at a time, the way
+//! host calls did before `buffer.rs` had slice views. Run with `cargo bench -p
+//! rusty-workers-runtime`.
+
+#![feature(test)]
+
+extern crate test;
+
+#[macro_use]
+extern crate log;
+
+#[allow(dead_code)]
+#[path = "../src/buffer.rs"]
+mod buffer;
+
+#[allow(dead_code)]
+#[path = "../src/error.rs"]
+mod error;
+
+use buffer::*;
+use rusty_v8 as v8;
+use std::sync::Once;
+use test::Bencher;
+
+const DIGEST_SIZE: usize = 1024 * 1024;
+
+/// `MAX_FETCH_REQUEST_BODY_SIZE` in `io.rs`.
+const FETCH_BODY_SIZE: usize = 2 * 1024 * 1024;
+
+/// `MAX_KV_VALUE_SIZE` in `io.rs`.
+const KV_VALUE_SIZE: usize = 4 * 1024 * 1024;
+
+fn init() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        let platform = v8::new_default_platform(0, false).make_shared();
+        v8::V8::initialize_platform(platform);
+        v8::V8::initialize();
+    });
+}
+
+/// Calls `f` with a fresh `ArrayBuffer` of `len` bytes.
+fn with_buffer<F: FnOnce(&mut v8::HandleScope<'_>, JsArrayBufferViewRef)>(len: usize, f: F) {
+    init();
+    let isolate = &mut v8::Isolate::new(Default::default());
+    let scope = &mut v8::HandleScope::new(isolate);
+    let context = v8::Context::new(scope);
+    let scope = &mut v8::ContextScope::new(scope, context);
+    let buf = v8::ArrayBuffer::new(scope, len);
+    let view = JsArrayBufferViewRef::new(scope, buf.into()).unwrap();
+    f(scope, view);
+}
+
+#[bench]
+fn digest(b: &mut Bencher) {
+    with_buffer(DIGEST_SIZE, |scope, view| {
+        b.bytes = DIGEST_SIZE as u64;
+        b.iter(|| ring::digest::digest(&ring::digest::SHA256, view.as_bytes(scope)));
+    });
+}
+
+#[bench]
+fn digest_cells(b: &mut Bencher) {
+    with_buffer(DIGEST_SIZE, |_, view| {
+        b.bytes = DIGEST_SIZE as u64;
+        b.iter(|| {
+            let mut ctx = ring::digest::Context::new(&ring::digest::SHA256);
+            for x in view.iter() {
+                ctx.update(&[x.get()]);
+            }
+            ctx.finish()
+        });
+    });
+}
+
+#[bench]
+fn fetch_body_read(b: &mut Bencher) {
+    with_buffer(FETCH_BODY_SIZE, |_, view| {
+        b.bytes = FETCH_BODY_SIZE as u64;
+        b.iter(|| CopiedBuffer::new(&view, FETCH_BODY_SIZE).unwrap());
+    });
+}
+
+#[bench]
+fn fetch_body_read_cells(b: &mut Bencher) {
+    with_buffer(FETCH_BODY_SIZE, |_, view| {
+        b.bytes = FETCH_BODY_SIZE as u64;
+        b.iter(|| {
+            let mut buf = Vec::with_capacity(view.len());
+            buf.extend(view.iter().map(|x| x.get()));
+            buf
+        });
+    });
+}
+
+#[bench]
+fn kv_value_write(b: &mut Bencher) {
+    let data = vec![0x55u8; KV_VALUE_SIZE];
+    with_buffer(KV_VALUE_SIZE, |scope, view| {
+        b.bytes = KV_VALUE_SIZE as u64;
+        // `view` is the only reference to the buffer.
+        b.iter(|| unsafe { view.as_bytes_mut(scope) }.copy_from_slice(&data));
+    });
+}
+
+#[bench]
+fn kv_value_write_cells(b: &mut Bencher) {
+    let data = vec![0x55u8; KV_VALUE_SIZE];
+    with_buffer(KV_VALUE_SIZE, |_, view| {
+        b.bytes = KV_VALUE_SIZE as u64;
+        b.iter(|| {
+            for (x, y) in view.iter().zip(data.iter()) {
+                x.set(*y);
+            }
+        });
+    });
+}
@@ -0,0 +1,102 @@
+//! Startup cost of isolates and contexts, from the librt snapshot and by running librt directly.
+//!
+//! `isolate_*` create an isolate and its first context, as when an isolate thread starts.
+//! `context_*` create a context in an existing isolate, as for every job. The `_librt` variants do
+//! what isolate threads did before the snapshot: compile librt once per isolate and run it in
+//! every new context. Run with `cargo bench -p rusty-workers-runtime --bench isolate`.
+
+#![feature(test)]
+
+extern crate test;
+
+#[allow(dead_code)]
+#[path = "../src/snapshot.rs"]
+mod snapshot;
+
+use rusty_v8 as v8;
+use std::sync::Once;
+use test::Bencher;
+
+fn init() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        let platform = v8::new_default_platform(0, false).make_shared();
+        v8::V8::initialize_platform(platform);
+        v8::V8::initialize();
+    });
+}
+
+fn compile_librt(scope: &mut v8::HandleScope<'_>) -> v8::Global<v8::UnboundScript> {
+    let source = v8::String::new(scope, snapshot::LIBRT).unwrap();
+    let script = v8::Script::compile(scope, source, None).unwrap();
+    let unbound = script.get_unbound_script(scope);
+    v8::Global::new(scope, unbound)
+}
+
+/// Runs librt in the current context, with `global` set up the same way as in the snapshot.
+fn run_librt(scope: &mut v8::HandleScope<'_>, librt: &v8::Global<v8::UnboundScript>) {
+    let context = scope.get_current_context();
+    let global_key = v8::String::new(scope, "global").unwrap();
+    let global_obj = context.global(scope);
+    global_obj.set(scope, global_key.into(), global_obj.into());
+
+    let librt = v8::Local::new(scope, librt).bind_to_current_context(scope);
+    librt.run(scope).unwrap();
+}
+
+#[bench]
+fn isolate_snapshot(b: &mut Bencher) {
+    init();
+    let snapshot = snapshot::make_librt_snapshot();
+    b.iter(|| {
+        let params = v8::Isolate::create_params().snapshot_blob(snapshot.to_vec());
+        let isolate = &mut v8::Isolate::new(params);
+        let scope = &mut v8::HandleScope::new(isolate);
+        let context = v8::Context::new(scope);
+        test::black_box(context);
+    });
+}
+
+#[bench]
+fn isolate_librt(b: &mut Bencher) {
+    init();
+    b.iter(|| {
+        let isolate = &mut v8::Isolate::new(Default::default());
+        let scope = &mut v8::HandleScope::new(isolate);
+        let context = v8::Context::new(scope);
+        let scope = &mut v8::ContextScope::new(scope, context);
+        let librt = compile_librt(scope);
+        run_librt(scope, &librt);
+    });
+}
+
+#[bench]
+fn context_snapshot(b: &mut Bencher) {
+    init();
+    let snapshot = snapshot::make_librt_snapshot();
+    let params = v8::Isolate::create_params().snapshot_blob(snapshot.to_vec());
+    let isolate = &mut v8::Isolate::new(params);
+    b.iter(|| {
+        let scope = &mut v8::HandleScope::new(&mut **isolate);
+        let context = v8::Context::new(scope);
+        test::black_box(context);
+    });
+}
+
+#[bench]
+fn context_librt(b: &mut Bencher) {
+    init();
+    let isolate = &mut v8::Isolate::new(Default::default());
+    let librt = {
+        let scope = &mut v8::HandleScope::new(&mut **isolate);
+        let context = v8::Context::new(scope);
+        let scope = &mut v8::ContextScope::new(scope, context);
+        compile_librt(scope)
+    };
+    b.iter(|| {
+        let scope = &mut v8::HandleScope::new(&mut **isolate);
+        let context = v8::Context::new(scope);
+        let scope = &mut v8::ContextScope::new(scope, context);
+        run_librt(scope, &librt);
+    });
+}
@@ -0,0 +1,271 @@
+//! Streaming request and response bodies.
+//!
+//! A worker that responds with a stream body gets a `(stream id, sender)` pair from
+//! `BodyStreamSet::create_response`. The proxy pulls chunks by id with
+//! `RuntimeService::read_body_chunk` until the end of the stream. A stream whose sender is
+//! dropped before it sends the end marker, e.g. because the worker threw, is read as an error so
+//! that the proxy aborts the response instead of ending it cleanly. Streams the proxy stops
+//! reading are dropped after `RESPONSE_BODY_STREAM_IDLE_TIMEOUT`.
+//!
+//! The task holds its instance until the body ends, and the CPU time spent producing the body
+//! counts against the task's time limit. A long-running event stream is cut off, and read as
+//! aborted, once the worker is terminated for exceeding it.
+//!
+//! In the other direction, the proxy creates a request body stream with
+//! `RuntimeService::create_body_stream`, passes its id in the request and writes chunks with
+//! `RuntimeService::write_body_chunk`. Each read of the worker asks for one chunk. A write
+//! answers the pending read and only returns once the worker asks for the next chunk, so the
+//! proxy doesn't read from the client ahead of the worker. The first write also waits until a
+//! worker has taken the stream, so that the proxy can send the body to another worker if the
+//! request fails before that. Streams that neither side uses are dropped after
+//! `REQUEST_BODY_STREAM_IDLE_TIMEOUT`.
+
+use rusty_workers::types::*;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio::sync::oneshot;
+use tokio::sync::watch;
+use tokio::sync::Mutex as AsyncMutex;
+
+/// Number of chunks buffered between the worker and the proxy.
+const BODY_STREAM_BUFFER_SIZE: usize = 4;
+
+/// Time after which a response body stream that the proxy doesn't read is dropped.
+const RESPONSE_BODY_STREAM_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Time after which a request body stream that neither the proxy nor the worker uses is dropped.
+const REQUEST_BODY_STREAM_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Sending side of a response body stream. `None` marks the end of the body.
+pub type BodyStreamSender = mpsc::Sender<Option<Vec<u8>>>;
+
+/// A read of the worker waiting for the next chunk of a request body.
+type ChunkDemand = oneshot::Sender<Option<Vec<u8>>>;
+
+/// Receiving side of a request body stream.
+pub struct RequestBodyReceiver {
+    demands: mpsc::Sender<ChunkDemand>,
+    last_active: Arc<Mutex<Instant>>,
+}
+
+pub struct BodyStreamSet {
+    responses: Mutex<BTreeMap<String, Arc<ResponseBodyStream>>>,
+    requests: Mutex<BTreeMap<String, RequestBodyStream>>,
+}
+
+struct ResponseBodyStream {
+    rx: AsyncMutex<mpsc::Receiver<Option<Vec<u8>>>>,
+
+    /// When the stream was created or last read.
+    last_read: Mutex<Instant>,
+}
+
+struct RequestBodyStream {
+    /// Reads of the worker. Shared with in-flight writes.
+    demands: Arc<AsyncMutex<DemandQueue>>,
+
+    /// Taken by the task that consumes the body.
+    rx: Option<RequestBodyReceiver>,
+
+    /// Set to `true` once `rx` is taken. In-flight writes stop when it's dropped with the stream.
+    taken: watch::Sender<bool>,
+    taken_rx: watch::Receiver<bool>,
+
+    /// When the stream was created or last written or read.
+    last_active: Arc<Mutex<Instant>>,
+}
+
+struct DemandQueue {
+    rx: mpsc::Receiver<ChunkDemand>,
+
+    /// A read received ahead of the chunk for it.
+    next: Option<ChunkDemand>,
+}
+
+impl RequestBodyReceiver {
+    /// Asks the proxy for the next chunk and waits for it. `Some(None)` marks the end of the body,
+    /// and `None` means the proxy has aborted the stream.
+    pub async fn recv(&mut self) -> Option<Option<Vec<u8>>> {
+        *self.last_active.lock().unwrap() = Instant::now();
+        let (tx, rx) = oneshot::channel();
+        self.demands.send(tx).await.ok()?;
+        let chunk = rx.await.ok();
+        *self.last_active.lock().unwrap() = Instant::now();
+        chunk
+    }
+}
+
+impl DemandQueue {
+    async fn next(&mut self) -> Option<ChunkDemand> {
+        match self.next.take() {
+            Some(x) => Some(x),
+            None => self.rx.recv().await,
+        }
+    }
+
+    /// Waits for the next read without answering it. Returns `false` if the receiver is gone.
+    async fn peek(&mut self) -> bool {
+        if self.next.is_none() {
+            self.next = self.rx.recv().await;
+        }
+        self.next.is_some()
+    }
+}
+
+impl BodyStreamSet {
+    pub fn new() -> Self {
+        Self {
+            responses: Mutex::new(BTreeMap::new()),
+            requests: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn create_response(&self) -> (String, BodyStreamSender) {
+        let (tx, rx) = mpsc::channel(BODY_STREAM_BUFFER_SIZE);
+        let id = rusty_workers::util::rand_hex(16);
+        let now = Instant::now();
+        let mut responses = self.responses.lock().unwrap();
+
+        // Drop streams nobody is reading, so that they don't pile up.
+        responses.retain(|_, x| {
+            now.saturating_duration_since(*x.last_read.lock().unwrap())
+                < RESPONSE_BODY_STREAM_IDLE_TIMEOUT
+        });
+
+        responses.insert(
+            id.clone(),
+            Arc::new(ResponseBodyStream {
+                rx: AsyncMutex::new(rx),
+                last_read: Mutex::new(now),
+            }),
+        );
+        (id, tx)
+    }
+
+    /// Reads the next chunk of a response body. Returns `None` at the end of the stream, and an
+    /// error if the stream was aborted. The stream is removed in both cases.
+    pub async fn read_response(&self, id: &str) -> GenericResult<Option<Vec<u8>>> {
+        let stream = self
+            .responses
+            .lock()
+            .unwrap()
+            .get(id)
+            .cloned()
+            .ok_or_else(|| GenericError::Other("no such body stream".into()))?;
+        *stream.last_read.lock().unwrap() = Instant::now();
+        let chunk = stream.rx.lock().await.recv().await;
+        *stream.last_read.lock().unwrap() = Instant::now();
+        match chunk {
+            Some(Some(x)) => Ok(Some(x)),
+            Some(None) => {
+                self.remove_response(id);
+                Ok(None)
+            }
+            None => {
+                self.remove_response(id);
+                Err(GenericError::Other("body stream aborted".into()))
+            }
+        }
+    }
+
+    /// Removes a response body stream. The producing worker gets an error on its next write.
+    pub fn remove_response(&self, id: &str) -> bool {
+        self.responses.lock().unwrap().remove(id).is_some()
+    }
+
+    pub fn create_request(&self) -> String {
+        let (demands_tx, demands_rx) = mpsc::channel(1);
+        let (taken, taken_rx) = watch::channel(false);
+        let id = rusty_workers::util::rand_hex(16);
+        let now = Instant::now();
+        let last_active = Arc::new(Mutex::new(now));
+        let mut requests = self.requests.lock().unwrap();
+
+        // Drop streams nobody is using, e.g. when the request never reached a worker.
+        requests.retain(|_, x| {
+            now.saturating_duration_since(*x.last_active.lock().unwrap())
+                < REQUEST_BODY_STREAM_IDLE_TIMEOUT
+        });
+
+        requests.insert(
+            id.clone(),
+            RequestBodyStream {
+                demands: Arc::new(AsyncMutex::new(DemandQueue {
+                    rx: demands_rx,
+                    next: None,
+                })),
+                rx: Some(RequestBodyReceiver {
+                    demands: demands_tx,
+                    last_active: last_active.clone(),
+                }),
+                taken,
+                taken_rx,
+                last_active,
+            },
+        );
+        id
+    }
+
+    pub fn take_request_receiver(&self, id: &str) -> Option<RequestBodyReceiver> {
+        let mut requests = self.requests.lock().unwrap();
+        let stream = requests.get_mut(id)?;
+        let rx = stream.rx.take();
+        drop(stream.taken.send(true));
+        rx
+    }
+
+    /// Writes the next chunk of a request body, waiting for a worker to take the stream and read.
+    /// Unless the chunk is the end of the stream, `None`, this returns once the worker asks for
+    /// the next chunk.
+    pub async fn write_request(&self, id: &str, chunk: Option<Vec<u8>>) -> GenericResult<()> {
+        let (demands, mut taken, last_active) = self
+            .requests
+            .lock()
+            .unwrap()
+            .get(id)
+            .map(|x| (x.demands.clone(), x.taken_rx.clone(), x.last_active.clone()))
+            .ok_or_else(|| GenericError::Other("no such body stream".into()))?;
+        *last_active.lock().unwrap() = Instant::now();
+
+        // Fails once the stream is removed.
+        let mut closed = taken.clone();
+        let closed = async move { while closed.changed().await.is_ok() {} };
+
+        let end = chunk.is_none();
+        let write = async {
+            while !*taken.borrow() {
+                taken.changed().await.map_err(|_| ())?;
+            }
+            let mut demands = demands.lock().await;
+            let mut chunk = chunk;
+            loop {
+                match demands.next().await.ok_or(())?.send(chunk) {
+                    Ok(()) => break,
+                    // The read timed out. Answer the next one.
+                    Err(x) => chunk = x,
+                }
+            }
+            if !end && !demands.peek().await {
+                return Err(());
+            }
+            Ok(())
+        };
+        let result = tokio::select! {
+            x = write => x,
+            _ = closed => Err(()),
+        };
+        *last_active.lock().unwrap() = Instant::now();
+
+        if end || result.is_err() {
+            self.remove_request(id);
+        }
+        result.map_err(|()| GenericError::Other("body stream closed".into()))
+    }
+
+    /// Removes a request body stream. If it hasn't ended, the worker sees an aborted stream.
+    pub fn remove_request(&self, id: &str) -> bool {
+        self.requests.lock().unwrap().remove(id).is_some()
+    }
+}
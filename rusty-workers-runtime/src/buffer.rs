@@ -1,15 +1,40 @@
+//! Access to the bytes of `ArrayBuffer`s from host code.
+//!
+//! V8 exposes a backing store as `[Cell<u8>]`, since script code may write to it whenever it runs.
+//! But script code only runs on the isolate thread, and only while it holds the isolate. So the
+//! functions here view bytes as plain slices while borrowing the isolate: shared for reads and
+//! exclusive for writes. No script code can run while the slices are alive.
+//!
+//! `JsArrayBufferViewRef` is not `Send`, so its bytes are only read on the isolate thread. I/O
+//! tasks get a `CopiedBuffer` instead.
+//!
+//! So only synchronous host calls are zero-copy: digests, signing and verification,
+//! `getRandomValues` and decoding the call itself. Asynchronous calls (fetch, KV, cache and
+//! queues) copy their buffers once when the call is made, up to `MAX_ASYNC_CALL_BUFFER_SIZE`
+//! (8 MiB) each, because script code may change or detach an `ArrayBuffer` while the I/O is in
+//! flight. Their results are copied into new `ArrayBuffer`s. Slice views only replace the
+//! byte-by-byte `Cell<u8>` loops for them.
+
 use crate::error::*;
 use rusty_v8 as v8;
 use std::cell::Cell;
 use std::convert::TryFrom;
+use std::marker::PhantomData;
 use std::ops::Deref;
 use std::ops::Range;
 
 pub struct JsArrayBufferViewRef {
     backing: v8::SharedRef<v8::BackingStore>,
     range: Range<usize>,
+
+    /// Keeps this on the isolate thread.
+    _not_send: PhantomData<*const u8>,
 }
 
+/// Bytes of a buffer, copied out on the isolate thread for use by I/O tasks.
+#[derive(Default)]
+pub struct CopiedBuffer(Vec<u8>);
+
 impl JsArrayBufferViewRef {
     pub fn new(scope: &mut v8::HandleScope<'_>, other: v8::Local<'_, v8::Value>) -> JsResult<Self> {
         if let Ok(x) = v8::Local::<'_, v8::ArrayBuffer>::try_from(other) {
@@ -18,6 +43,7 @@ impl JsArrayBufferViewRef {
             Ok(Self {
                 backing,
                 range: 0..len,
+                _not_send: PhantomData,
             })
         } else if let Ok(x) = v8::Local::<'_, v8::ArrayBufferView>::try_from(other) {
             let buf = x.buffer(scope).ok_or_else(|| {
@@ -30,6 +56,7 @@ impl JsArrayBufferViewRef {
             Ok(Self {
                 backing,
                 range: x.byte_offset()..x.byte_offset() + x.byte_length(),
+                _not_send: PhantomData,
             })
         } else {
             Err(JsError::new(
@@ -39,17 +66,73 @@ impl JsArrayBufferViewRef {
         }
     }
 
+    /// Copies the contents out. Returns `None` if longer than `max_length`.
+    ///
+    /// This doesn't borrow the isolate, so that it can be used while the isolate is borrowed by
+    /// something else. Script code can't run during the copy since we are on the isolate thread.
     pub fn read_to_vec(&self, max_length: usize) -> Option<Vec<u8>> {
         let source: &[Cell<u8>] = self;
         if source.len() > max_length {
             return None;
         }
-        let mut buf = Vec::with_capacity(source.len());
-        buf.extend(source.iter().map(|x| x.get()));
-        Some(buf)
+        Some(cells_as_bytes(source).to_vec())
+    }
+
+    /// Views the contents as bytes.
+    pub fn as_bytes<'a>(&'a self, _isolate: &'a v8::Isolate) -> &'a [u8] {
+        cells_as_bytes(self)
+    }
+
+    /// Views the contents as mutable bytes.
+    ///
+    /// # Safety
+    ///
+    /// Other references to the same buffer, like another `JsArrayBufferViewRef` or a
+    /// `v8::SharedRef<v8::BackingStore>`, must not be used to view its bytes while the returned
+    /// slice is alive. Borrowing the isolate only keeps script code and `as_bytes` out.
+    pub unsafe fn as_bytes_mut<'a>(&'a self, _isolate: &'a mut v8::Isolate) -> &'a mut [u8] {
+        cells_as_bytes_mut(self)
+    }
+}
+
+impl CopiedBuffer {
+    /// Copies the contents of `source`. Returns `None` if longer than `max_length`.
+    pub fn new(source: &JsArrayBufferViewRef, max_length: usize) -> Option<Self> {
+        source.read_to_vec(max_length).map(CopiedBuffer)
+    }
+
+    /// Returns the bytes, or `None` if longer than `max_length`.
+    pub fn into_vec(self, max_length: usize) -> Option<Vec<u8>> {
+        if self.0.len() > max_length {
+            return None;
+        }
+        Some(self.0)
     }
 }
 
+/// Views a whole backing store as mutable bytes.
+///
+/// # Safety
+///
+/// No other view of the bytes, through `backing` or another reference to the same backing store,
+/// may be used while the returned slice is alive.
+pub unsafe fn backing_store_bytes_mut<'a>(
+    backing: &'a v8::SharedRef<v8::BackingStore>,
+    _isolate: &'a mut v8::Isolate,
+) -> &'a mut [u8] {
+    cells_as_bytes_mut(backing)
+}
+
+fn cells_as_bytes(cells: &[Cell<u8>]) -> &[u8] {
+    // `Cell<u8>` has the same layout as `u8`.
+    unsafe { std::slice::from_raw_parts(cells.as_ptr() as *const u8, cells.len()) }
+}
+
+/// The caller must make sure that no other view of the bytes is alive.
+unsafe fn cells_as_bytes_mut(cells: &[Cell<u8>]) -> &mut [u8] {
+    std::slice::from_raw_parts_mut(cells.as_ptr() as *mut u8, cells.len())
+}
+
 impl Deref for JsArrayBufferViewRef {
     type Target = [Cell<u8>];
     fn deref(&self) -> &Self::Target {
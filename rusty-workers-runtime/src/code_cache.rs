@@ -0,0 +1,294 @@
+//! V8 code cache for worker scripts and modules.
+//!
+//! Code cache data is produced on the first compilation of a script in a bundle and used on later
+//! compilations of the same script, on any isolate. Entries are keyed by bundle id and path, so
+//! they never go stale: a changed script comes in a new bundle.
+//!
+//! Entries are kept in memory, and in a directory if one is configured, so that they survive
+//! runtime restarts. The directory has a subdirectory per bundle, which `preload` reads into
+//! memory before an instance of the bundle is created. Files are written in the background, so
+//! compilation on the isolate thread never waits for the disk. Files written by a different V8
+//! version would be rejected by V8, so they are discarded on load instead. Data that V8 rejects
+//! anyway, e.g. because of different flags, is evicted and produced again.
+
+use lru_time_cache::LruCache;
+use rusty_v8 as v8;
+use rusty_workers::types::*;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+pub struct CodeCache {
+    /// Cache data by `entry_key`.
+    memory: Mutex<LruCache<String, Arc<[u8]>>>,
+
+    /// Bundles whose directory has been read by `preload`.
+    preloaded: Mutex<LruCache<String, ()>>,
+
+    dir: Option<PathBuf>,
+    rt: tokio::runtime::Handle,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    rejections: AtomicU64,
+}
+
+impl CodeCache {
+    pub fn new(capacity: usize, dir: Option<PathBuf>, rt: tokio::runtime::Handle) -> Self {
+        Self {
+            memory: Mutex::new(LruCache::with_capacity(capacity)),
+            preloaded: Mutex::new(LruCache::with_capacity(capacity)),
+            dir,
+            rt,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            rejections: AtomicU64::new(0),
+        }
+    }
+
+    pub fn stats(&self) -> CodeCacheStats {
+        CodeCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            rejections: self.rejections.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Reads the entries of a bundle from the cache directory into memory, if they haven't been
+    /// read already.
+    pub async fn preload(&self, bundle_id: &str) {
+        let dir = match self.dir {
+            Some(ref x) if bundle_id.len() > 0 => x.clone(),
+            _ => return,
+        };
+        let bundle_key = hash_hex(bundle_id);
+        if self
+            .preloaded
+            .lock()
+            .unwrap()
+            .insert(bundle_key.clone(), ())
+            .is_some()
+        {
+            return;
+        }
+
+        let bundle_dir = dir.join(&bundle_key);
+        let header = file_header();
+        let result = self
+            .rt
+            .spawn_blocking(move || {
+                let mut entries: Vec<(String, Arc<[u8]>)> = vec![];
+                let mut rejections = 0u64;
+                let dir_entries = match std::fs::read_dir(&bundle_dir) {
+                    Ok(x) => x,
+                    Err(_) => return (entries, rejections),
+                };
+                for entry in dir_entries.flatten() {
+                    let name = entry.file_name().to_string_lossy().into_owned();
+
+                    // Skip temporary files.
+                    if name.contains(".") {
+                        continue;
+                    }
+                    let data = match std::fs::read(entry.path()) {
+                        Ok(x) => x,
+                        Err(_) => continue,
+                    };
+                    match data.strip_prefix(header.as_bytes()) {
+                        Some(x) => entries.push((name, Arc::from(x))),
+                        None => {
+                            rejections += 1;
+                            drop(std::fs::remove_file(entry.path()));
+                        }
+                    }
+                }
+                (entries, rejections)
+            })
+            .await;
+        let (entries, rejections) = match result {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("cannot read code cache from {:?}: {:?}", dir, e);
+                return;
+            }
+        };
+        self.rejections.fetch_add(rejections, Ordering::Relaxed);
+        let mut memory = self.memory.lock().unwrap();
+        for (name, data) in entries {
+            memory.insert(format!("{}/{}", bundle_key, name), data);
+        }
+    }
+
+    /// Compiles a classic script, using and producing code cache data.
+    ///
+    /// An empty `bundle_id` disables caching.
+    pub fn compile_script<'s>(
+        &self,
+        scope: &mut v8::HandleScope<'s>,
+        bundle_id: &str,
+        path: &str,
+        source: v8::Local<'s, v8::String>,
+        origin: &v8::ScriptOrigin<'s>,
+    ) -> Option<v8::Local<'s, v8::Script>> {
+        if bundle_id.len() == 0 {
+            return v8::Script::compile(scope, source, Some(origin));
+        }
+
+        let key = entry_key(bundle_id, path);
+        let cached = self.lookup(&key);
+        let (mut source, options) = make_source(source, origin, cached.as_deref());
+        let script = v8::script_compiler::compile(
+            scope,
+            &mut source,
+            options,
+            v8::script_compiler::NoCacheReason::NoReason,
+        )?;
+
+        if self.should_produce(&key, cached.is_some(), &source) {
+            if let Some(data) = script.get_unbound_script(scope).create_code_cache() {
+                self.insert(key, &data);
+            }
+        }
+        Some(script)
+    }
+
+    /// Compiles an ES module, using and producing code cache data.
+    ///
+    /// An empty `bundle_id` disables caching.
+    pub fn compile_module<'s>(
+        &self,
+        scope: &mut v8::HandleScope<'s>,
+        bundle_id: &str,
+        path: &str,
+        source: v8::Local<'s, v8::String>,
+        origin: &v8::ScriptOrigin<'s>,
+    ) -> Option<v8::Local<'s, v8::Module>> {
+        if bundle_id.len() == 0 {
+            let source = v8::script_compiler::Source::new(source, Some(origin));
+            return v8::script_compiler::compile_module(scope, source);
+        }
+
+        let key = entry_key(bundle_id, path);
+        let cached = self.lookup(&key);
+        let (mut source, options) = make_source(source, origin, cached.as_deref());
+        let module = v8::script_compiler::compile_module2(
+            scope,
+            &mut source,
+            options,
+            v8::script_compiler::NoCacheReason::NoReason,
+        )?;
+
+        if self.should_produce(&key, cached.is_some(), &source) {
+            if let Some(data) = module.get_unbound_module_script(scope).create_code_cache() {
+                self.insert(key, &data);
+            }
+        }
+        Some(module)
+    }
+
+    /// Counts the result of a compilation and returns whether new cache data should be produced.
+    ///
+    /// Cached data that V8 rejected is evicted, and counted as a miss.
+    fn should_produce(
+        &self,
+        key: &str,
+        consumed: bool,
+        source: &v8::script_compiler::Source,
+    ) -> bool {
+        if !consumed {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            true
+        } else if source.get_cached_data().rejected {
+            warn!("code cache entry {} rejected by v8", key);
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            self.rejections.fetch_add(1, Ordering::Relaxed);
+            self.evict(key);
+            true
+        } else {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            false
+        }
+    }
+
+    fn lookup(&self, key: &str) -> Option<Arc<[u8]>> {
+        self.memory.lock().unwrap().get(key).cloned()
+    }
+
+    fn evict(&self, key: &str) {
+        self.memory.lock().unwrap().remove(key);
+
+        let path = match self.dir {
+            Some(ref x) => x.join(key),
+            None => return,
+        };
+        self.rt
+            .spawn_blocking(move || drop(std::fs::remove_file(&path)));
+    }
+
+    fn insert(&self, key: String, data: &[u8]) {
+        let data: Arc<[u8]> = Arc::from(data);
+        self.memory
+            .lock()
+            .unwrap()
+            .insert(key.clone(), data.clone());
+
+        let path = match self.dir {
+            Some(ref x) => x.join(&key),
+            None => return,
+        };
+        self.rt.spawn_blocking(move || {
+            // Write to a temporary file first so that readers never see partial data.
+            let tmp_path = path.with_extension(format!("tmp-{}", rusty_workers::util::rand_hex(8)));
+            let mut contents = Vec::from(file_header());
+            contents.extend_from_slice(&data);
+            let result = std::fs::create_dir_all(path.parent().unwrap())
+                .and_then(|_| std::fs::write(&tmp_path, &contents))
+                .and_then(|_| std::fs::rename(&tmp_path, &path));
+            if let Err(e) = result {
+                warn!("cannot write code cache to {:?}: {:?}", path, e);
+                drop(std::fs::remove_file(&tmp_path));
+            }
+        });
+    }
+}
+
+fn make_source<'s>(
+    source: v8::Local<'s, v8::String>,
+    origin: &v8::ScriptOrigin<'s>,
+    cached: Option<&[u8]>,
+) -> (
+    v8::script_compiler::Source,
+    v8::script_compiler::CompileOptions,
+) {
+    match cached {
+        Some(data) => (
+            v8::script_compiler::Source::new_with_cached_data(
+                source,
+                Some(origin),
+                v8::script_compiler::CachedData::new(data),
+            ),
+            v8::script_compiler::CompileOptions::ConsumeCodeCache,
+        ),
+        None => (
+            v8::script_compiler::Source::new(source, Some(origin)),
+            v8::script_compiler::CompileOptions::NoCompileOptions,
+        ),
+    }
+}
+
+/// Returns the key of an entry, which is also its path in the cache directory.
+fn entry_key(bundle_id: &str, path: &str) -> String {
+    format!("{}/{}", hash_hex(bundle_id), hash_hex(path))
+}
+
+fn hash_hex(x: &str) -> String {
+    ring::digest::digest(&ring::digest::SHA256, x.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|x| format!("{:02x}", x))
+        .collect()
+}
+
+/// Prefix of cache files, identifying the V8 version that produced them.
+fn file_header() -> String {
+    format!("v8 {}\n", v8::V8::get_version())
+}
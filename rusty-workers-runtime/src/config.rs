@@ -1,3 +1,4 @@
+use std::net::SocketAddr;
 use structopt::StructOpt;
 
 #[derive(StructOpt, Debug)]
@@ -38,7 +39,36 @@ pub struct Config {
     #[structopt(long, env = "RW_CPU_WAIT_TIMEOUT_MS", default_value = "1000")]
     pub cpu_wait_timeout_ms: u64,
 
-    /// TiKV cluster addresses, comma-separated.
-    #[structopt(long, env = "RW_TIKV_CLUSTER", default_value = "")]
-    pub tikv_cluster: String,
+    /// Max number of compiled WebAssembly modules to keep across instances.
+    #[structopt(long, env = "RW_WASM_MODULE_CACHE_SIZE", default_value = "100")]
+    pub wasm_module_cache_size: usize,
+
+    /// Max number of parsed source maps to keep across instances.
+    #[structopt(long, env = "RW_SOURCE_MAP_CACHE_SIZE", default_value = "100")]
+    pub source_map_cache_size: usize,
+
+    /// Max number of scripts to keep code cache data for in memory.
+    #[structopt(long, env = "RW_CODE_CACHE_SIZE", default_value = "100")]
+    pub code_cache_size: usize,
+
+    /// Directory to persist code cache data in. Code cache is only kept in memory if empty.
+    #[structopt(long, env = "RW_CODE_CACHE_DIR", default_value = "")]
+    pub code_cache_dir: String,
+
+    /// Max total size of responses stored with the Cache API, in bytes. Defaults to 64 MiB.
+    #[structopt(long, env = "RW_RESPONSE_CACHE_SIZE_BYTES", default_value = "67108864")]
+    pub response_cache_size_bytes: usize,
+
+    /// Listen address for DevTools inspector connections. The inspector is disabled if not set.
+    #[structopt(long, env = "RW_INSPECTOR_LISTEN")]
+    pub inspector_listen: Option<SocketAddr>,
+
+    /// Address (`host:port`) that DevTools should connect to, if different from the listen address.
+    /// Required when listening on an unspecified address like `0.0.0.0`.
+    #[structopt(long, env = "RW_INSPECTOR_ADVERTISE", default_value = "")]
+    pub inspector_advertise: String,
+
+    /// Storage URL (`tikv://pd1,pd2`, `memory://` or `file://path`). KV is disabled if empty.
+    #[structopt(long, env = "RW_STORAGE", default_value = "")]
+    pub storage: String,
 }
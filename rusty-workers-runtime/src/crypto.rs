@@ -1,16 +1,118 @@
 use crate::buffer::*;
+use crate::der;
 use crate::error::*;
 use crate::mm::*;
+use aes::{Aes128, Aes192, Aes256};
+use block_modes::block_padding::Pkcs7;
+use block_modes::{BlockMode, Cbc};
+use p256::elliptic_curve::sec1::ToEncodedPoint;
+use rand::Rng;
+use ring::signature::{self, KeyPair};
 use rusty_v8 as v8;
 use serde::{Deserialize, Serialize};
-use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+
+/// Max number of live keys an instance keeps. Importing or generating a key fails when the limit
+/// is reached.
+const MAX_KEYS_PER_INSTANCE: usize = 1024;
+
+/// Max size of data that has to be copied out of the V8 heap, like the input of `encrypt` and
+/// `decrypt`.
+const MAX_CRYPTO_INPUT_SIZE: usize = 16 * 1024 * 1024;
+
+const OID_EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+const OID_RSA_ENCRYPTION: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01];
+const OID_ED25519: &[u8] = &[0x2b, 0x65, 0x70];
+const OID_X25519: &[u8] = &[0x2b, 0x65, 0x6e];
+
+/// Curve OIDs, as complete elements.
+const OID_P256: &[u8] = &[0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const OID_P384: &[u8] = &[0x06, 0x05, 0x2b, 0x81, 0x04, 0x00, 0x22];
+
+/// Max number of HMAC blocks computed by one PBKDF2 call, summed over output blocks.
+///
+/// Crypto calls run synchronously on the instance thread with the execution timer started, so
+/// PBKDF2 is charged to the worker's time budget. But a running call can't be interrupted when the
+/// budget runs out, so this bounds how long it can overrun.
+const MAX_PBKDF2_ITERATIONS: u64 = 1_000_000;
+
+/// Max length of the output of `deriveBits`, in bytes.
+const MAX_DERIVED_BITS_SIZE: usize = 1024;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum CryptoCall {
     Digest(DigestAlgorithm),
+
+    /// Imports a key from the first buffer, or from the JWK in `format`. Returns the key handle.
+    ImportKey {
+        algorithm: KeyAlgorithm,
+        format: KeyFormat,
+        extractable: bool,
+    },
+
+    /// Generates a random key of `length` bits. Returns the key handle.
+    GenerateKey {
+        algorithm: KeyAlgorithm,
+        length: u32,
+        extractable: bool,
+    },
+
+    /// Exports the raw material of an extractable key.
+    ExportKey(u32),
+
+    /// Returns the public key of an asymmetric key, in raw format.
+    PublicKey(u32),
+
+    /// Signs the first buffer with a key. Returns the signature.
+    ///
+    /// The hash function is only given for ECDSA. Other algorithms take it from the key.
+    Sign(u32, Option<DigestAlgorithm>),
+
+    /// Verifies the signature in the first buffer over the second buffer. Returns a boolean.
+    Verify(u32, Option<DigestAlgorithm>),
+
+    /// Encrypts the first buffer with a key. The second buffer is the IV, and the optional third
+    /// buffer is the additional data for AES-GCM.
+    Encrypt(u32, CipherParams),
+
+    /// Decrypts the first buffer with a key. Buffers are the same as `Encrypt`.
+    Decrypt(u32, CipherParams),
+
+    /// Derives `length` bits from a key. The full shared secret is returned for ECDH when
+    /// `length` is not given.
+    ///
+    /// The first buffer is the salt for PBKDF2 and HKDF, and the second buffer is the info for
+    /// HKDF.
+    DeriveBits(u32, DeriveParams, Option<u32>),
+
+    /// Drops a reference to a key. Called when a JS `CryptoKey` is garbage collected.
+    ReleaseKey(u32),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum DeriveParams {
+    Pbkdf2 {
+        hash: DigestAlgorithm,
+        iterations: u32,
+    },
+    Hkdf {
+        hash: DigestAlgorithm,
+    },
+
+    /// ECDH with the public key of the peer, by handle.
+    Ecdh {
+        public: u32,
+    },
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum CipherParams {
+    AesGcm { tag_length: u32 },
+    AesCbc,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
 pub enum DigestAlgorithm {
     Sha1,
     Sha256,
@@ -18,35 +120,1041 @@ pub enum DigestAlgorithm {
     Sha512,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum KeyAlgorithm {
+    Hmac(DigestAlgorithm),
+    AesGcm,
+    AesCbc,
+    Ecdsa(EcCurve),
+    Ed25519,
+    RsaPkcs1(DigestAlgorithm),
+    RsaPss(DigestAlgorithm),
+    Pbkdf2,
+    Hkdf,
+    Ecdh(EcdhCurve),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum EcCurve {
+    P256,
+    P384,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum EcdhCurve {
+    P256,
+    X25519,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum KeyFormat {
+    Raw,
+    Spki,
+    Pkcs8,
+    Jwk(Jwk),
+}
+
+/// Components of an asymmetric JWK, base64url-encoded.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Jwk {
+    pub kty: String,
+
+    #[serde(default)]
+    pub crv: Option<String>,
+
+    #[serde(default)]
+    pub x: Option<String>,
+
+    #[serde(default)]
+    pub y: Option<String>,
+
+    #[serde(default)]
+    pub d: Option<String>,
+
+    #[serde(default)]
+    pub n: Option<String>,
+
+    #[serde(default)]
+    pub e: Option<String>,
+}
+
+#[derive(Copy, Clone, Debug)]
+enum RsaPadding {
+    Pkcs1,
+    Pss,
+}
+
+/// Keys imported by the current instance, by handle. Stored in an isolate slot.
+///
+/// Every handle returned to JS is owned by one `CryptoKey` object, which releases it when
+/// collected. Importing the same key material twice returns the same handle with one more
+/// reference, so that workers importing a key on every request don't grow the store.
+pub struct CryptoKeyStore {
+    keys: BTreeMap<u32, CryptoKey>,
+    next_handle: u32,
+
+    /// Handles by a digest of the algorithm and key material.
+    index: BTreeMap<Vec<u8>, u32>,
+}
+
+struct CryptoKey {
+    material: KeyMaterial,
+
+    /// Raw key material. Only kept for extractable keys.
+    raw: Option<Vec<u8>>,
+
+    /// Key of this key in `CryptoKeyStore::index`, if imported.
+    fingerprint: Option<Vec<u8>>,
+
+    /// Number of JS `CryptoKey` objects holding this handle.
+    refs: usize,
+}
+
+enum KeyMaterial {
+    Hmac(ring::hmac::Key),
+    AesGcm(ring::aead::LessSafeKey),
+    AesCbc(Vec<u8>),
+    Ecdsa {
+        curve: EcCurve,
+        private: Option<signature::EcdsaKeyPair>,
+
+        /// Uncompressed point.
+        public: Vec<u8>,
+    },
+    Ed25519 {
+        private: Option<signature::Ed25519KeyPair>,
+        public: Vec<u8>,
+    },
+    Rsa {
+        padding: RsaPadding,
+        hash: DigestAlgorithm,
+
+        /// DER-encoded `RSAPublicKey`.
+        public: Vec<u8>,
+    },
+
+    /// Password for PBKDF2.
+    Pbkdf2(Vec<u8>),
+
+    /// Input keying material for HKDF.
+    Hkdf(Vec<u8>),
+
+    Ecdh {
+        curve: EcdhCurve,
+        private: Option<EcdhPrivateKey>,
+
+        /// Uncompressed point for P-256.
+        public: Vec<u8>,
+    },
+}
+
+/// `ring` only supports ephemeral ECDH keys, which can derive bits once and can't be imported.
+enum EcdhPrivateKey {
+    P256(p256::SecretKey),
+    X25519(x25519_dalek::StaticSecret),
+}
+
+enum EcdhPublicKey {
+    P256(p256::PublicKey),
+    X25519(x25519_dalek::PublicKey),
+}
+
+impl CryptoKeyStore {
+    pub fn new() -> Self {
+        Self {
+            keys: BTreeMap::new(),
+            next_handle: 0,
+            index: BTreeMap::new(),
+        }
+    }
+
+    fn get(isolate: &mut v8::Isolate) -> &mut Self {
+        isolate
+            .get_slot_mut::<Option<Self>>()
+            .unwrap()
+            .as_mut()
+            .unwrap()
+    }
+
+    fn get_ref(isolate: &v8::Isolate) -> &Self {
+        isolate
+            .get_slot::<Option<Self>>()
+            .unwrap()
+            .as_ref()
+            .unwrap()
+    }
+
+    fn import(
+        &mut self,
+        alg: &KeyAlgorithm,
+        format: &KeyFormat,
+        data: &[u8],
+        extractable: bool,
+    ) -> JsResult<u32> {
+        let mut ctx = ring::digest::Context::new(&ring::digest::SHA256);
+        ctx.update(
+            serde_json::to_string(&(alg, format))
+                .map_err(|_| JsError::error("importKey: bad algorithm"))?
+                .as_bytes(),
+        );
+        ctx.update(&[extractable as u8]);
+        ctx.update(data);
+        let fingerprint = ctx.finish().as_ref().to_vec();
+        if let Some(handle) = self.index.get(&fingerprint).copied() {
+            self.key_mut(handle)?.refs += 1;
+            return Ok(handle);
+        }
+
+        let handle = self.insert(alg, format, data, extractable)?;
+        self.index.insert(fingerprint.clone(), handle);
+        self.keys.get_mut(&handle).unwrap().fingerprint = Some(fingerprint);
+        Ok(handle)
+    }
+
+    fn insert(
+        &mut self,
+        alg: &KeyAlgorithm,
+        format: &KeyFormat,
+        data: &[u8],
+        extractable: bool,
+    ) -> JsResult<u32> {
+        let material = match *alg {
+            KeyAlgorithm::Ecdsa(_)
+            | KeyAlgorithm::Ed25519
+            | KeyAlgorithm::RsaPkcs1(_)
+            | KeyAlgorithm::RsaPss(_)
+            | KeyAlgorithm::Ecdh(_) => import_asymmetric_key(alg, format, data)?,
+            _ if !matches!(format, KeyFormat::Raw) => {
+                return Err(JsError::error("importKey: secret keys must be raw"));
+            }
+            KeyAlgorithm::Hmac(hash) => {
+                KeyMaterial::Hmac(ring::hmac::Key::new(hmac_algorithm(hash), data))
+            }
+            KeyAlgorithm::AesGcm => {
+                let alg = match data.len() {
+                    16 => &ring::aead::AES_128_GCM,
+                    32 => &ring::aead::AES_256_GCM,
+                    _ => {
+                        return Err(JsError::error(
+                            "AES-GCM: key length must be 128 or 256 bits",
+                        ))
+                    }
+                };
+                let key = ring::aead::UnboundKey::new(alg, data)
+                    .map_err(|_| JsError::error("AES-GCM: bad key"))?;
+                KeyMaterial::AesGcm(ring::aead::LessSafeKey::new(key))
+            }
+            KeyAlgorithm::AesCbc => match data.len() {
+                16 | 24 | 32 => KeyMaterial::AesCbc(data.to_vec()),
+                _ => {
+                    return Err(JsError::error(
+                        "AES-CBC: key length must be 128, 192 or 256 bits",
+                    ))
+                }
+            },
+            KeyAlgorithm::Pbkdf2 => KeyMaterial::Pbkdf2(data.to_vec()),
+            KeyAlgorithm::Hkdf => KeyMaterial::Hkdf(data.to_vec()),
+        };
+        let is_secret = matches!(
+            material,
+            KeyMaterial::Hmac(_)
+                | KeyMaterial::AesGcm(_)
+                | KeyMaterial::AesCbc(_)
+                | KeyMaterial::Pbkdf2(_)
+                | KeyMaterial::Hkdf(_)
+        );
+        // Only secret keys can be exported for now.
+        let raw = if extractable && is_secret {
+            Some(data.to_vec())
+        } else {
+            None
+        };
+        self.push(material, raw)
+    }
+
+    /// Generates an ECDH private key.
+    fn generate_ecdh(&mut self, curve: EcdhCurve) -> JsResult<u32> {
+        let private = EcdhPrivateKey::generate(curve);
+        self.push(
+            KeyMaterial::Ecdh {
+                curve,
+                public: private.public_key(),
+                private: Some(private),
+            },
+            None,
+        )
+    }
+
+    fn push(&mut self, material: KeyMaterial, raw: Option<Vec<u8>>) -> JsResult<u32> {
+        if self.keys.len() >= MAX_KEYS_PER_INSTANCE {
+            return Err(JsError::error("crypto: too many keys"));
+        }
+        let handle = self.next_handle;
+        self.next_handle = self.next_handle.wrapping_add(1);
+        self.keys.insert(
+            handle,
+            CryptoKey {
+                material,
+                raw,
+                fingerprint: None,
+                refs: 1,
+            },
+        );
+        Ok(handle)
+    }
+
+    /// Drops a reference to a key, and removes the key once nothing refers to it.
+    fn release(&mut self, handle: u32) {
+        let key = match self.keys.get_mut(&handle) {
+            Some(x) => x,
+            None => return,
+        };
+        key.refs -= 1;
+        if key.refs > 0 {
+            return;
+        }
+        let key = self.keys.remove(&handle).unwrap();
+        if let Some(fingerprint) = key.fingerprint {
+            self.index.remove(&fingerprint);
+        }
+    }
+
+    fn key(&self, handle: u32) -> JsResult<&CryptoKey> {
+        self.keys
+            .get(&handle)
+            .ok_or_else(|| JsError::error("crypto: bad key handle"))
+    }
+
+    fn key_mut(&mut self, handle: u32) -> JsResult<&mut CryptoKey> {
+        self.keys
+            .get_mut(&handle)
+            .ok_or_else(|| JsError::error("crypto: bad key handle"))
+    }
+}
+
 impl CryptoCall {
     pub fn run<'s>(
         self,
         scope: &mut v8::HandleScope<'s>,
         buffers: Vec<JsArrayBufferViewRef>,
     ) -> JsResult<Option<v8::Local<'s, v8::Value>>> {
+        let encrypt = matches!(self, CryptoCall::Encrypt(..));
         let mut buffers = buffers.into_iter();
+        let mut next_buffer = || {
+            buffers
+                .next()
+                .ok_or_else(|| JsError::error("crypto: missing buffer"))
+        };
 
         match self {
             CryptoCall::Digest(alg) => {
-                let buf = buffers
-                    .next()
-                    .ok_or_else(|| JsError::error("crypto: missing buffer"))?;
+                let buf = next_buffer()?;
                 let alg: &'static ring::digest::Algorithm = match alg {
                     DigestAlgorithm::Sha1 => &ring::digest::SHA1_FOR_LEGACY_USE_ONLY,
                     DigestAlgorithm::Sha256 => &ring::digest::SHA256,
                     DigestAlgorithm::Sha384 => &ring::digest::SHA384,
                     DigestAlgorithm::Sha512 => &ring::digest::SHA512,
                 };
-                let bytes: &[Cell<u8>] = &*buf;
-                let mut ctx = ring::digest::Context::new(alg);
-                for b in bytes {
-                    ctx.update(&[b.get()]);
-                }
-                let output = ctx.finish();
+                let output = ring::digest::digest(alg, buf.as_bytes(scope));
                 let output: &[u8] = output.as_ref();
                 let output = slice_to_arraybuffer(scope, output)?;
                 Ok(Some(output.into()))
             }
+            CryptoCall::ImportKey {
+                algorithm,
+                format,
+                extractable,
+            } => {
+                let data = match format {
+                    KeyFormat::Jwk(_) => vec![],
+                    _ => next_buffer()?
+                        .read_to_vec(MAX_CRYPTO_INPUT_SIZE)
+                        .ok_or_else(|| JsError::error("crypto: data too large"))?,
+                };
+                let handle =
+                    CryptoKeyStore::get(scope).import(&algorithm, &format, &data, extractable)?;
+                Ok(Some(v8::Integer::new_from_unsigned(scope, handle).into()))
+            }
+            CryptoCall::GenerateKey {
+                algorithm,
+                length,
+                extractable,
+            } => {
+                if let KeyAlgorithm::Ecdh(curve) = algorithm {
+                    let handle = CryptoKeyStore::get(scope).generate_ecdh(curve)?;
+                    return Ok(Some(v8::Integer::new_from_unsigned(scope, handle).into()));
+                }
+                if length == 0 || length % 8 != 0 || length > 1024 {
+                    return Err(JsError::error("generateKey: bad key length"));
+                }
+                let mut data = vec![0u8; (length / 8) as usize];
+                rand::thread_rng().fill(&mut data[..]);
+                let handle = CryptoKeyStore::get(scope).insert(
+                    &algorithm,
+                    &KeyFormat::Raw,
+                    &data,
+                    extractable,
+                )?;
+                Ok(Some(v8::Integer::new_from_unsigned(scope, handle).into()))
+            }
+            CryptoCall::ExportKey(handle) => {
+                let raw = CryptoKeyStore::get(scope)
+                    .key(handle)?
+                    .raw
+                    .clone()
+                    .ok_or_else(|| JsError::error("exportKey: key is not extractable"))?;
+                let output = slice_to_arraybuffer(scope, &raw)?;
+                Ok(Some(output.into()))
+            }
+            CryptoCall::PublicKey(handle) => {
+                let public = match CryptoKeyStore::get(scope).key(handle)?.material {
+                    KeyMaterial::Ecdsa { ref public, .. }
+                    | KeyMaterial::Ed25519 { ref public, .. }
+                    | KeyMaterial::Ecdh { ref public, .. } => public.clone(),
+                    _ => return Err(JsError::error("exportKey: unsupported key")),
+                };
+                let output = slice_to_arraybuffer(scope, &public)?;
+                Ok(Some(output.into()))
+            }
+            CryptoCall::DeriveBits(handle, params, length) => {
+                let length = match length {
+                    Some(x) if x == 0 || x % 8 != 0 => {
+                        return Err(JsError::error(
+                            "deriveBits: length must be a positive multiple of 8",
+                        ))
+                    }
+                    Some(x) => Some(x as usize / 8),
+                    None => None,
+                };
+                if length.map(|x| x > MAX_DERIVED_BITS_SIZE).unwrap_or(false) {
+                    return Err(JsError::error("deriveBits: length too large"));
+                }
+                let store = CryptoKeyStore::get(scope);
+                let output = match (params, &store.key(handle)?.material) {
+                    (DeriveParams::Pbkdf2 { hash, iterations }, KeyMaterial::Pbkdf2(password)) => {
+                        let length =
+                            length.ok_or_else(|| JsError::error("deriveBits: missing length"))?;
+                        let salt = next_buffer()?
+                            .read_to_vec(MAX_CRYPTO_INPUT_SIZE)
+                            .ok_or_else(|| JsError::error("crypto: data too large"))?;
+                        pbkdf2(hash, iterations, &salt, password, length)?
+                    }
+                    (DeriveParams::Hkdf { hash }, KeyMaterial::Hkdf(ikm)) => {
+                        let length =
+                            length.ok_or_else(|| JsError::error("deriveBits: missing length"))?;
+                        let salt = next_buffer()?
+                            .read_to_vec(MAX_CRYPTO_INPUT_SIZE)
+                            .ok_or_else(|| JsError::error("crypto: data too large"))?;
+                        let info = next_buffer()?
+                            .read_to_vec(MAX_CRYPTO_INPUT_SIZE)
+                            .ok_or_else(|| JsError::error("crypto: data too large"))?;
+                        hkdf(hash, &salt, &info, ikm, length)?
+                    }
+                    (DeriveParams::Ecdh { public }, KeyMaterial::Ecdh { curve, private, .. }) => {
+                        let peer = match store.key(public)?.material {
+                            KeyMaterial::Ecdh {
+                                curve: peer_curve,
+                                ref public,
+                                ..
+                            } if peer_curve == *curve => EcdhPublicKey::parse(*curve, public),
+                            _ => None,
+                        }
+                        .ok_or_else(|| JsError::error("deriveBits: bad public key"))?;
+                        let secret = private
+                            .as_ref()
+                            .ok_or_else(|| JsError::error("deriveBits: expecting a private key"))?
+                            .agree(&peer)?;
+                        match length {
+                            Some(x) if x > secret.len() => {
+                                return Err(JsError::error("deriveBits: length too large"))
+                            }
+                            Some(x) => secret[..x].to_vec(),
+                            None => secret,
+                        }
+                    }
+                    _ => return Err(JsError::error("deriveBits: key algorithm mismatch")),
+                };
+                let output = slice_to_arraybuffer(scope, &output)?;
+                Ok(Some(output.into()))
+            }
+            CryptoCall::ReleaseKey(handle) => {
+                CryptoKeyStore::get(scope).release(handle);
+                Ok(None)
+            }
+            CryptoCall::Sign(handle, hash) => {
+                let data = next_buffer()?;
+                let output = {
+                    let isolate: &v8::Isolate = scope;
+                    let data = data.as_bytes(isolate);
+                    match CryptoKeyStore::get_ref(isolate).key(handle)?.material {
+                        KeyMaterial::Hmac(ref key) => ring::hmac::sign(key, data).as_ref().to_vec(),
+                        ref material => asymmetric_sign(material, hash, data)?,
+                    }
+                };
+                let output = slice_to_arraybuffer(scope, &output)?;
+                Ok(Some(output.into()))
+            }
+            CryptoCall::Encrypt(handle, params) | CryptoCall::Decrypt(handle, params) => {
+                let mut data = next_buffer()?
+                    .read_to_vec(MAX_CRYPTO_INPUT_SIZE)
+                    .ok_or_else(|| JsError::error("crypto: data too large"))?;
+                let iv = next_buffer()?
+                    .read_to_vec(MAX_CRYPTO_INPUT_SIZE)
+                    .ok_or_else(|| JsError::error("crypto: data too large"))?;
+                let additional_data = match next_buffer() {
+                    Ok(x) => x
+                        .read_to_vec(MAX_CRYPTO_INPUT_SIZE)
+                        .ok_or_else(|| JsError::error("crypto: data too large"))?,
+                    Err(_) => vec![],
+                };
+                let output = match (&CryptoKeyStore::get(scope).key(handle)?.material, params) {
+                    (KeyMaterial::AesGcm(ref key), CipherParams::AesGcm { tag_length }) => {
+                        if tag_length != 128 {
+                            return Err(JsError::error("AES-GCM: tag length must be 128 bits"));
+                        }
+                        let nonce = ring::aead::Nonce::try_assume_unique_for_key(&iv)
+                            .map_err(|_| JsError::error("AES-GCM: iv must be 96 bits"))?;
+                        let aad = ring::aead::Aad::from(&additional_data[..]);
+                        if encrypt {
+                            key.seal_in_place_append_tag(nonce, aad, &mut data)
+                                .map_err(|_| JsError::error("encrypt: operation failed"))?;
+                            data
+                        } else {
+                            let len = key
+                                .open_in_place(nonce, aad, &mut data)
+                                .map_err(|_| JsError::error("decrypt: operation failed"))?
+                                .len();
+                            data.truncate(len);
+                            data
+                        }
+                    }
+                    (KeyMaterial::AesCbc(ref key), CipherParams::AesCbc) => {
+                        aes_cbc(key, &iv, &data, encrypt)?
+                    }
+                    _ => return Err(JsError::error("crypto: key algorithm mismatch")),
+                };
+                let output = slice_to_arraybuffer(scope, &output)?;
+                Ok(Some(output.into()))
+            }
+            CryptoCall::Verify(handle, hash) => {
+                let signature = next_buffer()?;
+                let data = next_buffer()?;
+                let ok = {
+                    let isolate: &v8::Isolate = scope;
+                    let signature = signature.as_bytes(isolate);
+                    let data = data.as_bytes(isolate);
+                    match CryptoKeyStore::get_ref(isolate).key(handle)?.material {
+                        KeyMaterial::Hmac(ref key) => {
+                            ring::hmac::verify(key, data, signature).is_ok()
+                        }
+                        ref material => asymmetric_verify(material, hash, signature, data)?,
+                    }
+                };
+                Ok(Some(v8::Boolean::new(scope, ok).into()))
+            }
+        }
+    }
+}
+
+fn hmac_algorithm(hash: DigestAlgorithm) -> ring::hmac::Algorithm {
+    match hash {
+        DigestAlgorithm::Sha1 => ring::hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY,
+        DigestAlgorithm::Sha256 => ring::hmac::HMAC_SHA256,
+        DigestAlgorithm::Sha384 => ring::hmac::HMAC_SHA384,
+        DigestAlgorithm::Sha512 => ring::hmac::HMAC_SHA512,
+    }
+}
+
+impl EcdhPrivateKey {
+    fn generate(curve: EcdhCurve) -> Self {
+        let mut rng = rand::thread_rng();
+        loop {
+            // Out of range scalars are rejected for P-256. This is very unlikely.
+            let mut d = [0u8; 32];
+            rng.fill(&mut d[..]);
+            if let Ok(x) = Self::from_bytes(curve, &d) {
+                return x;
+            }
+        }
+    }
+
+    fn from_bytes(curve: EcdhCurve, d: &[u8]) -> JsResult<Self> {
+        match curve {
+            EcdhCurve::P256 => p256::SecretKey::from_be_bytes(d)
+                .map(EcdhPrivateKey::P256)
+                .map_err(|_| bad_key_data()),
+            EcdhCurve::X25519 => {
+                let d: [u8; 32] = d.try_into().map_err(|_| bad_key_data())?;
+                Ok(EcdhPrivateKey::X25519(x25519_dalek::StaticSecret::from(d)))
+            }
+        }
+    }
+
+    fn public_key(&self) -> Vec<u8> {
+        match self {
+            EcdhPrivateKey::P256(x) => x.public_key().to_encoded_point(false).as_bytes().to_vec(),
+            EcdhPrivateKey::X25519(x) => x25519_dalek::PublicKey::from(x).as_bytes().to_vec(),
+        }
+    }
+
+    fn agree(&self, peer: &EcdhPublicKey) -> JsResult<Vec<u8>> {
+        let secret = match (self, peer) {
+            (EcdhPrivateKey::P256(private), EcdhPublicKey::P256(public)) => {
+                p256::ecdh::diffie_hellman(private.to_nonzero_scalar(), public.as_affine())
+                    .as_bytes()
+                    .to_vec()
+            }
+            (EcdhPrivateKey::X25519(private), EcdhPublicKey::X25519(public)) => {
+                private.diffie_hellman(public).as_bytes().to_vec()
+            }
+            _ => return Err(JsError::error("deriveBits: bad public key")),
+        };
+
+        // Low order X25519 points give an all-zero secret.
+        if secret.iter().all(|x| *x == 0) {
+            return Err(JsError::error("deriveBits: bad public key"));
+        }
+        Ok(secret)
+    }
+}
+
+impl EcdhPublicKey {
+    fn parse(curve: EcdhCurve, data: &[u8]) -> Option<Self> {
+        match curve {
+            EcdhCurve::P256 => p256::PublicKey::from_sec1_bytes(data)
+                .ok()
+                .map(EcdhPublicKey::P256),
+            EcdhCurve::X25519 => {
+                let data: [u8; 32] = data.try_into().ok()?;
+                Some(EcdhPublicKey::X25519(x25519_dalek::PublicKey::from(data)))
+            }
+        }
+    }
+}
+
+fn pbkdf2(
+    hash: DigestAlgorithm,
+    iterations: u32,
+    salt: &[u8],
+    password: &[u8],
+    length: usize,
+) -> JsResult<Vec<u8>> {
+    let (alg, output_len) = match hash {
+        DigestAlgorithm::Sha1 => (ring::pbkdf2::PBKDF2_HMAC_SHA1, 20),
+        DigestAlgorithm::Sha256 => (ring::pbkdf2::PBKDF2_HMAC_SHA256, 32),
+        DigestAlgorithm::Sha384 => (ring::pbkdf2::PBKDF2_HMAC_SHA384, 48),
+        DigestAlgorithm::Sha512 => (ring::pbkdf2::PBKDF2_HMAC_SHA512, 64),
+    };
+    let iterations = std::num::NonZeroU32::new(iterations)
+        .ok_or_else(|| JsError::error("PBKDF2: iterations must be positive"))?;
+    let blocks = ((length + output_len - 1) / output_len) as u64;
+    if iterations.get() as u64 * blocks > MAX_PBKDF2_ITERATIONS {
+        return Err(JsError::error(format!(
+            "PBKDF2: iterations times output blocks must not exceed {}",
+            MAX_PBKDF2_ITERATIONS
+        )));
+    }
+    let mut output = vec![0u8; length];
+    ring::pbkdf2::derive(alg, iterations, salt, password, &mut output);
+    Ok(output)
+}
+
+/// Output length for `ring::hkdf`.
+struct HkdfLength(usize);
+
+impl ring::hkdf::KeyType for HkdfLength {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+fn hkdf(
+    hash: DigestAlgorithm,
+    salt: &[u8],
+    info: &[u8],
+    ikm: &[u8],
+    length: usize,
+) -> JsResult<Vec<u8>> {
+    let alg = match hash {
+        DigestAlgorithm::Sha1 => ring::hkdf::HKDF_SHA1_FOR_LEGACY_USE_ONLY,
+        DigestAlgorithm::Sha256 => ring::hkdf::HKDF_SHA256,
+        DigestAlgorithm::Sha384 => ring::hkdf::HKDF_SHA384,
+        DigestAlgorithm::Sha512 => ring::hkdf::HKDF_SHA512,
+    };
+    let info = [info];
+    let okm = ring::hkdf::Salt::new(alg, salt)
+        .extract(ikm)
+        .expand(&info, HkdfLength(length))
+        .map_err(|_| JsError::error("HKDF: length too large"))?;
+    let mut output = vec![0u8; length];
+    okm.fill(&mut output)
+        .map_err(|_| JsError::error("HKDF: length too large"))?;
+    Ok(output)
+}
+
+fn aes_cbc(key: &[u8], iv: &[u8], data: &[u8], encrypt: bool) -> JsResult<Vec<u8>> {
+    macro_rules! cbc {
+        ($cipher:ty) => {{
+            let mode = Cbc::<$cipher, Pkcs7>::new_from_slices(key, iv)
+                .map_err(|_| JsError::error("AES-CBC: iv must be 128 bits"))?;
+            if encrypt {
+                Ok(mode.encrypt_vec(data))
+            } else {
+                mode.decrypt_vec(data)
+                    .map_err(|_| JsError::error("decrypt: operation failed"))
+            }
+        }};
+    }
+    match key.len() {
+        16 => cbc!(Aes128),
+        24 => cbc!(Aes192),
+        _ => cbc!(Aes256),
+    }
+}
+
+fn decode_jwk_field(field: &Option<String>, name: &str) -> JsResult<Vec<u8>> {
+    let field = field
+        .as_ref()
+        .ok_or_else(|| JsError::error(format!("importKey: missing JWK field '{}'", name)))?;
+    base64::decode_config(field, base64::URL_SAFE_NO_PAD)
+        .map_err(|_| JsError::error(format!("importKey: bad JWK field '{}'", name)))
+}
+
+fn bad_key_data() -> JsError {
+    JsError::error("importKey: bad key data")
+}
+
+fn import_asymmetric_key(
+    alg: &KeyAlgorithm,
+    format: &KeyFormat,
+    data: &[u8],
+) -> JsResult<KeyMaterial> {
+    match *alg {
+        KeyAlgorithm::Ecdsa(curve) => {
+            let (signing_alg, curve_oid, coordinate_len): (
+                &'static signature::EcdsaSigningAlgorithm,
+                _,
+                _,
+            ) = match curve {
+                EcCurve::P256 => (&signature::ECDSA_P256_SHA256_FIXED_SIGNING, OID_P256, 32),
+                EcCurve::P384 => (&signature::ECDSA_P384_SHA384_FIXED_SIGNING, OID_P384, 48),
+            };
+            let pkcs8 = match format {
+                KeyFormat::Raw => {
+                    return Ok(KeyMaterial::Ecdsa {
+                        curve,
+                        private: None,
+                        public: data.to_vec(),
+                    })
+                }
+                KeyFormat::Spki => {
+                    let (oid, params, public) = der::parse_spki(data).ok_or_else(bad_key_data)?;
+                    if oid != OID_EC_PUBLIC_KEY || params != curve_oid {
+                        return Err(JsError::error("importKey: key algorithm mismatch"));
+                    }
+                    return Ok(KeyMaterial::Ecdsa {
+                        curve,
+                        private: None,
+                        public: public.to_vec(),
+                    });
+                }
+                KeyFormat::Pkcs8 => data.to_vec(),
+                KeyFormat::Jwk(jwk) => {
+                    if jwk.kty != "EC" {
+                        return Err(JsError::error("importKey: JWK kty must be 'EC'"));
+                    }
+                    let x = decode_jwk_field(&jwk.x, "x")?;
+                    let y = decode_jwk_field(&jwk.y, "y")?;
+                    if x.len() != coordinate_len || y.len() != coordinate_len {
+                        return Err(bad_key_data());
+                    }
+                    let mut public = vec![0x04];
+                    public.extend_from_slice(&x);
+                    public.extend_from_slice(&y);
+                    if jwk.d.is_none() {
+                        return Ok(KeyMaterial::Ecdsa {
+                            curve,
+                            private: None,
+                            public,
+                        });
+                    }
+
+                    // `ring` only takes private keys in PKCS#8.
+                    let d = decode_jwk_field(&jwk.d, "d")?;
+                    let ec_private_key = der::write_sequence(&[
+                        &der::write(der::TAG_INTEGER, &[1]),
+                        &der::write(der::TAG_OCTET_STRING, &d),
+                        &der::write(der::TAG_CONTEXT_1, &der::write_bit_string(&public)),
+                    ]);
+                    let algorithm_id = der::write_sequence(&[
+                        &der::write(der::TAG_OID, OID_EC_PUBLIC_KEY),
+                        curve_oid,
+                    ]);
+                    der::write_sequence(&[
+                        &der::write(der::TAG_INTEGER, &[0]),
+                        &algorithm_id,
+                        &der::write(der::TAG_OCTET_STRING, &ec_private_key),
+                    ])
+                }
+            };
+            let private = signature::EcdsaKeyPair::from_pkcs8(signing_alg, &pkcs8)
+                .map_err(|_| bad_key_data())?;
+            Ok(KeyMaterial::Ecdsa {
+                curve,
+                public: private.public_key().as_ref().to_vec(),
+                private: Some(private),
+            })
+        }
+        KeyAlgorithm::Ed25519 => {
+            let private = match format {
+                KeyFormat::Raw => None,
+                KeyFormat::Spki => {
+                    let (oid, _, public) = der::parse_spki(data).ok_or_else(bad_key_data)?;
+                    if oid != OID_ED25519 {
+                        return Err(JsError::error("importKey: key algorithm mismatch"));
+                    }
+                    return Ok(KeyMaterial::Ed25519 {
+                        private: None,
+                        public: public.to_vec(),
+                    });
+                }
+                KeyFormat::Pkcs8 => Some(
+                    signature::Ed25519KeyPair::from_pkcs8_maybe_unchecked(data)
+                        .map_err(|_| bad_key_data())?,
+                ),
+                KeyFormat::Jwk(jwk) => {
+                    if jwk.kty != "OKP" || jwk.crv.as_deref() != Some("Ed25519") {
+                        return Err(JsError::error("importKey: JWK must be an Ed25519 OKP key"));
+                    }
+                    let x = decode_jwk_field(&jwk.x, "x")?;
+                    if jwk.d.is_none() {
+                        return Ok(KeyMaterial::Ed25519 {
+                            private: None,
+                            public: x,
+                        });
+                    }
+                    let d = decode_jwk_field(&jwk.d, "d")?;
+                    Some(
+                        signature::Ed25519KeyPair::from_seed_and_public_key(&d, &x)
+                            .map_err(|_| bad_key_data())?,
+                    )
+                }
+            };
+            Ok(match private {
+                Some(private) => KeyMaterial::Ed25519 {
+                    public: private.public_key().as_ref().to_vec(),
+                    private: Some(private),
+                },
+                None => KeyMaterial::Ed25519 {
+                    private: None,
+                    public: data.to_vec(),
+                },
+            })
+        }
+        KeyAlgorithm::RsaPkcs1(hash) | KeyAlgorithm::RsaPss(hash) => {
+            let padding = match alg {
+                KeyAlgorithm::RsaPss(_) => RsaPadding::Pss,
+                _ => RsaPadding::Pkcs1,
+            };
+            let public = match format {
+                KeyFormat::Spki => {
+                    let (oid, _, public) = der::parse_spki(data).ok_or_else(bad_key_data)?;
+                    if oid != OID_RSA_ENCRYPTION {
+                        return Err(JsError::error("importKey: key algorithm mismatch"));
+                    }
+                    public.to_vec()
+                }
+                KeyFormat::Jwk(jwk) => {
+                    if jwk.kty != "RSA" {
+                        return Err(JsError::error("importKey: JWK kty must be 'RSA'"));
+                    }
+                    if jwk.d.is_some() {
+                        return Err(JsError::error(
+                            "importKey: RSA private keys are not supported",
+                        ));
+                    }
+                    let n = decode_jwk_field(&jwk.n, "n")?;
+                    let e = decode_jwk_field(&jwk.e, "e")?;
+                    der::write_sequence(&[
+                        &der::write_unsigned_integer(&n),
+                        &der::write_unsigned_integer(&e),
+                    ])
+                }
+                _ => {
+                    return Err(JsError::error(
+                        "importKey: RSA keys must be imported from spki or jwk",
+                    ))
+                }
+            };
+            Ok(KeyMaterial::Rsa {
+                padding,
+                hash,
+                public,
+            })
         }
+        KeyAlgorithm::Ecdh(curve) => {
+            let (public, private) = match format {
+                KeyFormat::Raw => (data.to_vec(), None),
+                KeyFormat::Spki => {
+                    let (oid, params, public) = der::parse_spki(data).ok_or_else(bad_key_data)?;
+                    let ok = match curve {
+                        EcdhCurve::P256 => oid == OID_EC_PUBLIC_KEY && params == OID_P256,
+                        EcdhCurve::X25519 => oid == OID_X25519,
+                    };
+                    if !ok {
+                        return Err(JsError::error("importKey: key algorithm mismatch"));
+                    }
+                    (public.to_vec(), None)
+                }
+                KeyFormat::Jwk(jwk) => {
+                    let public = match curve {
+                        EcdhCurve::P256 => {
+                            if jwk.kty != "EC" {
+                                return Err(JsError::error("importKey: JWK kty must be 'EC'"));
+                            }
+                            let mut public = vec![0x04];
+                            public.extend_from_slice(&decode_jwk_field(&jwk.x, "x")?);
+                            public.extend_from_slice(&decode_jwk_field(&jwk.y, "y")?);
+                            public
+                        }
+                        EcdhCurve::X25519 => {
+                            if jwk.kty != "OKP" || jwk.crv.as_deref() != Some("X25519") {
+                                return Err(JsError::error(
+                                    "importKey: JWK must be an X25519 OKP key",
+                                ));
+                            }
+                            decode_jwk_field(&jwk.x, "x")?
+                        }
+                    };
+                    let private = match jwk.d {
+                        Some(_) => {
+                            let d = decode_jwk_field(&jwk.d, "d")?;
+                            let private = EcdhPrivateKey::from_bytes(curve, &d)?;
+                            if private.public_key() != public {
+                                return Err(bad_key_data());
+                            }
+                            Some(private)
+                        }
+                        None => None,
+                    };
+                    (public, private)
+                }
+                KeyFormat::Pkcs8 => {
+                    return Err(JsError::error(
+                        "importKey: ECDH private keys must be imported from jwk",
+                    ))
+                }
+            };
+            if EcdhPublicKey::parse(curve, &public).is_none() {
+                return Err(bad_key_data());
+            }
+            Ok(KeyMaterial::Ecdh {
+                curve,
+                private,
+                public,
+            })
+        }
+        _ => Err(JsError::error("importKey: not an asymmetric algorithm")),
     }
 }
+
+fn asymmetric_sign(
+    material: &KeyMaterial,
+    hash: Option<DigestAlgorithm>,
+    data: &[u8],
+) -> JsResult<Vec<u8>> {
+    let rng = ring::rand::SystemRandom::new();
+    match *material {
+        KeyMaterial::Ecdsa {
+            curve,
+            private: Some(ref private),
+            ..
+        } => {
+            match (curve, hash) {
+                (EcCurve::P256, Some(DigestAlgorithm::Sha256))
+                | (EcCurve::P384, Some(DigestAlgorithm::Sha384)) => {}
+                _ => {
+                    return Err(JsError::error(
+                        "sign: ECDSA supports SHA-256 with P-256 and SHA-384 with P-384",
+                    ))
+                }
+            }
+            let sig = private
+                .sign(&rng, data)
+                .map_err(|_| JsError::error("sign: operation failed"))?;
+            Ok(sig.as_ref().to_vec())
+        }
+        KeyMaterial::Ed25519 {
+            private: Some(ref private),
+            ..
+        } => Ok(private.sign(data).as_ref().to_vec()),
+        KeyMaterial::Ecdsa { .. } | KeyMaterial::Ed25519 { .. } => {
+            Err(JsError::error("sign: expecting a private key"))
+        }
+        _ => Err(JsError::error("sign: unsupported key")),
+    }
+}
+
+fn asymmetric_verify(
+    material: &KeyMaterial,
+    hash: Option<DigestAlgorithm>,
+    sig: &[u8],
+    data: &[u8],
+) -> JsResult<bool> {
+    let (alg, public): (&'static dyn signature::VerificationAlgorithm, &[u8]) = match *material {
+        KeyMaterial::Ecdsa {
+            curve, ref public, ..
+        } => {
+            let alg: &'static dyn signature::VerificationAlgorithm = match (curve, hash) {
+                (EcCurve::P256, Some(DigestAlgorithm::Sha256)) => {
+                    &signature::ECDSA_P256_SHA256_FIXED
+                }
+                (EcCurve::P256, Some(DigestAlgorithm::Sha384)) => {
+                    &signature::ECDSA_P256_SHA384_FIXED
+                }
+                (EcCurve::P384, Some(DigestAlgorithm::Sha256)) => {
+                    &signature::ECDSA_P384_SHA256_FIXED
+                }
+                (EcCurve::P384, Some(DigestAlgorithm::Sha384)) => {
+                    &signature::ECDSA_P384_SHA384_FIXED
+                }
+                _ => return Err(JsError::error("verify: unsupported ECDSA hash")),
+            };
+            (alg, public.as_slice())
+        }
+        KeyMaterial::Ed25519 { ref public, .. } => (&signature::ED25519, public.as_slice()),
+        KeyMaterial::Rsa {
+            padding,
+            hash,
+            ref public,
+        } => {
+            let alg: &'static dyn signature::VerificationAlgorithm = match (padding, hash) {
+                (RsaPadding::Pkcs1, DigestAlgorithm::Sha1) => {
+                    &signature::RSA_PKCS1_2048_8192_SHA1_FOR_LEGACY_USE_ONLY
+                }
+                (RsaPadding::Pkcs1, DigestAlgorithm::Sha256) => {
+                    &signature::RSA_PKCS1_2048_8192_SHA256
+                }
+                (RsaPadding::Pkcs1, DigestAlgorithm::Sha384) => {
+                    &signature::RSA_PKCS1_2048_8192_SHA384
+                }
+                (RsaPadding::Pkcs1, DigestAlgorithm::Sha512) => {
+                    &signature::RSA_PKCS1_2048_8192_SHA512
+                }
+                (RsaPadding::Pss, DigestAlgorithm::Sha256) => &signature::RSA_PSS_2048_8192_SHA256,
+                (RsaPadding::Pss, DigestAlgorithm::Sha384) => &signature::RSA_PSS_2048_8192_SHA384,
+                (RsaPadding::Pss, DigestAlgorithm::Sha512) => &signature::RSA_PSS_2048_8192_SHA512,
+                (RsaPadding::Pss, DigestAlgorithm::Sha1) => {
+                    return Err(JsError::error(
+                        "verify: RSA-PSS with SHA-1 is not supported",
+                    ))
+                }
+            };
+            (alg, public.as_slice())
+        }
+        _ => return Err(JsError::error("verify: unsupported key")),
+    };
+    Ok(signature::UnparsedPublicKey::new(alg, public)
+        .verify(data, sig)
+        .is_ok())
+}
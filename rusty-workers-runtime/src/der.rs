@@ -0,0 +1,90 @@
+//! Minimal DER reading and writing for key formats that `ring` doesn't parse or build itself.
+
+pub const TAG_INTEGER: u8 = 0x02;
+pub const TAG_BIT_STRING: u8 = 0x03;
+pub const TAG_OCTET_STRING: u8 = 0x04;
+pub const TAG_OID: u8 = 0x06;
+pub const TAG_SEQUENCE: u8 = 0x30;
+pub const TAG_CONTEXT_1: u8 = 0xa1;
+
+/// Reads one element with the expected tag. Returns its contents and the rest of the input.
+pub fn read(input: &[u8], expected_tag: u8) -> Option<(&[u8], &[u8])> {
+    let (&tag, input) = input.split_first()?;
+    if tag != expected_tag {
+        return None;
+    }
+    let (&first, mut input) = input.split_first()?;
+    let len = if first < 0x80 {
+        first as usize
+    } else {
+        let n = (first & 0x7f) as usize;
+        if n == 0 || n > 4 || input.len() < n {
+            return None;
+        }
+        let len = input[..n]
+            .iter()
+            .fold(0usize, |len, b| (len << 8) | *b as usize);
+        input = &input[n..];
+        len
+    };
+    if input.len() < len {
+        return None;
+    }
+    Some((&input[..len], &input[len..]))
+}
+
+/// Reads a bit string with no unused bits.
+pub fn read_bit_string(input: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (contents, rest) = read(input, TAG_BIT_STRING)?;
+    match contents.split_first() {
+        Some((0, bits)) => Some((bits, rest)),
+        _ => None,
+    }
+}
+
+pub fn write(tag: u8, contents: &[u8]) -> Vec<u8> {
+    let mut output = vec![tag];
+    let len = contents.len();
+    if len < 0x80 {
+        output.push(len as u8);
+    } else {
+        let bytes = (len as u32).to_be_bytes();
+        let skip = bytes.iter().take_while(|x| **x == 0).count();
+        output.push(0x80 | (bytes.len() - skip) as u8);
+        output.extend_from_slice(&bytes[skip..]);
+    }
+    output.extend_from_slice(contents);
+    output
+}
+
+/// Writes a non-negative integer from its big-endian bytes.
+pub fn write_unsigned_integer(value: &[u8]) -> Vec<u8> {
+    let skip = value.iter().take_while(|x| **x == 0).count();
+    let value = &value[skip..];
+    let mut contents = vec![];
+    if value.first().map(|x| x & 0x80 != 0).unwrap_or(true) {
+        contents.push(0);
+    }
+    contents.extend_from_slice(value);
+    write(TAG_INTEGER, &contents)
+}
+
+pub fn write_bit_string(bits: &[u8]) -> Vec<u8> {
+    let mut contents = vec![0];
+    contents.extend_from_slice(bits);
+    write(TAG_BIT_STRING, &contents)
+}
+
+pub fn write_sequence(elements: &[&[u8]]) -> Vec<u8> {
+    write(TAG_SEQUENCE, &elements.concat())
+}
+
+/// Parses a `SubjectPublicKeyInfo`. Returns the algorithm OID, the algorithm parameters (as a
+/// complete element, or empty) and the public key.
+pub fn parse_spki(input: &[u8]) -> Option<(&[u8], &[u8], &[u8])> {
+    let (spki, _) = read(input, TAG_SEQUENCE)?;
+    let (algorithm, rest) = read(spki, TAG_SEQUENCE)?;
+    let (oid, params) = read(algorithm, TAG_OID)?;
+    let (public_key, _) = read_bit_string(rest)?;
+    Some((oid, params, public_key))
+}
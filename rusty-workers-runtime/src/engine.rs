@@ -1,5 +1,6 @@
 use rusty_v8 as v8;
 use rusty_workers::types::*;
+use std::convert::TryFrom;
 use std::sync::{Arc, Mutex};
 
 /// Alias for callback function types.
@@ -55,7 +56,7 @@ impl<T> CheckedResult for Option<T> {
 }
 
 pub trait CheckedTryCatch {
-    fn exception_description(&mut self) -> Option<String>;
+    fn script_exception(&mut self) -> Option<ScriptException>;
     fn check_on_init(&mut self) -> GenericResult<()>;
     fn check_on_task(&mut self) -> ExecutionResult<()>;
 }
@@ -64,15 +65,30 @@ impl<'s, 'p: 's, P> CheckedTryCatch for v8::TryCatch<'s, P>
 where
     v8::TryCatch<'s, P>: AsMut<v8::HandleScope<'p, ()>> + AsMut<v8::HandleScope<'p, v8::Context>>,
 {
-    fn exception_description(&mut self) -> Option<String> {
-        let exception = self.exception();
-        if let Some(exc) = exception {
-            let scope: &mut v8::HandleScope<'p> = self.as_mut();
-            let desc = exc.to_rust_string_lossy(scope);
-            Some(desc)
-        } else {
-            None
+    /// Builds a record of the caught exception from the exception value, its message and its
+    /// stack trace.
+    ///
+    /// May run JavaScript (getters of the error object), so it must not be called after
+    /// termination.
+    fn script_exception(&mut self) -> Option<ScriptException> {
+        let exc = self.exception()?;
+        let message = self.message();
+        let stack = self.stack_trace();
+        let scope: &mut v8::HandleScope<'p> = self.as_mut();
+
+        let mut record = describe_exception_value(scope, exc);
+        record.stack = stack
+            .filter(|x| x.is_string())
+            .map(|x| x.to_rust_string_lossy(scope));
+        if let Some(message) = message {
+            record.resource_name = message
+                .get_script_resource_name(scope)
+                .filter(|x| x.is_string())
+                .map(|x| x.to_rust_string_lossy(scope));
+            record.line = message.get_line_number(scope).map(|x| x as u32);
+            record.column = Some(message.get_start_column() as u32 + 1);
         }
+        Some(record)
     }
 
     fn check_on_init(&mut self) -> GenericResult<()> {
@@ -80,27 +96,83 @@ where
     }
 
     fn check_on_task(&mut self) -> ExecutionResult<()> {
-        match self.exception_description() {
-            Some(x) => {
-                let e = if self.has_terminated() {
-                    match get_exception(self.as_mut()) {
-                        TerminationReason::Unknown => {
-                            // This should not happen.
-                            warn!("check_on_task: termination requested without reason");
-                            ExecutionError::RuntimeThrowsException
-                        }
-                        TerminationReason::TimeLimit => ExecutionError::TimeLimitExceeded,
-                        TerminationReason::CachePolicy => ExecutionError::RuntimeThrowsException,
-                    }
-                } else {
-                    // Otherwise this is a normal exception thrown from JavaScript.
-                    ExecutionError::ScriptThrowsException(x)
-                };
-                Err(e)
+        if self.exception().is_none() {
+            return Ok(());
+        }
+        let e = if self.has_terminated() {
+            match get_exception(self.as_mut()) {
+                TerminationReason::Unknown => {
+                    // This should not happen.
+                    warn!("check_on_task: termination requested without reason");
+                    ExecutionError::RuntimeThrowsException
+                }
+                TerminationReason::TimeLimit => ExecutionError::TimeLimitExceeded,
+                TerminationReason::CachePolicy => ExecutionError::RuntimeThrowsException,
             }
-            None => Ok(()),
+        } else {
+            // Otherwise this is a normal exception thrown from JavaScript.
+            ExecutionError::ScriptThrowsException(self.script_exception().unwrap_or_default())
+        };
+        Err(e)
+    }
+}
+
+/// Builds a record of a promise rejection from the rejection reason.
+///
+/// The stack trace and position are those captured when the reason was created, if it's an error
+/// object.
+pub fn script_exception_from_value<'s>(
+    scope: &mut v8::HandleScope<'s>,
+    reason: v8::Local<'s, v8::Value>,
+) -> ScriptException {
+    let mut record = describe_exception_value(scope, reason);
+    if let Ok(obj) = v8::Local::<'_, v8::Object>::try_from(reason) {
+        record.stack = get_string_property(scope, obj, "stack");
+    }
+    if reason.is_native_error() {
+        let message = v8::Exception::create_message(scope, reason);
+        record.resource_name = message
+            .get_script_resource_name(scope)
+            .filter(|x| x.is_string())
+            .map(|x| x.to_rust_string_lossy(scope));
+        record.line = message.get_line_number(scope).map(|x| x as u32);
+        record.column = Some(message.get_start_column() as u32 + 1);
+    }
+    record
+}
+
+/// Builds an exception record with the name and message of a thrown value.
+fn describe_exception_value<'s>(
+    scope: &mut v8::HandleScope<'s>,
+    exc: v8::Local<'s, v8::Value>,
+) -> ScriptException {
+    let mut record = ScriptException::default();
+    match v8::Local::<'_, v8::Object>::try_from(exc) {
+        Ok(obj) => {
+            record.name = get_string_property(scope, obj, "name").unwrap_or_default();
+            record.message = get_string_property(scope, obj, "message").unwrap_or_default();
+        }
+        Err(_) => {
+            record.message = exc.to_rust_string_lossy(scope);
         }
     }
+    record
+}
+
+/// Reads a string property of an error object. Exceptions thrown by getters are ignored.
+fn get_string_property<'s>(
+    scope: &mut v8::HandleScope<'s>,
+    obj: v8::Local<'s, v8::Object>,
+    key: &str,
+) -> Option<String> {
+    let scope = &mut v8::TryCatch::new(scope);
+    let key = v8::String::new(scope, key)?;
+    let value = obj.get(scope, key.into())?;
+    if value.is_string() {
+        Some(value.to_rust_string_lossy(scope))
+    } else {
+        None
+    }
 }
 
 pub fn make_function<'s, C: Callback>(
@@ -1,25 +1,37 @@
+use crate::body_stream::{BodyStreamSender, RequestBodyReceiver};
 use crate::buffer::*;
+use crate::crypto::CryptoKeyStore;
 use crate::engine::*;
 use crate::error::*;
+use crate::inspector::{self, InspectorAttachPoint, InspectorConnection};
 use crate::interface::*;
 use crate::io::*;
 use crate::isolate::{IsolateGeneration, IsolateGenerationBox, MemoryPoolBox, Poison};
 use crate::mm::*;
+use crate::module_loader::{self, BundleFiles, BundleManifest, Entrypoint, ModuleMap};
 use crate::runtime::{InstanceStatistics, Runtime};
 use maplit::btreemap;
 use rand::Rng;
 use rusty_v8 as v8;
 use rusty_workers::types::*;
-use std::cell::{Cell, UnsafeCell};
+use std::cell::UnsafeCell;
 use std::collections::BTreeMap;
 use std::convert::TryFrom;
 use std::io::Read;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc;
+use tokio::sync::Mutex as AsyncMutex;
 
 const MAX_RESPONSE_BODY_SIZE: usize = 8 * 1024 * 1024;
 
+/// Max size of an encoded service call, not including the buffers passed with it.
+const MAX_SERVICE_CALL_SIZE: usize = 64 * 1024;
+
+/// Max size of a buffer passed to an async call. Calls check their own, smaller limits later.
+const MAX_ASYNC_CALL_BUFFER_SIZE: usize = 8 * 1024 * 1024;
+
 pub struct Instance {
     state: Option<InstanceState>,
 }
@@ -37,9 +49,12 @@ struct InstanceState {
     task_rx: mpsc::Receiver<Task>,
 
     /// Unpacked files in the worker bundle.
-    files: BTreeMap<String, Arc<[u8]>>,
+    files: BundleFiles,
+
+    entry: Entrypoint,
 
-    script: Arc<[u8]>,
+    /// WebAssembly modules to expose as globals, as a map from global names to bundle paths.
+    wasm_modules: BTreeMap<String, String>,
 
     timer_tx: tokio::sync::mpsc::UnboundedSender<TimerControl>,
     conf: Arc<WorkerConfiguration>,
@@ -48,9 +63,33 @@ struct InstanceState {
 
     done: bool,
 
-    fetch_response_channel: Option<tokio::sync::oneshot::Sender<ExecutionResult<ResponseObject>>>,
+    /// Where the result of the current task goes.
+    result_channel: Option<TaskResultChannel>,
+
+    /// Id and sender of the streaming response body of the current task, if any.
+    response_body: Option<(String, BodyStreamSender)>,
+
+    /// Id and receiver of the streaming request body of the current task, if any.
+    request_body: Option<(String, Arc<AsyncMutex<RequestBodyReceiver>>)>,
+
+    inspector: Arc<InspectorAttachPoint>,
+
+    /// Set while a task, including its `waitUntil()` work, is running.
+    busy: Arc<AtomicBool>,
+
+    /// End of the `waitUntil()` budget of the current task. Set when the response is complete.
+    wait_until_deadline: Option<Instant>,
 
     appid: String,
+
+    /// Id of the current task, attached to log records.
+    request_id: Option<String>,
+
+    /// Promises rejected without a handler since the last host entry, keyed by identity hash.
+    pending_rejections: Vec<(i32, v8::Global<v8::Promise>, v8::Global<v8::Value>)>,
+
+    /// The first unhandled rejection of the current task, reported as its result.
+    task_rejection: Option<ScriptException>,
 }
 
 pub struct InstanceHandle {
@@ -59,11 +98,19 @@ pub struct InstanceHandle {
     termination_reason: TerminationReasonBox,
     creation_generation: IsolateGeneration,
     current_generation: IsolateGenerationBox,
+    inspector: Arc<InspectorAttachPoint>,
+    busy: Arc<AtomicBool>,
+
+    /// Whether tasks sent to a busy instance wait for it instead of failing with `WorkerBusy`.
+    queue_when_busy: bool,
 }
 
 pub struct InstanceTimeControl {
     pub budget: Duration,
     pub timer_rx: mpsc::UnboundedReceiver<TimerControl>,
+
+    /// The time limit is not enforced while an inspector is attached.
+    pub inspector: Arc<InspectorAttachPoint>,
 }
 
 enum Task {
@@ -72,14 +119,52 @@ enum Task {
         tokio::sync::oneshot::Sender<ExecutionResult<ResponseObject>>,
         IoScopeConsumer,
     ),
+    Scheduled(
+        ScheduledEventObject,
+        tokio::sync::oneshot::Sender<ExecutionResult<()>>,
+        IoScopeConsumer,
+    ),
+    Queue(
+        QueueBatchObject,
+        tokio::sync::oneshot::Sender<ExecutionResult<QueueBatchResult>>,
+        IoScopeConsumer,
+    ),
+}
+
+enum TaskResultChannel {
+    Fetch(tokio::sync::oneshot::Sender<ExecutionResult<ResponseObject>>),
+    Scheduled(tokio::sync::oneshot::Sender<ExecutionResult<()>>),
+    Queue(tokio::sync::oneshot::Sender<ExecutionResult<QueueBatchResult>>),
 }
 
 impl Task {
-    fn make_event(&self) -> ServiceEvent {
+    /// Builds the event passed to the script.
+    ///
+    /// A binary request body is moved out of the event and returned separately, to be passed as
+    /// an `ArrayBuffer` instead of through JSON.
+    fn make_event(&mut self, actor: &Option<ActorAddress>) -> (ServiceEvent, Option<Vec<u8>>) {
         match self {
-            Task::Fetch(ref req, _, _) => ServiceEvent::Fetch(FetchEvent {
-                request: req.clone(),
-            }),
+            Task::Fetch(ref mut req, _, _) => {
+                let body = match req.body {
+                    HttpBody::Binary(ref mut x) => Some(std::mem::take(x)),
+                    HttpBody::Stream(_) => None,
+                };
+                let event = ServiceEvent::Fetch(FetchEvent {
+                    request: req.clone(),
+                    actor: actor.clone(),
+                });
+                (event, body)
+            }
+            Task::Scheduled(ref ev, _, _) => (ServiceEvent::Scheduled(ev.clone()), None),
+            Task::Queue(ref batch, _, _) => (ServiceEvent::Queue(batch.clone()), None),
+        }
+    }
+
+    /// Depth of this task in a chain of requests between workers.
+    fn call_depth(&self) -> u32 {
+        match self {
+            Task::Fetch(ref req, _, _) => req.caller.as_ref().map(|x| x.depth).unwrap_or(0),
+            Task::Scheduled(..) | Task::Queue(..) => 0,
         }
     }
 }
@@ -105,13 +190,47 @@ impl InstanceHandle {
         }
     }
 
+    /// Hands an inspector connection to the instance. It's attached when the instance is idle.
+    pub fn attach_inspector(&self, conn: InspectorConnection) {
+        self.inspector.set_pending(conn);
+        self.notify_inspector();
+    }
+
+    /// Wakes up the instance to process inspector messages.
+    pub fn notify_inspector(&self) {
+        self.inspector.notify();
+
+        // Take the lock so that we don't interrupt another instance on the same isolate.
+        let current_generation = self.current_generation.0.lock().unwrap();
+        if *current_generation == self.creation_generation {
+            self.isolate_handle
+                .request_interrupt(inspector::interrupt_callback, std::ptr::null_mut());
+        }
+    }
+
     pub async fn terminate_for_time_limit(&self) {
         tokio::task::block_in_place(|| {
             self.do_remote_termination(Some(TerminationReason::TimeLimit));
         });
     }
 
+    /// Fails if the instance is still running a previous task, e.g. its `waitUntil()` work, so
+    /// that the caller can pick another instance instead of waiting behind it. Actors process
+    /// their tasks in order, so their tasks are queued instead.
+    fn check_busy(&self) -> ExecutionResult<()> {
+        if !self.queue_when_busy && self.busy.load(Ordering::SeqCst) {
+            Err(ExecutionError::WorkerBusy)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Issues a "fetch" event.
+    ///
+    /// I/O of the task is canceled if this future is dropped before the response is sent. After
+    /// that, the task keeps running `waitUntil()` work within its budget.
     pub async fn fetch(&self, req: RequestObject) -> ExecutionResult<ResponseObject> {
+        self.check_busy()?;
         let (result_tx, result_rx) = tokio::sync::oneshot::channel();
         let (_io_scope, io_scope_consumer) = IoScope::new();
 
@@ -131,6 +250,46 @@ impl InstanceHandle {
             }
         }
     }
+
+    pub async fn scheduled(&self, event: ScheduledEventObject) -> ExecutionResult<()> {
+        self.check_busy()?;
+        let (result_tx, result_rx) = tokio::sync::oneshot::channel();
+        let (_io_scope, io_scope_consumer) = IoScope::new();
+
+        // Send fails if the instance has terminated
+        self.task_tx
+            .send(Task::Scheduled(event, result_tx, io_scope_consumer))
+            .await
+            .map_err(|_| ExecutionError::NoSuchWorker)?;
+
+        // This errors if the instance terminates without sending a response
+        match result_rx.await {
+            Ok(res) => res,
+            Err(_) => {
+                // Instance dropped sender without sending a response.
+                // Most probably a runtime error.
+                Err(ExecutionError::RuntimeThrowsException)
+            }
+        }
+    }
+
+    pub async fn queue(&self, batch: QueueBatchObject) -> ExecutionResult<QueueBatchResult> {
+        self.check_busy()?;
+        let (result_tx, result_rx) = tokio::sync::oneshot::channel();
+        let (_io_scope, io_scope_consumer) = IoScope::new();
+
+        // Send fails if the instance has terminated
+        self.task_tx
+            .send(Task::Queue(batch, result_tx, io_scope_consumer))
+            .await
+            .map_err(|_| ExecutionError::NoSuchWorker)?;
+
+        // This errors if the instance terminates without sending a result
+        match result_rx.await {
+            Ok(res) => res,
+            Err(_) => Err(ExecutionError::RuntimeThrowsException),
+        }
+    }
 }
 
 impl Drop for InstanceHandle {
@@ -184,12 +343,24 @@ impl Instance {
             files.insert(path, Arc::from(data));
         }
         drop(archive);
-
-        // Lookup the script.
-        let script = files
-            .get("./index.js")
-            .ok_or_else(|| GenericError::Other("cannot find ./index.js in bundle".into()))?
-            .clone();
+        let files: BundleFiles = Arc::new(files);
+
+        // Lookup the entrypoint.
+        let manifest = BundleManifest::load(&files)?;
+        let entry = Entrypoint::lookup(&files, &manifest)?;
+
+        // Modules declared in the worker configuration take precedence over the manifest.
+        let mut wasm_modules = BTreeMap::new();
+        for (name, path) in manifest.wasm_modules.iter().chain(conf.wasm_modules.iter()) {
+            let path = module_loader::resolve_bundle_path(path)?;
+            if !files.contains_key(&path) {
+                return Err(GenericError::Other(format!(
+                    "cannot find wasm module {} in bundle",
+                    path
+                )));
+            }
+            wasm_modules.insert(name.clone(), path);
+        }
 
         let termination_reason =
             TerminationReasonBox(Arc::new(Mutex::new(TerminationReason::Unknown)));
@@ -199,6 +370,13 @@ impl Instance {
         isolate.set_promise_reject_callback(on_promise_rejection);
         isolate.set_slot(Some(termination_reason.clone()));
         isolate.set_oom_error_handler(oom_protected_callback);
+        isolate.set_host_import_module_dynamically_callback(module_loader::dynamic_import_callback);
+        isolate.set_slot(Some(ModuleMap::new(
+            files.clone(),
+            worker_runtime.clone(),
+            conf.bundle_id.clone(),
+        )));
+        isolate.set_slot(Some(CryptoKeyStore::new()));
 
         // Reset memory pool.
         isolate
@@ -214,9 +392,13 @@ impl Instance {
         // TODO: unbounded ok here?
         let (timer_tx, timer_rx) = mpsc::unbounded_channel();
 
+        let inspector = Arc::new(InspectorAttachPoint::new());
+        let busy = Arc::new(AtomicBool::new(false));
+
         let time_control = InstanceTimeControl {
             timer_rx,
             budget: Duration::from_millis(conf.executor.max_time_ms as u64),
+            inspector: inspector.clone(),
         };
 
         let isolate_handle = isolate.thread_safe_handle();
@@ -228,6 +410,9 @@ impl Instance {
             termination_reason,
             creation_generation: *generation.0.lock().unwrap(),
             current_generation: generation.clone(),
+            inspector: inspector.clone(),
+            busy: busy.clone(),
+            queue_when_busy: conf.actor.is_some(),
         };
         let instance = Instance {
             state: Some(InstanceState {
@@ -235,14 +420,23 @@ impl Instance {
                 worker_runtime,
                 task_rx,
                 files,
-                script,
+                entry,
+                wasm_modules,
                 timer_tx,
                 conf: Arc::new(conf.clone()),
                 handle: worker_handle,
                 io_waiter: None,
                 done: false,
-                fetch_response_channel: None,
+                result_channel: None,
+                response_body: None,
+                request_body: None,
+                inspector,
+                busy,
+                wait_until_deadline: None,
                 appid,
+                request_id: None,
+                pending_rejections: vec![],
+                task_rejection: None,
             }),
         };
         Ok((instance, handle, time_control))
@@ -259,6 +453,8 @@ impl Instance {
 
         // Drop `io_waiter` and any `Global` references it holds.
         InstanceState::get(isolate).io_waiter = None;
+        InstanceState::get(isolate).pending_rejections.clear();
+        inspector::cleanup(isolate);
 
         // `protected_js` expects `InstanceState` to be present
         protected_js(&mut Wrapper(isolate), |isolate| {
@@ -268,16 +464,25 @@ impl Instance {
         // Prepare for isolate reuse. Cleanup state.
         isolate.set_slot(Option::<TerminationReasonBox>::None);
         isolate.set_slot(Option::<InstanceState>::None);
+        isolate.set_slot(Option::<ModuleMap>::None);
+        isolate.set_slot(Option::<CryptoKeyStore>::None);
         Ok(())
     }
 
     fn compile<'s>(
         scope: &mut v8::HandleScope<'s>,
+        worker_runtime: &Runtime,
+        bundle_id: &str,
+        name: &str,
         script: &str,
     ) -> GenericResult<v8::Local<'s, v8::Script>> {
         let script = v8::String::new(scope, script)
             .ok_or_else(|| GenericError::ScriptInitException("script compilation failed".into()))?;
-        let script = v8::Script::compile(scope, script, None)
+        let name_js = make_string(scope, name)?;
+        let origin = module_loader::script_origin(scope, name_js, false);
+        let script = worker_runtime
+            .code_cache()
+            .compile_script(scope, bundle_id, name, script, &origin)
             .ok_or_else(|| GenericError::ScriptInitException("script compilation failed".into()))?;
         Ok(script)
     }
@@ -293,7 +498,8 @@ impl Instance {
         let worker_handle = state.handle.clone();
 
         // Take an execution thread.
-        let mut permit = worker_runtime.acquire_execution_token()?;
+        // Released while waiting for tasks or I/O.
+        let mut permit = Some(worker_runtime.acquire_execution_token()?);
 
         // Take a HandleScope and initialize the environment.
         {
@@ -303,10 +509,26 @@ impl Instance {
             state.init_global_env(scope)?;
 
             // TODO: Compiler bombs?
-            let script = std::str::from_utf8(&state.script).map_err(|_| {
-                GenericError::ScriptInitException("cannot decode script as utf-8 text".into())
-            })?;
-            let script = Self::compile(scope, script)?;
+            // Modules are compiled as they are imported, after the timer is started.
+            let entry = state.entry.clone();
+            let script = match entry {
+                Entrypoint::Script(ref path) => {
+                    let script = std::str::from_utf8(&state.files[path]).map_err(|_| {
+                        GenericError::ScriptInitException(
+                            "cannot decode script as utf-8 text".into(),
+                        )
+                    })?;
+                    Some(Self::compile(
+                        scope,
+                        &state.worker_runtime,
+                        &state.conf.bundle_id,
+                        path,
+                        script,
+                    )?)
+                }
+                Entrypoint::Module(_) => None,
+            };
+            let env = state.conf.env.clone();
 
             // Notify that we are ready so that timing etc. can start
             ready_callback();
@@ -317,10 +539,23 @@ impl Instance {
             // Now start the timer, since we are starting to run user code.
             InstanceState::get(try_catch).start_timer();
 
-            protected_js(try_catch.as_mut(), |scope| {
-                script.run(scope);
+            let mut module_result = Ok(());
+            protected_js(try_catch.as_mut(), |scope| match script {
+                Some(script) => {
+                    script.run(scope);
+                }
+                None => {
+                    module_result = module_loader::run_entry_module(scope, entry.path(), &env);
+                }
             })?;
-            try_catch.check_on_init()?;
+            dispatch_unhandled_rejections(try_catch.as_mut())?;
+            if let Err(mut e) = try_catch.check_on_task() {
+                let state = InstanceState::get(try_catch);
+                state.map_exception(&mut e);
+                state.log_exception(&e);
+                return Err(GenericError::Execution(e));
+            }
+            module_result?;
         }
         info!("worker instance {} ready", worker_handle.id);
 
@@ -337,28 +572,66 @@ impl Instance {
 
             // Cleanup state
             state.io_waiter = None; // drop it
+            state.response_body = None; // aborts the stream, if it has not ended
+            if let Some((id, _)) = state.request_body.take() {
+                state.worker_runtime.body_streams().remove_request(&id);
+            }
             state.done = false;
+            state.wait_until_deadline = None;
+            state.request_id = None;
+            state.pending_rejections.clear();
+            state.task_rejection = None;
+            state.busy.store(false, Ordering::SeqCst);
 
-            drop(permit);
+            drop(permit.take());
 
-            let task = match state.task_rx.blocking_recv() {
+            // Wait for the next task, handling inspector messages in the meantime.
+            let next_task = loop {
+                let state = InstanceState::get(scope);
+                let inspector = state.inspector.clone();
+                let task_rx = &mut state.task_rx;
+                let next_task = futures::executor::block_on(async {
+                    tokio::select! {
+                        x = task_rx.recv() => Some(x),
+                        _ = inspector.notified() => None,
+                    }
+                });
+                match next_task {
+                    Some(x) => break x,
+                    None => inspector::poll(scope, &inspector),
+                }
+            };
+            let mut task = match next_task {
                 Some(x) => x,
                 None => {
                     // channel closed
                     break;
                 }
             };
-            permit = worker_runtime.acquire_execution_token()?;
-            let event = task.make_event();
+            let state = InstanceState::get(scope);
+            state.busy.store(true, Ordering::SeqCst);
+            permit = Some(worker_runtime.acquire_execution_token()?);
+            let (event, body) = task.make_event(&state.conf.actor);
+            let call_depth = task.call_depth();
             let io_scope = state.populate_with_task(task)?;
             state.start_timer();
 
+            // Run the tasks V8 posted since the last event, like `FinalizationRegistry` callbacks
+            // that release the native state of collected objects. This runs under the timer since
+            // workers can register their own callbacks.
+            while v8::Platform::pump_message_loop(&v8::V8::get_current_platform(), scope, false) {}
+
             // Start I/O processor (per-request).
             //
             // An `IoProcessor` receives the task's `IoScopeConsumer` as its argument, and stops when the
-            // corresponding `IoScope` is dropped.
-            let (io_waiter, io_processor) =
-                IoWaiter::new(state.conf.clone(), state.worker_runtime.clone());
+            // corresponding `IoScope` is dropped before the response is sent, or when `io_waiter` is
+            // dropped at the end of the task.
+            let (io_waiter, io_processor) = IoWaiter::new(
+                state.appid.clone(),
+                state.conf.clone(),
+                state.worker_runtime.clone(),
+                call_depth,
+            );
             state.rt.spawn(io_processor.run(io_scope));
             state.io_waiter = Some(io_waiter);
 
@@ -369,23 +642,31 @@ impl Instance {
                 .map_err(|_| GenericError::Other("bad _dispatchEvent".into()))?;
             let recv = v8::undefined(scope);
             let event_js = native_to_js(scope, &event)?;
+            let body_js: v8::Local<'_, v8::Value> = match body {
+                Some(x) => slice_to_arraybuffer(scope, &x)?.into(),
+                None => v8::null(scope).into(),
+            };
 
             protected_js(scope, |scope| {
-                callback.call(scope, recv.into(), &[event_js]);
+                callback.call(scope, recv.into(), &[event_js, body_js]);
             })?;
 
             // Drive to completion.
             loop {
+                dispatch_unhandled_rejections(try_catch.as_mut())?;
                 match try_catch.check_on_task() {
                     Ok(()) => {}
-                    Err(e) => {
+                    Err(mut e) => {
                         if e.terminates_worker() {
-                            InstanceState::try_send_fetch_response(try_catch, Err(e.clone()));
+                            InstanceState::try_send_error(try_catch, e.clone());
                             return Err(GenericError::Execution(e));
                         } else {
+                            let state = InstanceState::get(try_catch);
+                            state.map_exception(&mut e);
                             debug!("non-critical exception: {:?}", e);
+                            state.log_exception(&e);
                             try_catch.reset();
-                            InstanceState::try_send_fetch_response(try_catch, Err(e));
+                            InstanceState::try_send_error(try_catch, e);
                             break;
                         }
                     }
@@ -405,42 +686,40 @@ impl Instance {
                 update_stats(&worker_runtime, &worker_handle, scope);
 
                 // We are not using CPU now so drop CPU permit
-                drop(permit);
+                drop(permit.take());
 
                 // Take the IO waiter (lifetime conflict with `scope`)
                 let mut io_waiter = InstanceState::get(scope).io_waiter.take().unwrap();
-                let wait_result = io_waiter.wait(scope);
+                let inspector_rx = inspector::incoming(scope);
+                let deadline = InstanceState::get(scope).wait_until_deadline;
+                let wait_result = io_waiter.wait(scope, inspector_rx, deadline);
                 InstanceState::get(scope).io_waiter = Some(io_waiter);
 
                 let (callback, data, buffers) = match wait_result {
                     Some(x) => x,
+                    None if deadline.map(|x| Instant::now() >= x).unwrap_or(false) => {
+                        // The response is already sent. Abandon the remaining background work;
+                        // its I/O stops when `io_waiter` is dropped.
+                        info!("worker {} exceeded waitUntil budget", worker_handle.id);
+                        break;
+                    }
                     None => {
                         // Doesn't necessarily need to terminate the instance but would need a lot of graceful
                         // handling on both the proxy side and the script side.
                         //
                         // So just terminate it now.
-                        InstanceState::try_send_fetch_response(
-                            scope,
-                            Err(ExecutionError::IoTimeout),
-                        );
+                        InstanceState::try_send_error(scope, ExecutionError::IoTimeout);
                         return Err(GenericError::Execution(ExecutionError::IoTimeout));
                     }
                 };
 
-                permit = worker_runtime.acquire_execution_token()?;
+                permit = Some(worker_runtime.acquire_execution_token()?);
                 InstanceState::get(scope).start_timer();
 
                 let callback = v8::Local::<'_, v8::Function>::new(scope, callback);
 
                 // Don't deserialize onto V8 heap here to ensure OOM safety
-                let data = data.as_bytes();
-                acquire_arraybuffer_precheck(scope, data.len())?;
-                let json_data = v8::ArrayBuffer::new(scope, data.len());
-                let json_data_backing = json_data.get_backing_store();
-                json_data_backing
-                    .iter()
-                    .enumerate()
-                    .for_each(|(i, x)| x.set(data[i]));
+                let data = slice_to_arraybuffer(scope, &data)?;
 
                 let local_buffers: Vec<v8::Local::<'_, v8::Value>> = buffers.into_iter().map(|x| x.unwrap_on_v8_thread())
                     .map(|x| v8::Local::new(scope, x.expect("we are on v8 thread but unwrap_on_v8_thread failed to upgrade reference")).into())
@@ -448,22 +727,11 @@ impl Instance {
                 let target_buffers = v8::Array::new_with_elements(scope, &local_buffers);
 
                 protected_js(scope, |scope| {
-                    callback.call(
-                        scope,
-                        recv.into(),
-                        &[json_data.into(), target_buffers.into()],
-                    );
+                    callback.call(scope, recv.into(), &[data.into(), target_buffers.into()]);
                 })?;
             }
 
-            // Script marked itself as done but we haven't got any response.
-            InstanceState::try_send_fetch_response(
-                try_catch,
-                Ok(ResponseObject {
-                    status: 500,
-                    ..Default::default()
-                }),
-            );
+            InstanceState::try_send_done(try_catch);
         }
         Ok(())
     }
@@ -496,6 +764,49 @@ impl InstanceState {
         drop(self.timer_tx.send(TimerControl::Reset));
     }
 
+    /// Rewrites positions in a script exception with source maps from the bundle.
+    fn map_exception(&self, e: &mut ExecutionError) {
+        if let ExecutionError::ScriptThrowsException(ref mut exc)
+        | ExecutionError::UnhandledRejection(ref mut exc) = e
+        {
+            self.worker_runtime
+                .source_maps()
+                .for_bundle(&self.conf.bundle_id, &self.files)
+                .map_exception(exc);
+        }
+    }
+
+    /// Writes an uncaught script exception or unhandled rejection to the app's log topic.
+    fn log_exception(&self, e: &ExecutionError) {
+        let (prefix, exc) = match e {
+            ExecutionError::ScriptThrowsException(ref exc) => ("uncaught exception", exc),
+            ExecutionError::UnhandledRejection(ref exc) => ("unhandled promise rejection", exc),
+            _ => return,
+        };
+        let mut text = format!("{}: {}", prefix, exc);
+        if let Some(ref stack) = exc.stack {
+            text.push_str("\n");
+            text.push_str(stack);
+        }
+        self.worker_runtime.write_log(
+            format!("app-{}", self.appid),
+            LogRecord {
+                level: LogLevel::Error,
+                request_id: self.request_id.clone(),
+                message: text,
+                ..Default::default()
+            },
+        );
+    }
+
+    /// Starts the wall-clock budget for `waitUntil()` work after the response is complete.
+    fn start_wait_until(&mut self) {
+        if self.wait_until_deadline.is_none() {
+            let budget = Duration::from_millis(self.conf.executor.max_wait_until_ms as u64);
+            self.wait_until_deadline = Some(Instant::now() + budget);
+        }
+    }
+
     /// Builds the global object.
     fn init_global_env<'s>(&self, scope: &mut v8::HandleScope<'s>) -> GenericResult<()> {
         let global = scope.get_current_context().global(scope);
@@ -514,15 +825,71 @@ impl InstanceState {
         add_props_to_object(scope, &global, user_props?)?;
 
         add_props_to_object(scope, &global, global_props)?;
+
+        let bindings = Bindings {
+            actors: &self.conf.actor_namespaces,
+            services: self.conf.services.keys().map(|x| x.as_str()).collect(),
+            queues: self
+                .conf
+                .queue_producers
+                .keys()
+                .map(|x| x.as_str())
+                .collect(),
+        };
+        let install_key = make_string(scope, "_installBindings")?;
+        let install = global.get(scope, install_key.into()).check()?;
+        let install = v8::Local::<'_, v8::Function>::try_from(install)
+            .map_err(|_| GenericError::Other("bad _installBindings".into()))?;
+        let bindings = native_to_js(scope, &bindings)?;
+        let recv = v8::undefined(scope);
+        install.call(scope, recv.into(), &[bindings]).check()?;
+
+        for (name, path) in &self.wasm_modules {
+            let module = self.worker_runtime.wasm_modules().get_or_compile(
+                scope,
+                &self.conf.bundle_id,
+                path,
+                &self.files[path],
+            )?;
+            add_props_to_object(scope, &global, vec![(name, module.into())])?;
+        }
         Ok(())
     }
 
     fn populate_with_task(&mut self, task: Task) -> GenericResult<IoScopeConsumer> {
+        self.request_id = Some(format!("{:016x}", rand::thread_rng().gen::<u64>()));
         match task {
-            Task::Fetch(_, res, io_scope) => {
-                self.fetch_response_channel = Some(res);
+            Task::Fetch(req, res, io_scope) => {
+                if let HttpBody::Stream(id) = req.body {
+                    self.request_body = self
+                        .worker_runtime
+                        .body_streams()
+                        .take_request_receiver(&id)
+                        .map(|rx| (id, Arc::new(AsyncMutex::new(rx))));
+                }
+                self.result_channel = Some(TaskResultChannel::Fetch(res));
+                Ok(io_scope)
+            }
+            Task::Scheduled(_, res, io_scope) => {
+                self.result_channel = Some(TaskResultChannel::Scheduled(res));
                 Ok(io_scope)
             }
+            Task::Queue(_, res, io_scope) => {
+                self.result_channel = Some(TaskResultChannel::Queue(res));
+                Ok(io_scope)
+            }
+        }
+    }
+
+    fn try_send_queue_result(isolate: &mut v8::Isolate, res: QueueBatchResult) -> bool {
+        let state = InstanceState::get(isolate);
+        match state.result_channel.take() {
+            Some(TaskResultChannel::Queue(ch)) => ch.send(Ok(res)).is_ok(),
+            other => {
+                // Not a queue task.
+                state.result_channel = other;
+                false
+            }
         }
     }
 
@@ -530,10 +897,45 @@ impl InstanceState {
         isolate: &mut v8::Isolate,
         res: ExecutionResult<ResponseObject>,
     ) -> bool {
-        if let Some(ch) = InstanceState::get(isolate).fetch_response_channel.take() {
-            ch.send(res).is_ok()
-        } else {
-            false
+        let state = InstanceState::get(isolate);
+        match state.result_channel.take() {
+            Some(TaskResultChannel::Fetch(ch)) => ch.send(res).is_ok(),
+            other => {
+                // Not a fetch task.
+                state.result_channel = other;
+                false
+            }
+        }
+    }
+
+    fn try_send_error(isolate: &mut v8::Isolate, e: ExecutionError) -> bool {
+        match InstanceState::get(isolate).result_channel.take() {
+            Some(TaskResultChannel::Fetch(ch)) => ch.send(Err(e)).is_ok(),
+            Some(TaskResultChannel::Scheduled(ch)) => ch.send(Err(e)).is_ok(),
+            Some(TaskResultChannel::Queue(ch)) => ch.send(Err(e)).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Completes the current task after the script marked itself as done.
+    ///
+    /// An unhandled rejection during the task takes precedence over the default result.
+    fn try_send_done(isolate: &mut v8::Isolate) -> bool {
+        if let Some(exc) = InstanceState::get(isolate).task_rejection.take() {
+            return InstanceState::try_send_error(isolate, ExecutionError::UnhandledRejection(exc));
+        }
+        match InstanceState::get(isolate).result_channel.take() {
+            // Script marked itself as done but we haven't got any response.
+            Some(TaskResultChannel::Fetch(ch)) => ch
+                .send(Ok(ResponseObject {
+                    status: 500,
+                    ..Default::default()
+                }))
+                .is_ok(),
+            Some(TaskResultChannel::Scheduled(ch)) => ch.send(Ok(())).is_ok(),
+            // No result means every message is acknowledged.
+            Some(TaskResultChannel::Queue(ch)) => ch.send(Ok(Default::default())).is_ok(),
+            None => false,
         }
     }
 
@@ -566,8 +968,85 @@ fn update_stats(worker_runtime: &Runtime, worker_handle: &WorkerHandle, scope: &
     );
 }
 
-extern "C" fn on_promise_rejection(_msg: v8::PromiseRejectMessage<'_>) {
-    debug!("unhandled promise rejection");
+extern "C" fn on_promise_rejection(msg: v8::PromiseRejectMessage<'_>) {
+    let scope = &mut unsafe { v8::CallbackScope::new(&msg) };
+    let scope = &mut v8::HandleScope::new(scope);
+
+    // Rejections may happen while the isolate is being set up or cleaned up.
+    if !matches!(scope.get_slot::<Option<InstanceState>>(), Some(Some(_))) {
+        return;
+    }
+
+    let promise = msg.get_promise();
+    let hash = promise.get_identity_hash();
+    match msg.get_event() {
+        v8::PromiseRejectEvent::PromiseRejectWithNoHandler => {
+            let reason = msg
+                .get_value()
+                .unwrap_or_else(|| v8::undefined(scope).into());
+            let promise = v8::Global::new(scope, promise);
+            let reason = v8::Global::new(scope, reason);
+            InstanceState::get(scope)
+                .pending_rejections
+                .push((hash, promise, reason));
+        }
+        v8::PromiseRejectEvent::PromiseHandlerAddedAfterReject => {
+            InstanceState::get(scope)
+                .pending_rejections
+                .retain(|(x, _, _)| *x != hash);
+        }
+        _ => {}
+    }
+}
+
+/// Dispatches `unhandledrejection` events for promises rejected without a handler, and records
+/// the ones not handled by any listener as the result of the current task.
+fn dispatch_unhandled_rejections(scope: &mut v8::HandleScope<'_>) -> GenericResult<()> {
+    // Listeners may reject more promises. Bound the number of rounds.
+    for _ in 0..16 {
+        let pending = std::mem::replace(&mut InstanceState::get(scope).pending_rejections, vec![]);
+        if pending.is_empty() {
+            break;
+        }
+        let global = scope.get_current_context().global(scope);
+        let dispatch_key = make_string(scope, "_dispatchUnhandledRejection")?;
+        let dispatch = global.get(scope, dispatch_key.into()).check()?;
+        let dispatch = v8::Local::<'_, v8::Function>::try_from(dispatch)
+            .map_err(|_| GenericError::Other("bad _dispatchUnhandledRejection".into()))?;
+        let recv = v8::undefined(scope);
+
+        for (_, promise, reason) in pending {
+            let scope = &mut v8::HandleScope::new(scope);
+            let promise = v8::Local::new(scope, promise);
+            let reason = v8::Local::new(scope, reason);
+
+            let mut prevented = false;
+            {
+                let try_catch = &mut v8::TryCatch::new(scope);
+                protected_js(try_catch.as_mut(), |scope| {
+                    prevented = dispatch
+                        .call(scope, recv.into(), &[promise.into(), reason])
+                        .map(|x| x.is_true())
+                        .unwrap_or(false);
+                })?;
+            }
+            if prevented {
+                continue;
+            }
+
+            let mut e =
+                ExecutionError::UnhandledRejection(script_exception_from_value(scope, reason));
+            let state = InstanceState::get(scope);
+            state.map_exception(&mut e);
+            state.log_exception(&e);
+            if let ExecutionError::UnhandledRejection(exc) = e {
+                if state.task_rejection.is_none() && state.result_channel.is_some() {
+                    state.task_rejection = Some(exc);
+                }
+            }
+        }
+    }
+    Ok(())
 }
 
 fn protected_js<F: FnOnce(&mut T), T: AsMut<v8::Isolate>>(
@@ -631,29 +1110,19 @@ fn call_service_callback(
     mut retval: v8::ReturnValue,
 ) {
     wrap_callback(scope, |scope| {
-        let call_str = v8::Local::<'_, v8::String>::try_from(args.get(0))?;
-        let call_buf_len = call_str.utf8_length(scope);
+        // The call is MessagePack-encoded by librt.
+        let call = JsArrayBufferViewRef::new(scope, args.get(0))?;
 
-        // Large buffers are passed independently so we can enforce a small call_buf_len here.
-        if call_buf_len > 65536 {
+        // Large buffers are passed independently, so the call itself is small. This bounds the
+        // native memory that decoding it can take.
+        if call.len() > MAX_SERVICE_CALL_SIZE {
             return Err(JsError::new(
                 JsErrorKind::Error,
-                Some("call string too large".into()),
+                Some("service call too large".into()),
             ));
         }
-
-        // Decode string to UTF-8 arraybuffer first.
-        // Do not use native heap (`Vec<u8>`) to enforce memory limit.
-        acquire_arraybuffer_precheck(scope, call_buf_len)?;
-        let call_buf = v8::ArrayBuffer::new(scope, call_buf_len);
-        write_utf8_to_arraybuffer(scope, call_str, call_buf, None);
-
-        let call_backing = call_buf.get_backing_store();
-        let call_reader = ReadableByteCellSlice::new(&call_backing);
-
-        // Now decode to the ServiceCall type.
         let call: ServiceCall =
-            serde_json::from_reader(call_reader).map_err(|_| GenericError::Conversion)?;
+            rmp_serde::from_slice(call.as_bytes(scope)).map_err(|_| GenericError::Conversion)?;
         let buffers = v8::Local::<'_, v8::Array>::try_from(args.get(1))?;
         let buffers_count = buffers.length();
 
@@ -677,38 +1146,87 @@ fn call_service_callback(
         match call {
             ServiceCall::Sync(call) => {
                 match call {
-                    SyncCall::Log(s) => {
-                        debug!("log: {}", s);
+                    SyncCall::Log {
+                        level,
+                        message,
+                        args,
+                    } => {
+                        debug!("log ({:?}): {}", level, message);
                         let state = InstanceState::get(scope);
-                        state
+                        let message = state
                             .worker_runtime
-                            .write_log(format!("app-{}", state.appid), s);
+                            .source_maps()
+                            .for_bundle(&state.conf.bundle_id, &state.files)
+                            .map_text(&message);
+                        state.worker_runtime.write_log(
+                            format!("app-{}", state.appid),
+                            LogRecord {
+                                level,
+                                request_id: state.request_id.clone(),
+                                message,
+                                args,
+                                ..Default::default()
+                            },
+                        );
                     }
                     SyncCall::Done => {
                         let state = InstanceState::get(scope);
                         state.done = true;
                     }
                     SyncCall::SendFetchResponse(mut res) => {
-                        let body = local_buffers
-                            .get(0)
-                            .ok_or_else(|| {
-                                JsError::new(
-                                    JsErrorKind::Error,
-                                    Some("SendFetchResponse: missing buffer".into()),
-                                )
-                            })?
-                            .read_to_vec(MAX_RESPONSE_BODY_SIZE)
-                            .ok_or_else(|| {
-                                JsError::new(
-                                    JsErrorKind::Error,
-                                    Some("SendFetchResponse: body too large".into()),
-                                )
-                            })?;
-                        res.body = HttpBody::Binary(body);
-                        InstanceState::try_send_fetch_response(scope, Ok(res));
+                        let stream_id = match res.body {
+                            HttpBody::Stream(_) => {
+                                // The body follows in `WriteResponseBody` calls.
+                                let state = InstanceState::get(scope);
+                                let (id, tx) =
+                                    state.worker_runtime.body_streams().create_response();
+                                state.response_body = Some((id.clone(), tx));
+                                res.body = HttpBody::Stream(id.clone());
+                                Some(id)
+                            }
+                            HttpBody::Binary(_) => {
+                                let body = local_buffers
+                                    .get(0)
+                                    .ok_or_else(|| {
+                                        JsError::new(
+                                            JsErrorKind::Error,
+                                            Some("SendFetchResponse: missing buffer".into()),
+                                        )
+                                    })?
+                                    .read_to_vec(MAX_RESPONSE_BODY_SIZE)
+                                    .ok_or_else(|| {
+                                        JsError::new(
+                                            JsErrorKind::Error,
+                                            Some("SendFetchResponse: body too large".into()),
+                                        )
+                                    })?;
+                                res.body = HttpBody::Binary(body);
+                                None
+                            }
+                        };
+                        if InstanceState::try_send_fetch_response(scope, Ok(res)) {
+                            let state = InstanceState::get(scope);
+                            state.io_waiter()?.detach_scope();
+                            if stream_id.is_none() {
+                                state.start_wait_until();
+                            }
+                        } else if let Some(id) = stream_id {
+                            // Nobody is going to read it.
+                            let state = InstanceState::get(scope);
+                            state.worker_runtime.body_streams().remove_response(&id);
+                            state.response_body = None;
+                        }
+                    }
+                    SyncCall::SendQueueResult(res) => {
+                        InstanceState::try_send_queue_result(scope, res);
+                    }
+                    SyncCall::CloseResponseBody => {
+                        let state = InstanceState::get(scope);
+                        state.response_body = None;
+                        state.start_wait_until();
                     }
                     SyncCall::GetRandomValues => {
-                        let output: &[Cell<u8>] = local_buffers.get(0).ok_or_else(|| {
+                        let output = local_buffers.get(0).ok_or_else(|| {
                             JsError::new(
                                 JsErrorKind::Error,
                                 Some("GetRandomValues: missing buffer".into()),
@@ -717,10 +1235,8 @@ fn call_service_callback(
                         if output.len() > 65536 {
                             return Err(JsError::new(JsErrorKind::Error, Some("SyncCall::GetRandomValues invoked with length greater than 65536".into())));
                         }
-                        let mut rng = rand::thread_rng();
-                        for byte in output.iter() {
-                            byte.set(rng.gen());
-                        }
+                        // Other buffers of this call are not viewed while this one is filled.
+                        rand::thread_rng().fill(unsafe { output.as_bytes_mut(scope) });
                     }
                     SyncCall::GetFile(name) => {
                         let state = InstanceState::get(scope);
@@ -743,11 +1259,26 @@ fn call_service_callback(
                 let callback = v8::Local::<'_, v8::Function>::try_from(args.get(2))?;
                 let callback = v8::Global::new(scope, callback);
                 let state = InstanceState::get(scope);
+                let response_body = match call {
+                    AsyncCallV::WriteResponseBody => state.response_body.clone(),
+                    _ => None,
+                };
+                let request_body = match call {
+                    AsyncCallV::ReadRequestBody => state.request_body.as_ref().map(|x| x.1.clone()),
+                    _ => None,
+                };
+                let buffers = local_buffers
+                    .iter()
+                    .map(|x| CopiedBuffer::new(x, MAX_ASYNC_CALL_BUFFER_SIZE))
+                    .collect::<Option<Vec<_>>>()
+                    .ok_or_else(|| JsError::error("buffer too large"))?;
                 state.io_waiter()?.issue(
                     false,
                     AsyncCall {
                         v: call,
-                        buffers: local_buffers,
+                        buffers,
+                        response_body,
+                        request_body,
                     },
                     callback,
                 )?;
@@ -769,20 +1300,6 @@ fn queue_microtask_callback(
     })
 }
 
-fn write_utf8_to_arraybuffer(
-    scope: &mut v8::HandleScope<'_>,
-    src: v8::Local<'_, v8::String>,
-    buf: v8::Local<'_, v8::ArrayBuffer>,
-    num_utf16_read_out: Option<&mut usize>,
-) -> usize {
-    let backing = buf.get_backing_store();
-    let backing: &[Cell<u8>] = &backing;
-    let backing = unsafe {
-        std::mem::transmute::<*const [Cell<u8>], &mut [u8]>(backing as *const [Cell<u8>])
-    };
-    src.write_utf8(scope, backing, num_utf16_read_out, Default::default())
-}
-
 /// Converts a `Result` from a callback function into a JavaScript exception.
 fn wrap_callback<'s, F: FnOnce(&mut v8::HandleScope<'s>) -> JsResult<()>>(
     scope: &mut v8::HandleScope<'s>,
@@ -0,0 +1,447 @@
+//! Chrome DevTools inspector sessions for running workers.
+//!
+//! `Runtime::inspect` hands out a one-time DevTools URL for a worker. When DevTools connects to
+//! the inspector server, the connection is handed to the worker's isolate thread, which creates a
+//! V8 inspector session for it. Protocol messages reach the isolate thread in a way that depends
+//! on what it's doing:
+//!
+//! - When idle between tasks, the instance loop is woken up by `InspectorAttachPoint`.
+//! - When waiting for I/O, `IoWaiter::wait` also waits on incoming messages.
+//! - When running JavaScript, an interrupt is requested on the isolate.
+//!
+//! When paused at a breakpoint, the isolate thread blocks on incoming messages until execution is
+//! resumed. The time limit of a worker is not enforced while an inspector is attached. A task that
+//! runs out of time while attached gets a fresh time budget when the inspector detaches.
+
+use crate::runtime::Runtime;
+use futures::{SinkExt, StreamExt};
+use rusty_v8 as v8;
+use rusty_v8::inspector::*;
+use rusty_v8::{UniquePtr, UniqueRef};
+use rusty_workers::types::*;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, Notify};
+use tokio_tungstenite::tungstenite::Message;
+
+const CONTEXT_GROUP_ID: i32 = 1;
+
+/// Time after which an unused DevTools URL expires.
+const TOKEN_TTL: Duration = Duration::from_secs(60);
+
+/// Shared between the inspector server, the isolate thread and the time limit monitor of an
+/// instance.
+pub struct InspectorAttachPoint {
+    /// A connection waiting to be attached by the isolate thread.
+    pending: Mutex<Option<InspectorConnection>>,
+    wakeup: Notify,
+    attached: AtomicBool,
+
+    /// Notified when a session detaches.
+    detached: Notify,
+}
+
+pub struct InspectorConnection {
+    incoming: crossbeam::channel::Receiver<String>,
+    outgoing: mpsc::UnboundedSender<String>,
+}
+
+impl InspectorAttachPoint {
+    pub fn new() -> Self {
+        Self {
+            pending: Mutex::new(None),
+            wakeup: Notify::new(),
+            attached: AtomicBool::new(false),
+            detached: Notify::new(),
+        }
+    }
+
+    pub fn is_attached(&self) -> bool {
+        self.attached.load(Ordering::SeqCst)
+    }
+
+    /// Replaces any pending connection. The isolate thread must be notified afterwards.
+    pub fn set_pending(&self, conn: InspectorConnection) {
+        *self.pending.lock().unwrap() = Some(conn);
+    }
+
+    pub fn notify(&self) {
+        self.wakeup.notify_one();
+    }
+
+    /// Waits for a call to `notify`.
+    pub async fn notified(&self) {
+        self.wakeup.notified().await
+    }
+
+    /// Waits until no session is attached.
+    pub async fn wait_detached(&self) {
+        while self.is_attached() {
+            self.detached.notified().await;
+        }
+    }
+}
+
+/// Inspector state of the current instance. Stored in an isolate slot.
+pub struct WorkerInspector {
+    client: V8InspectorClientBase,
+    inspector: Option<UniqueRef<V8Inspector>>,
+    session: Option<Box<InspectorSession>>,
+    attach_point: Arc<InspectorAttachPoint>,
+    paused: bool,
+}
+
+struct InspectorSession {
+    channel: ChannelBase,
+    session: Option<UniqueRef<V8InspectorSession>>,
+    incoming: crossbeam::channel::Receiver<String>,
+    outgoing: mpsc::UnboundedSender<String>,
+}
+
+impl WorkerInspector {
+    fn get(isolate: &mut v8::Isolate) -> Option<&mut Self> {
+        isolate
+            .get_slot_mut::<Option<Box<Self>>>()
+            .and_then(|x| x.as_mut())
+            .map(|x| &mut **x)
+    }
+
+    fn dispatch_pending(&mut self) {
+        loop {
+            let session = match self.session {
+                Some(ref mut x) => x,
+                None => return,
+            };
+            match session.incoming.try_recv() {
+                Ok(msg) => session.dispatch(&msg),
+                Err(crossbeam::channel::TryRecvError::Empty) => return,
+                Err(crossbeam::channel::TryRecvError::Disconnected) => {
+                    self.detach();
+                    return;
+                }
+            }
+        }
+    }
+
+    fn detach(&mut self) {
+        self.session = None;
+        self.paused = false;
+        self.attach_point.attached.store(false, Ordering::SeqCst);
+        self.attach_point.detached.notify_one();
+    }
+}
+
+impl Drop for WorkerInspector {
+    fn drop(&mut self) {
+        // Sessions must go before the inspector.
+        self.detach();
+        self.inspector = None;
+    }
+}
+
+impl V8InspectorClientImpl for WorkerInspector {
+    fn base(&self) -> &V8InspectorClientBase {
+        &self.client
+    }
+
+    fn base_mut(&mut self) -> &mut V8InspectorClientBase {
+        &mut self.client
+    }
+
+    fn run_message_loop_on_pause(&mut self, _context_group_id: i32) {
+        self.paused = true;
+        while self.paused {
+            let session = match self.session {
+                Some(ref mut x) => x,
+                None => break,
+            };
+            match session.incoming.recv() {
+                Ok(msg) => session.dispatch(&msg),
+                Err(_) => self.detach(),
+            }
+        }
+    }
+
+    fn quit_message_loop_on_pause(&mut self) {
+        self.paused = false;
+    }
+
+    fn run_if_waiting_for_debugger(&mut self, _context_group_id: i32) {}
+}
+
+impl InspectorSession {
+    fn dispatch(&mut self, msg: &str) {
+        if let Some(ref mut session) = self.session {
+            session.dispatch_protocol_message(StringView::from(msg.as_bytes()));
+        }
+    }
+
+    fn send(&mut self, message: UniquePtr<StringBuffer>) {
+        let message = message.unwrap().string().to_string();
+        drop(self.outgoing.send(message));
+    }
+}
+
+impl ChannelImpl for InspectorSession {
+    fn base(&self) -> &ChannelBase {
+        &self.channel
+    }
+
+    fn base_mut(&mut self) -> &mut ChannelBase {
+        &mut self.channel
+    }
+
+    fn send_response(&mut self, _call_id: i32, message: UniquePtr<StringBuffer>) {
+        self.send(message);
+    }
+
+    fn send_notification(&mut self, message: UniquePtr<StringBuffer>) {
+        self.send(message);
+    }
+
+    fn flush_protocol_notifications(&mut self) {}
+}
+
+/// Attaches a pending connection, if any, and dispatches messages that have arrived.
+///
+/// Called on the isolate thread when woken up by the attach point.
+pub fn poll(scope: &mut v8::HandleScope<'_>, attach_point: &Arc<InspectorAttachPoint>) {
+    if let Some(conn) = attach_point.pending.lock().unwrap().take() {
+        attach(scope, attach_point, conn);
+    }
+    dispatch_pending(scope);
+}
+
+fn attach(
+    scope: &mut v8::HandleScope<'_>,
+    attach_point: &Arc<InspectorAttachPoint>,
+    conn: InspectorConnection,
+) {
+    if WorkerInspector::get(scope).is_none() {
+        let mut inspector = Box::new(WorkerInspector {
+            client: V8InspectorClientBase::new::<WorkerInspector>(),
+            inspector: None,
+            session: None,
+            attach_point: attach_point.clone(),
+            paused: false,
+        });
+        let mut v8_inspector = V8Inspector::create(scope, &mut *inspector);
+        let context = scope.get_current_context();
+        v8_inspector.context_created(context, CONTEXT_GROUP_ID, StringView::from(&b"worker"[..]));
+        inspector.inspector = Some(v8_inspector);
+        scope.set_slot(Some(inspector));
+    }
+
+    let inspector = WorkerInspector::get(scope).unwrap();
+
+    // A new connection replaces the current session.
+    inspector.detach();
+    let mut session = Box::new(InspectorSession {
+        channel: ChannelBase::new::<InspectorSession>(),
+        session: None,
+        incoming: conn.incoming,
+        outgoing: conn.outgoing,
+    });
+    let v8_session = inspector.inspector.as_mut().unwrap().connect(
+        CONTEXT_GROUP_ID,
+        &mut *session,
+        StringView::empty(),
+    );
+    session.session = Some(v8_session);
+    inspector.session = Some(session);
+    attach_point.attached.store(true, Ordering::SeqCst);
+    info!("inspector attached");
+}
+
+/// Dispatches messages that have arrived, and detaches if the connection is closed.
+pub fn dispatch_pending(isolate: &mut v8::Isolate) {
+    if let Some(inspector) = WorkerInspector::get(isolate) {
+        inspector.dispatch_pending();
+    }
+}
+
+/// Dispatches a message received from `incoming`.
+pub fn dispatch_message(isolate: &mut v8::Isolate, msg: &str) {
+    if let Some(inspector) = WorkerInspector::get(isolate) {
+        if let Some(ref mut session) = inspector.session {
+            session.dispatch(msg);
+        }
+    }
+}
+
+/// Returns the receiver of incoming messages of the current session, if any.
+pub fn incoming(isolate: &mut v8::Isolate) -> Option<crossbeam::channel::Receiver<String>> {
+    WorkerInspector::get(isolate)
+        .and_then(|x| x.session.as_ref())
+        .map(|x| x.incoming.clone())
+}
+
+/// Drops the inspector of the current instance.
+pub fn cleanup(isolate: &mut v8::Isolate) {
+    isolate.set_slot(Option::<Box<WorkerInspector>>::None);
+}
+
+/// Interrupt callback for dispatching messages while JavaScript is running.
+pub extern "C" fn interrupt_callback(isolate: &mut v8::Isolate, _data: *mut std::ffi::c_void) {
+    dispatch_pending(isolate);
+}
+
+/// Accepts DevTools connections.
+pub struct InspectorServer {
+    listen: SocketAddr,
+
+    /// Address in DevTools URLs.
+    advertise: String,
+
+    /// One-time tokens of DevTools URLs, with the workers they refer to and when they were
+    /// issued.
+    tokens: Mutex<BTreeMap<String, (WorkerHandle, Instant)>>,
+}
+
+impl InspectorServer {
+    pub fn new(listen: SocketAddr, advertise: &str) -> GenericResult<Self> {
+        let advertise = if advertise.len() > 0 {
+            advertise.to_string()
+        } else if listen.ip().is_unspecified() {
+            return Err(GenericError::Other(
+                "an inspector advertise address is required when listening on an unspecified address"
+                    .into(),
+            ));
+        } else {
+            listen.to_string()
+        };
+        Ok(Self {
+            listen,
+            advertise,
+            tokens: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    pub fn listen_addr(&self) -> SocketAddr {
+        self.listen
+    }
+
+    /// Returns a DevTools URL that connects to `worker_handle` once, within `TOKEN_TTL`.
+    pub fn devtools_url(&self, worker_handle: WorkerHandle) -> String {
+        let token = rusty_workers::util::rand_hex(16);
+        let now = Instant::now();
+        let mut tokens = self.tokens.lock().unwrap();
+        tokens.retain(|_, x| now.saturating_duration_since(x.1) < TOKEN_TTL);
+        tokens.insert(token.clone(), (worker_handle, now));
+        drop(tokens);
+        format!(
+            "devtools://devtools/bundled/js_app.html?experiments=true&v8only=true&ws={}/{}",
+            self.advertise, token
+        )
+    }
+
+    fn take_token(&self, token: &str) -> Option<WorkerHandle> {
+        self.tokens
+            .lock()
+            .unwrap()
+            .remove(token)
+            .filter(|x| x.1.elapsed() < TOKEN_TTL)
+            .map(|x| x.0)
+    }
+
+    /// Drops the tokens of a worker that is gone.
+    pub fn forget_worker(&self, worker_handle: &WorkerHandle) {
+        self.tokens
+            .lock()
+            .unwrap()
+            .retain(|_, x| x.0 != *worker_handle);
+    }
+}
+
+pub async fn serve(rt: Weak<Runtime>, listen: SocketAddr) {
+    let listener = match tokio::net::TcpListener::bind(listen).await {
+        Ok(x) => x,
+        Err(e) => {
+            error!("cannot listen for inspector connections: {:?}", e);
+            return;
+        }
+    };
+    info!("inspector listening on {}", listen);
+
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("inspector accept error: {:?}", e);
+                continue;
+            }
+        };
+        let rt = match rt.upgrade() {
+            Some(x) => x,
+            None => break,
+        };
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(rt, stream).await {
+                info!("inspector connection from {} closed: {:?}", addr, e);
+            }
+        });
+    }
+}
+
+async fn handle_connection(rt: Arc<Runtime>, stream: tokio::net::TcpStream) -> GenericResult<()> {
+    let mut path = String::new();
+    let ws = tokio_tungstenite::accept_hdr_async(
+        stream,
+        |req: &tokio_tungstenite::tungstenite::handshake::server::Request, res| {
+            path = req.uri().path().to_string();
+            Ok(res)
+        },
+    )
+    .await
+    .map_err(|e| GenericError::Other(format!("websocket handshake failed: {:?}", e)))?;
+
+    let worker_handle = rt
+        .inspector_server()
+        .and_then(|x| x.take_token(path.trim_start_matches("/")))
+        .ok_or_else(|| GenericError::Other("bad inspector token".into()))?;
+    let instance = rt
+        .instance_handle(&worker_handle)
+        .await
+        .ok_or_else(|| GenericError::Other("no such worker".into()))?;
+
+    let (incoming_tx, incoming_rx) = crossbeam::channel::unbounded();
+    let (outgoing_tx, mut outgoing_rx) = mpsc::unbounded_channel();
+    instance.attach_inspector(InspectorConnection {
+        incoming: incoming_rx,
+        outgoing: outgoing_tx,
+    });
+
+    let (mut ws_tx, mut ws_rx) = ws.split();
+    let result = loop {
+        tokio::select! {
+            msg = ws_rx.next() => match msg {
+                Some(Ok(Message::Text(msg))) => {
+                    if incoming_tx.send(msg).is_err() {
+                        break Ok(());
+                    }
+                    instance.notify_inspector();
+                }
+                Some(Ok(Message::Close(_))) | None => break Ok(()),
+                Some(Ok(_)) => {}
+                Some(Err(e)) => break Err(GenericError::Other(format!("websocket error: {:?}", e))),
+            },
+            msg = outgoing_rx.recv() => match msg {
+                Some(msg) => {
+                    if let Err(e) = ws_tx.send(Message::Text(msg)).await {
+                        break Err(GenericError::Other(format!("websocket error: {:?}", e)));
+                    }
+                }
+                // The instance is gone.
+                None => break Ok(()),
+            },
+        }
+    };
+
+    // Let the instance see the closed connection and detach.
+    drop(incoming_tx);
+    instance.notify_inspector();
+    result
+}
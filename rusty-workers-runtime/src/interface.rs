@@ -1,8 +1,9 @@
+use crate::body_stream::{BodyStreamSender, RequestBodyReceiver};
 use crate::buffer::*;
 use rusty_workers::types::*;
 use serde::{Deserialize, Serialize};
-use std::cell::Cell;
-use std::io::Read;
+use std::sync::Arc;
+use tokio::sync::Mutex as AsyncMutex;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum ServiceCall {
@@ -12,17 +13,34 @@ pub enum ServiceCall {
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum SyncCall {
-    Log(String),
+    Log {
+        level: LogLevel,
+
+        /// Arguments formatted as text.
+        message: String,
+
+        /// Arguments that can be represented as JSON.
+        #[serde(default)]
+        args: Vec<serde_json::Value>,
+    },
     Done,
     SendFetchResponse(ResponseObject),
+    SendQueueResult(QueueBatchResult),
     GetRandomValues,
     GetFile(String),
     Crypto(crate::crypto::CryptoCall),
+    CloseResponseBody,
 }
 
 pub struct AsyncCall {
     pub v: AsyncCallV,
-    pub buffers: Vec<JsArrayBufferViewRef>,
+    pub buffers: Vec<CopiedBuffer>,
+
+    /// The streaming response body of the current task. Only set for `WriteResponseBody`.
+    pub response_body: Option<(String, BodyStreamSender)>,
+
+    /// The streaming request body of the current task. Only set for `ReadRequestBody`.
+    pub request_body: Option<Arc<AsyncMutex<RequestBodyReceiver>>>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -47,33 +65,68 @@ pub enum AsyncCallV {
     KvBeginTransaction,
     KvRollbackTransaction,
     KvCommitTransaction,
+    ReadRequestBody,
+
+    /// Writes the first buffer to the streaming response body, or ends the body if there is none.
+    WriteResponseBody,
+
+    /// Looks up a response in the Cache API cache named `cache`. The body comes back in a buffer.
+    CacheMatch {
+        cache: String,
+        request: RequestObject,
+    },
+
+    /// Stores a response in a cache. The response body is the first buffer.
+    CachePut {
+        cache: String,
+        request: RequestObject,
+        response: ResponseObject,
+    },
+
+    CacheDelete {
+        cache: String,
+        request: RequestObject,
+    },
+
+    /// Sends a request to an actor of the app. The request body is the first buffer.
+    ActorFetch {
+        namespace: String,
+        id: String,
+        request: RequestObject,
+    },
+
+    /// Sends a request to the app of a service binding. The request body is the first buffer.
+    ServiceFetch {
+        service: String,
+        request: RequestObject,
+    },
+
+    /// Sends a message to the queue of a producer binding. The JSON-encoded body is the first
+    /// buffer.
+    QueueSend {
+        queue: String,
+    },
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum ServiceEvent {
     Fetch(FetchEvent),
+    Scheduled(ScheduledEventObject),
+    Queue(QueueBatchObject),
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct FetchEvent {
     pub request: RequestObject,
-}
-
-pub struct ReadableByteCellSlice<'a>(&'a [Cell<u8>]);
 
-impl<'a> ReadableByteCellSlice<'a> {
-    pub fn new(inner: &'a [Cell<u8>]) -> Self {
-        Self(inner)
-    }
+    /// The actor the worker runs as, if any.
+    pub actor: Option<ActorAddress>,
 }
 
-impl<'a> Read for ReadableByteCellSlice<'a> {
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        let read_len = buf.len().min(self.0.len());
-        for i in 0..read_len {
-            buf[i] = self.0[i].get();
-        }
-        self.0 = &self.0[read_len..];
-        Ok(read_len)
-    }
+/// Bindings declared in the app configuration, passed to `_installBindings` in librt.
+#[derive(Serialize, Debug)]
+pub struct Bindings<'a> {
+    pub actors: &'a [String],
+    pub services: Vec<&'a str>,
+    pub queues: Vec<&'a str>,
 }
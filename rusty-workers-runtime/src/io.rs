@@ -1,15 +1,15 @@
+use crate::buffer::CopiedBuffer;
 use crate::interface::{AsyncCall, AsyncCallV};
 use crate::remote_buffer::*;
 use crate::runtime::Runtime;
 use anyhow::Result;
 use rusty_v8 as v8;
 use rusty_workers::kv::WorkerDataTransaction;
-use rusty_workers::rpc::FetchServiceClient;
+use rusty_workers::rpc::{FetchServiceClient, SchedulerServiceClient};
 use rusty_workers::tarpc;
 use rusty_workers::types::*;
 use serde::{Deserialize, Serialize};
 use slab::Slab;
-use std::cell::Cell;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tokio::sync::oneshot;
@@ -19,6 +19,24 @@ const MAX_KV_KEY_SIZE: usize = 2048;
 const MAX_KV_VALUE_SIZE: usize = 4 * 1024 * 1024;
 const MAX_FETCH_REQUEST_BODY_SIZE: usize = 2 * 1024 * 1024;
 const MAX_KV_SCAN_LIMIT: u32 = 100; // 100 * 2K = 200K max
+const MAX_RESPONSE_BODY_CHUNK_SIZE: usize = 1024 * 1024;
+const MAX_CACHE_NAME_SIZE: usize = 256;
+const MAX_CACHE_BODY_SIZE: usize = 8 * 1024 * 1024;
+const MAX_ACTOR_ID_SIZE: usize = 512;
+
+/// Max number of requests between workers in a chain, through actors and service bindings.
+const MAX_CALL_DEPTH: u32 = 8;
+
+const MAX_QUEUE_MESSAGE_SIZE: usize = 128 * 1024;
+
+/// `{"Ok": ...}` in MessagePack. Prepended to the encoded result of a successful I/O operation.
+const IO_OK_PREFIX: &[u8] = &[0x81, 0xa2, b'O', b'k'];
+
+/// Time to wait for the proxy to accept a chunk of a streaming response body.
+const RESPONSE_BODY_WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Time to wait for the proxy to send a chunk of a streaming request body.
+const REQUEST_BODY_READ_TIMEOUT: Duration = Duration::from_secs(30);
 
 pub struct IoWaiter {
     remaining_budget: u32,
@@ -27,15 +45,17 @@ pub struct IoWaiter {
     result: crossbeam::channel::Receiver<BackToExecutorItem>,
     conf: Arc<WorkerConfiguration>,
     remote_buffer_set: RemoteBufferSet,
+    detach: Option<oneshot::Sender<()>>,
 }
 
 pub struct IoProcessor {
     task: tokio::sync::mpsc::UnboundedReceiver<(usize, AsyncCall)>,
     shared: Arc<IoProcessorSharedState>,
+    detach: oneshot::Receiver<()>,
 }
 
 enum BackToExecutorItem {
-    TaskResult((usize, String, Vec<RemoteBuffer>)),
+    TaskResult((usize, Vec<u8>, Vec<RemoteBuffer>)),
     BufferCreation {
         size: usize,
         return_channel: oneshot::Sender<Option<RemoteBuffer>>,
@@ -43,9 +63,15 @@ enum BackToExecutorItem {
 }
 
 struct IoProcessorSharedState {
+    appid: String,
     conf: Arc<WorkerConfiguration>,
     worker_runtime: Arc<Runtime>,
     fetch_client: AsyncMutex<Option<FetchServiceClient>>,
+    scheduler_client: AsyncMutex<Option<SchedulerServiceClient>>,
+
+    /// Depth of the current task in a chain of requests between workers. 0 for requests from
+    /// outside.
+    call_depth: u32,
 
     /// The current KV transaction.
     ///
@@ -57,7 +83,8 @@ struct IoProcessorSharedState {
 
 /// An `IoScope` is a handle that a task sender holds to signal that I/O operations should
 /// continue. When an `IoScope` is dropped, all ongoing I/O operations that depend on it
-/// will be canceled.
+/// will be canceled, unless the executor has detached I/O from the scope with
+/// `IoWaiter::detach_scope`.
 pub struct IoScope {
     _kill: oneshot::Sender<()>,
 }
@@ -86,12 +113,15 @@ impl IoScope {
 
 impl IoWaiter {
     pub fn new(
+        appid: String,
         conf: Arc<WorkerConfiguration>,
         worker_runtime: Arc<Runtime>,
+        call_depth: u32,
     ) -> (Self, IoProcessor) {
         let init_budget = conf.executor.max_io_per_request;
         let (result_tx, result_rx) = crossbeam::channel::unbounded();
         let (task_tx, task_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (detach_tx, detach_rx) = oneshot::channel();
         let waiter = IoWaiter {
             remaining_budget: init_budget,
             inflight: Slab::new(),
@@ -99,20 +129,36 @@ impl IoWaiter {
             result: result_rx,
             conf: conf.clone(),
             remote_buffer_set: RemoteBufferSet::new(),
+            detach: Some(detach_tx),
         };
         let processor = IoProcessor {
             task: task_rx,
             shared: Arc::new(IoProcessorSharedState {
+                appid,
                 conf,
                 worker_runtime,
                 fetch_client: AsyncMutex::new(None),
+                scheduler_client: AsyncMutex::new(None),
+                call_depth,
                 ongoing_txn: AsyncMutex::new(None),
                 result: result_tx,
             }),
+            detach: detach_rx,
         };
         (waiter, processor)
     }
 
+    /// Keeps I/O running after the `IoScope` is dropped. I/O then stops when this `IoWaiter` is
+    /// dropped.
+    ///
+    /// Called once the response of a task is sent, so that `waitUntil()` work isn't canceled when
+    /// the proxy is done with the request.
+    pub fn detach_scope(&mut self) {
+        if let Some(tx) = self.detach.take() {
+            drop(tx.send(()));
+        }
+    }
+
     pub fn issue(
         &mut self,
         count_budget: bool,
@@ -140,12 +186,38 @@ impl IoWaiter {
         }
     }
 
+    /// Waits for the next I/O result. Messages from `inspector` are dispatched while waiting.
+    ///
+    /// Returns `None` if I/O has stopped or `deadline` has passed.
     pub fn wait(
         &mut self,
         scope: &mut v8::HandleScope<'_>,
-    ) -> Option<(v8::Global<v8::Function>, String, Vec<RemoteBuffer>)> {
+        mut inspector: Option<crossbeam::channel::Receiver<String>>,
+        deadline: Option<Instant>,
+    ) -> Option<(v8::Global<v8::Function>, Vec<u8>, Vec<RemoteBuffer>)> {
+        let timeout = match deadline {
+            Some(x) => crossbeam::channel::at(x),
+            None => crossbeam::channel::never(),
+        };
         let (index, result, buffers) = loop {
-            match self.result.recv().ok()? {
+            let never = crossbeam::channel::never();
+            let inspector_rx = inspector.as_ref().unwrap_or(&never);
+            let item = crossbeam::select! {
+                recv(self.result) -> x => x.ok()?,
+                recv(inspector_rx) -> msg => {
+                    match msg {
+                        Ok(msg) => crate::inspector::dispatch_message(scope, &msg),
+                        Err(_) => {
+                            // Connection closed. Detach.
+                            crate::inspector::dispatch_pending(scope);
+                            inspector = None;
+                        }
+                    }
+                    continue;
+                }
+                recv(timeout) -> _ => return None,
+            };
+            match item {
                 BackToExecutorItem::TaskResult(x) => break x,
                 BackToExecutorItem::BufferCreation {
                     size,
@@ -167,31 +239,32 @@ impl IoWaiter {
 }
 
 impl IoProcessor {
-    async fn next(&mut self) -> Option<(AsyncCall, IoResponseHandle)> {
-        let (index, task) = self.task.recv().await?;
-        Some((
-            task,
-            IoResponseHandle {
-                result: self.shared.result.clone(),
-                index,
-            },
-        ))
+    fn response_handle(&self, index: usize) -> IoResponseHandle {
+        IoResponseHandle {
+            result: self.shared.result.clone(),
+            index,
+        }
     }
 
     pub async fn run(mut self, mut scope: IoScopeConsumer) {
         use tokio::sync::watch;
         let (_kill_tx, kill_rx) = watch::channel(());
+        let mut detached = false;
 
         loop {
             let next = tokio::select! {
-                _ = &mut scope.kill => {
+                _ = &mut scope.kill, if !detached => {
                     debug!("IoScope killed");
                     break;
                 }
-                x = self.next() => x
+                _ = &mut self.detach, if !detached => {
+                    detached = true;
+                    continue;
+                }
+                x = self.task.recv() => x
             };
             let (task, res) = match next {
-                Some(x) => x,
+                Some((index, task)) => (task, self.response_handle(index)),
                 None => {
                     debug!("executor dropped IoWaiter");
                     break;
@@ -206,10 +279,16 @@ impl IoProcessor {
                     }
                     ret = shared.handle_task(task) => {
                         match ret {
-                            Ok((x, buffers)) => res.respond(format!("{{\"Ok\":{}}}", x), buffers),
+                            Ok((x, buffers)) => {
+                                let mut data = IO_OK_PREFIX.to_vec();
+                                data.extend_from_slice(&x);
+                                res.respond(data, buffers);
+                            }
                             Err(e) => {
                                 debug!("io error: {:?}", e);
-                                res.respond(format!("{{\"Err\":{}}}", "\"io error\""), vec![]);
+                                let data = rmp_serde::to_vec_named(&Result::<(), _>::Err("io error"))
+                                    .expect("cannot encode io error");
+                                res.respond(data, vec![]);
                             }
                         }
                     }
@@ -248,28 +327,90 @@ impl IoProcessorSharedState {
     }
 
     async fn allocate_arraybuffer_with_data(&self, data: &[u8]) -> GenericResult<RemoteBuffer> {
-        self.allocate_arraybuffer(data.len()).await.map(|x| {
-            let view: &[Cell<u8>] = x.backing();
-            for (i, b) in view.iter().enumerate() {
-                b.set(data[i]);
-            }
+        self.allocate_arraybuffer(data.len()).await.map(|mut x| {
+            x.copy_from_slice(data);
             x
         })
     }
 
-    async fn handle_task(self: Arc<Self>, task: AsyncCall) -> Result<(String, Vec<RemoteBuffer>)> {
+    /// Prepares a request to another worker with `body`, sent through the scheduler service.
+    ///
+    /// Returns a client of the scheduler service and the request with its body and caller set, or
+    /// an error for the script.
+    async fn internal_request(
+        &self,
+        body: Option<CopiedBuffer>,
+        mut request: RequestObject,
+    ) -> Result<Result<(SchedulerServiceClient, RequestObject), &'static str>> {
+        if self.call_depth >= MAX_CALL_DEPTH {
+            return Ok(Err("max call depth exceeded"));
+        }
+        let scheduler_service = match self.conf.scheduler_service {
+            Some(x) => x,
+            None => return Ok(Err("scheduler service not available")),
+        };
+        let body = match body
+            .ok_or_else(|| GenericError::Other("missing body".into()))?
+            .into_vec(MAX_FETCH_REQUEST_BODY_SIZE)
+        {
+            Some(x) => x,
+            None => return Ok(Err("request body too large")),
+        };
+        request.body = HttpBody::Binary(body);
+        request.caller = Some(CallerInfo {
+            appid: self.appid.clone(),
+            depth: self.call_depth + 1,
+        });
+
+        let mut scheduler_client_locked = self.scheduler_client.lock().await;
+        let scheduler_client = if let Some(ref inner) = *scheduler_client_locked {
+            inner.clone()
+        } else {
+            let client = SchedulerServiceClient::connect(scheduler_service).await?;
+            *scheduler_client_locked = Some(client.clone());
+            client
+        };
+        Ok(Ok((scheduler_client, request)))
+    }
+
+    /// Encodes the result of a fetch, with the response body moved into a buffer.
+    async fn encode_fetch_result(
+        &self,
+        mut fetch_result: Result<ResponseObject, String>,
+    ) -> Result<(Vec<u8>, Vec<RemoteBuffer>)> {
+        let buffers = if let Ok(ref mut v) = fetch_result {
+            match v.body {
+                HttpBody::Binary(ref mut body) => {
+                    let buf = self.allocate_arraybuffer_with_data(&body).await?;
+                    *body = vec![];
+                    vec![buf]
+                }
+                HttpBody::Stream(_) => {
+                    return Err(
+                        GenericError::Other("unexpected stream body from fetch".into()).into(),
+                    )
+                }
+            }
+        } else {
+            vec![]
+        };
+        Ok((rmp_serde::to_vec_named(&fetch_result)?, buffers))
+    }
+
+    async fn handle_task(
+        self: Arc<Self>,
+        mut task: AsyncCall,
+    ) -> Result<(Vec<u8>, Vec<RemoteBuffer>)> {
         match task.v {
             AsyncCallV::SetTimeout(n) => {
                 let dur = Duration::from_millis(n);
                 tokio::time::sleep(dur).await;
-                Ok(("null".into(), vec![]))
+                Ok((rmp_serde::to_vec_named(&())?, vec![]))
             }
             AsyncCallV::Fetch(mut req) => {
-                let body = match task
-                    .buffers
-                    .get(0)
+                let body = match take_buffer(&mut task.buffers, 0)
                     .ok_or_else(|| GenericError::Other("missing body".into()))?
-                    .read_to_vec(MAX_FETCH_REQUEST_BODY_SIZE)
+                    .into_vec(MAX_FETCH_REQUEST_BODY_SIZE)
                 {
                     Some(x) => x,
                     None => return Ok(mk_user_error("fetch request body too large")?),
@@ -286,27 +427,144 @@ impl IoProcessorSharedState {
                 };
                 drop(fetch_client_locked);
 
-                let mut fetch_result: Result<ResponseObject, String> =
+                let fetch_result: Result<ResponseObject, String> =
                     fetch_client.fetch(tarpc::context::current(), req).await??;
-                let buffers = if let Ok(ref mut v) = fetch_result {
-                    match v.body {
-                        HttpBody::Binary(ref mut body) => {
-                            let buf = self.allocate_arraybuffer_with_data(&body).await?;
-                            *body = vec![];
-                            vec![buf]
-                        }
+                self.encode_fetch_result(fetch_result).await
+            }
+            AsyncCallV::ActorFetch {
+                namespace,
+                id,
+                request,
+            } => {
+                if !self.conf.actor_namespaces.contains(&namespace) {
+                    return Ok(mk_user_error("actor namespace not found")?);
+                }
+                if id.len() > MAX_ACTOR_ID_SIZE {
+                    return Ok(mk_user_error("actor id too long")?);
+                }
+                let (mut client, request) = match self
+                    .internal_request(take_buffer(&mut task.buffers, 0), request)
+                    .await?
+                {
+                    Ok(x) => x,
+                    Err(e) => return Ok(mk_user_error(e)?),
+                };
+                let actor = ActorAddress { namespace, id };
+                let fetch_result: Result<ResponseObject, String> = client
+                    .actor_fetch(
+                        tarpc::context::current(),
+                        self.appid.clone(),
+                        actor,
+                        request,
+                    )
+                    .await??;
+                self.encode_fetch_result(fetch_result).await
+            }
+            AsyncCallV::ServiceFetch { service, request } => {
+                if !self.conf.services.contains_key(&service) {
+                    return Ok(mk_user_error("service binding not found")?);
+                }
+                let (mut client, request) = match self
+                    .internal_request(take_buffer(&mut task.buffers, 0), request)
+                    .await?
+                {
+                    Ok(x) => x,
+                    Err(e) => return Ok(mk_user_error(e)?),
+                };
+                let fetch_result: Result<ResponseObject, String> = client
+                    .service_fetch(
+                        tarpc::context::current(),
+                        self.appid.clone(),
+                        service,
+                        request,
+                    )
+                    .await??;
+                self.encode_fetch_result(fetch_result).await
+            }
+            AsyncCallV::QueueSend { queue } => {
+                let queue = match self.conf.queue_producers.get(&queue) {
+                    Some(x) => x,
+                    None => return Ok(mk_user_error("queue binding not found")?),
+                };
+                let body = match take_buffer(&mut task.buffers, 0)
+                    .ok_or_else(|| GenericError::Other("missing body".into()))?
+                    .into_vec(MAX_QUEUE_MESSAGE_SIZE)
+                {
+                    Some(x) => x,
+                    None => return Ok(mk_user_error("message too large")?),
+                };
+                let body = match String::from_utf8(body) {
+                    Ok(x) => x,
+                    Err(_) => return Ok(mk_user_error("message is not valid utf-8")?),
+                };
+                let kv = match self.worker_runtime.kv() {
+                    Some(x) => x,
+                    None => return Ok(mk_user_error("kv disabled")?),
+                };
+                let now = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis() as i64;
+                let id = kv.queue_send(queue, body, now).await?;
+                Ok(mk_user_ok(id)?)
+            }
+            AsyncCallV::CacheMatch { cache, request } => {
+                if cache.len() > MAX_CACHE_NAME_SIZE {
+                    return Ok(mk_user_error("cache name too long")?);
+                }
+                let cached =
+                    self.worker_runtime
+                        .response_cache()
+                        .lookup(&self.appid, &cache, &request);
+                match cached {
+                    Some(mut res) => {
+                        let body = match std::mem::take(&mut res.body) {
+                            HttpBody::Binary(x) => x,
+                            HttpBody::Stream(_) => vec![],
+                        };
+                        Ok(mk_user_ok_with_buffers(
+                            Some(res),
+                            vec![self.allocate_arraybuffer_with_data(&body).await?],
+                        )?)
                     }
-                } else {
-                    vec![]
+                    None => Ok(mk_user_ok(Option::<ResponseObject>::None)?),
+                }
+            }
+            AsyncCallV::CachePut {
+                cache,
+                request,
+                mut response,
+            } => {
+                if cache.len() > MAX_CACHE_NAME_SIZE {
+                    return Ok(mk_user_error("cache name too long")?);
+                }
+                let body = match take_buffer(&mut task.buffers, 0)
+                    .ok_or_else(|| GenericError::Other("missing body".into()))?
+                    .into_vec(MAX_CACHE_BODY_SIZE)
+                {
+                    Some(x) => x,
+                    None => return Ok(mk_user_error("response body too large to cache")?),
                 };
-                Ok((serde_json::to_string(&fetch_result)?, buffers))
+                response.body = HttpBody::Binary(body);
+                let stored = self.worker_runtime.response_cache().put(
+                    &self.appid,
+                    &cache,
+                    &request,
+                    response,
+                );
+                Ok(mk_user_ok(stored)?)
+            }
+            AsyncCallV::CacheDelete { cache, request } => {
+                let deleted =
+                    self.worker_runtime
+                        .response_cache()
+                        .delete(&self.appid, &cache, &request);
+                Ok(mk_user_ok(deleted)?)
             }
             AsyncCallV::KvGet { namespace, lock } => {
-                let key = match task
-                    .buffers
-                    .get(0)
+                let key = match take_buffer(&mut task.buffers, 0)
                     .ok_or_else(|| GenericError::Other("missing key".into()))?
-                    .read_to_vec(MAX_KV_KEY_SIZE)
+                    .into_vec(MAX_KV_KEY_SIZE)
                 {
                     Some(x) => x,
                     None => return Ok(mk_user_error("key too large")?),
@@ -345,20 +603,16 @@ impl IoProcessorSharedState {
                 }
             }
             AsyncCallV::KvPut { namespace } => {
-                let key = match task
-                    .buffers
-                    .get(0)
+                let key = match take_buffer(&mut task.buffers, 0)
                     .ok_or_else(|| GenericError::Other("missing key".into()))?
-                    .read_to_vec(MAX_KV_KEY_SIZE)
+                    .into_vec(MAX_KV_KEY_SIZE)
                 {
                     Some(x) => x,
                     None => return Ok(mk_user_error("key too large")?),
                 };
-                let value = match task
-                    .buffers
-                    .get(1)
+                let value = match take_buffer(&mut task.buffers, 1)
                     .ok_or_else(|| GenericError::Other("missing value".into()))?
-                    .read_to_vec(MAX_KV_VALUE_SIZE)
+                    .into_vec(MAX_KV_VALUE_SIZE)
                 {
                     Some(x) => x,
                     None => return Ok(mk_user_error("value too large")?),
@@ -380,11 +634,9 @@ impl IoProcessorSharedState {
                 Ok(mk_user_ok(())?)
             }
             AsyncCallV::KvDelete { namespace } => {
-                let key = match task
-                    .buffers
-                    .get(0)
+                let key = match take_buffer(&mut task.buffers, 0)
                     .ok_or_else(|| GenericError::Other("missing key".into()))?
-                    .read_to_vec(MAX_KV_KEY_SIZE)
+                    .into_vec(MAX_KV_KEY_SIZE)
                 {
                     Some(x) => x,
                     None => return Ok(mk_user_error("key too large")?),
@@ -410,20 +662,19 @@ impl IoProcessorSharedState {
                 limit,
                 lock,
             } => {
-                let start_key = match task
-                    .buffers
-                    .get(0)
+                let start_key = match take_buffer(&mut task.buffers, 0)
                     .ok_or_else(|| GenericError::Other("missing start key".into()))?
-                    .read_to_vec(MAX_KV_KEY_SIZE)
+                    .into_vec(MAX_KV_KEY_SIZE)
                 {
                     Some(x) => x,
                     None => return Ok(mk_user_error("start_key too large")?),
                 };
-                let end_key = match task.buffers.get(1).map(|x| x.read_to_vec(MAX_KV_KEY_SIZE)) {
-                    Some(Some(x)) => Some(x),
-                    Some(None) => return Ok(mk_user_error("end_key too large")?),
-                    None => None,
-                };
+                let end_key =
+                    match take_buffer(&mut task.buffers, 1).map(|x| x.into_vec(MAX_KV_KEY_SIZE)) {
+                        Some(Some(x)) => Some(x),
+                        Some(None) => return Ok(mk_user_error("end_key too large")?),
+                        None => None,
+                    };
                 let namespace_id = match self.conf.kv_namespaces.get(&namespace) {
                     Some(id) => id,
                     None => return Ok(mk_user_error("namespace does not exist")?),
@@ -501,12 +752,57 @@ impl IoProcessorSharedState {
                     Ok(mk_user_error("no ongoing transaction to commit")?)
                 }
             }
+            AsyncCallV::ReadRequestBody => {
+                let rx = match task.request_body {
+                    Some(x) => x,
+                    None => return Ok(mk_user_error("no request body stream")?),
+                };
+                let chunk = match tokio::time::timeout(REQUEST_BODY_READ_TIMEOUT, async {
+                    rx.lock().await.recv().await
+                })
+                .await
+                {
+                    Ok(x) => x,
+                    Err(_) => return Ok(mk_user_error("request body stream timed out")?),
+                };
+                match chunk {
+                    Some(Some(chunk)) => Ok(mk_user_ok_with_buffers(
+                        true,
+                        vec![self.allocate_arraybuffer_with_data(&chunk).await?],
+                    )?),
+                    Some(None) => Ok(mk_user_ok(false)?),
+                    None => Ok(mk_user_error("request body stream aborted")?),
+                }
+            }
+            AsyncCallV::WriteResponseBody => {
+                // No chunk ends the body.
+                let chunk = match take_buffer(&mut task.buffers, 0) {
+                    Some(x) => match x.into_vec(MAX_RESPONSE_BODY_CHUNK_SIZE) {
+                        Some(x) => Some(x),
+                        None => return Ok(mk_user_error("chunk too large")?),
+                    },
+                    None => None,
+                };
+                let (id, tx) = match task.response_body {
+                    Some(x) => x,
+                    None => return Ok(mk_user_error("no response body stream")?),
+                };
+                match tokio::time::timeout(RESPONSE_BODY_WRITE_TIMEOUT, tx.send(chunk)).await {
+                    Ok(Ok(())) => Ok(mk_user_ok(())?),
+                    Ok(Err(_)) => Ok(mk_user_error("response body stream closed")?),
+                    Err(_) => {
+                        // The proxy stopped reading.
+                        self.worker_runtime.body_streams().remove_response(&id);
+                        Ok(mk_user_error("response body stream timed out")?)
+                    }
+                }
+            }
         }
     }
 }
 
 impl IoResponseHandle {
-    fn respond(self, data: String, buffers: Vec<RemoteBuffer>) {
+    fn respond(self, data: Vec<u8>, buffers: Vec<RemoteBuffer>) {
         drop(
             self.result
                 .send(BackToExecutorItem::TaskResult((self.index, data, buffers))),
@@ -514,19 +810,24 @@ impl IoResponseHandle {
     }
 }
 
-fn mk_user_ok<T: serde::Serialize>(value: T) -> Result<(String, Vec<RemoteBuffer>)> {
+fn mk_user_ok<T: serde::Serialize>(value: T) -> Result<(Vec<u8>, Vec<RemoteBuffer>)> {
     mk_user_ok_with_buffers(value, vec![])
 }
 
+/// Takes a buffer of an async call, leaving an empty one behind.
+fn take_buffer(buffers: &mut [CopiedBuffer], index: usize) -> Option<CopiedBuffer> {
+    buffers.get_mut(index).map(std::mem::take)
+}
+
 fn mk_user_ok_with_buffers<T: serde::Serialize>(
     value: T,
     buffers: Vec<RemoteBuffer>,
-) -> Result<(String, Vec<RemoteBuffer>)> {
+) -> Result<(Vec<u8>, Vec<RemoteBuffer>)> {
     let value: Result<T, ()> = Ok(value);
-    Ok((serde_json::to_string(&value)?, buffers))
+    Ok((rmp_serde::to_vec_named(&value)?, buffers))
 }
 
-fn mk_user_error<T: serde::Serialize>(value: T) -> Result<(String, Vec<RemoteBuffer>)> {
+fn mk_user_error<T: serde::Serialize>(value: T) -> Result<(Vec<u8>, Vec<RemoteBuffer>)> {
     let value: Result<(), T> = Err(value);
-    Ok((serde_json::to_string(&value)?, vec![]))
+    Ok((rmp_serde::to_vec_named(&value)?, vec![]))
 }
@@ -1,13 +1,11 @@
 //! V8 isolate owner threads and pools.
 
 use crate::mm::MemoryPool;
+use crate::snapshot::{check_librt_snapshot, make_librt_snapshot};
 use rusty_v8 as v8;
 use std::sync::Arc;
 use tokio::sync::{mpsc, oneshot, Semaphore};
 
-/// JavaScript-side runtime.
-static LIBRT: &'static str = include_str!("../../librt/dist/main.js");
-
 pub struct Poison;
 
 pub struct IsolateThreadPool {
@@ -66,8 +64,25 @@ pub struct MemoryPoolBox(pub Arc<MemoryPool>);
 impl IsolateThreadPool {
     pub async fn new(size: usize, config: IsolateConfig) -> Self {
         let start_time = std::time::Instant::now();
-        let threads: Vec<IsolateThread> =
-            futures::future::join_all((0..size).map(|_| IsolateThread::new(config.clone()))).await;
+        let snapshot = tokio::task::spawn_blocking(|| {
+            let snapshot = make_librt_snapshot();
+            if let Err(e) = check_librt_snapshot(&snapshot) {
+                panic!("{}", e);
+            }
+            snapshot
+        })
+        .await
+        .expect("cannot create librt snapshot");
+        info!(
+            "librt snapshot of {} bytes created in {:?}",
+            snapshot.len(),
+            std::time::Instant::now().duration_since(start_time)
+        );
+
+        let threads: Vec<IsolateThread> = futures::future::join_all(
+            (0..size).map(|_| IsolateThread::new(config.clone(), snapshot.clone())),
+        )
+        .await;
         let end_time = std::time::Instant::now();
         info!(
             "isolate pool of size {} initialized in {:?}",
@@ -116,10 +131,10 @@ impl IsolateThreadPool {
 }
 
 impl IsolateThread {
-    pub async fn new(config: IsolateConfig) -> Self {
+    pub async fn new(config: IsolateConfig, snapshot: Arc<[u8]>) -> Self {
         let (job_tx, mut job_rx) = mpsc::channel(1);
         std::thread::spawn(move || loop {
-            isolate_worker(&config, &mut job_rx);
+            isolate_worker(&config, &snapshot, &mut job_rx);
             std::thread::sleep(std::time::Duration::from_millis(100));
             info!("restarting isolate worker");
         });
@@ -127,34 +142,22 @@ impl IsolateThread {
     }
 }
 
-fn isolate_worker(config: &IsolateConfig, job_rx: &mut mpsc::Receiver<IsolateJob>) {
+fn isolate_worker(
+    config: &IsolateConfig,
+    snapshot: &Arc<[u8]>,
+    job_rx: &mut mpsc::Receiver<IsolateJob>,
+) {
     // Don't allocate any budget for arraybuffers at start.
     let pool = crate::mm::MemoryPool::new(0);
 
     let params = v8::Isolate::create_params()
         .array_buffer_allocator(pool.clone().get_allocator())
-        .heap_limits(0, config.max_memory_bytes);
+        .heap_limits(0, config.max_memory_bytes)
+        .snapshot_blob(snapshot.to_vec());
 
     // Must not be moved
     let mut isolate = v8::Isolate::new(params);
 
-    let librt_persistent;
-
-    // Compile librt.
-    // Many unwraps here! but since we are initializing it should be fine.
-    {
-        let mut isolate_scope = v8::HandleScope::new(&mut isolate);
-        let context = v8::Context::new(&mut isolate_scope);
-        let mut context_scope = v8::ContextScope::new(&mut isolate_scope, context);
-        let scope = &mut v8::HandleScope::new(&mut context_scope);
-
-        let librt = v8::String::new(scope, LIBRT).unwrap();
-        let librt = v8::Script::compile(scope, librt, None)
-            .unwrap()
-            .get_unbound_script(scope);
-        librt_persistent = v8::Global::new(scope, librt);
-    }
-
     let generation = IsolateGenerationBox(Arc::new(std::sync::Mutex::new(IsolateGeneration(0))));
     isolate.set_slot(generation.clone());
 
@@ -166,7 +169,7 @@ fn isolate_worker(config: &IsolateConfig, job_rx: &mut mpsc::Receiver<IsolateJob
             None => break,
         };
 
-        // Reset arraybuffer memory pool budget for librt initialization.
+        // Reset arraybuffer memory pool budget for librt state deserialized from the snapshot.
         // Hardcoded to 16 MiB here.
         isolate
             .get_slot::<MemoryPoolBox>()
@@ -174,24 +177,11 @@ fn isolate_worker(config: &IsolateConfig, job_rx: &mut mpsc::Receiver<IsolateJob
             .0
             .reset(1048576 * 16);
 
-        // Enter context.
+        // Enter context. The new context comes from the snapshot, with librt initialized.
         let mut isolate_scope = v8::HandleScope::new(&mut isolate);
         let context = v8::Context::new(&mut isolate_scope);
         let mut context_scope = v8::ContextScope::new(&mut isolate_scope, context);
 
-        // Run librt initialization.
-        {
-            let scope = &mut v8::HandleScope::new(&mut context_scope);
-
-            let global_key = v8::String::new(scope, "global").unwrap();
-            let global_obj = scope.get_current_context().global(scope);
-            global_obj.set(scope, global_key.into(), global_obj.into());
-
-            let librt = v8::Local::<'_, v8::UnboundScript>::new(scope, librt_persistent.clone())
-                .bind_to_current_context(scope);
-            librt.run(scope);
-        }
-
         job(&mut context_scope);
 
         // Release scopes.
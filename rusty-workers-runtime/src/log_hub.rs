@@ -0,0 +1,148 @@
+//! Recent log records of a runtime, for live tailing.
+//!
+//! Records are published by the log worker into a bounded buffer, numbered with a sequence that
+//! subscribers use as a cursor. A subscriber that falls behind by more than the buffer size loses
+//! the records in between, and is told how many.
+
+use rusty_workers::types::*;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// Number of records kept.
+const LOG_HUB_CAPACITY: usize = 4096;
+
+/// Max number of records returned by one poll.
+const MAX_TAIL_LIMIT: u32 = 1000;
+
+/// Max time a poll waits for new records.
+const MAX_TAIL_TIMEOUT_MS: u64 = 20000;
+
+pub struct LogHub {
+    state: Mutex<HubState>,
+
+    /// Sequence number of the last record.
+    last_seq_tx: watch::Sender<u64>,
+    last_seq_rx: watch::Receiver<u64>,
+}
+
+#[derive(Default)]
+struct HubState {
+    records: VecDeque<HubRecord>,
+    last_seq: u64,
+}
+
+struct HubRecord {
+    seq: u64,
+    appid: String,
+    level: LogLevel,
+    text: String,
+}
+
+impl LogHub {
+    pub fn new() -> Self {
+        let (last_seq_tx, last_seq_rx) = watch::channel(0);
+        Self {
+            state: Mutex::new(HubState::default()),
+            last_seq_tx,
+            last_seq_rx,
+        }
+    }
+
+    pub fn publish(&self, appid: &str, level: LogLevel, text: &str) {
+        let seq = {
+            let mut state = self.state.lock().unwrap();
+            state.last_seq += 1;
+            let seq = state.last_seq;
+            if state.records.len() == LOG_HUB_CAPACITY {
+                state.records.pop_front();
+            }
+            state.records.push_back(HubRecord {
+                seq,
+                appid: appid.to_string(),
+                level,
+                text: text.to_string(),
+            });
+            seq
+        };
+        drop(self.last_seq_tx.send(seq));
+    }
+
+    /// Returns the records matching `req` after its cursor, waiting up to `req.timeout_ms` for
+    /// at least one.
+    pub async fn tail(&self, req: &LogTailRequest) -> LogTailBatch {
+        let mut last_seq_rx = self.last_seq_rx.clone();
+        let limit = req.limit.min(MAX_TAIL_LIMIT).max(1) as usize;
+        let timeout = Duration::from_millis(req.timeout_ms.min(MAX_TAIL_TIMEOUT_MS));
+        let deadline = tokio::time::Instant::now() + timeout;
+        let mut cursor = match req.cursor {
+            Some(x) => x,
+            None => self.state.lock().unwrap().last_seq,
+        };
+        let mut lost = 0;
+
+        // `changed()` returns once a record has been published since its last return, so records
+        // published between `collect` and `changed` aren't missed. The first call may return
+        // immediately.
+        loop {
+            let batch = self.collect(req, cursor, limit);
+            lost += batch.lost;
+            cursor = batch.cursor;
+            if !batch.entries.is_empty() {
+                return LogTailBatch { lost, ..batch };
+            }
+            match tokio::time::timeout_at(deadline, last_seq_rx.changed()).await {
+                Ok(Ok(())) => {}
+                _ => {
+                    return LogTailBatch {
+                        cursor,
+                        lost,
+                        entries: vec![],
+                    }
+                }
+            }
+        }
+    }
+
+    fn collect(&self, req: &LogTailRequest, cursor: u64, limit: usize) -> LogTailBatch {
+        let state = self.state.lock().unwrap();
+
+        // The client may come from another runtime instance at this address, with a cursor
+        // that is ahead of us.
+        let cursor = cursor.min(state.last_seq);
+
+        let first_seq = state
+            .records
+            .front()
+            .map(|x| x.seq)
+            .unwrap_or(state.last_seq + 1);
+        let lost = first_seq.saturating_sub(cursor + 1);
+        let mut next_cursor = cursor;
+        let mut entries = vec![];
+        for record in state.records.iter().skip_while(|x| x.seq <= cursor) {
+            if entries.len() == limit {
+                break;
+            }
+            next_cursor = record.seq;
+            if req
+                .appid
+                .as_ref()
+                .map(|x| *x != record.appid)
+                .unwrap_or(false)
+                || req.level.map(|x| record.level < x).unwrap_or(false)
+            {
+                continue;
+            }
+            entries.push(LogTailEntry {
+                appid: record.appid.clone(),
+                record: record.text.clone(),
+            });
+        }
+        LogTailBatch {
+            cursor: next_cursor,
+            lost,
+            entries,
+        }
+    }
+}
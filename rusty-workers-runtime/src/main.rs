@@ -4,20 +4,30 @@
 #[macro_use]
 extern crate log;
 
+mod body_stream;
 mod buffer;
+mod code_cache;
 mod config;
 mod crypto;
+mod der;
 mod engine;
 mod error;
 mod executor;
+mod inspector;
 mod interface;
 mod io;
 mod isolate;
+mod log_hub;
 mod mm;
+mod module_loader;
 mod remote_buffer;
+mod response_cache;
 mod runtime;
 mod semaphore;
 mod server;
+mod snapshot;
+mod source_map;
+mod wasm_cache;
 
 use anyhow::Result;
 use std::net::SocketAddr;
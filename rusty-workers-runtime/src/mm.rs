@@ -1,7 +1,7 @@
+use crate::buffer::backing_store_bytes_mut;
 use crate::isolate::MemoryPoolBox;
 use rusty_v8 as v8;
 use rusty_workers::types::*;
-use std::cell::Cell;
 use std::ffi::c_void;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
@@ -131,11 +131,7 @@ pub fn slice_to_arraybuffer<'s>(
     acquire_arraybuffer_precheck(scope, data.len())?;
     let buf = v8::ArrayBuffer::new(scope, data.len());
     let backing = buf.get_backing_store();
-    let backing: &[Cell<u8>] = &backing;
-
-    for (i, b) in data.iter().enumerate() {
-        backing[i].set(*b);
-    }
-
+    // The buffer was just created, so there is no other view of it.
+    unsafe { backing_store_bytes_mut(&backing, scope) }.copy_from_slice(data);
     Ok(buf)
 }
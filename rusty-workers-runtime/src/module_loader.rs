@@ -0,0 +1,372 @@
+//! ES modules in worker bundles.
+//!
+//! The entrypoint of a bundle is, in order of preference:
+//!
+//! - The `main` field of `./manifest.json`. It's loaded as a module if its name ends with `.mjs`
+//!   or the manifest has `"type": "module"`, and as a classic script otherwise.
+//! - `./index.mjs`, loaded as a module.
+//! - `./index.js`, loaded as a classic script.
+//!
+//! Static and dynamic imports are resolved against the files in the bundle. Specifiers must be
+//! relative (`./x.mjs`, `../x.mjs`) or absolute from the bundle root (`/x.mjs`).
+//!
+//! The default export of an entry module, if any, is registered with librt as the event handlers
+//! (`export default { fetch, scheduled }`).
+//!
+//! The manifest can also declare WebAssembly modules to expose as globals, as a map from global
+//! names to bundle paths (`"wasm_modules": { "IMAGE_WASM": "./image.wasm" }`).
+
+use crate::engine::*;
+use crate::error::*;
+use crate::runtime::Runtime;
+use rusty_v8 as v8;
+use rusty_workers::types::*;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+const MANIFEST_PATH: &str = "./manifest.json";
+
+pub type BundleFiles = Arc<BTreeMap<String, Arc<[u8]>>>;
+
+#[derive(Deserialize, Default)]
+pub struct BundleManifest {
+    #[serde(default)]
+    main: Option<String>,
+
+    #[serde(default, rename = "type")]
+    ty: Option<String>,
+
+    #[serde(default)]
+    pub wasm_modules: BTreeMap<String, String>,
+}
+
+impl BundleManifest {
+    /// Reads `./manifest.json` from the bundle. Returns an empty manifest if there isn't one.
+    pub fn load(files: &BundleFiles) -> GenericResult<Self> {
+        match files.get(MANIFEST_PATH) {
+            Some(x) => serde_json::from_slice(x)
+                .map_err(|e| GenericError::Other(format!("bad manifest: {}", e))),
+            None => Ok(Self::default()),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Entrypoint {
+    Script(String),
+    Module(String),
+}
+
+impl Entrypoint {
+    pub fn lookup(files: &BundleFiles, manifest: &BundleManifest) -> GenericResult<Self> {
+        if let Some(ref main) = manifest.main {
+            let path = resolve_bundle_path(main)?;
+            if !files.contains_key(&path) {
+                return Err(GenericError::Other(format!(
+                    "cannot find entrypoint {} in bundle",
+                    path
+                )));
+            }
+            let is_module = path.ends_with(".mjs") || manifest.ty.as_deref() == Some("module");
+            return Ok(if is_module {
+                Entrypoint::Module(path)
+            } else {
+                Entrypoint::Script(path)
+            });
+        }
+
+        if files.contains_key("./index.mjs") {
+            Ok(Entrypoint::Module("./index.mjs".into()))
+        } else if files.contains_key("./index.js") {
+            Ok(Entrypoint::Script("./index.js".into()))
+        } else {
+            Err(GenericError::Other(
+                "cannot find ./index.js or ./index.mjs in bundle".into(),
+            ))
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        match self {
+            Entrypoint::Script(x) | Entrypoint::Module(x) => x,
+        }
+    }
+}
+
+/// Modules compiled in the current instance. Stored in an isolate slot.
+pub struct ModuleMap {
+    files: BundleFiles,
+    worker_runtime: Arc<Runtime>,
+    bundle_id: String,
+    modules: BTreeMap<String, v8::Global<v8::Module>>,
+
+    /// Paths of compiled modules, by identity hash. Used to resolve imports relative to the
+    /// importing module. Identity hashes aren't unique, so each entry holds all the modules with
+    /// that hash.
+    paths: BTreeMap<i32, Vec<(v8::Global<v8::Module>, String)>>,
+}
+
+impl ModuleMap {
+    pub fn new(files: BundleFiles, worker_runtime: Arc<Runtime>, bundle_id: String) -> Self {
+        Self {
+            files,
+            worker_runtime,
+            bundle_id,
+            modules: BTreeMap::new(),
+            paths: BTreeMap::new(),
+        }
+    }
+
+    fn get(isolate: &mut v8::Isolate) -> &mut Self {
+        isolate
+            .get_slot_mut::<Option<Self>>()
+            .unwrap()
+            .as_mut()
+            .unwrap()
+    }
+
+    fn path_of(&self, module: v8::Local<'_, v8::Module>) -> Option<&str> {
+        self.paths
+            .get(&module.get_identity_hash())?
+            .iter()
+            .find(|(x, _)| *x == module)
+            .map(|(_, path)| path.as_str())
+    }
+}
+
+/// Resolves a path given in the manifest or the worker configuration, relative to the bundle root.
+pub fn resolve_bundle_path(path: &str) -> GenericResult<String> {
+    resolve_path(MANIFEST_PATH, path)
+        .map_err(|e| GenericError::Other(e.message.unwrap_or_default()))
+}
+
+/// Resolves `specifier` relative to the bundle path `referrer`. Returns a normalized path in the
+/// form of `./a/b.mjs`.
+pub fn resolve_path(referrer: &str, specifier: &str) -> JsResult<String> {
+    let mut segments: Vec<&str> = if specifier.starts_with("/") {
+        vec![]
+    } else if specifier.starts_with("./") || specifier.starts_with("../") {
+        let mut dir: Vec<&str> = referrer.split("/").collect();
+        dir.pop();
+        dir
+    } else {
+        return Err(JsError::new(
+            JsErrorKind::TypeError,
+            Some(format!("bad module specifier: {}", specifier)),
+        ));
+    };
+    segments.retain(|x| *x != "." && !x.is_empty());
+
+    for seg in specifier.split("/") {
+        match seg {
+            "" | "." => {}
+            ".." => {
+                if segments.pop().is_none() {
+                    return Err(JsError::new(
+                        JsErrorKind::TypeError,
+                        Some(format!("module specifier escapes bundle: {}", specifier)),
+                    ));
+                }
+            }
+            x => segments.push(x),
+        }
+    }
+    Ok(format!("./{}", segments.join("/")))
+}
+
+pub fn script_origin<'s>(
+    scope: &mut v8::HandleScope<'s>,
+    name: v8::Local<'s, v8::String>,
+    is_module: bool,
+) -> v8::ScriptOrigin<'s> {
+    let source_map_url = v8::undefined(scope).into();
+    v8::ScriptOrigin::new(
+        scope,
+        name.into(),
+        0,
+        0,
+        false,
+        0,
+        source_map_url,
+        false,
+        false,
+        is_module,
+    )
+}
+
+fn throw(scope: &mut v8::HandleScope<'_>, e: JsError) {
+    let exception = e.build(scope);
+    scope.throw_exception(exception);
+}
+
+/// Compiles the module at `path`, or returns it from the module map if it's already compiled.
+///
+/// Returns `None` with an exception thrown on failure.
+fn load_module<'s>(
+    scope: &mut v8::HandleScope<'s>,
+    path: &str,
+) -> Option<v8::Local<'s, v8::Module>> {
+    let map = ModuleMap::get(scope);
+    if let Some(module) = map.modules.get(path).cloned() {
+        return Some(module.into_local(scope));
+    }
+
+    let source = match map.files.get(path).cloned() {
+        Some(x) => x,
+        None => {
+            throw(
+                scope,
+                JsError::error(format!("cannot find module {}", path)),
+            );
+            return None;
+        }
+    };
+    let source = match std::str::from_utf8(&source) {
+        Ok(x) => x,
+        Err(_) => {
+            throw(
+                scope,
+                JsError::error(format!("cannot decode module {} as utf-8 text", path)),
+            );
+            return None;
+        }
+    };
+    let source = v8::String::new(scope, source)?;
+    let name = v8::String::new(scope, path)?;
+    let origin = script_origin(scope, name, true);
+    let map = ModuleMap::get(scope);
+    let worker_runtime = map.worker_runtime.clone();
+    let bundle_id = map.bundle_id.clone();
+    let module = worker_runtime
+        .code_cache()
+        .compile_module(scope, &bundle_id, path, source, &origin)?;
+
+    let global = v8::Global::new(scope, module);
+    let map = ModuleMap::get(scope);
+    map.paths
+        .entry(module.get_identity_hash())
+        .or_default()
+        .push((global.clone(), path.to_string()));
+    map.modules.insert(path.to_string(), global);
+    Some(module)
+}
+
+/// Instantiates and evaluates a module if needed, and returns its namespace object.
+///
+/// Returns `None` with an exception thrown on failure.
+fn evaluate_module<'s>(
+    scope: &mut v8::HandleScope<'s>,
+    module: v8::Local<'s, v8::Module>,
+) -> Option<v8::Local<'s, v8::Value>> {
+    if module.get_status() == v8::ModuleStatus::Uninstantiated {
+        module.instantiate_module(scope, resolve_module_callback)?;
+    }
+    if module.get_status() == v8::ModuleStatus::Instantiated {
+        module.evaluate(scope)?;
+    }
+    if module.get_status() == v8::ModuleStatus::Errored {
+        let exception = module.get_exception();
+        scope.throw_exception(exception);
+        return None;
+    }
+    let namespace = module.get_module_namespace();
+    Some(v8::Local::new(scope, namespace))
+}
+
+fn resolve_module_callback<'s>(
+    context: v8::Local<'s, v8::Context>,
+    specifier: v8::Local<'s, v8::String>,
+    _import_assertions: v8::Local<'s, v8::FixedArray>,
+    referrer: v8::Local<'s, v8::Module>,
+) -> Option<v8::Local<'s, v8::Module>> {
+    let scope = &mut unsafe { v8::CallbackScope::new(context) };
+    let referrer = ModuleMap::get(scope)
+        .path_of(referrer)
+        .unwrap_or_default()
+        .to_string();
+    let specifier = specifier.to_rust_string_lossy(scope);
+    match resolve_path(&referrer, &specifier) {
+        Ok(path) => load_module(scope, &path),
+        Err(e) => {
+            throw(scope, e);
+            None
+        }
+    }
+}
+
+/// Handles dynamic `import()` from modules and classic scripts.
+pub extern "C" fn dynamic_import_callback(
+    context: v8::Local<'_, v8::Context>,
+    referrer: v8::Local<'_, v8::ScriptOrModule>,
+    specifier: v8::Local<'_, v8::String>,
+    _import_assertions: v8::Local<'_, v8::FixedArray>,
+) -> *mut v8::Promise {
+    // The promise is returned to V8, so it must be created in the callback's own scope rather
+    // than in a `HandleScope` that is gone by then.
+    let scope = &mut unsafe { v8::CallbackScope::new(context) };
+    let resolver = v8::PromiseResolver::new(scope).unwrap();
+    let promise = resolver.get_promise(scope);
+
+    let referrer = referrer.get_resource_name();
+    let referrer = if referrer.is_string() {
+        referrer.to_rust_string_lossy(scope)
+    } else {
+        String::new()
+    };
+    let specifier = specifier.to_rust_string_lossy(scope);
+
+    let try_catch = &mut v8::TryCatch::new(scope);
+    let namespace = match resolve_path(&referrer, &specifier) {
+        Ok(path) => load_module(try_catch, &path).and_then(|x| evaluate_module(try_catch, x)),
+        Err(e) => {
+            throw(try_catch, e);
+            None
+        }
+    };
+    match namespace {
+        Some(x) => {
+            resolver.resolve(try_catch, x);
+        }
+        None => {
+            let exception = try_catch
+                .exception()
+                .unwrap_or_else(|| v8::undefined(try_catch).into());
+            try_catch.reset();
+            resolver.reject(try_catch, exception);
+        }
+    }
+
+    &*promise as *const v8::Promise as *mut v8::Promise
+}
+
+/// Loads and evaluates the entry module, and registers its default export with librt.
+///
+/// Exceptions are left in the caller's `TryCatch`.
+pub fn run_entry_module(
+    scope: &mut v8::HandleScope<'_>,
+    path: &str,
+    env: &BTreeMap<String, String>,
+) -> GenericResult<()> {
+    let namespace = match load_module(scope, path).and_then(|x| evaluate_module(scope, x)) {
+        Some(x) => v8::Local::<'_, v8::Object>::try_from(x)
+            .map_err(|_| GenericError::Other("bad module namespace".into()))?,
+        None => return Ok(()),
+    };
+    let default_key = make_string(scope, "default")?;
+    let handlers = namespace.get(scope, default_key.into()).check()?;
+    if handlers.is_null_or_undefined() {
+        return Ok(());
+    }
+
+    let global = scope.get_current_context().global(scope);
+    let register_key = make_string(scope, "_registerModuleHandlers")?;
+    let register = global.get(scope, register_key.into()).check()?;
+    let register = v8::Local::<'_, v8::Function>::try_from(register)
+        .map_err(|_| GenericError::Other("bad _registerModuleHandlers".into()))?;
+    let env = native_to_js(scope, env)?;
+    let recv = v8::undefined(scope);
+    register.call(scope, recv.into(), &[handlers, env]);
+    Ok(())
+}
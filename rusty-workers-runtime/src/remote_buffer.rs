@@ -3,6 +3,7 @@
 
 use rusty_v8 as v8;
 use send_wrapper::SendWrapper;
+use std::cell::Cell;
 use std::collections::BTreeMap;
 use std::sync::{Arc, Mutex, Weak};
 
@@ -20,8 +21,15 @@ pub struct RemoteBuffer {
 }
 
 impl RemoteBuffer {
-    pub fn backing(&self) -> &v8::SharedRef<v8::BackingStore> {
-        &self.backing
+    /// Copies `data` into the start of the buffer.
+    ///
+    /// The buffer isn't reachable from script code before `unwrap_on_v8_thread`, and that
+    /// consumes `self`. So `&mut self` is exclusive access to the bytes, from any thread.
+    pub fn copy_from_slice(&mut self, data: &[u8]) {
+        let cells: &[Cell<u8>] = &self.backing;
+        let bytes =
+            unsafe { std::slice::from_raw_parts_mut(cells.as_ptr() as *mut u8, cells.len()) };
+        bytes[..data.len()].copy_from_slice(data);
     }
 
     pub fn unwrap_on_v8_thread(self) -> Option<v8::Global<v8::ArrayBuffer>> {
@@ -0,0 +1,369 @@
+//! Runtime-local response cache behind the Cache API (`caches.default` and `caches.open`).
+//!
+//! Entries are keyed by app id, cache name and URL, so an app never sees the entries of another.
+//! A URL holds one variant of its response per combination of the request headers named by
+//! `Vary`. Variants expire after the `s-maxage` or `max-age` in `Cache-Control`.
+//!
+//! When the total size is over the configured number of bytes, least recently used URLs are
+//! evicted from the app that stored the response if it uses more than an equal share of the
+//! cache, or else from the app that uses the most. So one busy app can't flush the entries of the
+//! others.
+
+use rusty_workers::types::*;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Max number of `Vary` variants kept for one URL. The oldest one is dropped first.
+const MAX_VARIANTS_PER_URL: usize = 16;
+
+/// Longest time a variant is kept, whatever its `max-age`. Larger values would overflow `Instant`.
+const MAX_TTL: Duration = Duration::from_secs(365 * 24 * 3600);
+
+pub struct ResponseCache {
+    state: Mutex<CacheState>,
+    max_bytes: usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    insertions: AtomicU64,
+    evictions: AtomicU64,
+}
+
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+struct CacheKey {
+    appid: String,
+    cache: String,
+    url: String,
+}
+
+#[derive(Default)]
+struct CacheState {
+    entries: HashMap<CacheKey, CacheEntry>,
+
+    /// Usage by app id. Only apps with entries are present.
+    apps: HashMap<String, AppUsage>,
+
+    next_seq: u64,
+    total_bytes: usize,
+}
+
+#[derive(Default)]
+struct AppUsage {
+    bytes: usize,
+
+    /// Keys by the sequence number of their last use.
+    lru: BTreeMap<u64, CacheKey>,
+}
+
+struct CacheEntry {
+    seq: u64,
+    variants: Vec<Variant>,
+}
+
+struct Variant {
+    /// Request headers named by `Vary`, with their values in the request the response was stored
+    /// for.
+    vary: Vec<(String, Option<Vec<String>>)>,
+
+    expires: Instant,
+    response: ResponseObject,
+    size: usize,
+}
+
+impl ResponseCache {
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            state: Mutex::new(CacheState::default()),
+            max_bytes,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            insertions: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    pub fn stats(&self) -> ResponseCacheStats {
+        ResponseCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            insertions: self.insertions.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            bytes: self.state.lock().unwrap().total_bytes as u64,
+        }
+    }
+
+    /// Returns a fresh response stored for `req`.
+    pub fn lookup(&self, appid: &str, cache: &str, req: &RequestObject) -> Option<ResponseObject> {
+        let res = if req.method.eq_ignore_ascii_case("GET") {
+            let key = CacheKey::new(appid, cache, &req.url);
+            self.state.lock().unwrap().lookup(&key, req)
+        } else {
+            None
+        };
+        if res.is_some() {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+        res
+    }
+
+    /// Stores `res` as the response to `req`. `res` must have a binary body.
+    ///
+    /// Returns `false` if the response is not cacheable: the request is not a GET, the response is
+    /// partial, has no positive `max-age`, is `no-store` or `private`, varies on `*`, or is larger
+    /// than the whole cache.
+    pub fn put(&self, appid: &str, cache: &str, req: &RequestObject, res: ResponseObject) -> bool {
+        if !req.method.eq_ignore_ascii_case("GET") || res.status == 206 {
+            return false;
+        }
+        let max_age = match max_age(&res.headers) {
+            Some(x) if x > 0 => x,
+            _ => return false,
+        };
+
+        let mut vary = vec![];
+        for name in header_values(&res.headers, "vary")
+            .into_iter()
+            .flatten()
+            .flat_map(|x| x.split(','))
+        {
+            let name = name.trim().to_ascii_lowercase();
+            if name == "*" {
+                return false;
+            }
+            if name.len() > 0 {
+                let values = header_values(&req.headers, &name).cloned();
+                vary.push((name, values));
+            }
+        }
+
+        let body_len = match res.body {
+            HttpBody::Binary(ref x) => x.len(),
+            HttpBody::Stream(_) => return false,
+        };
+        let size = req.url.len()
+            + body_len
+            + headers_size(&res.headers)
+            + vary
+                .iter()
+                .map(|(k, v)| k.len() + v.iter().flatten().map(|x| x.len()).sum::<usize>())
+                .sum::<usize>();
+        if size > self.max_bytes {
+            return false;
+        }
+
+        let variant = Variant {
+            vary,
+            expires: Instant::now() + Duration::from_secs(max_age).min(MAX_TTL),
+            response: res,
+            size,
+        };
+        let key = CacheKey::new(appid, cache, &req.url);
+        let evicted = {
+            let mut state = self.state.lock().unwrap();
+            state.insert(key, variant);
+            state.evict(self.max_bytes, appid)
+        };
+        self.insertions.fetch_add(1, Ordering::Relaxed);
+        self.evictions.fetch_add(evicted, Ordering::Relaxed);
+        true
+    }
+
+    /// Removes all variants stored for the URL of `req`. Returns whether there were any.
+    pub fn delete(&self, appid: &str, cache: &str, req: &RequestObject) -> bool {
+        let key = CacheKey::new(appid, cache, &req.url);
+        self.state.lock().unwrap().remove(&key)
+    }
+}
+
+impl CacheKey {
+    fn new(appid: &str, cache: &str, url: &str) -> Self {
+        Self {
+            appid: appid.to_string(),
+            cache: cache.to_string(),
+            url: url.to_string(),
+        }
+    }
+}
+
+impl CacheState {
+    fn lookup(&mut self, key: &CacheKey, req: &RequestObject) -> Option<ResponseObject> {
+        let now = Instant::now();
+        let entry = self.entries.get_mut(key)?;
+
+        let mut freed = 0;
+        entry.variants.retain(|x| {
+            if x.expires > now {
+                true
+            } else {
+                freed += x.size;
+                false
+            }
+        });
+        self.free(&key.appid, freed);
+        let entry = self.entries.get(key)?;
+        if entry.variants.len() == 0 {
+            self.remove(key);
+            return None;
+        }
+
+        let res = entry
+            .variants
+            .iter()
+            .find(|x| {
+                x.vary
+                    .iter()
+                    .all(|(name, values)| header_values(&req.headers, name) == values.as_ref())
+            })
+            .map(|x| x.response.clone())?;
+        self.touch(key);
+        Some(res)
+    }
+
+    fn insert(&mut self, key: CacheKey, variant: Variant) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.total_bytes += variant.size;
+        let app = self.apps.entry(key.appid.clone()).or_default();
+        app.bytes += variant.size;
+
+        let entry = self
+            .entries
+            .entry(key.clone())
+            .or_insert_with(|| CacheEntry {
+                seq,
+                variants: vec![],
+            });
+        let mut freed = 0;
+        entry.variants.retain(|x| {
+            if x.vary == variant.vary {
+                freed += x.size;
+                false
+            } else {
+                true
+            }
+        });
+        if entry.variants.len() >= MAX_VARIANTS_PER_URL {
+            freed += entry.variants.remove(0).size;
+        }
+        entry.variants.push(variant);
+
+        app.lru.remove(&entry.seq);
+        entry.seq = seq;
+        app.lru.insert(seq, key.clone());
+        self.free(&key.appid, freed);
+    }
+
+    fn touch(&mut self, key: &CacheKey) {
+        if let Some(entry) = self.entries.get_mut(key) {
+            let app = self.apps.get_mut(&key.appid).unwrap();
+            app.lru.remove(&entry.seq);
+            entry.seq = self.next_seq;
+            self.next_seq += 1;
+            app.lru.insert(entry.seq, key.clone());
+        }
+    }
+
+    fn remove(&mut self, key: &CacheKey) -> bool {
+        match self.entries.remove(key) {
+            Some(entry) => {
+                let app = self.apps.get_mut(&key.appid).unwrap();
+                app.lru.remove(&entry.seq);
+                let empty = app.lru.is_empty();
+                self.free(
+                    &key.appid,
+                    entry.variants.iter().map(|x| x.size).sum::<usize>(),
+                );
+                if empty {
+                    self.apps.remove(&key.appid);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Subtracts `n` bytes from the total and from the usage of `appid`.
+    fn free(&mut self, appid: &str, n: usize) {
+        self.total_bytes -= n;
+        if let Some(app) = self.apps.get_mut(appid) {
+            app.bytes -= n;
+        }
+    }
+
+    /// Removes least recently used URLs until the cache fits in `max_bytes`, after `appid` stored
+    /// a response. Returns the number of removed URLs.
+    ///
+    /// URLs are removed from `appid` while it uses more than its share, except for the one it
+    /// just stored, and from the app using the most bytes otherwise.
+    fn evict(&mut self, max_bytes: usize, appid: &str) -> u64 {
+        let mut evicted = 0;
+        while self.total_bytes > max_bytes {
+            let share = max_bytes / self.apps.len().max(1);
+            let app = match self.apps.get(appid) {
+                Some(x) if x.bytes > share && x.lru.len() > 1 => x,
+                _ => {
+                    let largest = self
+                        .apps
+                        .iter()
+                        .filter(|(id, x)| id.as_str() != appid || x.lru.len() > 1)
+                        .max_by_key(|(_, x)| x.bytes);
+                    match largest {
+                        Some((_, x)) => x,
+                        None => break,
+                    }
+                }
+            };
+            let key = match app.lru.iter().next() {
+                Some((_, key)) => key.clone(),
+                None => break,
+            };
+            self.remove(&key);
+            evicted += 1;
+        }
+        evicted
+    }
+}
+
+fn header_values<'a>(
+    headers: &'a BTreeMap<String, Vec<String>>,
+    name: &str,
+) -> Option<&'a Vec<String>> {
+    headers
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v)
+}
+
+fn headers_size(headers: &BTreeMap<String, Vec<String>>) -> usize {
+    headers
+        .iter()
+        .map(|(k, v)| k.len() + v.iter().map(|x| x.len()).sum::<usize>())
+        .sum()
+}
+
+/// Returns the freshness lifetime in seconds from `Cache-Control`, or `None` if the response must
+/// not be stored.
+fn max_age(headers: &BTreeMap<String, Vec<String>>) -> Option<u64> {
+    let mut max_age = None;
+    let mut s_maxage = None;
+    for directive in header_values(headers, "cache-control")?
+        .iter()
+        .flat_map(|x| x.split(','))
+    {
+        let mut parts = directive.splitn(2, '=');
+        let name = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+        let value = parts
+            .next()
+            .and_then(|x| x.trim().trim_matches('"').parse::<u64>().ok());
+        match name.as_str() {
+            "no-store" | "private" | "no-cache" => return None,
+            "max-age" => max_age = value,
+            "s-maxage" => s_maxage = value,
+            _ => {}
+        }
+    }
+    s_maxage.or(max_age)
+}
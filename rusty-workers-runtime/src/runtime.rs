@@ -1,14 +1,21 @@
+use crate::body_stream::BodyStreamSet;
+use crate::code_cache::CodeCache;
 use crate::config::Config;
 use crate::executor::{Instance, InstanceHandle, InstanceTimeControl, TimerControl};
+use crate::inspector::InspectorServer;
 use crate::isolate::{IsolateConfig, IsolateThreadPool};
+use crate::log_hub::LogHub;
+use crate::response_cache::ResponseCache;
 use crate::semaphore::{Permit, Semaphore};
+use crate::source_map::SourceMapCache;
+use crate::wasm_cache::WasmModuleCache;
 use lru_time_cache::LruCache;
 use rusty_v8 as v8;
-use rusty_workers::kv::KvClient;
+use rusty_workers::kv::{ActorLeaseRenewal, KvClient};
 use rusty_workers::types::*;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::{Arc, Weak};
-use std::time::{Duration, SystemTime};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant, SystemTime};
 use tokio::sync::oneshot;
 use tokio::sync::RwLock as AsyncRwLock;
 
@@ -21,17 +28,52 @@ pub struct Runtime {
     execution_token: Semaphore,
     kv: Option<KvClient>,
     log_tx: tokio::sync::mpsc::Sender<LogEntry>,
+    log_hub: LogHub,
     isolate_config: IsolateConfig,
+    body_streams: BodyStreamSet,
+    wasm_modules: WasmModuleCache,
+    source_maps: SourceMapCache,
+    code_cache: CodeCache,
+    response_cache: ResponseCache,
+    inspector: Option<InspectorServer>,
 }
 
 struct WorkerState {
     handle: Arc<InstanceHandle>,
     memory_bytes: AtomicUsize,
+    actor_lease: Option<Arc<ActorLeaseState>>,
+}
+
+/// The lease on an actor that a worker was created for.
+struct ActorLeaseState {
+    appid: String,
+    actor: ActorAddress,
+    epoch: u64,
+    lease: Duration,
+
+    /// Until when requests can be served without renewing the lease first. `None` before the first
+    /// renewal.
+    valid_until: Mutex<Option<Instant>>,
+}
+
+impl ActorLeaseState {
+    fn is_confirmed(&self) -> bool {
+        self.valid_until.lock().unwrap().is_some()
+    }
+
+    fn is_valid(&self) -> bool {
+        self.valid_until
+            .lock()
+            .unwrap()
+            .map(|x| Instant::now() < x)
+            .unwrap_or(false)
+    }
 }
 
 struct LogEntry {
     topic: String,
     time: SystemTime,
+    level: LogLevel,
     text: String,
 }
 
@@ -40,7 +82,7 @@ pub struct InstanceStatistics {
 }
 
 pub fn init() {
-    let platform = v8::new_default_platform().unwrap();
+    let platform = v8::new_default_platform(0, false).make_shared();
     v8::V8::initialize_platform(platform);
     v8::V8::initialize();
 }
@@ -54,12 +96,31 @@ impl Runtime {
         let max_isolate_memory_bytes = config.max_isolate_memory_bytes;
         let isolate_pool_size = config.isolate_pool_size;
         let execution_concurrency = config.execution_concurrency;
+        let wasm_module_cache_size = config.wasm_module_cache_size;
+        let source_map_cache_size = config.source_map_cache_size;
+        let response_cache_size_bytes = config.response_cache_size_bytes;
+        let inspector_listen = config.inspector_listen;
+        let inspector = match inspector_listen {
+            Some(listen) => Some(InspectorServer::new(listen, &config.inspector_advertise)?),
+            None => None,
+        };
+        if config.code_cache_dir.len() > 0 {
+            std::fs::create_dir_all(&config.code_cache_dir).map_err(|e| {
+                GenericError::Other(format!("cannot create code cache directory: {:?}", e))
+            })?;
+        }
+        let code_cache = CodeCache::new(
+            config.code_cache_size,
+            if config.code_cache_dir.len() > 0 {
+                Some(config.code_cache_dir.clone().into())
+            } else {
+                None
+            },
+            tokio::runtime::Handle::current(),
+        );
 
-        let kv = if config.tikv_cluster.len() > 0 {
-            let cluster: Vec<_> = config.tikv_cluster.split(",").collect();
-            let client = Some(KvClient::new(cluster).await?);
-            info!("connected to tikv cluster");
-            client
+        let kv = if config.storage.len() > 0 {
+            Some(KvClient::open(&config.storage).await?)
         } else {
             None
         };
@@ -83,11 +144,21 @@ impl Runtime {
             execution_token: Semaphore::new(execution_concurrency),
             kv,
             log_tx,
+            log_hub: LogHub::new(),
+            body_streams: BodyStreamSet::new(),
+            wasm_modules: WasmModuleCache::new(wasm_module_cache_size),
+            source_maps: SourceMapCache::new(source_map_cache_size),
+            code_cache,
+            response_cache: ResponseCache::new(response_cache_size_bytes),
+            inspector,
         });
         let rt_weak = Arc::downgrade(&rt);
         let rt_weak_2 = rt_weak.clone();
         tokio::spawn(statistics_update_worker(rt_weak, statistics_update_rx));
         tokio::spawn(log_worker(rt_weak_2, log_rx));
+        if let Some(listen) = inspector_listen {
+            tokio::spawn(crate::inspector::serve(Arc::downgrade(&rt), listen));
+        }
         Ok(rt)
     }
 
@@ -109,6 +180,57 @@ impl Runtime {
         &self.isolate_config
     }
 
+    pub fn body_streams(&self) -> &BodyStreamSet {
+        &self.body_streams
+    }
+
+    pub fn wasm_modules(&self) -> &WasmModuleCache {
+        &self.wasm_modules
+    }
+
+    pub fn source_maps(&self) -> &SourceMapCache {
+        &self.source_maps
+    }
+
+    pub fn code_cache(&self) -> &CodeCache {
+        &self.code_cache
+    }
+
+    pub fn response_cache(&self) -> &ResponseCache {
+        &self.response_cache
+    }
+
+    pub fn log_hub(&self) -> &LogHub {
+        &self.log_hub
+    }
+
+    pub fn inspector_server(&self) -> Option<&InspectorServer> {
+        self.inspector.as_ref()
+    }
+
+    pub async fn instance_handle(
+        &self,
+        worker_handle: &WorkerHandle,
+    ) -> Option<Arc<InstanceHandle>> {
+        self.instances
+            .write()
+            .await
+            .get(worker_handle)
+            .map(|x| x.handle.clone())
+    }
+
+    /// Returns a DevTools URL for debugging a worker.
+    pub async fn inspect(&self, worker_handle: &WorkerHandle) -> GenericResult<String> {
+        let server = self
+            .inspector
+            .as_ref()
+            .ok_or_else(|| GenericError::Other("inspector is not enabled".into()))?;
+        if self.instance_handle(worker_handle).await.is_none() {
+            return Err(GenericError::Other("no such worker".into()));
+        }
+        Ok(server.devtools_url(worker_handle.clone()))
+    }
+
     fn instance_thread(
         isolate: &mut v8::ContextScope<'_, v8::HandleScope<'_>>,
         rt: tokio::runtime::Handle,
@@ -156,6 +278,9 @@ impl Runtime {
         let mut deadline = None;
         let initial_budget = timectl.budget;
 
+        // Set when the deadline passed while an inspector was attached.
+        let mut suspended = false;
+
         loop {
             tokio::select! {
                 op = timectl.timer_rx.recv() => {
@@ -163,6 +288,7 @@ impl Runtime {
                         match op {
                             TimerControl::Start => {
                                 deadline = Some(tokio::time::Instant::now() + timectl.budget);
+                                suspended = false;
                             }
                             TimerControl::Stop => {
                                 let now = tokio::time::Instant::now();
@@ -172,6 +298,7 @@ impl Runtime {
                                     debug!("remaining time budget: {:?}", timectl.budget);
                                 }
                                 deadline = None;
+                                suspended = false;
                             }
                             TimerControl::Reset => {
                                 timectl.budget = initial_budget;
@@ -188,6 +315,13 @@ impl Runtime {
                     }
                 }
                 _ = wait_until(deadline) => {
+                    if timectl.inspector.is_attached() {
+                        // Don't interrupt a debugging session.
+                        deadline = None;
+                        suspended = true;
+                        continue;
+                    }
+
                     info!("worker {} timed out", worker_handle.id);
 
                     if let Some(handle) = self.instances.write().await.remove(&worker_handle) {
@@ -196,8 +330,18 @@ impl Runtime {
 
                     break;
                 }
+                _ = timectl.inspector.wait_detached(), if suspended => {
+                    // The task is still running after the debugger left. Give it a new time budget.
+                    deadline = Some(tokio::time::Instant::now() + initial_budget);
+                    suspended = false;
+                }
             }
         }
+
+        // The worker is gone, so its DevTools URLs can't be used anymore.
+        if let Some(ref inspector) = self.inspector {
+            inspector.forget_worker(&worker_handle);
+        }
     }
 
     pub async fn list(&self) -> GenericResult<Vec<WorkerHandle>> {
@@ -224,6 +368,127 @@ impl Runtime {
         req: RequestObject,
     ) -> ExecutionResult<ResponseObject> {
         // write() lock for LRU update
+        let (instance, actor_lease) = self
+            .instances
+            .write()
+            .await
+            .get(&worker_handle)
+            .map(|x| (x.handle.clone(), x.actor_lease.clone()))
+            .ok_or_else(|| ExecutionError::NoSuchWorker)?;
+
+        // Another worker may have taken over the actor since the last renewal.
+        if let Some(lease) = actor_lease {
+            if !lease.is_valid() {
+                match self.renew_actor_lease(worker_handle, &lease).await {
+                    Ok(ActorLeaseRenewal::Renewed) => {}
+                    res => {
+                        info!(
+                            "worker {} lost the lease on its actor: {:?}",
+                            worker_handle.id, res
+                        );
+                        self.terminate(worker_handle).await;
+                        return Err(ExecutionError::NoSuchWorker);
+                    }
+                }
+            }
+        }
+
+        instance.fetch(req).await
+    }
+
+    /// Extends the lease on the actor of a worker, from now on.
+    async fn renew_actor_lease(
+        &self,
+        worker_handle: &WorkerHandle,
+        state: &ActorLeaseState,
+    ) -> GenericResult<ActorLeaseRenewal> {
+        let kv = self
+            .kv
+            .as_ref()
+            .ok_or_else(|| GenericError::Other("renew_actor_lease: no kv".into()))?;
+        let start = Instant::now();
+        let expires = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|x| (x + state.lease).as_millis() as i64)
+            .unwrap_or(0);
+        let res = kv
+            .actor_lease_renew(
+                &state.appid,
+                &state.actor,
+                worker_handle,
+                state.epoch,
+                expires,
+            )
+            .await?;
+        if res == ActorLeaseRenewal::Renewed {
+            // Leave a margin for clock skew between this runtime and proxies, which take the lease
+            // over once it expires by their clocks.
+            *state.valid_until.lock().unwrap() = Some(start + state.lease * 2 / 3);
+        }
+        Ok(res)
+    }
+
+    /// Renews the lease on the actor of a worker while the worker lives, and stops the worker once
+    /// it can't.
+    async fn actor_lease_task(
+        self: Arc<Self>,
+        worker_handle: WorkerHandle,
+        state: Arc<ActorLeaseState>,
+    ) {
+        let spawned = Instant::now();
+        loop {
+            tokio::time::sleep(state.lease / 3).await;
+            if self.instances.read().await.peek(&worker_handle).is_none() {
+                break;
+            }
+            let stop = match self.renew_actor_lease(&worker_handle, &state).await {
+                Ok(ActorLeaseRenewal::Renewed) => false,
+                Ok(ActorLeaseRenewal::Superseded) => true,
+                // The proxy writes the lease after the worker is spawned.
+                Ok(ActorLeaseRenewal::Pending) => {
+                    state.is_confirmed() || spawned.elapsed() > state.lease
+                }
+                Err(e) => {
+                    warn!(
+                        "cannot renew actor lease of worker {}: {:?}",
+                        worker_handle.id, e
+                    );
+                    !state.is_valid() && spawned.elapsed() > state.lease
+                }
+            };
+            if stop {
+                info!("worker {} lost the lease on its actor", worker_handle.id);
+                self.terminate(&worker_handle).await;
+                break;
+            }
+        }
+    }
+
+    pub async fn read_body_chunk(&self, stream: &str) -> GenericResult<Option<Vec<u8>>> {
+        self.body_streams.read_response(stream).await
+    }
+
+    pub fn create_body_stream(&self) -> String {
+        self.body_streams.create_request()
+    }
+
+    pub async fn write_body_chunk(
+        &self,
+        stream: &str,
+        chunk: Option<Vec<u8>>,
+    ) -> GenericResult<()> {
+        self.body_streams.write_request(stream, chunk).await
+    }
+
+    pub fn close_body_stream(&self, stream: &str) -> bool {
+        self.body_streams.remove_response(stream) || self.body_streams.remove_request(stream)
+    }
+
+    pub async fn scheduled(
+        &self,
+        worker_handle: &WorkerHandle,
+        event: ScheduledEventObject,
+    ) -> ExecutionResult<()> {
         let instance = self
             .instances
             .write()
@@ -231,7 +496,22 @@ impl Runtime {
             .get(&worker_handle)
             .map(|x| x.handle.clone())
             .ok_or_else(|| ExecutionError::NoSuchWorker)?;
-        instance.fetch(req).await
+        instance.scheduled(event).await
+    }
+
+    pub async fn queue(
+        &self,
+        worker_handle: &WorkerHandle,
+        batch: QueueBatchObject,
+    ) -> ExecutionResult<QueueBatchResult> {
+        let instance = self
+            .instances
+            .write()
+            .await
+            .get(&worker_handle)
+            .map(|x| x.handle.clone())
+            .ok_or_else(|| ExecutionError::NoSuchWorker)?;
+        instance.queue(batch).await
     }
 
     pub async fn spawn(
@@ -240,6 +520,20 @@ impl Runtime {
         bundle: Vec<u8>,
         configuration: &WorkerConfiguration,
     ) -> GenericResult<WorkerHandle> {
+        // Compilation on the isolate thread only looks at the code cache in memory.
+        self.code_cache.preload(&configuration.bundle_id).await;
+
+        let actor_lease = match (&configuration.actor, &configuration.actor_lease) {
+            (Some(actor), Some(lease)) if self.kv.is_some() => Some(Arc::new(ActorLeaseState {
+                appid: appid.clone(),
+                actor: actor.clone(),
+                epoch: lease.epoch,
+                lease: Duration::from_millis(lease.lease_ms),
+                valid_until: Mutex::new(None),
+            })),
+            _ => None,
+        };
+
         let (result_tx, result_rx) = oneshot::channel();
         let worker_handle = WorkerHandle::generate();
         let this = self.clone();
@@ -272,9 +566,13 @@ impl Runtime {
                     WorkerState {
                         handle: Arc::new(handle),
                         memory_bytes: AtomicUsize::new(0),
+                        actor_lease: actor_lease.clone(),
                     },
                 );
                 tokio::spawn(self.clone().monitor_task(worker_handle.clone(), timectl));
+                if let Some(lease) = actor_lease {
+                    tokio::spawn(self.clone().actor_lease_task(worker_handle.clone(), lease));
+                }
                 Ok(worker_handle)
             }
             Ok(Err(e)) => Err(e),
@@ -332,11 +630,25 @@ impl Runtime {
         }
     }
 
-    pub fn write_log(&self, topic: impl Into<String>, text: impl Into<String>) {
+    /// Queues a record for the log topic. The timestamp of the record is set to the current time.
+    pub fn write_log(&self, topic: impl Into<String>, mut record: LogRecord) {
+        let time = SystemTime::now();
+        record.timestamp = time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|x| x.as_millis() as i64)
+            .unwrap_or(0);
+        let text = match serde_json::to_string(&record) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("write_log: cannot encode record: {:?}", e);
+                return;
+            }
+        };
         drop(self.log_tx.try_send(LogEntry {
             topic: topic.into(),
-            time: SystemTime::now(),
-            text: text.into(),
+            time,
+            level: record.level,
+            text,
         }));
     }
 }
@@ -361,6 +673,9 @@ async fn log_worker(rt: Weak<Runtime>, mut rx: tokio::sync::mpsc::Receiver<LogEn
         } else {
             break;
         };
+        if let Some(appid) = entry.topic.strip_prefix("app-") {
+            rt.log_hub.publish(appid, entry.level, &entry.text);
+        }
         if let Some(ref kv) = rt.kv {
             drop(kv.log_put(&entry.topic, entry.time, &entry.text).await);
         }
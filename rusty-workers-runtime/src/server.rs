@@ -41,9 +41,72 @@ impl rusty_workers::rpc::RuntimeService for RuntimeServer {
         self.runtime.fetch(&handle, req).await
     }
 
+    async fn read_body_chunk(
+        self,
+        _: tarpc::context::Context,
+        stream: String,
+    ) -> GenericResult<Option<Vec<u8>>> {
+        self.runtime.read_body_chunk(&stream).await
+    }
+
+    async fn create_body_stream(self, _: tarpc::context::Context) -> String {
+        self.runtime.create_body_stream()
+    }
+
+    async fn write_body_chunk(
+        self,
+        _: tarpc::context::Context,
+        stream: String,
+        chunk: Option<Vec<u8>>,
+    ) -> GenericResult<()> {
+        self.runtime.write_body_chunk(&stream, chunk).await
+    }
+
+    async fn close_body_stream(self, _: tarpc::context::Context, stream: String) -> bool {
+        self.runtime.close_body_stream(&stream)
+    }
+
+    async fn scheduled(
+        self,
+        _: tarpc::context::Context,
+        handle: WorkerHandle,
+        event: ScheduledEventObject,
+    ) -> ExecutionResult<()> {
+        self.runtime.scheduled(&handle, event).await
+    }
+
+    async fn queue(
+        self,
+        _: tarpc::context::Context,
+        handle: WorkerHandle,
+        batch: QueueBatchObject,
+    ) -> ExecutionResult<QueueBatchResult> {
+        self.runtime.queue(&handle, batch).await
+    }
+
     async fn load(self, _: tarpc::context::Context) -> GenericResult<u16> {
         self.runtime.load().await
     }
+
+    async fn code_cache_stats(self, _: tarpc::context::Context) -> CodeCacheStats {
+        self.runtime.code_cache().stats()
+    }
+
+    async fn response_cache_stats(self, _: tarpc::context::Context) -> ResponseCacheStats {
+        self.runtime.response_cache().stats()
+    }
+
+    async fn tail_logs(self, _: tarpc::context::Context, req: LogTailRequest) -> LogTailBatch {
+        self.runtime.log_hub().tail(&req).await
+    }
+
+    async fn inspect(
+        self,
+        _: tarpc::context::Context,
+        handle: WorkerHandle,
+    ) -> GenericResult<String> {
+        self.runtime.inspect(&handle).await
+    }
 }
 
 rusty_workers::impl_listen!(RuntimeServer, rusty_workers::rpc::RuntimeService);
@@ -0,0 +1,83 @@
+//! Startup snapshot of librt.
+
+use rusty_v8 as v8;
+use std::sync::Arc;
+
+/// JavaScript-side runtime.
+pub static LIBRT: &'static str = include_str!("../../librt/dist/main.js");
+
+/// Exercises state that librt sets up at load time, in a context created from the snapshot.
+/// Throws if anything is missing. Must not call into the host.
+static LIBRT_SNAPSHOT_CHECK: &'static str = r#"
+(() => {
+    const check = (name, ok) => {
+        if(!ok) throw new Error("librt snapshot check failed: " + name);
+    };
+    check("global", global === globalThis);
+    check("TextEncoder", new TextEncoder().encode("\u00e9").length == 2);
+    check("URL", new URL("https://example.com/?a=b").searchParams.get("a") == "b");
+    check("Buffer", Buffer.from("abc").toString("base64") == "YWJj");
+
+    let dispatched = false;
+    addEventListener("snapshotcheck", () => { dispatched = true; });
+    dispatchEvent({ type: "snapshotcheck" });
+    check("event listeners", dispatched);
+
+    const key = new crypto.subtle.CryptoKey(0, "secret", { name: "HMAC" }, false, []);
+    check("CryptoKey", key instanceof crypto.subtle.CryptoKey);
+})();
+"#;
+
+/// Creates a startup snapshot with librt evaluated in the default context.
+///
+/// Contexts created from the snapshot start with librt already initialized, so it doesn't need
+/// to be compiled and run for each job.
+pub fn make_librt_snapshot() -> Arc<[u8]> {
+    let mut creator = v8::SnapshotCreator::new(None);
+
+    // The isolate is owned by the snapshot creator and must not be dropped.
+    let mut isolate = unsafe { creator.get_owned_isolate() };
+
+    // Many unwraps here! but since we are initializing it should be fine.
+    {
+        let scope = &mut v8::HandleScope::new(&mut isolate);
+        let context = v8::Context::new(scope);
+        let scope = &mut v8::ContextScope::new(scope, context);
+
+        let global_key = v8::String::new(scope, "global").unwrap();
+        let global_obj = context.global(scope);
+        global_obj.set(scope, global_key.into(), global_obj.into());
+
+        let librt = v8::String::new(scope, LIBRT).unwrap();
+        let librt = v8::Script::compile(scope, librt, None).unwrap();
+        librt.run(scope).expect("librt initialization failed");
+
+        creator.set_default_context(context);
+    }
+    std::mem::forget(isolate);
+
+    let blob = creator
+        .create_blob(v8::FunctionCodeHandling::Keep)
+        .expect("cannot create snapshot blob");
+    Arc::from(&*blob)
+}
+
+/// Runs `LIBRT_SNAPSHOT_CHECK` in a context created from `snapshot`, the way jobs get theirs.
+pub fn check_librt_snapshot(snapshot: &Arc<[u8]>) -> Result<(), String> {
+    let params = v8::Isolate::create_params().snapshot_blob(snapshot.to_vec());
+    let isolate = &mut v8::Isolate::new(params);
+    let scope = &mut v8::HandleScope::new(isolate);
+    let context = v8::Context::new(scope);
+    let scope = &mut v8::ContextScope::new(scope, context);
+    let try_catch = &mut v8::TryCatch::new(scope);
+
+    let source = v8::String::new(try_catch, LIBRT_SNAPSHOT_CHECK).unwrap();
+    let result = v8::Script::compile(try_catch, source, None).and_then(|x| x.run(try_catch));
+    if result.is_some() {
+        return Ok(());
+    }
+    Err(try_catch
+        .exception()
+        .map(|x| x.to_rust_string_lossy(try_catch))
+        .unwrap_or_else(|| "librt snapshot check failed".into()))
+}
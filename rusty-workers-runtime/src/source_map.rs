@@ -0,0 +1,219 @@
+//! Source maps for stack traces of bundled scripts.
+//!
+//! The source map of a script in a bundle is, in order of preference:
+//!
+//! - The file named by a `//# sourceMappingURL=` comment at the end of the script, relative to
+//!   the script. Inline `data:application/json;base64,` URLs are supported too.
+//! - `<script>.map` next to the script.
+//!
+//! Positions in exception reports and stack frames in logs are rewritten to the original source,
+//! line and column. Parsed source maps are cached by `(bundle_id, path)`.
+
+use crate::module_loader::{resolve_path, BundleFiles};
+use lru_time_cache::LruCache;
+use rusty_workers::types::*;
+use sourcemap::{RawToken, SourceMap};
+use std::sync::{Arc, Mutex};
+
+const SOURCE_MAPPING_URL_PREFIX: &str = "//# sourceMappingURL=";
+const DATA_URL_PREFIX: &str = "data:application/json;base64,";
+
+pub struct SourceMapCache {
+    /// Parsed source maps by `(bundle_id, path)`. `None` if the script has no usable source map.
+    maps: Mutex<LruCache<(String, String), Option<Arc<Mappings>>>>,
+}
+
+/// The parts of a parsed source map needed for lookups.
+///
+/// `sourcemap::SourceMap` keeps lazily decoded source contents in `RefCell`s, so it can't be
+/// shared between isolate threads.
+struct Mappings {
+    sources: Vec<String>,
+
+    /// Sorted by destination position.
+    tokens: Vec<RawToken>,
+}
+
+impl Mappings {
+    fn new(map: &SourceMap) -> Self {
+        let sources = map.sources().map(|x| x.to_string()).collect();
+        let mut tokens: Vec<RawToken> = map.tokens().map(|x| x.get_raw_token()).collect();
+        tokens.sort_by_key(|x| (x.dst_line, x.dst_col));
+        Self { sources, tokens }
+    }
+
+    /// Returns the source, line and column of the closest token at or before a 0-based
+    /// position.
+    fn lookup(&self, line: u32, column: u32) -> Option<(&str, u32, u32)> {
+        let index = self
+            .tokens
+            .partition_point(|x| (x.dst_line, x.dst_col) <= (line, column));
+        let token = self.tokens.get(index.checked_sub(1)?)?;
+        let source = self.sources.get(token.src_id as usize)?;
+        Some((source, token.src_line, token.src_col))
+    }
+}
+
+/// Source maps of the scripts in one bundle.
+pub struct BundleSourceMaps<'a> {
+    cache: &'a SourceMapCache,
+    bundle_id: &'a str,
+    files: &'a BundleFiles,
+}
+
+impl SourceMapCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            maps: Mutex::new(LruCache::with_capacity(capacity)),
+        }
+    }
+
+    pub fn for_bundle<'a>(
+        &'a self,
+        bundle_id: &'a str,
+        files: &'a BundleFiles,
+    ) -> BundleSourceMaps<'a> {
+        BundleSourceMaps {
+            cache: self,
+            bundle_id,
+            files,
+        }
+    }
+}
+
+impl<'a> BundleSourceMaps<'a> {
+    /// Returns the source map of the script at `path`.
+    ///
+    /// An empty `bundle_id` disables caching.
+    fn get(&self, path: &str) -> Option<Arc<Mappings>> {
+        if self.bundle_id.len() == 0 {
+            return load_source_map(self.files, path).map(Arc::new);
+        }
+
+        let key = (self.bundle_id.to_string(), path.to_string());
+        if let Some(map) = self.cache.maps.lock().unwrap().get(&key).cloned() {
+            return map;
+        }
+        let map = load_source_map(self.files, path).map(Arc::new);
+        self.cache.maps.lock().unwrap().insert(key, map.clone());
+        map
+    }
+
+    /// Maps a 1-based position in a bundle script to the original source.
+    pub fn lookup(&self, path: &str, line: u32, column: u32) -> Option<(String, u32, u32)> {
+        if line == 0 || column == 0 {
+            return None;
+        }
+        let map = self.get(path)?;
+        let (source, line, column) = map.lookup(line - 1, column - 1)?;
+        Some((source.to_string(), line + 1, column + 1))
+    }
+
+    /// Rewrites the position and the stack trace of an exception.
+    pub fn map_exception(&self, exc: &mut ScriptException) {
+        if let (Some(path), Some(line), Some(column)) = (&exc.resource_name, exc.line, exc.column) {
+            if let Some((source, line, column)) = self.lookup(path, line, column) {
+                exc.resource_name = Some(source);
+                exc.line = Some(line);
+                exc.column = Some(column);
+            }
+        }
+        if let Some(ref stack) = exc.stack {
+            exc.stack = Some(self.map_text(stack));
+        }
+    }
+
+    /// Rewrites stack frames (`at f (./index.js:1:234)` or `at ./index.js:1:234`) in text.
+    ///
+    /// Lines that don't look like stack frames are left unchanged.
+    pub fn map_text(&self, text: &str) -> String {
+        let mut lines = text.split('\n').map(|line| self.map_frame(line));
+        let mut output = lines.next().unwrap_or_default();
+        for line in lines {
+            output.push('\n');
+            output.push_str(&line);
+        }
+        output
+    }
+
+    fn map_frame(&self, line: &str) -> String {
+        let trimmed = line.trim_start();
+        if !trimmed.starts_with("at ") {
+            return line.to_string();
+        }
+        let (location, suffix) = if trimmed.ends_with(')') {
+            match trimmed.rfind('(') {
+                Some(i) => (&trimmed[i + 1..trimmed.len() - 1], ")"),
+                None => return line.to_string(),
+            }
+        } else {
+            (&trimmed["at ".len()..], "")
+        };
+
+        let mut parts = location.rsplitn(3, ':');
+        let column = parts.next().and_then(|x| x.parse::<u32>().ok());
+        let line_number = parts.next().and_then(|x| x.parse::<u32>().ok());
+        let path = parts.next();
+        let (path, line_number, column) = match (path, line_number, column) {
+            (Some(p), Some(l), Some(c)) => (p, l, c),
+            _ => return line.to_string(),
+        };
+        match self.lookup(path, line_number, column) {
+            Some((source, line_number, column)) => {
+                // Everything up to the location stays the same.
+                let prefix_len = line.len() - suffix.len() - location.len();
+                format!(
+                    "{}{}:{}:{}{}",
+                    &line[..prefix_len],
+                    source,
+                    line_number,
+                    column,
+                    suffix
+                )
+            }
+            None => line.to_string(),
+        }
+    }
+}
+
+fn load_source_map(files: &BundleFiles, path: &str) -> Option<Mappings> {
+    let script = files.get(path)?;
+    let data = match find_source_mapping_url(script) {
+        Some(url) if url.starts_with(DATA_URL_PREFIX) => {
+            base64::decode(&url[DATA_URL_PREFIX.len()..]).ok()?
+        }
+        Some(url) => {
+            let specifier = if url.starts_with('.') || url.starts_with('/') {
+                url.to_string()
+            } else {
+                format!("./{}", url)
+            };
+            let map_path = resolve_path(path, &specifier).ok()?;
+            files.get(&map_path)?.to_vec()
+        }
+        None => files.get(&format!("{}.map", path))?.to_vec(),
+    };
+    match SourceMap::from_slice(&data) {
+        Ok(x) => Some(Mappings::new(&x)),
+        Err(e) => {
+            debug!("bad source map for {}: {:?}", path, e);
+            None
+        }
+    }
+}
+
+/// Returns the URL in a `sourceMappingURL` comment on the last non-empty line of a script.
+fn find_source_mapping_url(script: &[u8]) -> Option<&str> {
+    let end = script.iter().rposition(|x| !x.is_ascii_whitespace())? + 1;
+    let start = script[..end]
+        .iter()
+        .rposition(|x| *x == b'\n')
+        .map(|x| x + 1)
+        .unwrap_or(0);
+    let line = std::str::from_utf8(&script[start..end]).ok()?.trim();
+    if line.starts_with(SOURCE_MAPPING_URL_PREFIX) {
+        Some(line[SOURCE_MAPPING_URL_PREFIX.len()..].trim())
+    } else {
+        None
+    }
+}
@@ -0,0 +1,89 @@
+//! Compiled WebAssembly modules, shared across instances and isolates.
+//!
+//! Wasm files declared in a worker's configuration or bundle manifest are compiled once per
+//! bundle and exposed to every instance of the worker as `WebAssembly.Module` globals.
+
+use lru_time_cache::LruCache;
+use rusty_v8 as v8;
+use rusty_workers::types::*;
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex};
+
+pub struct WasmModuleCache {
+    /// Compiled modules by `(bundle_id, path)`.
+    modules: Mutex<LruCache<(String, String), Arc<v8::CompiledWasmModule>>>,
+}
+
+impl WasmModuleCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            modules: Mutex::new(LruCache::with_capacity(capacity)),
+        }
+    }
+
+    /// Returns the module at `path` in the bundle, compiling `wasm` if it isn't cached.
+    ///
+    /// An empty `bundle_id` disables caching.
+    pub fn get_or_compile<'s>(
+        &self,
+        scope: &mut v8::HandleScope<'s>,
+        bundle_id: &str,
+        path: &str,
+        wasm: &[u8],
+    ) -> GenericResult<v8::Local<'s, v8::WasmModuleObject>> {
+        let key = (bundle_id.to_string(), path.to_string());
+        if bundle_id.len() > 0 {
+            let compiled = self.modules.lock().unwrap().get(&key).cloned();
+            if let Some(compiled) = compiled {
+                if let Some(module) = v8::WasmModuleObject::from_compiled_module(scope, &compiled) {
+                    return Ok(module);
+                }
+            }
+        }
+
+        let try_catch = &mut v8::TryCatch::new(scope);
+        let module = match compile(try_catch, wasm) {
+            Some(x) => x,
+            None => {
+                let message = try_catch
+                    .exception()
+                    .map(|x| x.to_rust_string_lossy(try_catch))
+                    .unwrap_or_default();
+                return Err(GenericError::ScriptInitException(format!(
+                    "cannot compile wasm module {}: {}",
+                    path, message
+                )));
+            }
+        };
+        if bundle_id.len() > 0 {
+            self.modules
+                .lock()
+                .unwrap()
+                .insert(key, Arc::new(module.get_compiled_module()));
+        }
+        Ok(module)
+    }
+}
+
+/// Compiles a module with `new WebAssembly.Module(wasm)`.
+///
+/// Returns `None` on failure, with an exception thrown if the constructor threw.
+fn compile<'s>(
+    scope: &mut v8::HandleScope<'s>,
+    wasm: &[u8],
+) -> Option<v8::Local<'s, v8::WasmModuleObject>> {
+    let global = scope.get_current_context().global(scope);
+    let key = v8::String::new(scope, "WebAssembly")?;
+    let namespace = global.get(scope, key.into())?;
+    let namespace = v8::Local::<v8::Object>::try_from(namespace).ok()?;
+    let key = v8::String::new(scope, "Module")?;
+    let constructor = namespace.get(scope, key.into())?;
+    let constructor = v8::Local::<v8::Function>::try_from(constructor).ok()?;
+
+    // Not allocated through the instance's array buffer allocator, so that large modules don't
+    // count against its memory limit.
+    let backing = v8::ArrayBuffer::new_backing_store_from_boxed_slice(wasm.into());
+    let buf = v8::ArrayBuffer::with_backing_store(scope, &backing.make_shared());
+    let module = constructor.new_instance(scope, &[buf.into()])?;
+    v8::Local::<v8::WasmModuleObject>::try_from(module).ok()
+}
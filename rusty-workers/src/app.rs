@@ -1,3 +1,5 @@
+use crate::cron::CronSchedule;
+use crate::types::*;
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
 
@@ -13,6 +15,25 @@ pub struct AppConfig {
 
     #[serde(default)]
     pub kv_namespaces: Vec<KvNamespaceConfig>,
+
+    #[serde(default)]
+    pub triggers: TriggersConfig,
+
+    /// WebAssembly modules in the bundle to expose as `WebAssembly.Module` globals, as a map
+    /// from global names to bundle paths.
+    #[serde(default)]
+    pub wasm_modules: BTreeMap<String, String>,
+
+    /// Namespaces of single-instance actors, exposed as bindings with the same names.
+    #[serde(default)]
+    pub actors: Vec<ActorNamespaceConfig>,
+
+    /// Bindings to other apps, called directly without going through a public route.
+    #[serde(default)]
+    pub services: Vec<ServiceBindingConfig>,
+
+    #[serde(default)]
+    pub queues: QueuesConfig,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
@@ -21,6 +42,130 @@ pub struct KvNamespaceConfig {
     pub id: String,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct ActorNamespaceConfig {
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct ServiceBindingConfig {
+    pub name: String,
+    pub app: AppId,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Default)]
+pub struct QueuesConfig {
+    /// Queues the app sends messages to.
+    #[serde(default)]
+    pub producers: Vec<QueueProducerConfig>,
+
+    /// Queues the app receives messages from, as "queue" events.
+    #[serde(default)]
+    pub consumers: Vec<QueueConsumerConfig>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct QueueProducerConfig {
+    /// Name of the binding.
+    pub name: String,
+
+    pub queue: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct QueueConsumerConfig {
+    pub queue: String,
+
+    /// Max number of messages in one event.
+    #[serde(default = "default_queue_max_batch_size")]
+    pub max_batch_size: u32,
+
+    /// Number of retries after which a message is moved to the dead-letter queue.
+    #[serde(default = "default_queue_max_retries")]
+    pub max_retries: u32,
+}
+
+fn default_queue_max_batch_size() -> u32 {
+    10
+}
+
+fn default_queue_max_retries() -> u32 {
+    3
+}
+
+/// Max length of a queue name.
+pub const MAX_QUEUE_NAME_SIZE: usize = 128;
+
+/// Max number of messages in one "queue" event.
+pub const MAX_QUEUE_BATCH_SIZE: u32 = 100;
+
+impl AppConfig {
+    /// Checks fields that are not validated on deserialization.
+    pub fn validate(&self) -> GenericResult<()> {
+        for cron in &self.triggers.crons {
+            cron.parse::<CronSchedule>()?;
+        }
+        for (i, actor) in self.actors.iter().enumerate() {
+            if actor.name.is_empty() || self.actors[..i].iter().any(|x| x.name == actor.name) {
+                return Err(GenericError::Other(format!(
+                    "bad or duplicate actor namespace: '{}'",
+                    actor.name
+                )));
+            }
+        }
+        for (i, service) in self.services.iter().enumerate() {
+            if service.name.is_empty() || self.services[..i].iter().any(|x| x.name == service.name)
+            {
+                return Err(GenericError::Other(format!(
+                    "bad or duplicate service binding: '{}'",
+                    service.name
+                )));
+            }
+        }
+        let producers = &self.queues.producers;
+        for (i, producer) in producers.iter().enumerate() {
+            if producer.name.is_empty() || producers[..i].iter().any(|x| x.name == producer.name) {
+                return Err(GenericError::Other(format!(
+                    "bad or duplicate queue binding: '{}'",
+                    producer.name
+                )));
+            }
+            validate_queue_name(&producer.queue)?;
+        }
+        let consumers = &self.queues.consumers;
+        for (i, consumer) in consumers.iter().enumerate() {
+            validate_queue_name(&consumer.queue)?;
+            if consumers[..i].iter().any(|x| x.queue == consumer.queue) {
+                return Err(GenericError::Other(format!(
+                    "duplicate consumer of queue '{}'",
+                    consumer.queue
+                )));
+            }
+            if consumer.max_batch_size == 0 || consumer.max_batch_size > MAX_QUEUE_BATCH_SIZE {
+                return Err(GenericError::Other(format!(
+                    "max_batch_size of queue '{}' must be between 1 and {}",
+                    consumer.queue, MAX_QUEUE_BATCH_SIZE
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn validate_queue_name(name: &str) -> GenericResult<()> {
+    if name.is_empty() || name.len() > MAX_QUEUE_NAME_SIZE || name.contains('\0') {
+        return Err(GenericError::Other(format!("bad queue name: '{}'", name)));
+    }
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Default)]
+pub struct TriggersConfig {
+    /// Cron expressions that fire a "scheduled" event. See `crate::cron` for the format.
+    #[serde(default)]
+    pub crons: Vec<String>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
 pub struct AppRoute {
     pub domain: String,
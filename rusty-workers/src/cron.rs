@@ -0,0 +1,215 @@
+//! Cron expressions for scheduled triggers.
+//!
+//! The standard five-field format (`minute hour day-of-month month day-of-week`) is supported,
+//! with `*`, ranges (`a-b`), steps (`*/n`, `a-b/n`) and lists (`a,b,c`). Month and day-of-week
+//! names (`jan`, `mon`, ...) are accepted. Schedules are evaluated in UTC.
+
+use crate::types::*;
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use std::str::FromStr;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CronSchedule {
+    minutes: u64,
+    hours: u64,
+    days_of_month: u64,
+    months: u64,
+    days_of_week: u64,
+
+    /// Whether day-of-month and day-of-week start with `*`. When both are restricted, a day
+    /// matches if either field matches.
+    any_day_of_month: bool,
+    any_day_of_week: bool,
+}
+
+const MONTH_NAMES: &[&str] = &[
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+
+const DAY_OF_WEEK_NAMES: &[&str] = &["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+impl CronSchedule {
+    /// Returns whether the minute containing `time` is a tick of this schedule.
+    pub fn matches(&self, time: &DateTime<Utc>) -> bool {
+        let day_of_month = bit_set(self.days_of_month, time.day());
+        let day_of_week = bit_set(self.days_of_week, time.weekday().num_days_from_sunday());
+        let day = match (self.any_day_of_month, self.any_day_of_week) {
+            (true, true) => true,
+            (true, false) => day_of_week,
+            (false, true) => day_of_month,
+            (false, false) => day_of_month || day_of_week,
+        };
+        day && bit_set(self.minutes, time.minute())
+            && bit_set(self.hours, time.hour())
+            && bit_set(self.months, time.month())
+    }
+}
+
+impl FromStr for CronSchedule {
+    type Err = GenericError;
+
+    fn from_str(s: &str) -> GenericResult<Self> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(GenericError::Other(format!(
+                "cron: expecting 5 fields, got {}: {}",
+                fields.len(),
+                s
+            )));
+        }
+
+        let mut days_of_week = parse_field(fields[4], 0, 7, DAY_OF_WEEK_NAMES, 0)?;
+
+        // Both 0 and 7 are Sunday.
+        if bit_set(days_of_week, 7) {
+            days_of_week = (days_of_week | 1) & !(1 << 7);
+        }
+
+        Ok(Self {
+            minutes: parse_field(fields[0], 0, 59, &[], 0)?,
+            hours: parse_field(fields[1], 0, 23, &[], 0)?,
+            days_of_month: parse_field(fields[2], 1, 31, &[], 0)?,
+            months: parse_field(fields[3], 1, 12, MONTH_NAMES, 1)?,
+            days_of_week,
+            any_day_of_month: fields[2].starts_with('*'),
+            any_day_of_week: fields[4].starts_with('*'),
+        })
+    }
+}
+
+fn bit_set(bits: u64, i: u32) -> bool {
+    bits & (1 << i) != 0
+}
+
+/// Parses one field into a bitset. `names[i]` is an alias for `i + names_base`.
+fn parse_field(
+    field: &str,
+    min: u32,
+    max: u32,
+    names: &[&str],
+    names_base: u32,
+) -> GenericResult<u64> {
+    let bad_field = || GenericError::Other(format!("cron: bad field: {}", field));
+    let parse_value = |x: &str| -> GenericResult<u32> {
+        let lower = x.to_ascii_lowercase();
+        let value = match names.iter().position(|n| *n == lower) {
+            Some(i) => i as u32 + names_base,
+            None => x.parse().map_err(|_| bad_field())?,
+        };
+        if value < min || value > max {
+            return Err(bad_field());
+        }
+        Ok(value)
+    };
+
+    let mut bits = 0u64;
+    for part in field.split(",") {
+        let (range, step) = match part.find("/") {
+            Some(i) => {
+                let step: u32 = part[i + 1..].parse().map_err(|_| bad_field())?;
+                if step == 0 {
+                    return Err(bad_field());
+                }
+                (&part[..i], step)
+            }
+            None => (part, 1),
+        };
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some(i) = range.find("-") {
+            (parse_value(&range[..i])?, parse_value(&range[i + 1..])?)
+        } else {
+            let value = parse_value(range)?;
+
+            // `a/n` means `a-max/n`.
+            (value, if step == 1 { value } else { max })
+        };
+        if start > end {
+            return Err(bad_field());
+        }
+        for i in (start..=end).step_by(step as usize) {
+            bits |= 1 << i;
+        }
+    }
+    Ok(bits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(y: i32, m: u32, d: u32, h: u32, min: u32) -> DateTime<Utc> {
+        Utc.ymd(y, m, d).and_hms(h, min, 0)
+    }
+
+    fn parse(s: &str) -> CronSchedule {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn fields() {
+        let every_minute = parse("* * * * *");
+        assert!(every_minute.matches(&at(2021, 1, 1, 0, 0)));
+        assert!(every_minute.matches(&at(2021, 6, 15, 13, 37)));
+
+        let s = parse("*/15 9-17 * * *");
+        assert!(s.matches(&at(2021, 1, 1, 9, 0)));
+        assert!(s.matches(&at(2021, 1, 1, 17, 45)));
+        assert!(!s.matches(&at(2021, 1, 1, 9, 10)));
+        assert!(!s.matches(&at(2021, 1, 1, 18, 0)));
+
+        let s = parse("0,30 0 1 jan,Jul *");
+        assert!(s.matches(&at(2021, 7, 1, 0, 30)));
+        assert!(!s.matches(&at(2021, 2, 1, 0, 30)));
+
+        // `a/n` means `a-max/n`.
+        let s = parse("50/5 * * * *");
+        assert!(s.matches(&at(2021, 1, 1, 0, 55)));
+        assert!(!s.matches(&at(2021, 1, 1, 0, 5)));
+    }
+
+    #[test]
+    fn days() {
+        // 2021-01-03 is a Sunday.
+        let sunday = at(2021, 1, 3, 0, 0);
+        let monday = at(2021, 1, 4, 0, 0);
+        assert!(parse("0 0 * * 0").matches(&sunday));
+        assert!(parse("0 0 * * 7").matches(&sunday));
+        assert!(parse("0 0 * * sun").matches(&sunday));
+        assert!(!parse("0 0 * * 7").matches(&monday));
+
+        // Either restricted field matches.
+        let s = parse("0 0 4 * sun");
+        assert!(s.matches(&sunday));
+        assert!(s.matches(&monday));
+        assert!(!s.matches(&at(2021, 1, 5, 0, 0)));
+
+        // A field starting with `*` is unrestricted, so only the other one applies.
+        let s = parse("0 0 */1 * sun");
+        assert!(s.matches(&sunday));
+        assert!(!s.matches(&monday));
+        let s = parse("0 0 4 * */1");
+        assert!(!s.matches(&sunday));
+        assert!(s.matches(&monday));
+    }
+
+    #[test]
+    fn bad_expressions() {
+        for s in &[
+            "* * * *",
+            "* * * * * *",
+            "60 * * * *",
+            "* 24 * * *",
+            "* * 0 * *",
+            "* * * 13 *",
+            "* * * * 8",
+            "*/0 * * * *",
+            "5-1 * * * *",
+            "x * * * *",
+            "* * * foo *",
+        ] {
+            assert!(s.parse::<CronSchedule>().is_err(), "{}", s);
+        }
+    }
+}
@@ -1,11 +1,29 @@
+//! Key-value storage.
+//!
+//! `KvClient` implements the data model (worker data, routes, apps, bundles and logs) on top of
+//! a pluggable `KvBackend`. Backends are selected with a storage URL:
+//!
+//! - `tikv://pd1:2379,pd2:2379`: A TiKV cluster.
+//! - `memory://`: A process-local in-memory store.
+//! - `file:///path/to/data.log`: An embedded on-disk store.
+
+mod file;
+mod local;
+mod memory;
+mod tikv;
+
+#[cfg(test)]
+mod tests;
+
 use crate::types::*;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
-use std::ops::{Deref, DerefMut};
-use std::sync::Arc;
-use std::time::{Instant, SystemTime};
-use tikv_client::{BoundRange, CheckLevel, Key, KvPair, Transaction, TransactionOptions};
-use tokio::sync::mpsc::{channel, Receiver, Sender};
-use tokio::sync::Semaphore;
+use std::time::SystemTime;
+
+pub use file::FileBackend;
+pub use memory::MemoryBackend;
+pub use tikv::TikvBackend;
 
 macro_rules! impl_scan_prefix {
     ($name:ident, $cb_value_ty:ty, $scan_func:ident, $deref_key:ident, $deref_value:ident) => {
@@ -28,8 +46,8 @@ macro_rules! impl_scan_prefix {
 
             loop {
                 let batch = self
-                    .raw
-                    .$scan_func(start_prefix..end_prefix.clone(), batch_size)
+                    .backend
+                    .$scan_func(start_prefix, end_prefix.clone(), batch_size)
                     .await
                     .map_err(|e| GenericError::Other(format!("scan_prefix: {:?}", e)))?;
                 for item in batch.iter() {
@@ -65,25 +83,126 @@ pub static PREFIX_ROUTE_MAPPING_V1: &'static [u8] = b"V1\x00ROUTEMAP\x00";
 
 pub static PREFIX_LOG_V1: &'static [u8] = b"V1\x00LOG\x00";
 
+pub static PREFIX_CRON_LEASE_V1: &'static [u8] = b"V1\x00CRONLEASE\x00";
+
+pub static PREFIX_ACTOR_LEASE_V1: &'static [u8] = b"V1\x00ACTORLEASE\x00";
+
+/// Queue messages, next to worker data.
+pub static PREFIX_QUEUE_V2: &'static [u8] = b"V2\x00Q\x00";
+
+pub static PREFIX_QUEUE_LEASE_V1: &'static [u8] = b"V1\x00QUEUELEASE\x00";
+
 const MAX_LOCKS_PER_WORKER_DATA_TRANSACTION: usize = 256;
 
+/// A storage backend.
+///
+/// "Raw" operations are used for cluster metadata (routes, apps, bundles and logs) and
+/// transactions are used for worker data, leases and queue messages. Callers never mix the two
+/// on the same key.
+#[async_trait]
+pub trait KvBackend: Send + Sync {
+    async fn raw_get(&self, key: Vec<u8>) -> GenericResult<Option<Vec<u8>>>;
+
+    async fn raw_put(&self, key: Vec<u8>, value: Vec<u8>) -> GenericResult<()>;
+
+    async fn raw_delete(&self, key: Vec<u8>) -> GenericResult<()>;
+
+    /// Deletes all keys in `start..end`.
+    async fn raw_delete_range(&self, start: Vec<u8>, end: Vec<u8>) -> GenericResult<()>;
+
+    /// Returns at most `limit` key-value pairs in `start..end`, ordered by key.
+    async fn raw_scan(
+        &self,
+        start: Vec<u8>,
+        end: Vec<u8>,
+        limit: u32,
+    ) -> GenericResult<Vec<(Vec<u8>, Vec<u8>)>>;
+
+    /// Returns at most `limit` keys in `start..end`, ordered by key.
+    async fn raw_scan_keys(
+        &self,
+        start: Vec<u8>,
+        end: Vec<u8>,
+        limit: u32,
+    ) -> GenericResult<Vec<Vec<u8>>>;
+
+    /// Begins an optimistic transaction.
+    async fn begin_transaction(&self, read_only: bool) -> GenericResult<Box<dyn KvTransaction>>;
+}
+
+/// An optimistic transaction returned by `KvBackend::begin_transaction`.
+///
+/// A transaction that is dropped without being committed is rolled back.
+#[async_trait]
+pub trait KvTransaction: Send {
+    async fn get(&mut self, key: Vec<u8>) -> GenericResult<Option<Vec<u8>>>;
+
+    async fn put(&mut self, key: Vec<u8>, value: Vec<u8>) -> GenericResult<()>;
+
+    async fn delete(&mut self, key: Vec<u8>) -> GenericResult<()>;
+
+    /// Adds `keys` to the conflict set of this transaction without writing them.
+    async fn lock_keys(&mut self, keys: Vec<Vec<u8>>) -> GenericResult<()>;
+
+    /// Returns at most `limit` keys from `start` to `end` (exclusive), or to the end of the
+    /// keyspace if `end` is `None`.
+    async fn scan_keys(
+        &mut self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: u32,
+    ) -> GenericResult<Vec<Vec<u8>>>;
+
+    /// Commits the transaction. Returns `Ok(false)` on conflict.
+    async fn commit(self: Box<Self>) -> GenericResult<bool>;
+
+    async fn rollback(self: Box<Self>) -> GenericResult<()>;
+}
+
+/// A message received from a queue, to be passed back to `queue_ack`, `queue_retry` or
+/// `queue_dead_letter`.
+#[derive(Clone, Debug)]
+pub struct QueuedMessage {
+    key: Vec<u8>,
+    pub message: QueueMessageObject,
+}
+
+/// Result of `KvClient::actor_lease_renew`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ActorLeaseRenewal {
+    /// The worker holds the lease, and its expiration time was set.
+    Renewed,
+
+    /// The lease is missing or older than the worker's. The proxy that created the worker may
+    /// not have recorded it yet.
+    Pending,
+
+    /// The actor has moved to another worker.
+    Superseded,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct QueueLease {
+    owner: String,
+    expires: i64,
+}
+
 pub struct KvClient {
-    raw: tikv_client::RawClient,
-    transactional: tikv_client::TransactionClient,
-    txn_collector_tx: Sender<Transaction>,
+    backend: Box<dyn KvBackend>,
 }
 
 pub struct WorkerDataTransaction {
-    protected: ProtectedTransaction,
+    txn: Box<dyn KvTransaction>,
     num_locks: usize,
 }
 
 impl WorkerDataTransaction {
-    pub async fn get(&self, namespace_id: &[u8; 16], key: &[u8]) -> GenericResult<Option<Vec<u8>>> {
-        self.protected
-            .get(make_worker_data_key(namespace_id, key))
-            .await
-            .map_err(tikv_error_to_generic)
+    pub async fn get(
+        &mut self,
+        namespace_id: &[u8; 16],
+        key: &[u8],
+    ) -> GenericResult<Option<Vec<u8>>> {
+        self.txn.get(make_worker_data_key(namespace_id, key)).await
     }
 
     pub async fn lock_keys(
@@ -100,18 +219,13 @@ impl WorkerDataTransaction {
         }
         self.num_locks = new_num_locks;
 
-        self.protected
-            .lock_keys(keys)
-            .await
-            .map_err(tikv_error_to_generic)
-            .map(|_| true)
+        self.txn.lock_keys(keys).await.map(|_| true)
     }
 
     pub async fn delete(&mut self, namespace_id: &[u8; 16], key: &[u8]) -> GenericResult<()> {
-        self.protected
+        self.txn
             .delete(make_worker_data_key(namespace_id, key))
             .await
-            .map_err(tikv_error_to_generic)
     }
 
     pub async fn put(
@@ -120,10 +234,9 @@ impl WorkerDataTransaction {
         key: &[u8],
         value: Vec<u8>,
     ) -> GenericResult<()> {
-        self.protected
+        self.txn
             .put(make_worker_data_key(namespace_id, key), value)
             .await
-            .map_err(tikv_error_to_generic)
     }
 
     /// Scans keys from `start` to `end`.
@@ -139,121 +252,62 @@ impl WorkerDataTransaction {
     ) -> GenericResult<Vec<Vec<u8>>> {
         let prefix = worker_data_key_prefix(namespace_id);
         let start = make_worker_data_key(namespace_id, start);
-        let end = end.map(|x| make_worker_data_key(namespace_id, x));
-        let range: BoundRange = if let Some(end) = end {
-            (start..end).into()
-        } else {
-            (start..).into()
-        };
-        self.protected
-            .scan_keys(range, limit)
+        let end = end
+            .map(|x| make_worker_data_key(namespace_id, x))
+            .unwrap_or_else(|| worker_data_key_end(namespace_id));
+        self.txn
+            .scan_keys(start, Some(end), limit)
             .await
-            .map_err(tikv_error_to_generic)
-            .map(|x| x.map(|x| Vec::from(x)[prefix.len()..].to_vec()).collect())
+            .map(|x| x.into_iter().map(|x| x[prefix.len()..].to_vec()).collect())
     }
 
     pub async fn commit(self) -> GenericResult<bool> {
-        self.protected.commit().await
+        self.txn.commit().await
     }
 
     pub async fn rollback(self) -> GenericResult<()> {
-        self.protected.rollback().await
-    }
-}
-
-/// A protected transaction is automatically rolled back when dropped.
-///
-/// This is important as asynchronous tasks can be cancelled.
-struct ProtectedTransaction {
-    txn: Option<Transaction>,
-    txn_collector_tx: Sender<Transaction>,
-}
-
-impl Drop for ProtectedTransaction {
-    fn drop(&mut self) {
-        if let Some(txn) = self.txn.take() {
-            drop(self.txn_collector_tx.try_send(txn));
-        }
-    }
-}
-
-impl Deref for ProtectedTransaction {
-    type Target = Transaction;
-    fn deref(&self) -> &Self::Target {
-        self.txn.as_ref().unwrap()
-    }
-}
-
-impl DerefMut for ProtectedTransaction {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        self.txn.as_mut().unwrap()
-    }
-}
-
-impl ProtectedTransaction {
-    async fn commit(mut self) -> GenericResult<bool> {
-        let mut txn = self.txn.take().unwrap();
-        txn.commit().await.map(|_| true).or_else(|e| match e {
-            tikv_client::Error::KeyError(e) if e.conflict.is_some() => Ok(false),
-            e => Err(tikv_error_to_generic(e)),
-        })
-    }
-
-    async fn rollback(mut self) -> GenericResult<()> {
-        let mut txn = self.txn.take().unwrap();
-        txn.rollback()
-            .await
-            .map(|_| ())
-            .map_err(tikv_error_to_generic)
+        self.txn.rollback().await
     }
 }
 
 impl KvClient {
-    pub async fn new<S: Into<String> + Clone>(pd_endpoints: Vec<S>) -> GenericResult<Self> {
-        let raw = tikv_client::RawClient::new(pd_endpoints.clone())
-            .await
-            .map_err(|e| {
-                GenericError::Other(format!("tikv (raw) initialization failed: {:?}", e))
-            })?;
-        let transactional = tikv_client::TransactionClient::new(pd_endpoints)
-            .await
-            .map_err(|e| {
-                GenericError::Other(format!(
-                    "tikv (transactional) initialization failed: {:?}",
-                    e
-                ))
-            })?;
-        let (txn_collector_tx, txn_collector_rx) = channel(1000);
-        tokio::spawn(async move {
-            txn_collector_worker(txn_collector_rx).await;
-        });
-        Ok(Self {
-            raw,
-            transactional,
-            txn_collector_tx,
-        })
+    /// Opens a storage backend by URL. See the module documentation for supported schemes.
+    pub async fn open(url: &str) -> GenericResult<Self> {
+        let (scheme, rest) = match url.find("://") {
+            Some(i) => (&url[..i], &url[i + 3..]),
+            None => {
+                return Err(GenericError::Other(format!(
+                    "bad storage url (missing scheme): {}",
+                    url
+                )))
+            }
+        };
+        let backend: Box<dyn KvBackend> = match scheme {
+            "tikv" => Box::new(TikvBackend::new(rest.split(",").collect()).await?),
+            "memory" => Box::new(MemoryBackend::new()),
+            "file" => {
+                let path = rest.to_string();
+                Box::new(
+                    tokio::task::spawn_blocking(move || FileBackend::open(&path))
+                        .await
+                        .map_err(|e| {
+                            GenericError::Other(format!("file backend open failed: {:?}", e))
+                        })??,
+                )
+            }
+            _ => {
+                return Err(GenericError::Other(format!(
+                    "unsupported storage scheme: {}",
+                    scheme
+                )))
+            }
+        };
+        info!("opened {} storage backend", scheme);
+        Ok(Self::with_backend(backend))
     }
 
-    /// Creates a "protected" transaction that rolls back automatically if neither committed nor rolled back.
-    ///
-    /// Asynchronous tasks can be cancelled. So this is important.
-    async fn new_protected_transaction(
-        &self,
-        opts: TransactionOptions,
-    ) -> GenericResult<ProtectedTransaction> {
-        // If we run out of space in `txn_collector_tx` the transaction may be dropped without being committed or
-        // rolled back. Let's print a warning in this case.
-        let opts = opts.drop_check(CheckLevel::Warn);
-
-        let txn = self
-            .transactional
-            .begin_with_options(opts)
-            .await
-            .map_err(tikv_error_to_generic)?;
-        Ok(ProtectedTransaction {
-            txn: Some(txn),
-            txn_collector_tx: self.txn_collector_tx.clone(),
-        })
+    pub fn with_backend(backend: Box<dyn KvBackend>) -> Self {
+        Self { backend }
     }
 
     async fn delete_prefix(&self, prefix: &[u8]) -> GenericResult<()> {
@@ -266,14 +320,20 @@ impl KvClient {
         let mut end_prefix = prefix.to_vec();
         *end_prefix.last_mut().unwrap() = 1;
 
-        self.raw
-            .delete_range(start_prefix..end_prefix)
+        self.backend
+            .raw_delete_range(start_prefix, end_prefix)
             .await
             .map_err(|e| GenericError::Other(format!("delete_prefix: {:?}", e)))
     }
 
-    impl_scan_prefix!(scan_prefix, &[u8], scan, kvp_deref_key, kvp_deref_value);
-    impl_scan_prefix!(scan_prefix_keys, (), scan_keys, key_deref, mk_unit);
+    impl_scan_prefix!(
+        scan_prefix,
+        &[u8],
+        raw_scan,
+        pair_deref_key,
+        pair_deref_value
+    );
+    impl_scan_prefix!(scan_prefix_keys, (), raw_scan_keys, key_deref, mk_unit);
 
     pub async fn worker_data_get(
         &self,
@@ -284,11 +344,7 @@ impl KvClient {
         // doing so is unsafe and unsupported."
         //
         // So here we use transactional API for all worker data operations.
-        let txn = self
-            .transactional
-            .begin_with_options(TransactionOptions::new_optimistic().read_only())
-            .await
-            .map_err(tikv_error_to_generic)?;
+        let mut txn = self.backend.begin_transaction(true).await?;
         txn.get(make_worker_data_key(namespace_id, key))
             .await
             .map_err(|e| GenericError::Other(format!("worker_data_get: {:?}", e)))
@@ -300,13 +356,10 @@ impl KvClient {
         key: &[u8],
         value: Vec<u8>,
     ) -> GenericResult<()> {
-        let mut txn = self
-            .new_protected_transaction(TransactionOptions::new_optimistic())
-            .await?;
+        let mut txn = self.backend.begin_transaction(false).await?;
         let result = txn
             .put(make_worker_data_key(namespace_id, key), value)
-            .await
-            .map_err(tikv_error_to_generic);
+            .await;
         if let Err(e) = result {
             drop(txn.rollback().await);
             Err(e)
@@ -322,20 +375,15 @@ impl KvClient {
         end: Option<&[u8]>,
         limit: u32,
     ) -> GenericResult<Vec<Vec<u8>>> {
-        let txn = self
-            .transactional
-            .begin_with_options(TransactionOptions::new_optimistic().read_only())
-            .await
-            .map_err(tikv_error_to_generic)?;
+        let mut txn = self.backend.begin_transaction(true).await?;
         let prefix = worker_data_key_prefix(namespace_id);
         let start = make_worker_data_key(namespace_id, start);
         let end = end
             .map(|x| make_worker_data_key(namespace_id, x))
-            .unwrap_or_else(|| join_slices(&[PREFIX_WORKER_DATA_V2, namespace_id, b"\x01"]));
-        txn.scan_keys(start..end, limit)
+            .unwrap_or_else(|| worker_data_key_end(namespace_id));
+        txn.scan_keys(start, Some(end), limit)
             .await
-            .map_err(tikv_error_to_generic)
-            .map(|x| x.map(|x| Vec::from(x)[prefix.len()..].to_vec()).collect())
+            .map(|x| x.into_iter().map(|x| x[prefix.len()..].to_vec()).collect())
     }
 
     pub async fn worker_data_delete(
@@ -343,13 +391,8 @@ impl KvClient {
         namespace_id: &[u8; 16],
         key: &[u8],
     ) -> GenericResult<()> {
-        let mut txn = self
-            .new_protected_transaction(TransactionOptions::new_optimistic())
-            .await?;
-        let result = txn
-            .delete(make_worker_data_key(namespace_id, key))
-            .await
-            .map_err(tikv_error_to_generic);
+        let mut txn = self.backend.begin_transaction(false).await?;
+        let result = txn.delete(make_worker_data_key(namespace_id, key)).await;
         if let Err(e) = result {
             drop(txn.rollback().await);
             Err(e)
@@ -360,14 +403,9 @@ impl KvClient {
 
     pub async fn worker_data_begin_transaction(&self) -> GenericResult<WorkerDataTransaction> {
         // Only support optimistic mode for now.
-        let txn = self
-            .new_protected_transaction(TransactionOptions::new_optimistic())
-            .await?;
+        let txn = self.backend.begin_transaction(false).await?;
 
-        Ok(WorkerDataTransaction {
-            protected: txn,
-            num_locks: 0,
-        })
+        Ok(WorkerDataTransaction { txn, num_locks: 0 })
     }
 
     pub async fn route_mapping_delete_domain(&self, domain: &str) -> GenericResult<()> {
@@ -439,8 +477,8 @@ impl KvClient {
             b"\x00",
             path.as_bytes(),
         ]);
-        self.raw
-            .put(key, Vec::from(appid))
+        self.backend
+            .raw_put(key, Vec::from(appid))
             .await
             .map_err(|e| GenericError::Other(format!("route_mapping_insert: {:?}", e)))
     }
@@ -452,8 +490,8 @@ impl KvClient {
             b"\x00",
             path.as_bytes(),
         ]);
-        self.raw
-            .delete(key)
+        self.backend
+            .raw_delete(key)
             .await
             .map_err(|e| GenericError::Other(format!("route_mapping_delete: {:?}", e)))
     }
@@ -472,24 +510,24 @@ impl KvClient {
 
     pub async fn app_metadata_get(&self, appid: &str) -> GenericResult<Option<Vec<u8>>> {
         let key = join_slices(&[PREFIX_APP_METADATA_V1, appid.as_bytes()]);
-        self.raw
-            .get(key)
+        self.backend
+            .raw_get(key)
             .await
             .map_err(|e| GenericError::Other(format!("app_metadata_get: {:?}", e)))
     }
 
     pub async fn app_metadata_put(&self, appid: &str, value: Vec<u8>) -> GenericResult<()> {
         let key = join_slices(&[PREFIX_APP_METADATA_V1, appid.as_bytes()]);
-        self.raw
-            .put(key, value)
+        self.backend
+            .raw_put(key, value)
             .await
             .map_err(|e| GenericError::Other(format!("app_metadata_put: {:?}", e)))
     }
 
     pub async fn app_metadata_delete(&self, appid: &str) -> GenericResult<()> {
         let key = join_slices(&[PREFIX_APP_METADATA_V1, appid.as_bytes()]);
-        self.raw
-            .delete(key)
+        self.backend
+            .raw_delete(key)
             .await
             .map_err(|e| GenericError::Other(format!("app_metadata_delete: {:?}", e)))
     }
@@ -505,16 +543,16 @@ impl KvClient {
 
     pub async fn app_bundle_get(&self, id: &[u8; 16]) -> GenericResult<Option<Vec<u8>>> {
         let key = join_slices(&[PREFIX_APP_BUNDLE_V1, id]);
-        self.raw
-            .get(key)
+        self.backend
+            .raw_get(key)
             .await
             .map_err(|e| GenericError::Other(format!("app_bundle_get: {:?}", e)))
     }
 
     pub async fn app_bundle_put(&self, id: &[u8; 16], value: Vec<u8>) -> GenericResult<()> {
         let key = join_slices(&[PREFIX_APP_BUNDLE_V1, id]);
-        self.raw
-            .put(key, value)
+        self.backend
+            .raw_put(key, value)
             .await
             .map_err(|e| GenericError::Other(format!("app_bundle_put: {:?}", e)))
     }
@@ -529,12 +567,447 @@ impl KvClient {
     /// Argument is not restricted to [u8; 16] because we want to allow deleting "dirty" data.
     pub async fn app_bundle_delete_dirty(&self, id: &[u8]) -> GenericResult<()> {
         let key = join_slices(&[PREFIX_APP_BUNDLE_V1, id]);
-        self.raw
-            .delete(key)
+        self.backend
+            .raw_delete(key)
             .await
             .map_err(|e| GenericError::Other(format!("app_bundle_delete: {:?}", e)))
     }
 
+    pub async fn cron_lease_get(
+        &self,
+        appid: &str,
+        cron: &str,
+    ) -> GenericResult<Option<CronLease>> {
+        // Leases are written in transactions, so they must be read in one too.
+        let key = make_cron_lease_key(appid, cron);
+        let mut txn = self.backend.begin_transaction(true).await?;
+        Ok(txn
+            .get(key)
+            .await?
+            .and_then(|x| serde_json::from_slice(&x).ok()))
+    }
+
+    /// Claims the tick at `time` of a cron trigger from `now` until `expires`. Times are in
+    /// milliseconds since the Unix epoch.
+    ///
+    /// Returns `true` if the caller should fire the trigger: either this tick has not been
+    /// claimed, or it was claimed fewer than `max_attempts` times, never completed and the last
+    /// claim has expired. A tick can't be claimed once a later one has been. Concurrent claims of
+    /// the same tick conflict and only one of them succeeds.
+    pub async fn cron_lease_claim(
+        &self,
+        appid: &str,
+        cron: &str,
+        time: i64,
+        now: i64,
+        expires: i64,
+        max_attempts: u32,
+    ) -> GenericResult<bool> {
+        self.cron_lease_update(appid, cron, |prev| {
+            let attempts = match prev {
+                Some(prev) if prev.scheduled_time > time => return None,
+                Some(prev) if prev.scheduled_time == time => {
+                    if prev.completed || prev.expires > now || prev.attempts >= max_attempts {
+                        return None;
+                    }
+                    prev.attempts + 1
+                }
+                _ => 1,
+            };
+            Some(CronLease {
+                scheduled_time: time,
+                expires,
+                attempts,
+                completed: false,
+            })
+        })
+        .await
+    }
+
+    /// Marks the tick at `time` of a cron trigger as delivered, so that it isn't fired again.
+    pub async fn cron_lease_complete(
+        &self,
+        appid: &str,
+        cron: &str,
+        time: i64,
+    ) -> GenericResult<bool> {
+        self.cron_lease_update(appid, cron, |prev| match prev {
+            Some(prev) if prev.scheduled_time == time && !prev.completed => Some(CronLease {
+                completed: true,
+                ..prev.clone()
+            }),
+            _ => None,
+        })
+        .await
+    }
+
+    /// Replaces the lease on a cron trigger with the result of `f`, or leaves it untouched if
+    /// `f` returns `None`.
+    ///
+    /// Returns whether the lease was replaced.
+    async fn cron_lease_update(
+        &self,
+        appid: &str,
+        cron: &str,
+        f: impl FnOnce(Option<&CronLease>) -> Option<CronLease>,
+    ) -> GenericResult<bool> {
+        let key = make_cron_lease_key(appid, cron);
+        let mut txn = self.backend.begin_transaction(false).await?;
+        let prev = match txn.get(key.clone()).await {
+            Ok(x) => x,
+            Err(e) => {
+                drop(txn.rollback().await);
+                return Err(e);
+            }
+        };
+        let prev: Option<CronLease> = prev.and_then(|x| serde_json::from_slice(&x).ok());
+        let next = match f(prev.as_ref()) {
+            Some(x) => x,
+            None => {
+                txn.rollback().await?;
+                return Ok(false);
+            }
+        };
+        if let Err(e) = txn.put(key, serde_json::to_vec(&next).unwrap()).await {
+            drop(txn.rollback().await);
+            return Err(e);
+        }
+        txn.commit().await
+    }
+
+    pub async fn actor_lease_get(
+        &self,
+        appid: &str,
+        actor: &ActorAddress,
+    ) -> GenericResult<Option<ActorLease>> {
+        // Leases are written in transactions, so they must be read in one too.
+        let key = make_actor_lease_key(appid, actor);
+        let mut txn = self.backend.begin_transaction(true).await?;
+        Ok(txn
+            .get(key)
+            .await?
+            .and_then(|x| serde_json::from_slice(&x).ok()))
+    }
+
+    /// Replaces the lease on an actor with `next` if the current lease is `prev`.
+    ///
+    /// Returns `false` if the lease has been changed by someone else. Concurrent swaps from the
+    /// same lease conflict and only one of them succeeds.
+    pub async fn actor_lease_swap(
+        &self,
+        appid: &str,
+        actor: &ActorAddress,
+        prev: Option<&ActorLease>,
+        next: Option<&ActorLease>,
+    ) -> GenericResult<bool> {
+        let key = make_actor_lease_key(appid, actor);
+        let mut txn = self.backend.begin_transaction(false).await?;
+        let current = match txn.get(key.clone()).await {
+            Ok(x) => x,
+            Err(e) => {
+                drop(txn.rollback().await);
+                return Err(e);
+            }
+        };
+        let current: Option<ActorLease> = current.and_then(|x| serde_json::from_slice(&x).ok());
+        if current.as_ref() != prev {
+            txn.rollback().await?;
+            return Ok(false);
+        }
+        let res = match next {
+            Some(next) => {
+                let value = serde_json::to_vec(next)
+                    .map_err(|e| GenericError::Other(format!("{:?}", e)))?;
+                txn.put(key, value).await
+            }
+            None => txn.delete(key).await,
+        };
+        if let Err(e) = res {
+            drop(txn.rollback().await);
+            return Err(e);
+        }
+        txn.commit().await
+    }
+
+    /// Sets the expiration time of the lease on an actor, if `handle` holds the lease of `epoch`.
+    /// Setting it to zero releases the lease.
+    ///
+    /// Unlike `actor_lease_swap`, this succeeds whatever the current expiration time is, so that
+    /// the owner and proxies don't conflict on it.
+    pub async fn actor_lease_renew(
+        &self,
+        appid: &str,
+        actor: &ActorAddress,
+        handle: &WorkerHandle,
+        epoch: u64,
+        expires: i64,
+    ) -> GenericResult<ActorLeaseRenewal> {
+        let key = make_actor_lease_key(appid, actor);
+        let mut txn = self.backend.begin_transaction(false).await?;
+        let current = match txn.get(key.clone()).await {
+            Ok(x) => x,
+            Err(e) => {
+                drop(txn.rollback().await);
+                return Err(e);
+            }
+        };
+        let current: Option<ActorLease> = current.and_then(|x| serde_json::from_slice(&x).ok());
+        let mut lease = match current {
+            Some(x) if x.epoch == epoch && x.handle == *handle => x,
+            Some(x) if x.epoch >= epoch => {
+                txn.rollback().await?;
+                return Ok(ActorLeaseRenewal::Superseded);
+            }
+            _ => {
+                txn.rollback().await?;
+                return Ok(ActorLeaseRenewal::Pending);
+            }
+        };
+
+        // A released lease can't be taken back.
+        if lease.expires == 0 && expires != 0 {
+            txn.rollback().await?;
+            return Ok(ActorLeaseRenewal::Superseded);
+        }
+        lease.expires = expires;
+        if let Err(e) = txn.put(key, serde_json::to_vec(&lease).unwrap()).await {
+            drop(txn.rollback().await);
+            return Err(e);
+        }
+        if txn.commit().await? {
+            Ok(ActorLeaseRenewal::Renewed)
+        } else {
+            Err(GenericError::Other(
+                "actor_lease_renew: transaction conflict".into(),
+            ))
+        }
+    }
+
+    /// Appends a message to a queue. `body` is JSON text.
+    ///
+    /// Returns the id of the new message.
+    pub async fn queue_send(&self, queue: &str, body: String, now: i64) -> GenericResult<String> {
+        let id = format!("{:016x}{:016x}", now, rand::random::<u64>());
+        let message = QueueMessageObject {
+            id,
+            body,
+            timestamp: now,
+            attempts: 0,
+        };
+        let key = make_queue_message_key(queue, now, &message.id);
+        self.queue_write(Some((key, encode_json(&message)?)), None)
+            .await?;
+        Ok(message.id)
+    }
+
+    /// Returns at most `limit` messages that are visible at `now`, oldest first.
+    ///
+    /// Messages stay in the queue until acknowledged, so the caller must hold the lease on the
+    /// queue (`queue_lease_claim`) to avoid duplicate deliveries.
+    pub async fn queue_receive(
+        &self,
+        queue: &str,
+        now: i64,
+        limit: u32,
+    ) -> GenericResult<Vec<QueuedMessage>> {
+        let start = make_queue_section_prefix(queue, b'M');
+        let end = join_slices(&[&start, format!("{:016x}", now.saturating_add(1)).as_bytes()]);
+        let batch = self.queue_scan(start, end, limit).await?;
+        Ok(batch
+            .into_iter()
+            .filter_map(|(key, value)| match serde_json::from_slice(&value) {
+                Ok(message) => Some(QueuedMessage { key, message }),
+                Err(e) => {
+                    warn!(
+                        "queue_receive: dropping bad message in queue {}: {:?}",
+                        queue, e
+                    );
+                    None
+                }
+            })
+            .collect())
+    }
+
+    /// Removes a delivered message from its queue.
+    pub async fn queue_ack(&self, msg: &QueuedMessage) -> GenericResult<()> {
+        self.queue_write(None, Some(msg.key.clone())).await
+    }
+
+    /// Makes a message visible again at `visible_at`, counting one more failed delivery.
+    pub async fn queue_retry(
+        &self,
+        queue: &str,
+        msg: &QueuedMessage,
+        visible_at: i64,
+    ) -> GenericResult<()> {
+        let mut message = msg.message.clone();
+        message.attempts += 1;
+        let key = make_queue_message_key(queue, visible_at, &message.id);
+        self.queue_write(Some((key, encode_json(&message)?)), Some(msg.key.clone()))
+            .await
+    }
+
+    /// Moves a message to the dead-letter namespace of its queue.
+    pub async fn queue_dead_letter(&self, queue: &str, msg: &QueuedMessage) -> GenericResult<()> {
+        let mut message = msg.message.clone();
+        message.attempts += 1;
+        let key = make_queue_dead_letter_key(queue, &message.id);
+        self.queue_write(Some((key, encode_json(&message)?)), Some(msg.key.clone()))
+            .await
+    }
+
+    /// Returns the number of pending and dead-lettered messages in a queue.
+    pub async fn queue_depth(&self, queue: &str) -> GenericResult<(u64, u64)> {
+        let pending = self
+            .queue_count(make_queue_section_prefix(queue, b'M'))
+            .await?;
+        let dead = self
+            .queue_count(make_queue_section_prefix(queue, b'D'))
+            .await?;
+        Ok((pending, dead))
+    }
+
+    /// Moves all dead-lettered messages of a queue back to the queue, resetting their attempt
+    /// counters.
+    ///
+    /// Returns the number of messages moved.
+    pub async fn queue_redrive(&self, queue: &str, now: i64) -> GenericResult<u64> {
+        let batch_size: u32 = 20;
+        let mut messages: Vec<(Vec<u8>, QueueMessageObject)> = vec![];
+        let mut start = make_queue_section_prefix(queue, b'D');
+        let mut end = start.clone();
+        *end.last_mut().unwrap() = 1;
+        loop {
+            let batch = self.queue_scan(start, end.clone(), batch_size).await?;
+            for (key, value) in batch.iter() {
+                match serde_json::from_slice(value) {
+                    Ok(x) => messages.push((key.clone(), x)),
+                    Err(e) => warn!("queue_redrive: bad message in queue {}: {:?}", queue, e),
+                }
+            }
+            if batch.len() == batch_size as usize {
+                start = join_slices(&[&batch.last().unwrap().0, &[0u8]]);
+            } else {
+                break;
+            }
+        }
+
+        let count = messages.len() as u64;
+        for (dead_key, mut message) in messages {
+            message.attempts = 0;
+            let key = make_queue_message_key(queue, now, &message.id);
+            self.queue_write(Some((key, encode_json(&message)?)), Some(dead_key))
+                .await?;
+        }
+        Ok(count)
+    }
+
+    /// Writes and/or deletes a queue entry in one transaction, so that a message moved to
+    /// another key is never lost or duplicated.
+    ///
+    /// Queue entries only go through transactions: TiKV doesn't make raw writes visible to
+    /// transactions and the other way around.
+    async fn queue_write(
+        &self,
+        put: Option<(Vec<u8>, Vec<u8>)>,
+        delete: Option<Vec<u8>>,
+    ) -> GenericResult<()> {
+        let mut txn = self.backend.begin_transaction(false).await?;
+        if let Some((key, value)) = put {
+            if let Err(e) = txn.put(key, value).await {
+                drop(txn.rollback().await);
+                return Err(e);
+            }
+        }
+        if let Some(key) = delete {
+            if let Err(e) = txn.delete(key).await {
+                drop(txn.rollback().await);
+                return Err(e);
+            }
+        }
+        if txn.commit().await? {
+            Ok(())
+        } else {
+            Err(GenericError::Other(
+                "queue_write: transaction conflict".into(),
+            ))
+        }
+    }
+
+    /// Returns at most `limit` queue entries in `start..end` with their values, ordered by key.
+    async fn queue_scan(
+        &self,
+        start: Vec<u8>,
+        end: Vec<u8>,
+        limit: u32,
+    ) -> GenericResult<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut txn = self.backend.begin_transaction(true).await?;
+        let keys = txn.scan_keys(start, Some(end), limit).await?;
+        let mut entries = Vec::with_capacity(keys.len());
+        for key in keys {
+            if let Some(value) = txn.get(key.clone()).await? {
+                entries.push((key, value));
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Counts the queue entries under `prefix`, which must end with zero.
+    async fn queue_count(&self, prefix: Vec<u8>) -> GenericResult<u64> {
+        let batch_size: u32 = 100;
+        let mut txn = self.backend.begin_transaction(true).await?;
+        let mut end = prefix.clone();
+        *end.last_mut().unwrap() = 1;
+        let mut start = prefix;
+        let mut count = 0u64;
+        loop {
+            let batch = txn.scan_keys(start, Some(end.clone()), batch_size).await?;
+            count += batch.len() as u64;
+            if batch.len() == batch_size as usize {
+                start = join_slices(&[batch.last().unwrap(), &[0u8]]);
+            } else {
+                return Ok(count);
+            }
+        }
+    }
+
+    /// Claims or renews the consumer lease of a queue for `ttl` milliseconds.
+    ///
+    /// Returns `true` if `owner` holds the lease afterwards.
+    pub async fn queue_lease_claim(
+        &self,
+        queue: &str,
+        owner: &str,
+        now: i64,
+        ttl: i64,
+    ) -> GenericResult<bool> {
+        let key = join_slices(&[PREFIX_QUEUE_LEASE_V1, queue.as_bytes()]);
+        let mut txn = self.backend.begin_transaction(false).await?;
+        let prev = match txn.get(key.clone()).await {
+            Ok(x) => x,
+            Err(e) => {
+                drop(txn.rollback().await);
+                return Err(e);
+            }
+        };
+        let prev: Option<QueueLease> = prev.and_then(|x| serde_json::from_slice(&x).ok());
+        if let Some(prev) = prev {
+            if prev.owner != owner && prev.expires > now {
+                txn.rollback().await?;
+                return Ok(false);
+            }
+        }
+        let lease = QueueLease {
+            owner: owner.to_string(),
+            expires: now + ttl,
+        };
+        if let Err(e) = txn.put(key, encode_json(&lease)?).await {
+            drop(txn.rollback().await);
+            return Err(e);
+        }
+        txn.commit().await
+    }
+
     pub async fn log_range(
         &self,
         topic: &str,
@@ -559,12 +1032,12 @@ impl KvClient {
 
         loop {
             let batch = self
-                .raw
-                .scan(current_prefix..end_prefix.clone(), batch_size)
+                .backend
+                .raw_scan(current_prefix, end_prefix.clone(), batch_size)
                 .await
                 .map_err(|e| GenericError::Other(format!("log_range: {:?}", e)))?;
             for item in batch.iter() {
-                let key: &[u8] = (&item.0).into();
+                let key: &[u8] = &item.0;
                 let key = match std::str::from_utf8(&key[trim_prefix.len()..]) {
                     Ok(x) => x,
                     Err(_) => continue,
@@ -579,7 +1052,7 @@ impl KvClient {
             }
 
             if batch.len() == batch_size as usize {
-                current_prefix = join_slices(&[(&batch.last().unwrap().0).into(), &[0u8]]);
+                current_prefix = join_slices(&[&batch.last().unwrap().0[..], &[0u8]]);
             } else {
                 return Ok(());
             }
@@ -593,8 +1066,8 @@ impl KvClient {
             b"\x00",
             make_time_str(time).as_bytes(),
         ]);
-        self.raw
-            .put(key, text)
+        self.backend
+            .raw_put(key, Vec::from(text))
             .await
             .map_err(|e| GenericError::Other(format!("log_put: {:?}", e)))
     }
@@ -616,8 +1089,8 @@ impl KvClient {
             b"\x00",
             make_time_str(range.end).as_bytes(),
         ]);
-        self.raw
-            .delete_range(start_prefix..end_prefix)
+        self.backend
+            .raw_delete_range(start_prefix, end_prefix)
             .await
             .map_err(|e| GenericError::Other(format!("log_delete_range: {:?}", e)))
     }
@@ -632,10 +1105,54 @@ fn worker_data_key_prefix(namespace_id: &[u8; 16]) -> Vec<u8> {
     join_slices(&[PREFIX_WORKER_DATA_V2, namespace_id, b"\x00"])
 }
 
+fn worker_data_key_end(namespace_id: &[u8; 16]) -> Vec<u8> {
+    join_slices(&[PREFIX_WORKER_DATA_V2, namespace_id, b"\x01"])
+}
+
 fn make_worker_data_key(namespace_id: &[u8; 16], key: &[u8]) -> Vec<u8> {
     join_slices(&[PREFIX_WORKER_DATA_V2, namespace_id, b"\x00", key])
 }
 
+fn make_cron_lease_key(appid: &str, cron: &str) -> Vec<u8> {
+    join_slices(&[
+        PREFIX_CRON_LEASE_V1,
+        appid.as_bytes(),
+        b"\x00",
+        cron.as_bytes(),
+    ])
+}
+
+fn make_actor_lease_key(appid: &str, actor: &ActorAddress) -> Vec<u8> {
+    join_slices(&[
+        PREFIX_ACTOR_LEASE_V1,
+        appid.as_bytes(),
+        b"\x00",
+        actor.namespace.as_bytes(),
+        b"\x00",
+        actor.id.as_bytes(),
+    ])
+}
+
+fn make_queue_section_prefix(queue: &str, section: u8) -> Vec<u8> {
+    join_slices(&[PREFIX_QUEUE_V2, queue.as_bytes(), &[0, section, 0]])
+}
+
+fn make_queue_message_key(queue: &str, visible_at: i64, id: &str) -> Vec<u8> {
+    join_slices(&[
+        &make_queue_section_prefix(queue, b'M'),
+        format!("{:016x}", visible_at.max(0)).as_bytes(),
+        id.as_bytes(),
+    ])
+}
+
+fn make_queue_dead_letter_key(queue: &str, id: &str) -> Vec<u8> {
+    join_slices(&[&make_queue_section_prefix(queue, b'D'), id.as_bytes()])
+}
+
+fn encode_json<T: serde::Serialize>(value: &T) -> GenericResult<Vec<u8>> {
+    serde_json::to_vec(value).map_err(|e| GenericError::Other(format!("{:?}", e)))
+}
+
 fn join_slices(slices: &[&[u8]]) -> Vec<u8> {
     let mut buf = Vec::with_capacity(slices.iter().map(|x| x.len()).sum());
     for s in slices {
@@ -644,48 +1161,18 @@ fn join_slices(slices: &[&[u8]]) -> Vec<u8> {
     buf
 }
 
-fn kvp_deref_key(p: &KvPair) -> &[u8] {
-    (&p.0).into()
+fn pair_deref_key(p: &(Vec<u8>, Vec<u8>)) -> &[u8] {
+    &p.0
 }
 
-fn kvp_deref_value(p: &KvPair) -> &[u8] {
+fn pair_deref_value(p: &(Vec<u8>, Vec<u8>)) -> &[u8] {
     &p.1
 }
 
-fn key_deref(k: &Key) -> &[u8] {
-    k.into()
+fn key_deref(k: &Vec<u8>) -> &[u8] {
+    k
 }
 
-fn mk_unit(_: &Key) -> () {
+fn mk_unit(_: &Vec<u8>) -> () {
     ()
 }
-
-async fn txn_collector_worker(mut rx: Receiver<Transaction>) {
-    // Concurrency control: Don't blow up.
-    let sem = Arc::new(Semaphore::new(8));
-
-    loop {
-        let mut txn = match rx.recv().await {
-            Some(x) => x,
-            None => return,
-        };
-        let permit = sem.clone().acquire_owned();
-        tokio::spawn(async move {
-            // Guard
-            let _permit = permit;
-
-            let start_time = Instant::now();
-            let res = txn.rollback().await;
-            let end_time = Instant::now();
-            debug!(
-                "rolled back dropped transaction in {:?}: {:?}",
-                end_time.duration_since(start_time),
-                res
-            );
-        });
-    }
-}
-
-fn tikv_error_to_generic(e: tikv_client::Error) -> GenericError {
-    GenericError::Other(format!("tikv error: {:?}", e))
-}
@@ -0,0 +1,350 @@
+//! Embedded on-disk storage backend.
+//!
+//! All data is kept in memory and every committed batch of mutations is appended to a log file.
+//! The log is replayed on open. Multiple processes on the same machine (e.g. proxy, runtime and
+//! cli) may share one file: access is serialized with an advisory lock on a `.lock` file next to
+//! the log, and each process catches up with batches appended by others before every operation.
+//!
+//! Once the log has grown enough, the writer holding the lock rewrites it with only the live
+//! data and replaces it. The lock file holds a generation number that is bumped on each
+//! compaction, so that other processes know to reopen and replay the new log. Their transactions
+//! that started before the compaction can no longer read, since the history is gone.
+//!
+//! File locking and I/O block, so operations run on tokio's blocking thread pool.
+
+use super::local::{LocalBackend, LocalStore, Mutation, StoreHandle};
+use crate::types::*;
+use fs2::FileExt;
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::sync::Mutex;
+
+pub type FileBackend = LocalBackend<FileStore>;
+
+pub struct FileStore {
+    inner: Mutex<FileState>,
+}
+
+struct FileState {
+    path: String,
+    file: File,
+
+    /// Holds the advisory lock and the log generation.
+    lock: File,
+
+    store: LocalStore,
+
+    /// Length of the log prefix already applied to `store`.
+    offset: u64,
+
+    /// Generation of the log that `file` refers to.
+    generation: u64,
+
+    /// Length of the log right after it was last compacted.
+    compacted_len: u64,
+}
+
+const TAG_PUT: u8 = 0;
+const TAG_DELETE: u8 = 1;
+const TAG_DELETE_RANGE: u8 = 2;
+
+const LOG_MAGIC: &[u8; 8] = b"rwkvlog1";
+
+/// Magic, the store version before the first batch, and the oldest readable snapshot version.
+const HEADER_LEN: u64 = 24;
+
+/// The log is not compacted below this size.
+const COMPACT_MIN_LEN: u64 = 4 * 1024 * 1024;
+
+impl FileBackend {
+    pub fn open(path: &str) -> GenericResult<Self> {
+        let lock = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(format!("{}.lock", path))?;
+        let mut state = FileState {
+            path: path.to_string(),
+            file: open_log(path)?,
+            lock,
+            store: LocalStore::default(),
+            offset: 0,
+            generation: 0,
+            compacted_len: 0,
+        };
+
+        state.lock.lock_exclusive()?;
+        let result = state.init();
+        state.lock.unlock()?;
+        result?;
+
+        info!(
+            "file backend: replayed {} bytes from {}",
+            state.offset, path
+        );
+        Ok(LocalBackend::with_store(FileStore {
+            inner: Mutex::new(state),
+        }))
+    }
+}
+
+impl StoreHandle for FileStore {
+    const BLOCKING: bool = true;
+
+    fn read<R>(&self, f: impl FnOnce(&LocalStore) -> R) -> GenericResult<R> {
+        let mut state = self.inner.lock().unwrap();
+        state.lock.lock_shared()?;
+        let result = state.sync();
+        state.lock.unlock()?;
+        result?;
+        Ok(f(&state.store))
+    }
+
+    fn write<R>(&self, f: impl FnOnce(&LocalStore) -> (Vec<Mutation>, R)) -> GenericResult<R> {
+        let mut state = self.inner.lock().unwrap();
+        state.lock.lock_exclusive()?;
+        let result = state.sync().and_then(|()| {
+            let (mutations, ret) = f(&state.store);
+            if !mutations.is_empty() {
+                state.append(&mutations)?;
+                state.store.apply(&mutations);
+                state.maybe_compact();
+            }
+            Ok(ret)
+        });
+        state.lock.unlock()?;
+        result
+    }
+}
+
+impl FileState {
+    /// Writes the header of a new log, replays the log and drops a torn batch left by a crashed
+    /// writer, if any. Must be called with the exclusive lock held.
+    fn init(&mut self) -> GenericResult<()> {
+        self.generation = self.read_generation()?;
+        if self.file.metadata()?.len() == 0 {
+            self.file.write_all(&encode_header(0, 0))?;
+            self.file.sync_data()?;
+        }
+        self.catch_up()?;
+
+        let len = self.file.metadata()?.len();
+        if len != self.offset {
+            warn!(
+                "file backend: truncating {} trailing bytes of incomplete log",
+                len - self.offset
+            );
+            self.file.set_len(self.offset)?;
+        }
+        Ok(())
+    }
+
+    /// Brings `store` up to date with the log, reopening it if another process compacted it.
+    /// Must be called with the lock held.
+    fn sync(&mut self) -> GenericResult<()> {
+        let generation = self.read_generation()?;
+        if generation != self.generation {
+            self.file = open_log(&self.path)?;
+            self.offset = 0;
+            self.generation = generation;
+            self.catch_up()?;
+            self.compacted_len = self.offset;
+            return Ok(());
+        }
+        self.catch_up()
+    }
+
+    /// Applies batches appended since the last call. Must be called with the lock held.
+    fn catch_up(&mut self) -> GenericResult<()> {
+        let len = self.file.metadata()?.len();
+        if self.offset == 0 {
+            let mut header = [0u8; HEADER_LEN as usize];
+            self.file.seek(SeekFrom::Start(0))?;
+            self.file.read_exact(&mut header)?;
+            if &header[..8] != LOG_MAGIC {
+                return Err(GenericError::Other("file backend: not a log file".into()));
+            }
+            let version = u64::from_le_bytes(header[8..16].try_into().unwrap());
+            let base_version = u64::from_le_bytes(header[16..24].try_into().unwrap());
+            self.store.reset(version, base_version);
+            self.offset = HEADER_LEN;
+        }
+        if len == self.offset {
+            return Ok(());
+        }
+        self.file.seek(SeekFrom::Start(self.offset))?;
+        let mut buf = vec![0u8; (len - self.offset) as usize];
+        self.file.read_exact(&mut buf)?;
+
+        let mut cursor = &buf[..];
+        while cursor.len() >= 4 {
+            let batch_len = u32::from_le_bytes(cursor[..4].try_into().unwrap()) as usize;
+            if cursor.len() - 4 < batch_len {
+                // Incomplete batch.
+                break;
+            }
+            let mutations = decode_batch(&cursor[4..4 + batch_len])?;
+            self.store.apply(&mutations);
+            cursor = &cursor[4 + batch_len..];
+            self.offset += (4 + batch_len) as u64;
+        }
+        Ok(())
+    }
+
+    /// Appends a batch. Must be called with the exclusive lock held.
+    fn append(&mut self, mutations: &[Mutation]) -> GenericResult<()> {
+        let buf = frame_batch(&encode_batch(mutations))?;
+        self.file.write_all(&buf)?;
+        self.file.sync_data()?;
+        self.offset += buf.len() as u64;
+        Ok(())
+    }
+
+    fn read_generation(&mut self) -> GenericResult<u64> {
+        let mut buf = [0u8; 8];
+        self.lock.seek(SeekFrom::Start(0))?;
+        match self.lock.read_exact(&mut buf) {
+            Ok(()) => Ok(u64::from_le_bytes(buf)),
+            // Nothing has been compacted yet.
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(0),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Compacts the log if it has at least doubled since last compacted. Must be called with the
+    /// exclusive lock held.
+    fn maybe_compact(&mut self) {
+        if self.offset < COMPACT_MIN_LEN.max(self.compacted_len * 2) {
+            return;
+        }
+        if let Err(e) = self.compact() {
+            warn!("file backend: log compaction failed: {:?}", e);
+        }
+    }
+
+    /// Rewrites the log as a single batch of the live data. Must be called with the exclusive
+    /// lock held.
+    fn compact(&mut self) -> GenericResult<()> {
+        let version = self.store.version();
+        let live: Vec<Mutation> = self
+            .store
+            .scan(&[], None, usize::MAX, version)
+            .map(|(k, v)| Mutation::Put(k.to_vec(), v.to_vec()))
+            .collect();
+
+        // The batch is replayed as the last one before `version`.
+        let mut buf = encode_header(version - 1, version).to_vec();
+        buf.extend_from_slice(&frame_batch(&encode_batch(&live))?);
+
+        let tmp_path = format!("{}.compact", self.path);
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(&buf)?;
+        tmp.sync_all()?;
+        drop(tmp);
+
+        // Bump the generation before replacing the log. If we fail in between, other processes
+        // just replay the old log again, which is still complete.
+        let generation = self.generation + 1;
+        self.lock.seek(SeekFrom::Start(0))?;
+        self.lock.write_all(&generation.to_le_bytes())?;
+        self.lock.sync_data()?;
+        std::fs::rename(&tmp_path, &self.path)?;
+
+        self.file = open_log(&self.path)?;
+        self.generation = generation;
+        info!(
+            "file backend: compacted log from {} to {} bytes",
+            self.offset,
+            buf.len()
+        );
+        self.offset = buf.len() as u64;
+        self.compacted_len = self.offset;
+        Ok(())
+    }
+}
+
+fn open_log(path: &str) -> GenericResult<File> {
+    Ok(OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(path)?)
+}
+
+fn encode_header(version: u64, base_version: u64) -> [u8; HEADER_LEN as usize] {
+    let mut buf = [0u8; HEADER_LEN as usize];
+    buf[..8].copy_from_slice(LOG_MAGIC);
+    buf[8..16].copy_from_slice(&version.to_le_bytes());
+    buf[16..24].copy_from_slice(&base_version.to_le_bytes());
+    buf
+}
+
+/// Prefixes an encoded batch with its length.
+fn frame_batch(batch: &[u8]) -> GenericResult<Vec<u8>> {
+    let len: u32 = batch
+        .len()
+        .try_into()
+        .map_err(|_| GenericError::Other("file backend: batch too large".into()))?;
+    let mut buf = Vec::with_capacity(4 + batch.len());
+    buf.extend_from_slice(&len.to_le_bytes());
+    buf.extend_from_slice(batch);
+    Ok(buf)
+}
+
+fn encode_batch(mutations: &[Mutation]) -> Vec<u8> {
+    fn put_bytes(buf: &mut Vec<u8>, x: &[u8]) {
+        buf.extend_from_slice(&(x.len() as u32).to_le_bytes());
+        buf.extend_from_slice(x);
+    }
+
+    let mut buf = vec![];
+    for m in mutations {
+        match m {
+            Mutation::Put(k, v) => {
+                buf.push(TAG_PUT);
+                put_bytes(&mut buf, k);
+                put_bytes(&mut buf, v);
+            }
+            Mutation::Delete(k) => {
+                buf.push(TAG_DELETE);
+                put_bytes(&mut buf, k);
+            }
+            Mutation::DeleteRange(start, end) => {
+                buf.push(TAG_DELETE_RANGE);
+                put_bytes(&mut buf, start);
+                put_bytes(&mut buf, end);
+            }
+        }
+    }
+    buf
+}
+
+fn decode_batch(mut buf: &[u8]) -> GenericResult<Vec<Mutation>> {
+    fn take_bytes(buf: &mut &[u8]) -> GenericResult<Vec<u8>> {
+        if buf.len() < 4 {
+            return Err(GenericError::Other("file backend: corrupted log".into()));
+        }
+        let len = u32::from_le_bytes(buf[..4].try_into().unwrap()) as usize;
+        if buf.len() - 4 < len {
+            return Err(GenericError::Other("file backend: corrupted log".into()));
+        }
+        let x = buf[4..4 + len].to_vec();
+        *buf = &buf[4 + len..];
+        Ok(x)
+    }
+
+    let mut mutations = vec![];
+    while !buf.is_empty() {
+        let tag = buf[0];
+        buf = &buf[1..];
+        let m = match tag {
+            TAG_PUT => Mutation::Put(take_bytes(&mut buf)?, take_bytes(&mut buf)?),
+            TAG_DELETE => Mutation::Delete(take_bytes(&mut buf)?),
+            TAG_DELETE_RANGE => Mutation::DeleteRange(take_bytes(&mut buf)?, take_bytes(&mut buf)?),
+            _ => return Err(GenericError::Other("file backend: corrupted log".into())),
+        };
+        mutations.push(m);
+    }
+    Ok(mutations)
+}
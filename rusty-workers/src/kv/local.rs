@@ -0,0 +1,515 @@
+//! Shared implementation of the single-machine backends (`memory://` and `file://`).
+
+use super::{KvBackend, KvTransaction};
+use crate::types::*;
+use async_trait::async_trait;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound;
+use std::sync::{Arc, Mutex};
+
+/// An ordered multi-version map, used for snapshot reads and optimistic conflict detection.
+///
+/// Each key keeps the versions that a pinned snapshot may still read. Older versions, and
+/// tombstones that no snapshot can see past, are dropped as the store is written.
+#[derive(Default)]
+pub struct LocalStore {
+    /// Versions of each key, oldest first.
+    data: BTreeMap<Vec<u8>, Vec<Entry>>,
+
+    /// Incremented for each applied batch of mutations.
+    version: u64,
+
+    /// Snapshots older than this can no longer be read, because the history they need was
+    /// dropped when the file backend's log was compacted.
+    base_version: u64,
+
+    /// Start versions of live snapshots, with their reference counts.
+    snapshots: Arc<Mutex<BTreeMap<u64, usize>>>,
+
+    /// Keys that hold versions or tombstones a live snapshot may still need.
+    stale: BTreeSet<Vec<u8>>,
+
+    /// The oldest snapshot version when `stale` was last pruned.
+    gc_version: u64,
+}
+
+struct Entry {
+    version: u64,
+
+    /// `None` for deleted keys. Kept so that snapshots and concurrent transactions see the
+    /// delete.
+    value: Option<Vec<u8>>,
+}
+
+#[derive(Clone, Debug)]
+pub enum Mutation {
+    Put(Vec<u8>, Vec<u8>),
+    Delete(Vec<u8>),
+    DeleteRange(Vec<u8>, Vec<u8>),
+}
+
+/// A pinned read version of a `LocalStore`. Versions visible to it are kept until it is dropped.
+pub struct Snapshot {
+    version: u64,
+    snapshots: Arc<Mutex<BTreeMap<u64, usize>>>,
+}
+
+impl Snapshot {
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        let mut snapshots = self.snapshots.lock().unwrap();
+        if let Some(count) = snapshots.get_mut(&self.version) {
+            *count -= 1;
+            if *count == 0 {
+                snapshots.remove(&self.version);
+            }
+        }
+    }
+}
+
+/// Synchronized access to a `LocalStore`.
+pub trait StoreHandle: Send + Sync + 'static {
+    /// Whether `read` and `write` may block on I/O, in which case they are run on the blocking
+    /// thread pool instead of an async worker thread.
+    const BLOCKING: bool;
+
+    /// Runs `f` on an up-to-date view of the store.
+    fn read<R>(&self, f: impl FnOnce(&LocalStore) -> R) -> GenericResult<R>;
+
+    /// Runs `f` on an up-to-date view of the store and atomically applies the returned mutations.
+    fn write<R>(&self, f: impl FnOnce(&LocalStore) -> (Vec<Mutation>, R)) -> GenericResult<R>;
+}
+
+impl LocalStore {
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Clears the store for replaying a log whose first batch follows `version`. Snapshots older
+    /// than `base_version` become unreadable. Live snapshots stay pinned.
+    pub fn reset(&mut self, version: u64, base_version: u64) {
+        self.data.clear();
+        self.stale.clear();
+        self.version = version;
+        self.base_version = base_version;
+        self.gc_version = 0;
+    }
+
+    /// Pins the current version for reading.
+    pub fn snapshot(&self) -> Snapshot {
+        *self
+            .snapshots
+            .lock()
+            .unwrap()
+            .entry(self.version)
+            .or_insert(0) += 1;
+        Snapshot {
+            version: self.version,
+            snapshots: self.snapshots.clone(),
+        }
+    }
+
+    /// Fails if the history needed to read at `version` is gone.
+    pub fn check_snapshot(&self, version: u64) -> GenericResult<()> {
+        if version < self.base_version {
+            Err(GenericError::Other(format!(
+                "snapshot at version {} is too old (oldest readable: {})",
+                version, self.base_version
+            )))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns the value of `key` as of `version`.
+    pub fn get(&self, key: &[u8], version: u64) -> Option<Vec<u8>> {
+        self.data
+            .get(key)
+            .and_then(|x| visible(x, version))
+            .map(|x| x.to_vec())
+    }
+
+    /// Scans the data as of `version`.
+    pub fn scan<'a>(
+        &'a self,
+        start: &'a [u8],
+        end: Option<&'a [u8]>,
+        limit: usize,
+        version: u64,
+    ) -> impl Iterator<Item = (&'a [u8], &'a [u8])> + 'a {
+        let end = match end {
+            Some(x) => Bound::Excluded(x),
+            None => Bound::Unbounded,
+        };
+        self.data
+            .range::<[u8], _>((Bound::Included(start), end))
+            .filter_map(move |(k, v)| visible(v, version).map(|v| (k.as_slice(), v)))
+            .take(limit)
+    }
+
+    /// Returns whether any of `keys` was modified after `version`.
+    pub fn modified_since<'a>(
+        &self,
+        mut keys: impl Iterator<Item = &'a [u8]>,
+        version: u64,
+    ) -> bool {
+        keys.any(|k| {
+            self.data
+                .get(k)
+                .and_then(|x| x.last())
+                .map(|x| x.version > version)
+                .unwrap_or(false)
+        })
+    }
+
+    pub fn apply(&mut self, mutations: &[Mutation]) {
+        self.version += 1;
+        let version = self.version;
+        let mut touched = vec![];
+        for m in mutations {
+            match m {
+                Mutation::Put(k, v) => {
+                    self.push(k.clone(), version, Some(v.clone()));
+                    touched.push(k.clone());
+                }
+                Mutation::Delete(k) => {
+                    self.push(k.clone(), version, None);
+                    touched.push(k.clone());
+                }
+                Mutation::DeleteRange(start, end) => {
+                    // Leave tombstones like `Delete`, so that snapshots and transactions touching
+                    // these keys still see the delete.
+                    let keys: Vec<Vec<u8>> = self
+                        .data
+                        .range::<[u8], _>((
+                            Bound::Included(start.as_slice()),
+                            Bound::Excluded(end.as_slice()),
+                        ))
+                        .filter(|(_, v)| v.last().map(|x| x.value.is_some()).unwrap_or(false))
+                        .map(|(k, _)| k.clone())
+                        .collect();
+                    for k in keys {
+                        self.push(k.clone(), version, None);
+                        touched.push(k);
+                    }
+                }
+            }
+        }
+        self.collect_garbage(touched);
+    }
+
+    fn push(&mut self, key: Vec<u8>, version: u64, value: Option<Vec<u8>>) {
+        let versions = self.data.entry(key).or_default();
+
+        // Within a batch, the last mutation of a key wins.
+        if versions
+            .last()
+            .map(|x| x.version == version)
+            .unwrap_or(false)
+        {
+            versions.pop();
+        }
+        versions.push(Entry { version, value });
+    }
+
+    /// Prunes `touched`, plus keys held back by snapshots that have been released since the
+    /// last pass.
+    fn collect_garbage(&mut self, mut touched: Vec<Vec<u8>>) {
+        let oldest = self
+            .snapshots
+            .lock()
+            .unwrap()
+            .keys()
+            .next()
+            .copied()
+            .unwrap_or(self.version);
+        if oldest > self.gc_version {
+            self.gc_version = oldest;
+            touched.extend(std::mem::take(&mut self.stale));
+        }
+        for key in touched {
+            if self.prune(&key, oldest) {
+                self.stale.insert(key);
+            } else {
+                self.stale.remove(&key);
+            }
+        }
+    }
+
+    /// Drops the versions of `key` that no snapshot at or after `oldest` can read. Returns whether
+    /// anything is left to drop once `oldest` advances.
+    fn prune(&mut self, key: &[u8], oldest: u64) -> bool {
+        let versions = match self.data.get_mut(key) {
+            Some(x) => x,
+            None => return false,
+        };
+
+        // Keep the newest version visible at `oldest`, and everything after it.
+        let first = versions
+            .iter()
+            .rposition(|x| x.version <= oldest)
+            .unwrap_or(0);
+        versions.drain(..first);
+
+        // Every live snapshot sees past a tombstone this old, so nothing needs it to detect
+        // conflicts either.
+        if versions.len() == 1 && versions[0].value.is_none() && versions[0].version <= oldest {
+            self.data.remove(key);
+            return false;
+        }
+        versions.len() > 1 || versions[0].value.is_none()
+    }
+}
+
+fn visible(versions: &[Entry], version: u64) -> Option<&[u8]> {
+    versions
+        .iter()
+        .rev()
+        .find(|x| x.version <= version)
+        .and_then(|x| x.value.as_deref())
+}
+
+/// A `KvBackend` over any `StoreHandle`.
+pub struct LocalBackend<S> {
+    store: Arc<S>,
+}
+
+impl<S: StoreHandle> LocalBackend<S> {
+    pub fn with_store(store: S) -> Self {
+        Self {
+            store: Arc::new(store),
+        }
+    }
+
+    async fn read<R: Send + 'static>(
+        &self,
+        f: impl FnOnce(&LocalStore) -> R + Send + 'static,
+    ) -> GenericResult<R> {
+        read_store(&self.store, f).await
+    }
+
+    async fn write<R: Send + 'static>(
+        &self,
+        f: impl FnOnce(&LocalStore) -> (Vec<Mutation>, R) + Send + 'static,
+    ) -> GenericResult<R> {
+        write_store(&self.store, f).await
+    }
+}
+
+async fn read_store<S: StoreHandle, R: Send + 'static>(
+    store: &Arc<S>,
+    f: impl FnOnce(&LocalStore) -> R + Send + 'static,
+) -> GenericResult<R> {
+    if S::BLOCKING {
+        let store = store.clone();
+        tokio::task::spawn_blocking(move || store.read(f))
+            .await
+            .map_err(|e| GenericError::Other(format!("store read task failed: {:?}", e)))?
+    } else {
+        store.read(f)
+    }
+}
+
+async fn write_store<S: StoreHandle, R: Send + 'static>(
+    store: &Arc<S>,
+    f: impl FnOnce(&LocalStore) -> (Vec<Mutation>, R) + Send + 'static,
+) -> GenericResult<R> {
+    if S::BLOCKING {
+        let store = store.clone();
+        tokio::task::spawn_blocking(move || store.write(f))
+            .await
+            .map_err(|e| GenericError::Other(format!("store write task failed: {:?}", e)))?
+    } else {
+        store.write(f)
+    }
+}
+
+#[async_trait]
+impl<S: StoreHandle> KvBackend for LocalBackend<S> {
+    async fn raw_get(&self, key: Vec<u8>) -> GenericResult<Option<Vec<u8>>> {
+        self.read(move |s| s.get(&key, s.version())).await
+    }
+
+    async fn raw_put(&self, key: Vec<u8>, value: Vec<u8>) -> GenericResult<()> {
+        self.write(move |_| (vec![Mutation::Put(key, value)], ()))
+            .await
+    }
+
+    async fn raw_delete(&self, key: Vec<u8>) -> GenericResult<()> {
+        self.write(move |_| (vec![Mutation::Delete(key)], ())).await
+    }
+
+    async fn raw_delete_range(&self, start: Vec<u8>, end: Vec<u8>) -> GenericResult<()> {
+        self.write(move |_| (vec![Mutation::DeleteRange(start, end)], ()))
+            .await
+    }
+
+    async fn raw_scan(
+        &self,
+        start: Vec<u8>,
+        end: Vec<u8>,
+        limit: u32,
+    ) -> GenericResult<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.read(move |s| {
+            s.scan(&start, Some(&end), limit as usize, s.version())
+                .map(|(k, v)| (k.to_vec(), v.to_vec()))
+                .collect()
+        })
+        .await
+    }
+
+    async fn raw_scan_keys(
+        &self,
+        start: Vec<u8>,
+        end: Vec<u8>,
+        limit: u32,
+    ) -> GenericResult<Vec<Vec<u8>>> {
+        self.read(move |s| {
+            s.scan(&start, Some(&end), limit as usize, s.version())
+                .map(|(k, _)| k.to_vec())
+                .collect()
+        })
+        .await
+    }
+
+    async fn begin_transaction(&self, read_only: bool) -> GenericResult<Box<dyn KvTransaction>> {
+        let snapshot = self.read(|s| s.snapshot()).await?;
+        Ok(Box::new(LocalTransaction {
+            store: self.store.clone(),
+            snapshot,
+            read_only,
+            writes: BTreeMap::new(),
+            locks: BTreeSet::new(),
+        }))
+    }
+}
+
+/// An optimistic transaction on a `LocalStore`.
+///
+/// Writes are buffered until commit. Reads see the data committed before this transaction
+/// started plus its own writes. Commit fails if any written or locked key has been modified by
+/// another transaction since this transaction started.
+struct LocalTransaction<S> {
+    store: Arc<S>,
+    snapshot: Snapshot,
+    read_only: bool,
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    locks: BTreeSet<Vec<u8>>,
+}
+
+impl<S> LocalTransaction<S> {
+    fn check_writable(&self) -> GenericResult<()> {
+        if self.read_only {
+            Err(GenericError::Other("write in read-only transaction".into()))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[async_trait]
+impl<S: StoreHandle> KvTransaction for LocalTransaction<S> {
+    async fn get(&mut self, key: Vec<u8>) -> GenericResult<Option<Vec<u8>>> {
+        if let Some(x) = self.writes.get(&key) {
+            return Ok(x.clone());
+        }
+        let version = self.snapshot.version();
+        read_store(&self.store, move |s| {
+            s.check_snapshot(version)?;
+            Ok(s.get(&key, version))
+        })
+        .await?
+    }
+
+    async fn put(&mut self, key: Vec<u8>, value: Vec<u8>) -> GenericResult<()> {
+        self.check_writable()?;
+        self.writes.insert(key, Some(value));
+        Ok(())
+    }
+
+    async fn delete(&mut self, key: Vec<u8>) -> GenericResult<()> {
+        self.check_writable()?;
+        self.writes.insert(key, None);
+        Ok(())
+    }
+
+    async fn lock_keys(&mut self, keys: Vec<Vec<u8>>) -> GenericResult<()> {
+        self.check_writable()?;
+        self.locks.extend(keys);
+        Ok(())
+    }
+
+    async fn scan_keys(
+        &mut self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: u32,
+    ) -> GenericResult<Vec<Vec<u8>>> {
+        let limit = limit as usize;
+
+        // Buffered deletes can hide at most `self.writes.len()` keys from the store.
+        let scan_limit = limit + self.writes.len();
+        let (scan_start, scan_end) = (start.clone(), end.clone());
+        let version = self.snapshot.version();
+        let mut keys = read_store(&self.store, move |s| -> GenericResult<BTreeSet<Vec<u8>>> {
+            s.check_snapshot(version)?;
+            Ok(
+                s.scan(&scan_start, scan_end.as_deref(), scan_limit, version)
+                    .map(|(k, _)| k.to_vec())
+                    .collect(),
+            )
+        })
+        .await??;
+
+        let end_bound = match end {
+            Some(ref x) => Bound::Excluded(x.as_slice()),
+            None => Bound::Unbounded,
+        };
+        for (k, v) in self
+            .writes
+            .range::<[u8], _>((Bound::Included(start.as_slice()), end_bound))
+        {
+            if v.is_some() {
+                keys.insert(k.clone());
+            } else {
+                keys.remove(k);
+            }
+        }
+        Ok(keys.into_iter().take(limit).collect())
+    }
+
+    async fn commit(self: Box<Self>) -> GenericResult<bool> {
+        if self.writes.is_empty() && self.locks.is_empty() {
+            return Ok(true);
+        }
+
+        let this = *self;
+        let start_version = this.snapshot.version();
+        let writes = this.writes;
+        let locks = this.locks;
+        write_store(&this.store, move |s| {
+            let touched = writes.keys().chain(locks.iter()).map(|x| x.as_slice());
+            if s.modified_since(touched, start_version) {
+                return (vec![], false);
+            }
+            let mutations = writes
+                .into_iter()
+                .map(|(k, v)| match v {
+                    Some(v) => Mutation::Put(k, v),
+                    None => Mutation::Delete(k),
+                })
+                .collect();
+            (mutations, true)
+        })
+        .await
+    }
+
+    async fn rollback(self: Box<Self>) -> GenericResult<()> {
+        Ok(())
+    }
+}
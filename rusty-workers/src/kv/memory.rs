@@ -0,0 +1,38 @@
+//! In-memory storage backend.
+//!
+//! Data lives only as long as the process, so this is mostly useful for tests and for running
+//! a single-process setup.
+
+use super::local::{LocalBackend, LocalStore, Mutation, StoreHandle};
+use crate::types::*;
+use std::sync::Mutex;
+
+pub type MemoryBackend = LocalBackend<MemoryStore>;
+
+#[derive(Default)]
+pub struct MemoryStore {
+    inner: Mutex<LocalStore>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        LocalBackend::with_store(MemoryStore::default())
+    }
+}
+
+impl StoreHandle for MemoryStore {
+    const BLOCKING: bool = false;
+
+    fn read<R>(&self, f: impl FnOnce(&LocalStore) -> R) -> GenericResult<R> {
+        Ok(f(&self.inner.lock().unwrap()))
+    }
+
+    fn write<R>(&self, f: impl FnOnce(&LocalStore) -> (Vec<Mutation>, R)) -> GenericResult<R> {
+        let mut inner = self.inner.lock().unwrap();
+        let (mutations, ret) = f(&inner);
+        if !mutations.is_empty() {
+            inner.apply(&mutations);
+        }
+        Ok(ret)
+    }
+}
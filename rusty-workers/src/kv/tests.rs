@@ -0,0 +1,355 @@
+use super::*;
+use rand::Rng;
+use std::io::Write;
+use std::path::PathBuf;
+
+/// A log file path that is removed when dropped.
+struct TempLog(PathBuf);
+
+impl TempLog {
+    fn new() -> Self {
+        let name = format!("rw-kv-test-{:016x}.log", rand::thread_rng().gen::<u64>());
+        Self(std::env::temp_dir().join(name))
+    }
+
+    fn open(&self) -> FileBackend {
+        FileBackend::open(self.0.to_str().unwrap()).unwrap()
+    }
+}
+
+impl Drop for TempLog {
+    fn drop(&mut self) {
+        drop(std::fs::remove_file(&self.0));
+        drop(std::fs::remove_file(format!("{}.lock", self.0.display())));
+    }
+}
+
+fn k(x: &str) -> Vec<u8> {
+    x.as_bytes().to_vec()
+}
+
+async fn check_raw_ops(kv: &dyn KvBackend) {
+    kv.raw_put(k("a"), k("1")).await.unwrap();
+    kv.raw_put(k("b"), k("2")).await.unwrap();
+    kv.raw_put(k("c"), k("3")).await.unwrap();
+    assert_eq!(kv.raw_get(k("b")).await.unwrap(), Some(k("2")));
+    assert_eq!(kv.raw_get(k("x")).await.unwrap(), None);
+
+    assert_eq!(
+        kv.raw_scan(k("a"), k("c"), 10).await.unwrap(),
+        vec![(k("a"), k("1")), (k("b"), k("2"))]
+    );
+    assert_eq!(
+        kv.raw_scan_keys(k("a"), k("z"), 2).await.unwrap(),
+        vec![k("a"), k("b")]
+    );
+
+    kv.raw_delete(k("a")).await.unwrap();
+    assert_eq!(kv.raw_get(k("a")).await.unwrap(), None);
+    assert_eq!(
+        kv.raw_scan_keys(k("a"), k("z"), 10).await.unwrap(),
+        vec![k("b"), k("c")]
+    );
+
+    kv.raw_delete_range(k("b"), k("c")).await.unwrap();
+    assert_eq!(
+        kv.raw_scan_keys(k("a"), k("z"), 10).await.unwrap(),
+        vec![k("c")]
+    );
+}
+
+async fn check_transactions(kv: &dyn KvBackend) {
+    // Reads see the transaction's own writes; others only see them after commit.
+    let mut txn = kv.begin_transaction(false).await.unwrap();
+    txn.put(k("t1"), k("v")).await.unwrap();
+    assert_eq!(txn.get(k("t1")).await.unwrap(), Some(k("v")));
+    assert_eq!(kv.raw_get(k("t1")).await.unwrap(), None);
+    assert!(txn.commit().await.unwrap());
+    assert_eq!(kv.raw_get(k("t1")).await.unwrap(), Some(k("v")));
+
+    // Buffered writes and deletes are merged into scans.
+    let mut txn = kv.begin_transaction(false).await.unwrap();
+    txn.put(k("t2"), k("v")).await.unwrap();
+    txn.delete(k("t1")).await.unwrap();
+    assert_eq!(
+        txn.scan_keys(k("t"), None, 10).await.unwrap(),
+        vec![k("t2")]
+    );
+    txn.rollback().await.unwrap();
+    assert_eq!(kv.raw_get(k("t1")).await.unwrap(), Some(k("v")));
+
+    let mut txn = kv.begin_transaction(true).await.unwrap();
+    assert!(txn.put(k("t1"), k("w")).await.is_err());
+}
+
+async fn check_snapshots(kv: &dyn KvBackend) {
+    kv.raw_put(k("s1"), k("a")).await.unwrap();
+    let mut txn = kv.begin_transaction(true).await.unwrap();
+    kv.raw_put(k("s1"), k("b")).await.unwrap();
+    kv.raw_put(k("s2"), k("b")).await.unwrap();
+
+    // Reads see the data as of the transaction's start.
+    assert_eq!(txn.get(k("s1")).await.unwrap(), Some(k("a")));
+    assert_eq!(txn.get(k("s2")).await.unwrap(), None);
+    assert_eq!(
+        txn.scan_keys(k("s"), Some(k("t")), 10).await.unwrap(),
+        vec![k("s1")]
+    );
+
+    // Deletes after the start are not visible either.
+    kv.raw_delete_range(k("s1"), k("s3")).await.unwrap();
+    assert_eq!(txn.get(k("s1")).await.unwrap(), Some(k("a")));
+    txn.rollback().await.unwrap();
+    assert_eq!(kv.raw_get(k("s1")).await.unwrap(), None);
+}
+
+async fn check_conflicts(kv: &dyn KvBackend) {
+    // Write-write conflict: the second committer loses.
+    let mut t1 = kv.begin_transaction(false).await.unwrap();
+    let mut t2 = kv.begin_transaction(false).await.unwrap();
+    t1.put(k("c1"), k("a")).await.unwrap();
+    t2.put(k("c1"), k("b")).await.unwrap();
+    assert!(t1.commit().await.unwrap());
+    assert!(!t2.commit().await.unwrap());
+    assert_eq!(kv.raw_get(k("c1")).await.unwrap(), Some(k("a")));
+
+    // Locked keys conflict with concurrent writes too.
+    let mut t1 = kv.begin_transaction(false).await.unwrap();
+    t1.lock_keys(vec![k("c1")]).await.unwrap();
+    t1.put(k("c2"), k("a")).await.unwrap();
+    kv.raw_put(k("c1"), k("b")).await.unwrap();
+    assert!(!t1.commit().await.unwrap());
+    assert_eq!(kv.raw_get(k("c2")).await.unwrap(), None);
+
+    // A concurrent delete conflicts.
+    let mut t1 = kv.begin_transaction(false).await.unwrap();
+    t1.put(k("c1"), k("c")).await.unwrap();
+    kv.raw_delete(k("c1")).await.unwrap();
+    assert!(!t1.commit().await.unwrap());
+
+    // So does a concurrent range delete.
+    kv.raw_put(k("c3"), k("a")).await.unwrap();
+    let mut t1 = kv.begin_transaction(false).await.unwrap();
+    t1.put(k("c3"), k("b")).await.unwrap();
+    kv.raw_delete_range(k("c3"), k("c4")).await.unwrap();
+    assert!(!t1.commit().await.unwrap());
+    assert_eq!(kv.raw_get(k("c3")).await.unwrap(), None);
+
+    // Unrelated keys don't conflict.
+    let mut t1 = kv.begin_transaction(false).await.unwrap();
+    t1.put(k("c5"), k("a")).await.unwrap();
+    kv.raw_put(k("c6"), k("a")).await.unwrap();
+    assert!(t1.commit().await.unwrap());
+}
+
+#[tokio::test]
+async fn memory_backend() {
+    let kv = MemoryBackend::new();
+    check_raw_ops(&kv).await;
+    check_transactions(&kv).await;
+    check_snapshots(&kv).await;
+    check_conflicts(&kv).await;
+}
+
+#[tokio::test]
+async fn file_backend() {
+    let log = TempLog::new();
+    let kv = log.open();
+    check_raw_ops(&kv).await;
+    check_transactions(&kv).await;
+    check_snapshots(&kv).await;
+    check_conflicts(&kv).await;
+}
+
+#[tokio::test]
+async fn file_backend_replays_log() {
+    let log = TempLog::new();
+    {
+        let kv = log.open();
+        kv.raw_put(k("a"), k("1")).await.unwrap();
+        kv.raw_put(k("b"), k("2")).await.unwrap();
+        kv.raw_delete_range(k("b"), k("c")).await.unwrap();
+    }
+
+    // A torn batch from a crashed writer is dropped on open.
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .open(&log.0)
+        .unwrap();
+    file.write_all(&[100, 0, 0, 0, 0]).unwrap();
+    drop(file);
+
+    let kv = log.open();
+    assert_eq!(kv.raw_get(k("a")).await.unwrap(), Some(k("1")));
+    assert_eq!(kv.raw_get(k("b")).await.unwrap(), None);
+    kv.raw_put(k("c"), k("3")).await.unwrap();
+    drop(kv);
+
+    let kv = log.open();
+    assert_eq!(
+        kv.raw_scan_keys(k("a"), k("z"), 10).await.unwrap(),
+        vec![k("a"), k("c")]
+    );
+}
+
+#[tokio::test]
+async fn file_backend_shared_between_handles() {
+    let log = TempLog::new();
+    let kv1 = log.open();
+    let kv2 = log.open();
+
+    kv1.raw_put(k("a"), k("1")).await.unwrap();
+    assert_eq!(kv2.raw_get(k("a")).await.unwrap(), Some(k("1")));
+
+    // Conflicts are detected across handles.
+    let mut t1 = kv1.begin_transaction(false).await.unwrap();
+    t1.put(k("a"), k("2")).await.unwrap();
+    kv2.raw_put(k("a"), k("3")).await.unwrap();
+    assert!(!t1.commit().await.unwrap());
+    assert_eq!(kv1.raw_get(k("a")).await.unwrap(), Some(k("3")));
+}
+
+#[tokio::test]
+async fn file_backend_compacts_log() {
+    let log = TempLog::new();
+    let kv1 = log.open();
+    let kv2 = log.open();
+    kv1.raw_put(k("b"), k("1")).await.unwrap();
+    let mut txn = kv2.begin_transaction(true).await.unwrap();
+
+    let mut value = vec![0u8; 64 * 1024];
+    for i in 0..100 {
+        value[0] = i;
+        kv1.raw_put(k("a"), value.clone()).await.unwrap();
+    }
+    assert!(std::fs::metadata(&log.0).unwrap().len() < 50 * value.len() as u64);
+
+    // Other handles replay the compacted log, but their older transactions can't read anymore.
+    assert!(txn.get(k("b")).await.is_err());
+    assert_eq!(kv2.raw_get(k("a")).await.unwrap(), Some(value.clone()));
+    kv2.raw_put(k("c"), k("3")).await.unwrap();
+    assert_eq!(kv1.raw_get(k("c")).await.unwrap(), Some(k("3")));
+    drop(kv1);
+    drop(kv2);
+
+    let kv = log.open();
+    assert_eq!(
+        kv.raw_scan_keys(k("a"), k("z"), 10).await.unwrap(),
+        vec![k("a"), k("b"), k("c")]
+    );
+    assert_eq!(kv.raw_get(k("a")).await.unwrap(), Some(value));
+}
+
+#[tokio::test]
+async fn cron_leases() {
+    let kv = KvClient::open("memory://").await.unwrap();
+    let (app, cron) = ("app", "* * * * *");
+
+    assert!(kv.cron_lease_claim(app, cron, 60, 0, 10, 2).await.unwrap());
+    // Held until the claim expires.
+    assert!(!kv.cron_lease_claim(app, cron, 60, 5, 15, 2).await.unwrap());
+    // Claimed again after it expires without completing.
+    assert!(kv.cron_lease_claim(app, cron, 60, 10, 20, 2).await.unwrap());
+    // Given up after `max_attempts`.
+    assert!(!kv.cron_lease_claim(app, cron, 60, 20, 30, 2).await.unwrap());
+
+    // A later tick supersedes an earlier one.
+    assert!(kv
+        .cron_lease_claim(app, cron, 120, 20, 30, 2)
+        .await
+        .unwrap());
+    assert!(!kv.cron_lease_claim(app, cron, 60, 40, 50, 2).await.unwrap());
+
+    // Completed ticks aren't fired again.
+    assert!(kv.cron_lease_complete(app, cron, 120).await.unwrap());
+    assert!(!kv
+        .cron_lease_claim(app, cron, 120, 40, 50, 2)
+        .await
+        .unwrap());
+    let lease = kv.cron_lease_get(app, cron).await.unwrap().unwrap();
+    assert_eq!(lease.scheduled_time, 120);
+    assert!(lease.completed);
+}
+
+#[tokio::test]
+async fn actor_leases() {
+    let kv = KvClient::open("memory://").await.unwrap();
+    let actor = ActorAddress {
+        namespace: "rooms".into(),
+        id: "42".into(),
+    };
+    let (first, second) = (WorkerHandle::generate(), WorkerHandle::generate());
+    let lease = |handle: &WorkerHandle, epoch| ActorLease {
+        runtime: RuntimeId::generate(),
+        handle: handle.clone(),
+        expires: 10,
+        epoch,
+    };
+
+    // Not recorded by the proxy yet.
+    let renewal = kv.actor_lease_renew("app", &actor, &first, 1, 20).await;
+    assert_eq!(renewal.unwrap(), ActorLeaseRenewal::Pending);
+
+    let first_lease = lease(&first, 1);
+    assert!(kv
+        .actor_lease_swap("app", &actor, None, Some(&first_lease))
+        .await
+        .unwrap());
+    let renewal = kv.actor_lease_renew("app", &actor, &first, 1, 20).await;
+    assert_eq!(renewal.unwrap(), ActorLeaseRenewal::Renewed);
+    let current = kv.actor_lease_get("app", &actor).await.unwrap().unwrap();
+    assert_eq!(current.expires, 20);
+
+    // A newer worker doesn't own the actor until its lease is recorded.
+    let renewal = kv.actor_lease_renew("app", &actor, &second, 2, 30).await;
+    assert_eq!(renewal.unwrap(), ActorLeaseRenewal::Pending);
+    assert!(kv
+        .actor_lease_swap("app", &actor, Some(&current), Some(&lease(&second, 2)))
+        .await
+        .unwrap());
+    let renewal = kv.actor_lease_renew("app", &actor, &first, 1, 40).await;
+    assert_eq!(renewal.unwrap(), ActorLeaseRenewal::Superseded);
+    let renewal = kv.actor_lease_renew("app", &actor, &second, 2, 0).await;
+    assert_eq!(renewal.unwrap(), ActorLeaseRenewal::Renewed);
+    let current = kv.actor_lease_get("app", &actor).await.unwrap().unwrap();
+    assert_eq!((current.handle, current.expires), (second.clone(), 0));
+
+    // Released leases stay released.
+    let renewal = kv.actor_lease_renew("app", &actor, &second, 2, 50).await;
+    assert_eq!(renewal.unwrap(), ActorLeaseRenewal::Superseded);
+}
+
+#[tokio::test]
+async fn queue_messages() {
+    let kv = KvClient::open("memory://").await.unwrap();
+    let first = kv.queue_send("q", "1".into(), 10).await.unwrap();
+    let second = kv.queue_send("q", "2".into(), 20).await.unwrap();
+
+    // Only visible messages are received, oldest first.
+    let batch = kv.queue_receive("q", 15, 10).await.unwrap();
+    assert_eq!(batch.len(), 1);
+    assert_eq!(batch[0].message.id, first);
+    let batch = kv.queue_receive("q", 20, 10).await.unwrap();
+    assert_eq!(batch.len(), 2);
+
+    // A retried message moves to its new visibility time, and only exists there.
+    kv.queue_retry("q", &batch[0], 30).await.unwrap();
+    let batch = kv.queue_receive("q", 25, 10).await.unwrap();
+    assert_eq!(batch.len(), 1);
+    assert_eq!(batch[0].message.id, second);
+    assert_eq!(kv.queue_depth("q").await.unwrap(), (2, 0));
+    let batch = kv.queue_receive("q", 30, 10).await.unwrap();
+    assert_eq!(batch[1].message.id, first);
+    assert_eq!(batch[1].message.attempts, 1);
+
+    kv.queue_ack(&batch[0]).await.unwrap();
+    kv.queue_dead_letter("q", &batch[1]).await.unwrap();
+    assert_eq!(kv.queue_depth("q").await.unwrap(), (0, 1));
+
+    // Redriven messages start over.
+    assert_eq!(kv.queue_redrive("q", 40).await.unwrap(), 1);
+    assert_eq!(kv.queue_depth("q").await.unwrap(), (1, 0));
+    let batch = kv.queue_receive("q", 40, 10).await.unwrap();
+    assert_eq!(batch[0].message.id, first);
+    assert_eq!(batch[0].message.attempts, 0);
+}
@@ -0,0 +1,247 @@
+//! TiKV storage backend.
+
+use super::{KvBackend, KvTransaction};
+use crate::types::*;
+use async_trait::async_trait;
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+use std::time::Instant;
+use tikv_client::{BoundRange, CheckLevel, Transaction, TransactionOptions};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::Semaphore;
+
+pub struct TikvBackend {
+    raw: tikv_client::RawClient,
+    transactional: tikv_client::TransactionClient,
+    txn_collector_tx: Sender<Transaction>,
+}
+
+/// A protected transaction is automatically rolled back when dropped.
+///
+/// This is important as asynchronous tasks can be cancelled.
+struct ProtectedTransaction {
+    txn: Option<Transaction>,
+
+    /// `None` for read-only transactions, which don't need a rollback.
+    txn_collector_tx: Option<Sender<Transaction>>,
+}
+
+impl Drop for ProtectedTransaction {
+    fn drop(&mut self) {
+        if let Some(txn) = self.txn.take() {
+            if let Some(ref tx) = self.txn_collector_tx {
+                drop(tx.try_send(txn));
+            }
+        }
+    }
+}
+
+impl Deref for ProtectedTransaction {
+    type Target = Transaction;
+    fn deref(&self) -> &Self::Target {
+        self.txn.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for ProtectedTransaction {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.txn.as_mut().unwrap()
+    }
+}
+
+impl TikvBackend {
+    pub async fn new<S: Into<String> + Clone>(pd_endpoints: Vec<S>) -> GenericResult<Self> {
+        let raw = tikv_client::RawClient::new(pd_endpoints.clone())
+            .await
+            .map_err(|e| {
+                GenericError::Other(format!("tikv (raw) initialization failed: {:?}", e))
+            })?;
+        let transactional = tikv_client::TransactionClient::new(pd_endpoints)
+            .await
+            .map_err(|e| {
+                GenericError::Other(format!(
+                    "tikv (transactional) initialization failed: {:?}",
+                    e
+                ))
+            })?;
+        let (txn_collector_tx, txn_collector_rx) = channel(1000);
+        tokio::spawn(async move {
+            txn_collector_worker(txn_collector_rx).await;
+        });
+        Ok(Self {
+            raw,
+            transactional,
+            txn_collector_tx,
+        })
+    }
+}
+
+#[async_trait]
+impl KvBackend for TikvBackend {
+    async fn raw_get(&self, key: Vec<u8>) -> GenericResult<Option<Vec<u8>>> {
+        self.raw
+            .get(key)
+            .await
+            .map_err(|e| GenericError::Other(format!("raw_get: {:?}", e)))
+    }
+
+    async fn raw_put(&self, key: Vec<u8>, value: Vec<u8>) -> GenericResult<()> {
+        self.raw
+            .put(key, value)
+            .await
+            .map_err(|e| GenericError::Other(format!("raw_put: {:?}", e)))
+    }
+
+    async fn raw_delete(&self, key: Vec<u8>) -> GenericResult<()> {
+        self.raw
+            .delete(key)
+            .await
+            .map_err(|e| GenericError::Other(format!("raw_delete: {:?}", e)))
+    }
+
+    async fn raw_delete_range(&self, start: Vec<u8>, end: Vec<u8>) -> GenericResult<()> {
+        self.raw
+            .delete_range(start..end)
+            .await
+            .map_err(|e| GenericError::Other(format!("raw_delete_range: {:?}", e)))
+    }
+
+    async fn raw_scan(
+        &self,
+        start: Vec<u8>,
+        end: Vec<u8>,
+        limit: u32,
+    ) -> GenericResult<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.raw
+            .scan(start..end, limit)
+            .await
+            .map_err(|e| GenericError::Other(format!("raw_scan: {:?}", e)))
+            .map(|x| x.into_iter().map(|x| (Vec::from(x.0), x.1)).collect())
+    }
+
+    async fn raw_scan_keys(
+        &self,
+        start: Vec<u8>,
+        end: Vec<u8>,
+        limit: u32,
+    ) -> GenericResult<Vec<Vec<u8>>> {
+        self.raw
+            .scan_keys(start..end, limit)
+            .await
+            .map_err(|e| GenericError::Other(format!("raw_scan_keys: {:?}", e)))
+            .map(|x| x.into_iter().map(Vec::from).collect())
+    }
+
+    async fn begin_transaction(&self, read_only: bool) -> GenericResult<Box<dyn KvTransaction>> {
+        let (opts, txn_collector_tx) = if read_only {
+            (TransactionOptions::new_optimistic().read_only(), None)
+        } else {
+            // If we run out of space in `txn_collector_tx` the transaction may be dropped without being committed or
+            // rolled back. Let's print a warning in this case.
+            (
+                TransactionOptions::new_optimistic().drop_check(CheckLevel::Warn),
+                Some(self.txn_collector_tx.clone()),
+            )
+        };
+
+        let txn = self
+            .transactional
+            .begin_with_options(opts)
+            .await
+            .map_err(tikv_error_to_generic)?;
+        Ok(Box::new(ProtectedTransaction {
+            txn: Some(txn),
+            txn_collector_tx,
+        }))
+    }
+}
+
+#[async_trait]
+impl KvTransaction for ProtectedTransaction {
+    async fn get(&mut self, key: Vec<u8>) -> GenericResult<Option<Vec<u8>>> {
+        Transaction::get(self, key)
+            .await
+            .map_err(tikv_error_to_generic)
+    }
+
+    async fn put(&mut self, key: Vec<u8>, value: Vec<u8>) -> GenericResult<()> {
+        Transaction::put(self, key, value)
+            .await
+            .map_err(tikv_error_to_generic)
+    }
+
+    async fn delete(&mut self, key: Vec<u8>) -> GenericResult<()> {
+        Transaction::delete(self, key)
+            .await
+            .map_err(tikv_error_to_generic)
+    }
+
+    async fn lock_keys(&mut self, keys: Vec<Vec<u8>>) -> GenericResult<()> {
+        Transaction::lock_keys(self, keys)
+            .await
+            .map_err(tikv_error_to_generic)
+    }
+
+    async fn scan_keys(
+        &mut self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: u32,
+    ) -> GenericResult<Vec<Vec<u8>>> {
+        let range: BoundRange = if let Some(end) = end {
+            (start..end).into()
+        } else {
+            (start..).into()
+        };
+        Transaction::scan_keys(self, range, limit)
+            .await
+            .map_err(tikv_error_to_generic)
+            .map(|x| x.map(Vec::from).collect())
+    }
+
+    async fn commit(mut self: Box<Self>) -> GenericResult<bool> {
+        let mut txn = self.txn.take().unwrap();
+        txn.commit().await.map(|_| true).or_else(|e| match e {
+            tikv_client::Error::KeyError(e) if e.conflict.is_some() => Ok(false),
+            e => Err(tikv_error_to_generic(e)),
+        })
+    }
+
+    async fn rollback(mut self: Box<Self>) -> GenericResult<()> {
+        let mut txn = self.txn.take().unwrap();
+        txn.rollback()
+            .await
+            .map(|_| ())
+            .map_err(tikv_error_to_generic)
+    }
+}
+
+async fn txn_collector_worker(mut rx: Receiver<Transaction>) {
+    // Concurrency control: Don't blow up.
+    let sem = Arc::new(Semaphore::new(8));
+
+    loop {
+        let mut txn = match rx.recv().await {
+            Some(x) => x,
+            None => return,
+        };
+        let permit = sem.clone().acquire_owned();
+        tokio::spawn(async move {
+            // Guard
+            let _permit = permit;
+
+            let start_time = Instant::now();
+            let res = txn.rollback().await;
+            let end_time = Instant::now();
+            debug!(
+                "rolled back dropped transaction in {:?}: {:?}",
+                end_time.duration_since(start_time),
+                res
+            );
+        });
+    }
+}
+
+fn tikv_error_to_generic(e: tikv_client::Error) -> GenericError {
+    GenericError::Other(format!("tikv error: {:?}", e))
+}
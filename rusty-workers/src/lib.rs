@@ -2,7 +2,9 @@
 extern crate log;
 
 pub mod app;
+pub mod cron;
 pub mod rpc;
+pub mod tail;
 pub mod types;
 pub mod util;
 
@@ -90,8 +90,44 @@ pub trait RuntimeService {
     /// Issue a "fetch" event.
     async fn fetch(handle: WorkerHandle, req: RequestObject) -> ExecutionResult<ResponseObject>;
 
+    /// Read the next chunk of a streaming response body. Returns `None` at the end of the stream.
+    async fn read_body_chunk(stream: String) -> GenericResult<Option<Vec<u8>>>;
+
+    /// Create a stream for a request body, to be passed as `HttpBody::Stream` to `fetch`.
+    async fn create_body_stream() -> String;
+
+    /// Write the next chunk of a request body stream. `None` ends the stream.
+    ///
+    /// Waits until the worker reads the chunk. Unless the stream ended, returns once the worker
+    /// asks for the next one.
+    async fn write_body_chunk(stream: String, chunk: Option<Vec<u8>>) -> GenericResult<()>;
+
+    /// Close a body stream without reaching its end.
+    async fn close_body_stream(stream: String) -> bool;
+
+    /// Issue a "scheduled" event.
+    async fn scheduled(handle: WorkerHandle, event: ScheduledEventObject) -> ExecutionResult<()>;
+
+    /// Issue a "queue" event.
+    async fn queue(
+        handle: WorkerHandle,
+        batch: QueueBatchObject,
+    ) -> ExecutionResult<QueueBatchResult>;
+
     /// The current load of this runtime instance. 0-65535.
     async fn load() -> GenericResult<u16>;
+
+    /// Hit and miss counters of the code cache.
+    async fn code_cache_stats() -> CodeCacheStats;
+
+    /// Counters of the Cache API response cache.
+    async fn response_cache_stats() -> ResponseCacheStats;
+
+    /// Waits for new log records of apps on this runtime.
+    async fn tail_logs(req: LogTailRequest) -> LogTailBatch;
+
+    /// Returns a one-time DevTools URL for debugging a worker.
+    async fn inspect(handle: WorkerHandle) -> GenericResult<String>;
 }
 
 impl_connect!(RuntimeServiceClient);
@@ -106,3 +142,25 @@ pub trait FetchService {
 }
 
 impl_connect!(FetchServiceClient);
+
+/// Scheduler service, served by the proxy for requests from workers.
+#[tarpc::service]
+pub trait SchedulerService {
+    /// Sends a request to an actor of an app, creating the actor if it isn't running.
+    ///
+    /// Result is wrapped twice like `FetchService::fetch`.
+    async fn actor_fetch(
+        appid: String,
+        actor: ActorAddress,
+        req: RequestObject,
+    ) -> GenericResult<Result<ResponseObject, String>>;
+
+    /// Sends a request to the app bound to the service binding `service` of an app.
+    async fn service_fetch(
+        appid: String,
+        service: String,
+        req: RequestObject,
+    ) -> GenericResult<Result<ResponseObject, String>>;
+}
+
+impl_connect!(SchedulerServiceClient);
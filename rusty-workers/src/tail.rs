@@ -0,0 +1,71 @@
+//! Live tailing of app logs across runtimes.
+
+use crate::rpc::RuntimeServiceClient;
+use crate::types::*;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime};
+use tokio::sync::mpsc;
+
+/// Time a runtime waits for new records in one poll.
+const TAIL_POLL_TIMEOUT_MS: u64 = 10000;
+
+/// Max number of records in one poll.
+const TAIL_POLL_LIMIT: u32 = 500;
+
+/// Delay before reconnecting to a runtime after an error.
+const TAIL_RECONNECT_DELAY_MS: u64 = 2000;
+
+/// Streams new log records from `runtimes` that match `appid` and `level`.
+///
+/// Each runtime is polled independently, so records of different runtimes may arrive out of
+/// order. Polling stops when the returned receiver is dropped.
+pub fn tail_runtimes(
+    runtimes: Vec<SocketAddr>,
+    appid: Option<String>,
+    level: Option<LogLevel>,
+) -> mpsc::Receiver<LogTailBatch> {
+    let (tx, rx) = mpsc::channel(16);
+    for addr in runtimes {
+        let req = LogTailRequest {
+            appid: appid.clone(),
+            level,
+            cursor: None,
+            limit: TAIL_POLL_LIMIT,
+            timeout_ms: TAIL_POLL_TIMEOUT_MS,
+        };
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = tail_runtime(addr, req, &tx) => {}
+                _ = tx.closed() => {}
+            }
+        });
+    }
+    rx
+}
+
+async fn tail_runtime(addr: SocketAddr, mut req: LogTailRequest, tx: &mpsc::Sender<LogTailBatch>) {
+    loop {
+        match RuntimeServiceClient::connect_noretry(addr).await {
+            Ok(mut client) => loop {
+                let mut ctx = tarpc::context::current();
+                ctx.deadline = SystemTime::now() + Duration::from_millis(TAIL_POLL_TIMEOUT_MS * 2);
+                let batch = match client.tail_logs(ctx, req.clone()).await {
+                    Ok(x) => x,
+                    Err(e) => {
+                        warn!("tail_runtime: error polling runtime {}: {:?}", addr, e);
+                        break;
+                    }
+                };
+                req.cursor = Some(batch.cursor);
+                if (!batch.entries.is_empty() || batch.lost > 0) && tx.send(batch).await.is_err() {
+                    return;
+                }
+            },
+            Err(e) => {
+                warn!("tail_runtime: cannot connect to runtime {}: {:?}", addr, e);
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(TAIL_RECONNECT_DELAY_MS)).await;
+    }
+}
@@ -1,5 +1,6 @@
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
+use std::fmt;
 use std::net::SocketAddr;
 use thiserror::Error;
 
@@ -9,6 +10,87 @@ pub struct WorkerConfiguration {
     pub fetch_service: SocketAddr,
     pub env: BTreeMap<String, String>,
     pub kv_namespaces: BTreeMap<String, [u8; 16]>,
+
+    /// Base64-encoded id of the bundle, used as the key for caching compiled code. Caching is
+    /// disabled if empty.
+    #[serde(default)]
+    pub bundle_id: String,
+
+    /// WebAssembly modules to expose as globals, as a map from global names to bundle paths.
+    #[serde(default)]
+    pub wasm_modules: BTreeMap<String, String>,
+
+    /// Names of the actor namespaces the worker can address.
+    #[serde(default)]
+    pub actor_namespaces: Vec<String>,
+
+    /// The actor this worker runs as, if any. Actor workers only receive requests for that actor.
+    #[serde(default)]
+    pub actor: Option<ActorAddress>,
+
+    /// The lease on `actor` this worker was created for. The runtime renews it while the worker
+    /// lives, and stops the worker once the actor has moved to another one.
+    #[serde(default)]
+    pub actor_lease: Option<ActorLeaseConfig>,
+
+    /// Service bindings, as a map from binding names to app ids.
+    #[serde(default)]
+    pub services: BTreeMap<String, String>,
+
+    /// Queue producer bindings, as a map from binding names to queue names.
+    #[serde(default)]
+    pub queue_producers: BTreeMap<String, String>,
+
+    /// Address of the scheduler service that routes requests to actors and service bindings.
+    /// Both are unavailable if unset.
+    #[serde(default)]
+    pub scheduler_service: Option<SocketAddr>,
+}
+
+/// Identifies an actor of an app.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct ActorAddress {
+    pub namespace: String,
+    pub id: String,
+}
+
+/// Ownership of an actor by a worker, stored in KV.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct ActorLease {
+    pub runtime: RuntimeId,
+    pub handle: WorkerHandle,
+
+    /// Expiration time, in milliseconds since the Unix epoch. Zero once released.
+    pub expires: i64,
+
+    /// Incremented every time the actor moves to a new worker.
+    #[serde(default)]
+    pub epoch: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct ActorLeaseConfig {
+    /// Epoch of the lease that names the worker.
+    pub epoch: u64,
+
+    /// Duration of the lease, in milliseconds.
+    pub lease_ms: u64,
+}
+
+/// A claim on a tick of a cron trigger, stored in KV.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct CronLease {
+    /// The claimed tick, in milliseconds since the Unix epoch.
+    pub scheduled_time: i64,
+
+    /// Expiration time of the claim, in milliseconds since the Unix epoch.
+    pub expires: i64,
+
+    /// Number of times the tick has been claimed.
+    pub attempts: u32,
+
+    /// Whether the event has been delivered.
+    pub completed: bool,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
@@ -17,6 +99,11 @@ pub struct ExecutorConfiguration {
     pub max_time_ms: u32,
     pub max_io_concurrency: u32,
     pub max_io_per_request: u32,
+
+    /// Wall-clock time a fetch task may keep running after its response is complete, for work
+    /// passed to `event.waitUntil()`.
+    #[serde(default)]
+    pub max_wait_until_ms: u32,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
@@ -32,6 +119,20 @@ pub struct RequestObject {
 
     #[serde(default)]
     pub body: HttpBody,
+
+    /// The worker that sent this request through a service binding or to an actor. Set by the
+    /// runtime of the caller, and `None` for requests from outside.
+    #[serde(default)]
+    pub caller: Option<CallerInfo>,
+}
+
+/// Identity of a worker that sent a request to another worker.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CallerInfo {
+    pub appid: String,
+
+    /// Number of requests between workers in the chain that led to this request, including it.
+    pub depth: u32,
 }
 
 #[derive(Default, Serialize, Deserialize, Clone, Debug)]
@@ -43,9 +144,51 @@ pub struct ResponseObject {
     pub body: HttpBody,
 }
 
+#[derive(Default, Serialize, Deserialize, Clone, Debug)]
+pub struct ScheduledEventObject {
+    /// Time of the tick, in milliseconds since the Unix epoch.
+    pub scheduled_time: i64,
+
+    /// The cron expression that triggered this event.
+    pub cron: String,
+}
+
+/// A message of a queue.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct QueueMessageObject {
+    pub id: String,
+
+    /// JSON-encoded body.
+    pub body: String,
+
+    /// Time the message was sent, in milliseconds since the Unix epoch.
+    pub timestamp: i64,
+
+    /// Number of failed deliveries so far.
+    pub attempts: u32,
+}
+
+/// A batch of messages delivered to a "queue" event.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct QueueBatchObject {
+    pub queue: String,
+    pub messages: Vec<QueueMessageObject>,
+}
+
+/// Outcome of a "queue" event. Messages not listed are acknowledged.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct QueueBatchResult {
+    /// Ids of the messages to deliver again later.
+    pub retry: Vec<String>,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum HttpBody {
     Binary(Vec<u8>),
+
+    /// A body that is sent in chunks. The value identifies the stream on the runtime. See
+    /// `RuntimeService::read_body_chunk` and `RuntimeService::write_body_chunk`.
+    Stream(String),
 }
 
 impl Default for HttpBody {
@@ -90,11 +233,67 @@ pub enum ExecutionError {
     /// An exception is thrown by the script during task execution.
     ///
     /// This does not terminate the worker, and the same `WorkerHandle` is still valid.
-    #[error("script throws exception")]
-    ScriptThrowsException(String),
+    #[error("script throws exception: {0}")]
+    ScriptThrowsException(ScriptException),
+
+    /// A promise is rejected without a handler during task execution, and no
+    /// `unhandledrejection` listener handles it.
+    ///
+    /// This does not terminate the worker.
+    #[error("unhandled promise rejection: {0}")]
+    UnhandledRejection(ScriptException),
+
+    /// The worker is still running a previous task, e.g. its `waitUntil()` work.
+    ///
+    /// This does not terminate the worker. The task was not started, so it can be sent to
+    /// another instance.
+    #[error("worker busy")]
+    WorkerBusy,
 }
 pub type ExecutionResult<T> = Result<T, ExecutionError>;
 
+/// An uncaught exception thrown by a script.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ScriptException {
+    /// `name` of the thrown error, e.g. `TypeError`. Empty if a non-error value is thrown.
+    pub name: String,
+
+    /// `message` of the thrown error, or the thrown value converted to a string.
+    pub message: String,
+
+    /// JavaScript stack trace, if available.
+    pub stack: Option<String>,
+
+    /// Script or module where the exception is thrown.
+    pub resource_name: Option<String>,
+
+    /// 1-based line number.
+    pub line: Option<u32>,
+
+    /// 1-based column number.
+    pub column: Option<u32>,
+}
+
+impl fmt::Display for ScriptException {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.name.is_empty() {
+            write!(f, "{}", self.message)?;
+        } else {
+            write!(f, "{}: {}", self.name, self.message)?;
+        }
+        if let Some(ref resource_name) = self.resource_name {
+            write!(f, " at {}", resource_name)?;
+            if let Some(line) = self.line {
+                write!(f, ":{}", line)?;
+                if let Some(column) = self.column {
+                    write!(f, ":{}", column)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
 impl ExecutionError {
     pub fn terminates_worker(&self) -> bool {
         match self {
@@ -103,11 +302,116 @@ impl ExecutionError {
             | ExecutionError::TimeLimitExceeded
             | ExecutionError::MemoryLimitExceeded
             | ExecutionError::IoTimeout => true,
-            ExecutionError::ScriptThrowsException(_) => false,
+            ExecutionError::ScriptThrowsException(_)
+            | ExecutionError::UnhandledRejection(_)
+            | ExecutionError::WorkerBusy => false,
         }
     }
 }
 
+/// Severity of a log record, from `console.debug` to `console.error`.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl Default for LogLevel {
+    fn default() -> Self {
+        LogLevel::Info
+    }
+}
+
+impl std::str::FromStr for LogLevel {
+    type Err = GenericError;
+
+    fn from_str(s: &str) -> GenericResult<Self> {
+        match s {
+            "debug" => Ok(LogLevel::Debug),
+            "info" | "log" => Ok(LogLevel::Info),
+            "warn" => Ok(LogLevel::Warn),
+            "error" => Ok(LogLevel::Error),
+            _ => Err(GenericError::Other(format!("bad log level: {}", s))),
+        }
+    }
+}
+
+/// A line of an app's log, stored as JSON.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct LogRecord {
+    #[serde(default)]
+    pub level: LogLevel,
+
+    /// Time of the record, in milliseconds since the Unix epoch.
+    #[serde(default)]
+    pub timestamp: i64,
+
+    /// Id of the event being handled when the record was written, if any.
+    #[serde(default)]
+    pub request_id: Option<String>,
+
+    /// Arguments formatted as text.
+    pub message: String,
+
+    /// Arguments as passed to the console, if they can be represented as JSON.
+    #[serde(default)]
+    pub args: Vec<serde_json::Value>,
+}
+
+impl LogRecord {
+    /// Decodes a stored record. Plain-text records written before log levels existed are read as
+    /// "info" records.
+    pub fn decode(text: &str) -> Self {
+        serde_json::from_str(text).unwrap_or_else(|_| LogRecord {
+            message: text.to_string(),
+            ..Default::default()
+        })
+    }
+}
+
+/// A long poll for new log records of a runtime.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct LogTailRequest {
+    /// Only return records of this app.
+    pub appid: Option<String>,
+
+    /// Only return records at this level or above.
+    pub level: Option<LogLevel>,
+
+    /// Return records after this cursor, from a previous `LogTailBatch`. `None` starts from the
+    /// next record.
+    pub cursor: Option<u64>,
+
+    /// Max number of records to return.
+    pub limit: u32,
+
+    /// Time to wait for new records if there are none.
+    pub timeout_ms: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct LogTailBatch {
+    /// Cursor for the next poll.
+    pub cursor: u64,
+
+    /// Number of records after the requested cursor that have been dropped from the runtime's
+    /// buffer before they could be returned.
+    pub lost: u64,
+
+    pub entries: Vec<LogTailEntry>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LogTailEntry {
+    pub appid: String,
+
+    /// The JSON-encoded `LogRecord`.
+    pub record: String,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, Error)]
 pub enum GenericError {
     #[error("io: {0}")]
@@ -150,6 +454,39 @@ pub type GenericResult<T> = Result<T, GenericError>;
 #[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub struct RuntimeId(pub String);
 
+/// Counters of the V8 code cache on a runtime.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct CodeCacheStats {
+    /// Compilations that used cached data.
+    pub hits: u64,
+
+    /// Compilations with no usable cached data, including rejected data.
+    pub misses: u64,
+
+    /// Cached data discarded because V8 rejected it, or because a cache file was produced by a
+    /// different V8 version.
+    pub rejections: u64,
+}
+
+/// Counters of the Cache API response cache on a runtime.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ResponseCacheStats {
+    /// Lookups that found a fresh response.
+    pub hits: u64,
+
+    /// Lookups that found nothing.
+    pub misses: u64,
+
+    /// Responses stored.
+    pub insertions: u64,
+
+    /// URLs evicted to make room for new responses.
+    pub evictions: u64,
+
+    /// Current size of the cache.
+    pub bytes: u64,
+}
+
 impl From<std::io::Error> for GenericError {
     fn from(other: std::io::Error) -> Self {
         Self::Io(format!("{:?}", other))